@@ -0,0 +1,894 @@
+//! Uploading whole folders at once, as a zip, tar or tar.gz archive.
+//!
+//! The archive is extracted on the spot into a staging dir next to it, and each of
+//! its files becomes an upload of its own, at its path inside of the archive. Entries
+//! that would end up outside of the destination are refused, as are archives that
+//! hold more than [[ReamioConfig::archive_max_bytes]] or
+//! [[ReamioConfig::archive_max_entries]], whatever their headers claim.
+
+use axum::{
+    Json,
+    body::Body,
+    extract::{Query, State},
+    http::StatusCode,
+};
+use flate2::read::{DeflateDecoder, GzDecoder};
+use futures::TryStreamExt;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+};
+use tokio::io::AsyncWriteExt;
+
+use crate::ReamioApp;
+use crate::auth::AuthUser;
+use crate::config::DuplicatePolicy;
+use crate::prelude::*;
+
+const ZIP_LOCAL_SIG: u32 = 0x04034b50;
+const ZIP_CENTRAL_SIG: u32 = 0x02014b50;
+const ZIP_EOCD_SIG: u32 = 0x06054b50;
+const ZIP64_LOCATOR_SIG: u32 = 0x07064b50;
+const ZIP64_EOCD_SIG: u32 = 0x06064b50;
+/// The end of central directory record, without its trailing comment.
+const ZIP_EOCD_LEN: u64 = 22;
+
+/// Why an archive was refused.
+#[derive(Debug)]
+enum ArchiveError {
+    /// Corrupt, unsupported, or trying to escape the destination
+    Invalid(String),
+    /// Over one of the limits
+    TooLarge(String),
+    /// Writing out the staged files failed, which is on us
+    IO(std::io::Error),
+}
+
+impl From<std::io::Error> for ArchiveError {
+    fn from(value: std::io::Error) -> Self {
+        Self::IO(value)
+    }
+}
+
+impl From<ArchiveError> for ReamioWebError {
+    fn from(value: ArchiveError) -> Self {
+        match value {
+            ArchiveError::Invalid(msg) => {
+                ReamioWebError::IncorrectArgs(msg, StatusCode::BAD_REQUEST)
+            }
+            ArchiveError::TooLarge(msg) => {
+                ReamioWebError::IncorrectArgs(msg, StatusCode::PAYLOAD_TOO_LARGE)
+            }
+            ArchiveError::IO(err) => err.into(),
+        }
+    }
+}
+
+fn invalid(err: impl std::fmt::Display) -> ArchiveError {
+    ArchiveError::Invalid(format!("archive could not be read: {err}"))
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ArchiveKind {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+impl ArchiveKind {
+    /// Tell archives apart from their first 262 bytes.
+    fn of(magic: &[u8]) -> Option<Self> {
+        if magic.starts_with(b"PK\x03\x04") || magic.starts_with(b"PK\x05\x06") {
+            Some(ArchiveKind::Zip)
+        } else if magic.starts_with(&[0x1f, 0x8b]) {
+            Some(ArchiveKind::TarGz)
+        } else if magic.get(257..262) == Some(b"ustar") {
+            Some(ArchiveKind::Tar)
+        } else {
+            None
+        }
+    }
+}
+
+/// A file taken out of an archive, waiting in the staging dir.
+#[derive(Debug)]
+struct ArchiveEntry {
+    staged: PathBuf,
+    /// Path inside of the archive, cleaned up by [[clean_entry_path]]
+    path: String,
+    /// sha256, in hex
+    hash: String,
+}
+
+/// Writes entries out to the staging dir, keeping count of the limits.
+#[derive(Debug)]
+struct Extractor {
+    staging: PathBuf,
+    max_bytes: u64,
+    max_entries: usize,
+    written: u64,
+    entries: Vec<ArchiveEntry>,
+    /// Names of entries that were left out, like `.DS_Store`
+    skipped: Vec<String>,
+}
+
+impl Extractor {
+    fn check_entries(&self, count: u64) -> Result<(), ArchiveError> {
+        if count > self.max_entries as u64 {
+            return Err(ArchiveError::TooLarge(format!(
+                "archive holds more than {} files",
+                self.max_entries
+            )));
+        }
+        Ok(())
+    }
+
+    fn check_bytes(&self, bytes: u64) -> Result<(), ArchiveError> {
+        if bytes > self.max_bytes {
+            return Err(ArchiveError::TooLarge(format!(
+                "archive holds more than {} bytes",
+                self.max_bytes
+            )));
+        }
+        Ok(())
+    }
+
+    /// Stage an entry, returning the crc32 of what was read. None when the entry was
+    /// skipped.
+    fn add(&mut self, name: &str, mut reader: impl Read) -> Result<Option<u32>, ArchiveError> {
+        let Some(path) = clean_entry_path(name)? else {
+            trace!(name, "entry skipped");
+            self.skipped.push(name.to_owned());
+            return Ok(None);
+        };
+        self.check_entries(self.entries.len() as u64 + 1)?;
+
+        let staged = self.staging.join(self.entries.len().to_string());
+        let mut out = File::create(&staged)?;
+        let mut sha = Sha256::new();
+        let mut crc = crc32fast::Hasher::new();
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let read = reader
+                .read(&mut buf)
+                .map_err(|err| invalid(format!("{name}: {err}")))?;
+            if read == 0 {
+                break;
+            }
+            // counted as it's inflated, so lying headers don't get past it
+            self.written += read as u64;
+            self.check_bytes(self.written)?;
+            out.write_all(&buf[..read])?;
+            sha.update(&buf[..read]);
+            crc.update(&buf[..read]);
+        }
+        out.sync_data()?;
+
+        let hash = sha
+            .finalize()
+            .iter()
+            .map(|x| format!("{x:02x}"))
+            .collect::<String>();
+        trace!(name, path, hash, "entry staged");
+        self.entries.push(ArchiveEntry { staged, path, hash });
+        Ok(Some(crc.finalize()))
+    }
+}
+
+/// Turn the name of an archive entry into a path relative to the destination, or
+/// None for junk that's better left out, like `__MACOSX/` and dotfiles. Names going
+/// up out of the archive are refused outright.
+fn clean_entry_path(name: &str) -> Result<Option<String>, ArchiveError> {
+    let items = name
+        .split(['/', '\\'])
+        .filter(|x| !x.is_empty() && *x != ".")
+        .collect::<Vec<_>>();
+    if items.iter().any(|x| *x == ".." || x.contains('\0')) {
+        return Err(ArchiveError::Invalid(format!(
+            "entry {name} points outside of the archive"
+        )));
+    }
+    if items.is_empty() || items.iter().any(|x| x.starts_with('.') || *x == "__MACOSX") {
+        return Ok(None);
+    }
+    Ok(Some(items.join("/")))
+}
+
+/// Little endian number of `len` bytes at `at`.
+fn le(buf: &[u8], at: usize, len: usize) -> Result<u64, ArchiveError> {
+    let bytes = buf
+        .get(at..at + len)
+        .ok_or_else(|| invalid("truncated zip record"))?;
+    Ok(bytes
+        .iter()
+        .rev()
+        .fold(0, |acc, x| (acc << 8) | u64::from(*x)))
+}
+
+/// Fill in whichever of `values` didn't fit in their zip record, and are 0xFFFF_FFFF
+/// there, from the zip64 extra field in `extra`, which holds them in this order.
+fn zip64_extra<const N: usize>(extra: &[u8], values: [&mut u64; N]) -> Result<(), ArchiveError> {
+    let mut field = 0;
+    while field + 4 <= extra.len() {
+        let id = le(extra, field, 2)?;
+        let field_len = le(extra, field + 2, 2)? as usize;
+        if id == 0x0001 {
+            let mut value = field + 4;
+            for x in values {
+                if *x == 0xFFFF_FFFF {
+                    *x = le(extra, value, 8)?;
+                    value += 8;
+                }
+            }
+            break;
+        }
+        field += 4 + field_len;
+    }
+    Ok(())
+}
+
+/// A file in the central directory of a zip.
+#[derive(Debug)]
+struct ZipEntry {
+    name: String,
+    flags: u64,
+    method: u64,
+    crc: u32,
+    compressed: u64,
+    size: u64,
+    offset: u64,
+    symlink: bool,
+}
+
+/// Read the central directory of a zip, PKWARE APPNOTE 6.3 section 4.3, including
+/// the zip64 records.
+fn zip_directory(file: &mut File) -> Result<Vec<ZipEntry>, ArchiveError> {
+    let len = file.metadata()?.len();
+    let tail_len = len.min(ZIP_EOCD_LEN + u64::from(u16::MAX));
+    file.seek(SeekFrom::Start(len - tail_len))?;
+    let mut tail = vec![];
+    file.take(tail_len).read_to_end(&mut tail)?;
+    let eocd = tail
+        .windows(4)
+        .rposition(|x| x == ZIP_EOCD_SIG.to_le_bytes())
+        .ok_or_else(|| invalid("no end of central directory"))?;
+
+    let record = &tail[eocd..];
+    let (mut count, mut cd_len, mut cd_offset) =
+        (le(record, 10, 2)?, le(record, 12, 4)?, le(record, 16, 4)?);
+    if count == 0xFFFF || cd_len == 0xFFFF_FFFF || cd_offset == 0xFFFF_FFFF {
+        // zip64, where the real numbers are in a record the locator points to
+        let locator = eocd
+            .checked_sub(20)
+            .map(|x| &tail[x..eocd])
+            .filter(|x| le(x, 0, 4).is_ok_and(|x| x == u64::from(ZIP64_LOCATOR_SIG)))
+            .ok_or_else(|| invalid("no zip64 end of central directory locator"))?;
+        file.seek(SeekFrom::Start(le(locator, 8, 8)?))?;
+        let mut record = vec![];
+        file.take(56).read_to_end(&mut record)?;
+        if le(&record, 0, 4)? != u64::from(ZIP64_EOCD_SIG) {
+            return Err(invalid("no zip64 end of central directory"));
+        }
+        (count, cd_len, cd_offset) = (
+            le(&record, 32, 8)?,
+            le(&record, 40, 8)?,
+            le(&record, 48, 8)?,
+        );
+    }
+    trace!(count, cd_len, cd_offset, "zip central directory");
+    if cd_offset.saturating_add(cd_len) > len {
+        return Err(invalid("central directory past the end of the file"));
+    }
+
+    file.seek(SeekFrom::Start(cd_offset))?;
+    let mut cd = vec![];
+    file.take(cd_len).read_to_end(&mut cd)?;
+    let mut entries = vec![];
+    let mut at = 0;
+    while entries.len() as u64 != count {
+        if le(&cd, at, 4)? != u64::from(ZIP_CENTRAL_SIG) {
+            return Err(invalid("bad central directory entry"));
+        }
+        let name_len = le(&cd, at + 28, 2)? as usize;
+        let extra_len = le(&cd, at + 30, 2)? as usize;
+        let comment_len = le(&cd, at + 32, 2)? as usize;
+        let name = cd
+            .get(at + 46..at + 46 + name_len)
+            .ok_or_else(|| invalid("truncated zip record"))?;
+        let extra = cd
+            .get(at + 46 + name_len..at + 46 + name_len + extra_len)
+            .ok_or_else(|| invalid("truncated zip record"))?;
+        let mut entry = ZipEntry {
+            name: String::from_utf8_lossy(name).into_owned(),
+            flags: le(&cd, at + 8, 2)?,
+            method: le(&cd, at + 10, 2)?,
+            crc: le(&cd, at + 16, 4)? as u32,
+            compressed: le(&cd, at + 20, 4)?,
+            size: le(&cd, at + 24, 4)?,
+            offset: le(&cd, at + 42, 4)?,
+            // made on unix, with a mode saying so
+            symlink: le(&cd, at + 5, 1)? == 3
+                && (le(&cd, at + 38, 4)? >> 16) & 0o170000 == 0o120000,
+        };
+
+        zip64_extra(
+            extra,
+            [&mut entry.size, &mut entry.compressed, &mut entry.offset],
+        )?;
+        entries.push(entry);
+        at += 46 + name_len + extra_len + comment_len;
+    }
+    Ok(entries)
+}
+
+fn extract_zip(mut file: File, ex: &mut Extractor) -> Result<(), ArchiveError> {
+    let entries = zip_directory(&mut file)?;
+    // the declared sizes turn away the obvious bombs before anything is inflated
+    ex.check_entries(entries.len() as u64)?;
+    let declared = entries
+        .iter()
+        .try_fold(0u64, |acc, x| acc.checked_add(x.size))
+        .ok_or_else(|| ArchiveError::TooLarge("archive declares impossible sizes".to_owned()))?;
+    ex.check_bytes(declared)?;
+
+    for entry in entries {
+        if entry.name.ends_with('/') || entry.symlink {
+            trace!(?entry, "not a file");
+            continue;
+        }
+        if entry.flags & 1 != 0 || !matches!(entry.method, 0 | 8) {
+            debug!(?entry, "encrypted or compressed with an unsupported method");
+            ex.skipped.push(entry.name);
+            continue;
+        }
+
+        file.seek(SeekFrom::Start(entry.offset))?;
+        let mut header = vec![];
+        (&mut file).take(30).read_to_end(&mut header)?;
+        if le(&header, 0, 4)? != u64::from(ZIP_LOCAL_SIG) {
+            return Err(invalid(format!("bad local header for {}", entry.name)));
+        }
+        let (name_len, extra_len) = (le(&header, 26, 2)?, le(&header, 28, 2)?);
+        let mut rest = vec![];
+        (&mut file)
+            .take(name_len + extra_len)
+            .read_to_end(&mut rest)?;
+        let (name, extra) = rest.split_at(rest.len().min(name_len as usize));
+
+        // only the central directory is read for what to extract, so a local header
+        // telling a different story is a crafted archive, or a broken one. with a data
+        // descriptor, the crc and sizes only come after the data
+        let (mut size, mut compressed) = (le(&header, 22, 4)?, le(&header, 18, 4)?);
+        zip64_extra(extra, [&mut size, &mut compressed])?;
+        let described = entry.flags & 0x8 != 0;
+        if String::from_utf8_lossy(name) != entry.name
+            || le(&header, 8, 2)? != entry.method
+            || !described
+                && (le(&header, 14, 4)? != u64::from(entry.crc)
+                    || size != entry.size
+                    || compressed != entry.compressed)
+        {
+            return Err(invalid(format!(
+                "local header for {} doesn't match the central directory",
+                entry.name
+            )));
+        }
+
+        let data = entry
+            .offset
+            .checked_add(30 + name_len + extra_len)
+            .ok_or_else(|| invalid(format!("bad local header offset for {}", entry.name)))?;
+        file.seek(SeekFrom::Start(data))?;
+        let raw = (&mut file).take(entry.compressed);
+        let written = ex.written;
+        let crc = match entry.method {
+            8 => ex.add(&entry.name, DeflateDecoder::new(raw))?,
+            _ => ex.add(&entry.name, raw)?,
+        };
+        if crc.is_some_and(|x| x != entry.crc || ex.written - written != entry.size) {
+            return Err(invalid(format!("{} is corrupt", entry.name)));
+        }
+    }
+    Ok(())
+}
+
+fn extract_tar(reader: impl Read, ex: &mut Extractor) -> Result<(), ArchiveError> {
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries().map_err(invalid)? {
+        let entry = entry.map_err(invalid)?;
+        // links could point anywhere, and dirs come with the files in them
+        if !entry.header().entry_type().is_file() {
+            trace!(entry_type = ?entry.header().entry_type(), "not a file");
+            continue;
+        }
+        let name = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+        ex.add(&name, entry)?;
+    }
+    Ok(())
+}
+
+/// Extract the archive at `path` into `ex`.
+#[tracing::instrument(skip(ex))]
+fn extract(path: &str, ex: &mut Extractor) -> Result<(), ArchiveError> {
+    let mut file = File::open(path)?;
+    let mut magic = vec![];
+    (&mut file).take(262).read_to_end(&mut magic)?;
+    file.rewind()?;
+    match ArchiveKind::of(&magic) {
+        Some(ArchiveKind::Zip) => extract_zip(file, ex),
+        Some(ArchiveKind::Tar) => extract_tar(file, ex),
+        Some(ArchiveKind::TarGz) => extract_tar(GzDecoder::new(file), ex),
+        None => Err(ArchiveError::Invalid(
+            "not a zip, tar or tar.gz archive".to_owned(),
+        )),
+    }
+}
+
+/// [[ArchiveArgs]]
+/// Query arguments for [[upload_archive]]
+///
+/// Fields:
+/// - path: Option<String>
+///   Folder the archive is extracted into, following the path semantics of
+///   [[UploadArgs]]. Defaults to the root of the user dir.
+/// - on_duplicate: Option<DuplicatePolicy>
+///   As in [[UploadArgs]], for every file in the archive.
+#[derive(Deserialize, Debug)]
+pub struct ArchiveArgs {
+    pub path: Option<String>,
+    pub on_duplicate: Option<DuplicatePolicy>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ArchiveReturn {
+    /// Bytes extracted
+    written: u64,
+    /// Paths of the files queued for processing
+    queued: Vec<String>,
+    /// Names of the entries that were left out
+    skipped: Vec<String>,
+}
+
+/// Upload a zip, tar or tar.gz archive, queueing every file in it as if it was
+/// uploaded on its own at its path inside of the archive.
+///
+/// Path: POST /api/upload/archive?path={}&on_duplicate={}
+///
+/// Arguments:
+/// - State(state): State<ReamioApp>
+///   Server state. [[ReamioApp]].
+/// - user: AuthUser
+///   The user the files are assigned to. [[AuthUser]].
+/// - Query(args): Query<ArchiveArgs>
+///   See [[ArchiveArgs]].
+/// - body: Body
+///   The archive, in its entirety.
+#[tracing::instrument(skip(body))]
+pub async fn upload_archive(
+    State(state): State<ReamioApp>,
+    user: AuthUser,
+    Query(args): Query<ArchiveArgs>,
+    body: Body,
+) -> Result<Json<ArchiveReturn>, ReamioWebError> {
+    let dest = args.path.unwrap_or_default();
+    let dest = dest.trim_end_matches('/');
+    let on_duplicate = args.on_duplicate.unwrap_or(state.config.on_duplicate);
+    let max_bytes = state.config.archive_max_bytes;
+
+    // not named like an upload, so the processor can't mistake it for one
+    let mut nonce = [0u8; 8];
+    rand::rngs::OsRng.fill_bytes(&mut nonce);
+    let nonce = nonce.iter().map(|x| format!("{x:02x}")).collect::<String>();
+    let archive_path = format!("./devdir/temp/archive-{nonce}");
+    let staging = PathBuf::from(format!("{archive_path}.d"));
+    debug!(archive_path, "receiving archive");
+
+    let received: Result<(), ReamioWebError> = async {
+        let mut file = tokio::fs::File::create(&archive_path).await?;
+        let mut body = body.into_data_stream();
+        let mut size_acc = 0u64;
+        while let Some(chunk) = body.try_next().await? {
+            size_acc += chunk.len() as u64;
+            if size_acc > max_bytes {
+                return Err(ArchiveError::TooLarge(format!(
+                    "archive is larger than {max_bytes} bytes"
+                ))
+                .into());
+            }
+            file.write_all(&chunk).await?;
+        }
+        file.sync_data().await?;
+        trace!(size_acc, "archive received");
+        tokio::fs::create_dir_all(&staging).await?;
+        Ok(())
+    }
+    .await;
+
+    let extracted = match received {
+        Ok(()) => {
+            let mut ex = Extractor {
+                staging: staging.clone(),
+                max_bytes,
+                max_entries: state.config.archive_max_entries,
+                written: 0,
+                entries: vec![],
+                skipped: vec![],
+            };
+            let archive_path = archive_path.clone();
+            tokio::task::spawn_blocking(move || extract(&archive_path, &mut ex).map(|()| ex))
+                .await?
+                .map_err(ReamioWebError::from)
+        }
+        Err(err) => Err(err),
+    };
+    if let Err(err) = tokio::fs::remove_file(&archive_path).await {
+        warn!(archive_path, ?err, "could not remove archive");
+    }
+    let ex = match extracted {
+        Ok(x) => x,
+        Err(err) => {
+            debug!(?err, "archive refused");
+            drop(tokio::fs::remove_dir_all(&staging).await);
+            return Err(err);
+        }
+    };
+    debug!(
+        written = ex.written,
+        entries = ex.entries.len(),
+        skipped = ex.skipped.len(),
+        "archive extracted"
+    );
+
+    // every entry becomes an upload, all or none of them
+    let queued: Result<Vec<String>, ReamioWebError> = async {
+        let mut txn = state.user_db.begin_with("BEGIN IMMEDIATE").await?;
+        let mut queued = vec![];
+        for entry in &ex.entries {
+            let path = format!("{dest}/{}", entry.path);
+            let fid: i64 = sqlx::query(
+                "INSERT INTO uploaded_files (orig_path, user, fid, on_duplicate, hash, created)
+                     VALUES ($1, $2, NULL, $3, $4, unixepoch()) RETURNING fid;",
+            )
+            .bind(&path)
+            .bind(&user.username)
+            .bind(on_duplicate.as_str())
+            .bind(&entry.hash)
+            .fetch_one(&mut *txn)
+            .await?
+            .get("fid");
+            tokio::fs::rename(&entry.staged, format!("./devdir/temp/{fid}")).await?;
+            trace!(fid, path, "entry queued");
+            queued.push(path);
+        }
+        txn.commit().await?;
+        Ok(queued)
+    }
+    .await;
+    drop(tokio::fs::remove_dir_all(&staging).await);
+    let queued = queued?;
+
+    if !queued.is_empty() {
+        wake(&state.populate_mdata_waker, PopulateMetadata);
+    }
+    Ok(Json(ArchiveReturn {
+        written: ex.written,
+        queued,
+        skipped: ex.skipped,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{Compression, write::DeflateEncoder};
+
+    /// A zip entry as [[zip]] writes it. The central directory and the local header
+    /// both come from this, with `local_name` and `local_size` to make them disagree.
+    #[derive(Clone)]
+    struct TestEntry {
+        name: String,
+        local_name: String,
+        method: u16,
+        /// The bytes as stored, compressed or not
+        stored: Vec<u8>,
+        crc: u32,
+        size: u64,
+        local_size: u64,
+        /// Unix mode, for the external attributes
+        mode: u32,
+    }
+
+    fn stored(name: &str, data: &[u8]) -> TestEntry {
+        TestEntry {
+            name: name.to_owned(),
+            local_name: name.to_owned(),
+            method: 0,
+            stored: data.to_vec(),
+            crc: crc32fast::hash(data),
+            size: data.len() as u64,
+            local_size: data.len() as u64,
+            mode: 0o100644,
+        }
+    }
+
+    fn deflated(name: &str, data: &[u8]) -> TestEntry {
+        let mut enc = DeflateEncoder::new(vec![], Compression::default());
+        enc.write_all(data).unwrap();
+        TestEntry {
+            method: 8,
+            stored: enc.finish().unwrap(),
+            ..stored(name, data)
+        }
+    }
+
+    /// Sizes that don't fit in 32 bits go in a zip64 extra field.
+    fn size_fields(size: u64, compressed: u64) -> (u32, u32, Vec<u8>) {
+        let mut extra = vec![];
+        let mut field = |x: u64| match u32::try_from(x) {
+            Ok(x) if x != u32::MAX => x,
+            _ => {
+                extra.extend(x.to_le_bytes());
+                u32::MAX
+            }
+        };
+        let (size, compressed) = (field(size), field(compressed));
+        if !extra.is_empty() {
+            let mut head = vec![1, 0];
+            head.extend((extra.len() as u16).to_le_bytes());
+            extra.splice(0..0, head);
+        }
+        (size, compressed, extra)
+    }
+
+    fn zip(entries: &[TestEntry]) -> Vec<u8> {
+        let mut out = vec![];
+        let mut cd = vec![];
+        for x in entries {
+            let offset = out.len() as u32;
+            let compressed = x.stored.len() as u64;
+
+            let (size, compressed32, extra) = size_fields(x.local_size, compressed);
+            out.extend(ZIP_LOCAL_SIG.to_le_bytes());
+            out.extend([20, 0, 0, 0]);
+            out.extend(x.method.to_le_bytes());
+            out.extend([0; 4]);
+            out.extend(x.crc.to_le_bytes());
+            out.extend(compressed32.to_le_bytes());
+            out.extend(size.to_le_bytes());
+            out.extend((x.local_name.len() as u16).to_le_bytes());
+            out.extend((extra.len() as u16).to_le_bytes());
+            out.extend(x.local_name.as_bytes());
+            out.extend(&extra);
+            out.extend(&x.stored);
+
+            let (size, compressed32, extra) = size_fields(x.size, compressed);
+            cd.extend(ZIP_CENTRAL_SIG.to_le_bytes());
+            // made by unix
+            cd.extend([20, 3, 20, 0, 0, 0]);
+            cd.extend(x.method.to_le_bytes());
+            cd.extend([0; 4]);
+            cd.extend(x.crc.to_le_bytes());
+            cd.extend(compressed32.to_le_bytes());
+            cd.extend(size.to_le_bytes());
+            cd.extend((x.name.len() as u16).to_le_bytes());
+            cd.extend((extra.len() as u16).to_le_bytes());
+            // comment length, disk number, internal attributes
+            cd.extend([0; 6]);
+            cd.extend((x.mode << 16).to_le_bytes());
+            cd.extend(offset.to_le_bytes());
+            cd.extend(x.name.as_bytes());
+            cd.extend(&extra);
+        }
+        let cd_offset = out.len() as u32;
+        out.extend(&cd);
+        out.extend(ZIP_EOCD_SIG.to_le_bytes());
+        out.extend([0; 4]);
+        out.extend((entries.len() as u16).to_le_bytes());
+        out.extend((entries.len() as u16).to_le_bytes());
+        out.extend((cd.len() as u32).to_le_bytes());
+        out.extend(cd_offset.to_le_bytes());
+        out.extend([0; 2]);
+        out
+    }
+
+    /// Removes the dir of a test once it's done.
+    struct Scratch(PathBuf);
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            drop(std::fs::remove_dir_all(&self.0));
+        }
+    }
+
+    /// Extract `archive` with the given limits, in a scratch dir of its own, which
+    /// lasts as long as the returned [[Scratch]].
+    fn run(
+        test: &str,
+        archive: &[u8],
+        max_bytes: u64,
+        max_entries: usize,
+    ) -> (Result<(), ArchiveError>, Extractor, Scratch) {
+        let dir =
+            std::env::temp_dir().join(format!("reamio-archive-{}-{test}", std::process::id()));
+        drop(std::fs::remove_dir_all(&dir));
+        let staging = dir.join("staging");
+        std::fs::create_dir_all(&staging).unwrap();
+        let path = dir.join("archive");
+        std::fs::write(&path, archive).unwrap();
+        let mut ex = Extractor {
+            staging,
+            max_bytes,
+            max_entries,
+            written: 0,
+            entries: vec![],
+            skipped: vec![],
+        };
+        let ret = extract(path.to_str().unwrap(), &mut ex);
+        (ret, ex, Scratch(dir))
+    }
+
+    #[test]
+    fn extracts_stored_and_deflated() {
+        let song = b"not really audio, ".repeat(100);
+        let archive = zip(&[
+            stored("Album/01.mp3", &song),
+            deflated("Album/02.flac", &song),
+            stored("Album/", b""),
+        ]);
+        let (ret, ex, _dir) = run("ok", &archive, 1 << 20, 10);
+        ret.unwrap();
+        let paths = ex
+            .entries
+            .iter()
+            .map(|x| x.path.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, ["Album/01.mp3", "Album/02.flac"]);
+        for entry in &ex.entries {
+            assert_eq!(std::fs::read(&entry.staged).unwrap(), song);
+        }
+        assert_eq!(ex.written, 2 * song.len() as u64);
+    }
+
+    #[test]
+    fn clean_entry_path_refuses_escapes() {
+        for name in [
+            "../x.mp3",
+            "a/../../x.mp3",
+            "a\\..\\x.mp3",
+            "a/..",
+            "a/b\0.mp3",
+        ] {
+            assert!(
+                matches!(clean_entry_path(name), Err(ArchiveError::Invalid(_))),
+                "{name}"
+            );
+        }
+        // absolute names end up inside of the destination all the same
+        for (name, clean) in [
+            ("/etc/x.mp3", "etc/x.mp3"),
+            ("\\\\server\\x.mp3", "server/x.mp3"),
+            ("./a//b/./c.mp3", "a/b/c.mp3"),
+        ] {
+            assert_eq!(clean_entry_path(name).unwrap().as_deref(), Some(clean));
+        }
+        for name in [
+            "__MACOSX/a/._x.mp3",
+            "a/.DS_Store",
+            ".hidden/x.mp3",
+            "/",
+            ".",
+        ] {
+            assert_eq!(clean_entry_path(name).unwrap(), None, "{name}");
+        }
+    }
+
+    #[test]
+    fn refuses_entries_escaping() {
+        let archive = zip(&[stored("a.mp3", b"a"), stored("../../b.mp3", b"b")]);
+        let (ret, ex, _dir) = run("escape", &archive, 1 << 20, 10);
+        assert!(matches!(ret, Err(ArchiveError::Invalid(_))));
+        assert_eq!(ex.entries.len(), 1);
+    }
+
+    #[test]
+    fn skips_symlinks() {
+        let link = TestEntry {
+            mode: 0o120777,
+            ..stored("link.mp3", b"/etc/passwd")
+        };
+        let archive = zip(&[link, stored("a.mp3", b"a")]);
+        let (ret, ex, _dir) = run("symlink", &archive, 1 << 20, 10);
+        ret.unwrap();
+        let paths = ex
+            .entries
+            .iter()
+            .map(|x| x.path.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, ["a.mp3"]);
+    }
+
+    #[test]
+    fn refuses_wrong_sizes() {
+        let data = b"twelve bytes";
+        // more data than the central directory says
+        let short = TestEntry {
+            size: 4,
+            local_size: 4,
+            ..deflated("a.mp3", data)
+        };
+        let (ret, _, _dir) = run("short", &zip(&[short]), 1 << 20, 10);
+        assert!(matches!(ret, Err(ArchiveError::Invalid(msg)) if msg.contains("corrupt")));
+
+        // a local header that disagrees with the central directory
+        let local = TestEntry {
+            local_size: 2,
+            ..stored("a.mp3", data)
+        };
+        let (ret, _, _dir) = run("local size", &zip(&[local]), 1 << 20, 10);
+        assert!(matches!(ret, Err(ArchiveError::Invalid(msg)) if msg.contains("doesn't match")));
+        let renamed = TestEntry {
+            local_name: "b.mp3".to_owned(),
+            ..stored("a.mp3", data)
+        };
+        let (ret, _, _dir) = run("local name", &zip(&[renamed]), 1 << 20, 10);
+        assert!(matches!(ret, Err(ArchiveError::Invalid(msg)) if msg.contains("doesn't match")));
+    }
+
+    #[test]
+    fn refuses_crc_mismatch() {
+        let corrupt = TestEntry {
+            crc: 0xdead_beef,
+            ..deflated("a.mp3", b"some audio")
+        };
+        let (ret, _, _dir) = run("crc", &zip(&[corrupt]), 1 << 20, 10);
+        assert!(matches!(ret, Err(ArchiveError::Invalid(msg)) if msg.contains("corrupt")));
+    }
+
+    #[test]
+    fn enforces_entry_limit() {
+        let archive = zip(&[
+            stored("a.mp3", b"a"),
+            stored("b.mp3", b"b"),
+            stored("c.mp3", b"c"),
+        ]);
+        let (ret, ex, _dir) = run("entries", &archive, 1 << 20, 2);
+        assert!(matches!(ret, Err(ArchiveError::TooLarge(_))));
+        assert!(ex.entries.is_empty());
+    }
+
+    #[test]
+    fn enforces_byte_limit() {
+        let data = vec![0; 4096];
+        // as declared
+        let (ret, ex, _dir) = run("declared", &zip(&[deflated("a.mp3", &data)]), 1000, 10);
+        assert!(matches!(ret, Err(ArchiveError::TooLarge(_))));
+        assert_eq!(ex.written, 0);
+
+        // and as inflated, for a bomb claiming to be small
+        let bomb = TestEntry {
+            size: 10,
+            local_size: 10,
+            ..deflated("a.mp3", &data)
+        };
+        let (ret, ex, _dir) = run("inflated", &zip(&[bomb]), 1000, 10);
+        assert!(matches!(ret, Err(ArchiveError::TooLarge(_))));
+        assert!(ex.written <= 1000 + 64 * 1024);
+    }
+
+    #[test]
+    fn refuses_overflowing_sizes() {
+        let huge = TestEntry {
+            size: u64::MAX - 1,
+            local_size: u64::MAX - 1,
+            ..stored("a.mp3", b"a")
+        };
+        let archive = zip(&[huge.clone(), huge]);
+        let (ret, ex, _dir) = run("overflow", &archive, u64::MAX, 10);
+        assert!(matches!(ret, Err(ArchiveError::TooLarge(msg)) if msg.contains("impossible")));
+        assert_eq!(ex.written, 0);
+    }
+}
@@ -0,0 +1,200 @@
+//! Cover art: keeping the images found in uploads, and serving them resized.
+//!
+//! Images are stored once per user, named after the sha256 of their contents, under
+//! `u/<user>/art/`. Tracks point at the art embedded in them, albums at the first art
+//! of their tracks (or the cover image in their folder), and folders at their cover
+//! image, see [[is_sidecar_name]].
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::Response,
+};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{io::Cursor, path::PathBuf};
+
+use crate::ReamioApp;
+use crate::auth::AuthUser;
+use crate::prelude::*;
+use crate::stream::serve_file;
+
+/// Where resized art is kept, one dir per user.
+const CACHE_DIR: &str = "./devdir/cache/art";
+
+/// Sizes thumbnails are made in. Requests are rounded up to the next one, so clients
+/// asking for every size under the sun can't fill up the cache.
+const THUMB_SIZES: [u32; 5] = [64, 128, 256, 512, 1024];
+
+/// File names, without their extension, that make an image the cover of the folder
+/// it's in.
+const SIDECAR_NAMES: [&str; 5] = ["cover", "folder", "front", "album", "albumart"];
+
+/// Whether an uploaded file name is that of a folder's cover image, like `cover.jpg`
+/// or `Folder.png`.
+pub fn is_sidecar_name(fname: &str) -> bool {
+    let Some((stem, ext)) = fname.rsplit_once('.') else {
+        return false;
+    };
+    ["jpg", "jpeg", "png"]
+        .iter()
+        .any(|x| ext.eq_ignore_ascii_case(x))
+        && SIDECAR_NAMES.iter().any(|x| stem.eq_ignore_ascii_case(x))
+}
+
+/// Store an image in the art store, returning its art id. Images that are already
+/// there are not stored twice.
+#[tracing::instrument(skip(db, data), fields(len = data.len()))]
+pub async fn store_art(
+    db: &mut sqlx::SqliteConnection,
+    user: &str,
+    data: &[u8],
+) -> Result<i64, ReamioProcessingErrorInternal> {
+    let format = image::guess_format(data)?;
+    let mime = format.to_mime_type();
+    let reader = image::ImageReader::with_format(Cursor::new(data), format);
+    let (width, height) = reader.into_dimensions()?;
+
+    let hash = Sha256::digest(data)
+        .iter()
+        .map(|x| format!("{x:02x}"))
+        .collect::<String>();
+    let dir = format!("./devdir/u/{user}/art");
+    let path = format!("{dir}/{hash}");
+    if !tokio::fs::try_exists(&path).await? {
+        tokio::fs::create_dir_all(&dir).await?;
+        // written aside first, so a crash never leaves half an image under its hash
+        let part = format!("{path}.part");
+        tokio::fs::write(&part, data).await?;
+        tokio::fs::rename(&part, &path).await?;
+        debug!(hash, "art stored");
+    }
+
+    sqlx::query(
+        "INSERT INTO art (hash, mime, width, height) VALUES ($1, $2, $3, $4)
+             ON CONFLICT (hash) DO NOTHING;",
+    )
+    .bind(&hash)
+    .bind(mime)
+    .bind(width)
+    .bind(height)
+    .execute(&mut *db)
+    .await?;
+    let id = sqlx::query("SELECT id FROM art WHERE hash = $1;")
+        .bind(&hash)
+        .fetch_one(&mut *db)
+        .await?
+        .get::<i64, _>("id");
+    trace!(id, mime, width, height, "art row");
+    Ok(id)
+}
+
+/// [[ArtArgs]]
+/// Query arguments for [[get_art]]
+///
+/// Fields:
+/// - size: Option<u32>
+///   Longest side of the image in pixels, rounded up to 64, 128, 256, 512 or 1024.
+///   Without it, or when the image isn't any bigger, the original is served.
+#[derive(Deserialize, Debug)]
+pub struct ArtArgs {
+    pub size: Option<u32>,
+}
+
+/// Serve a piece of cover art, optionally scaled down. Scaled images are cached.
+///
+/// Path: GET /api/art/{id}?size={}
+///
+/// Arguments:
+/// - State(state): State<ReamioApp>
+///   Server state. [[ReamioApp]].
+/// - user: AuthUser
+///   The user who owns the art. [[AuthUser]].
+/// - Path(id): Path<i64>
+///   Art id, as found on tracks and albums.
+/// - Query(args): Query<ArtArgs>
+///   See [[ArtArgs]].
+/// - headers: HeaderMap
+///   Request headers, for conditional requests.
+#[tracing::instrument(skip(headers))]
+pub async fn get_art(
+    State(state): State<ReamioApp>,
+    user: AuthUser,
+    Path(id): Path<i64>,
+    Query(args): Query<ArtArgs>,
+    headers: HeaderMap,
+) -> Result<Response, ReamioWebError> {
+    serve_art(&state, &user.username, id, args.size, &headers).await
+}
+
+/// Serve art `id` of `user`, scaled to fit `size` if given. See [[get_art]].
+#[tracing::instrument(skip(state, headers))]
+pub async fn serve_art(
+    state: &ReamioApp,
+    user: &str,
+    id: i64,
+    size: Option<u32>,
+    headers: &HeaderMap,
+) -> Result<Response, ReamioWebError> {
+    let mut db = fetch_users_music_db(state.music_dbs.clone(), user).await?;
+    let row = sqlx::query("SELECT hash, mime, width, height FROM art WHERE id = $1;")
+        .bind(id)
+        .fetch_optional(&mut *db)
+        .await?
+        .ok_or_else(|| {
+            ReamioWebError::IncorrectArgs("no such art exists".to_owned(), StatusCode::NOT_FOUND)
+        })?;
+    drop(db);
+    let hash: String = row.get("hash");
+    let mime: String = row.get("mime");
+    let longest = row.get::<u32, _>("width").max(row.get("height"));
+
+    // the hash is hex, so it's as safe in a path as the ids are
+    let original = format!("./devdir/u/{user}/art/{hash}");
+    let size = size.map(|size| {
+        THUMB_SIZES
+            .into_iter()
+            .find(|x| *x >= size)
+            .unwrap_or(THUMB_SIZES[THUMB_SIZES.len() - 1])
+    });
+    let Some(size) = size.filter(|x| *x < longest) else {
+        return serve_file(&original, &mime, headers).await;
+    };
+
+    // png keeps its transparency, everything else becomes jpeg
+    let (format, mime) = match mime.as_str() {
+        "image/png" => (image::ImageFormat::Png, "image/png"),
+        _ => (image::ImageFormat::Jpeg, "image/jpeg"),
+    };
+    let cache_dir = PathBuf::from(format!("{CACHE_DIR}/{user}"));
+    let cached = cache_dir.join(format!("{hash}-{size}.{}", format.extensions_str()[0]));
+    if !tokio::fs::try_exists(&cached).await? {
+        tokio::fs::create_dir_all(&cache_dir).await?;
+        let part = cached.with_extension("part");
+        let to = part.clone();
+        tokio::task::spawn_blocking(move || {
+            // stored art has no extension to go by
+            let img = image::ImageReader::open(&original)?
+                .with_guessed_format()?
+                .decode()?
+                .thumbnail(size, size);
+            let img = match format {
+                image::ImageFormat::Png => img,
+                // jpeg has no alpha channel
+                _ => img.into_rgb8().into(),
+            };
+            img.save_with_format(&to, format)
+        })
+        .await?
+        .map_err(|err| {
+            error!(?err, "while resizing art");
+            ReamioWebError::IncorrectArgs(
+                "art could not be resized".to_owned(),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+        })?;
+        tokio::fs::rename(&part, &cached).await?;
+        debug!(?cached, "thumbnail made");
+    }
+    serve_file(&cached.to_string_lossy(), mime, headers).await
+}
@@ -0,0 +1,263 @@
+use argon2::{
+    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+    password_hash::{SaltString, rand_core::OsRng},
+};
+use axum::{
+    Json,
+    extract::{FromRequestParts, State},
+    http::{StatusCode, header, request::Parts},
+};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::OnceLock;
+
+use crate::ReamioApp;
+use crate::prelude::*;
+
+/// How long a session token stays valid after login, in seconds.
+const SESSION_LIFETIME: i64 = 60 * 60 * 24 * 30;
+
+/// The user that made the request, resolved from the `Authorization: Bearer {token}`
+/// header against the `sessions` table.
+///
+/// Adding this as an argument to a handler makes the handler require a valid session,
+/// rejecting the request with 401 otherwise.
+#[derive(Clone)]
+pub struct AuthUser {
+    /// username_lower of the user, which is also the name of their `devdir/u/` dir
+    pub username: String,
+    pub admin: bool,
+    /// [[token_hash]] of the session token, the key of its `sessions` row
+    pub session: String,
+}
+
+impl std::fmt::Debug for AuthUser {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // the session is as good as a credential, keep it out of the logs
+        f.debug_struct("AuthUser")
+            .field("username", &self.username)
+            .field("admin", &self.admin)
+            .finish_non_exhaustive()
+    }
+}
+
+impl FromRequestParts<ReamioApp> for AuthUser {
+    type Rejection = ReamioWebError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &ReamioApp,
+    ) -> Result<Self, Self::Rejection> {
+        let token = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| x.strip_prefix("Bearer "))
+            .map(str::trim)
+            .ok_or_else(|| unauthorized("missing bearer token"))?;
+        resolve_session(state, token).await
+    }
+}
+
+/// [[AuthUser]], also taking the session token from a `token` query parameter when
+/// there's no `Authorization` header. For endpoints used as the src of `<audio>`
+/// elements and by external players, neither of which can set headers.
+#[derive(Debug, Clone)]
+pub struct MediaUser(pub AuthUser);
+
+impl FromRequestParts<ReamioApp> for MediaUser {
+    type Rejection = ReamioWebError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &ReamioApp,
+    ) -> Result<Self, Self::Rejection> {
+        if parts.headers.contains_key(header::AUTHORIZATION) {
+            return AuthUser::from_request_parts(parts, state).await.map(Self);
+        }
+        let token = parts
+            .uri
+            .query()
+            .and_then(|x| serde_urlencoded::from_str::<Vec<(String, String)>>(x).ok())
+            .and_then(|x| x.into_iter().find(|(k, _)| k == "token"))
+            .map(|(_, v)| v)
+            .ok_or_else(|| unauthorized("missing bearer token or token parameter"))?;
+        resolve_session(state, token.trim()).await.map(Self)
+    }
+}
+
+fn unauthorized(msg: &str) -> ReamioWebError {
+    ReamioWebError::IncorrectArgs(msg.to_owned(), StatusCode::UNAUTHORIZED)
+}
+
+/// The user of a session token, if it's a valid session of an enabled user.
+async fn resolve_session(state: &ReamioApp, token: &str) -> Result<AuthUser, ReamioWebError> {
+    let session = token_hash(token);
+    let row = sqlx::query(
+        "SELECT sessions.user, users.admin
+             FROM sessions JOIN users ON sessions.user = users.username_lower
+             WHERE sessions.token_hash = $1
+                   AND sessions.expires > unixepoch()
+                   AND users.disabled = 0;",
+    )
+    .bind(&session)
+    .fetch_optional(&state.user_db)
+    .await?
+    .ok_or_else(|| unauthorized("invalid or expired session"))?;
+    let user: String = row.get("user");
+    trace!(user, "session resolved");
+
+    Ok(AuthUser {
+        username: user,
+        admin: row.get("admin"),
+        session,
+    })
+}
+
+/// What the `sessions` table keys a session token by: its sha256, hex encoded. The
+/// token itself is only ever known to the client.
+fn token_hash(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|x| format!("{x:02x}"))
+        .collect::<String>()
+}
+
+/// Hash a password into a PHC string, suitable for the `users.phc` column.
+#[tracing::instrument(skip(password))]
+pub async fn hash_password(password: String) -> Result<String, ReamioWebError> {
+    // argon2 is deliberately slow, keep it off of the async workers
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|x| x.to_string())
+    })
+    .await?
+    .map_err(ReamioWebError::from)
+}
+
+/// PHC string of a password nobody has, checked against in place of one that
+/// doesn't exist so that failing takes just as long either way.
+fn dummy_phc() -> &'static str {
+    static DUMMY: OnceLock<String> = OnceLock::new();
+    DUMMY.get_or_init(|| {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(b"", &salt)
+            .expect("hashing a fixed password cannot fail")
+            .to_string()
+    })
+}
+
+/// Check a password against a PHC string. Unparsable or empty PHC strings (such as
+/// users that have never had a password set, or that don't exist) never verify, but
+/// take as long as a wrong password, so that the time taken doesn't tell them apart.
+#[tracing::instrument(skip(password, phc))]
+pub async fn verify_password(password: String, phc: String) -> Result<bool, ReamioWebError> {
+    Ok(tokio::task::spawn_blocking(move || {
+        let Ok(hash) = PasswordHash::new(&phc) else {
+            if !phc.is_empty() {
+                warn!("unparsable phc string, rejecting");
+            }
+            if let Ok(dummy) = PasswordHash::new(dummy_phc()) {
+                let _ = Argon2::default().verify_password(password.as_bytes(), &dummy);
+            }
+            return false;
+        };
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+    .await?)
+}
+
+#[derive(Deserialize)]
+pub struct LoginArgs {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Serialize)]
+pub struct LoginReturn {
+    token: String,
+    expires: i64,
+}
+
+/// Exchange a username and password for a session token. The token is then passed
+/// back as `Authorization: Bearer {token}` on every other request.
+///
+/// Path: /api/login
+///
+/// Arguments:
+/// - State(state): State<ReamioApp>
+///   Server state. [[ReamioApp]].
+/// - Json(LoginArgs { username, password }): Json<LoginArgs>
+///   Credentials. The username is matched case insensitively.
+#[tracing::instrument(skip(args))]
+pub async fn login(
+    State(state): State<ReamioApp>,
+    Json(args): Json<LoginArgs>,
+) -> Result<Json<LoginReturn>, ReamioWebError> {
+    let username = args.username.trim().to_lowercase();
+    debug!(username, "login attempt");
+
+    // the same message is used for every failure so that usernames cannot be probed
+    let failed = || {
+        ReamioWebError::IncorrectArgs(
+            "invalid username or password".to_owned(),
+            StatusCode::UNAUTHORIZED,
+        )
+    };
+
+    // a missing user is checked against an empty phc, which takes as long as a
+    // wrong password does
+    let phc: String =
+        sqlx::query("SELECT phc FROM users WHERE username_lower = $1 AND disabled = 0;")
+            .bind(&username)
+            .fetch_optional(&state.user_db)
+            .await?
+            .map(|x| x.get("phc"))
+            .unwrap_or_default();
+    if !verify_password(args.password, phc).await? {
+        debug!(username, "password rejected");
+        return Err(failed());
+    }
+
+    // 256 bits of randomness, hex encoded
+    let mut raw = [0u8; 32];
+    rand::rngs::OsRng.fill_bytes(&mut raw);
+    let token = raw.iter().map(|x| format!("{x:02x}")).collect::<String>();
+
+    let expires: i64 = sqlx::query(
+        "INSERT INTO sessions (token_hash, user, created, expires)
+             VALUES ($1, $2, unixepoch(), unixepoch() + $3)
+             RETURNING expires;",
+    )
+    .bind(token_hash(&token))
+    .bind(&username)
+    .bind(SESSION_LIFETIME)
+    .fetch_one(&state.user_db)
+    .await?
+    .get("expires");
+    info!(username, "session created");
+
+    Ok(Json(LoginReturn { token, expires }))
+}
+
+/// Invalidate the session token used to make this request.
+///
+/// Path: /api/logout
+#[tracing::instrument]
+pub async fn logout(
+    State(state): State<ReamioApp>,
+    user: AuthUser,
+) -> Result<StatusCode, ReamioWebError> {
+    sqlx::query("DELETE FROM sessions WHERE token_hash = $1;")
+        .bind(&user.session)
+        .execute(&state.user_db)
+        .await?;
+    debug!(user.username, "session removed");
+    Ok(StatusCode::NO_CONTENT)
+}
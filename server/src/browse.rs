@@ -0,0 +1,660 @@
+//! Cursor paginated listings of artists, albums and tracks.
+//!
+//! Tracks without an artist or album aren't dropped, they are gathered under an
+//! "unknown" artist or album with the id 0, which can be passed back in like any
+//! other id. The unknown artist has the tracks without anyone credited as their
+//! artist, even if they have a composer or such.
+
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+    http::StatusCode,
+};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use serde::{Deserialize, Serialize};
+use sqlx::{Sqlite, query::QueryAs, sqlite::SqliteArguments};
+use std::collections::HashMap;
+
+use crate::ReamioApp;
+use crate::auth::AuthUser;
+use crate::catalog::{ArtistRole, main_artist};
+use crate::prelude::*;
+use crate::probe::AudioInfo;
+
+/// Name of the bucket for tracks with no artist.
+pub const UNKNOWN_ARTIST: &str = "Unknown Artist";
+/// Name of the bucket for tracks with no album.
+pub const UNKNOWN_ALBUM: &str = "Unknown Album";
+
+/// Default page size.
+const PAGE_DEFAULT: i64 = 50;
+/// Largest page size.
+const PAGE_MAX: i64 = 500;
+
+/// Sort orders. Names sort case insensitively, `added` is upload order, and `number`
+/// is disc then track number, for listings of tracks.
+#[derive(Deserialize, Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BrowseSort {
+    #[default]
+    Name,
+    NameDesc,
+    Added,
+    AddedDesc,
+    Number,
+}
+
+impl BrowseSort {
+    /// SQL for the sort key, given the name column, and whether there are
+    /// `disc_number` and `track_number` columns to sort by. Keys are always text,
+    /// numbers are zero padded so that they still compare in order.
+    fn key(&self, name: &str, numbered: bool) -> Result<String, ReamioWebError> {
+        Ok(match self {
+            BrowseSort::Name | BrowseSort::NameDesc => format!("lower({name})"),
+            BrowseSort::Added | BrowseSort::AddedDesc => "printf('%020d', id)".to_owned(),
+            BrowseSort::Number if numbered => {
+                "printf('%010d%010d', COALESCE(disc_number, 0), COALESCE(track_number, 0))"
+                    .to_owned()
+            }
+            BrowseSort::Number => {
+                return Err(ReamioWebError::IncorrectArgs(
+                    "only tracks can be sorted by number".to_owned(),
+                    StatusCode::BAD_REQUEST,
+                ));
+            }
+        })
+    }
+
+    fn descending(&self) -> bool {
+        matches!(self, BrowseSort::NameDesc | BrowseSort::AddedDesc)
+    }
+}
+
+/// [[BrowseArgs]]
+/// Query arguments shared by every listing
+///
+/// Fields:
+/// - sort: Option<BrowseSort>
+///   `name`, `name_desc`, `added`, `added_desc` or `number`. Album track listings
+///   default to `number`, everything else to `name`.
+/// - limit: Option<i64>
+///   Page size, 50 by default and at most 500.
+/// - cursor: Option<String>
+///   The `next` value of the previous page. Has to be used with the same sort.
+#[derive(Deserialize, Debug)]
+pub struct BrowseArgs {
+    pub sort: Option<BrowseSort>,
+    pub limit: Option<i64>,
+    pub cursor: Option<String>,
+}
+
+/// [[TrackFilterArgs]]
+/// Extra query arguments for [[list_tracks]]
+///
+/// Fields:
+/// - artist: Option<i64>
+///   Only tracks crediting this artist, 0 being tracks with no artist.
+/// - role: Option<ArtistRole>
+///   Only count `artist` when they're credited as this. [[ArtistRole]].
+/// - album: Option<i64>
+///   Only tracks on this album, 0 being tracks with no album.
+#[derive(Deserialize, Debug)]
+pub struct TrackFilterArgs {
+    pub artist: Option<i64>,
+    pub role: Option<ArtistRole>,
+    pub album: Option<i64>,
+}
+
+/// Where the previous page left off: the sort key and id of its last row.
+#[derive(Serialize, Deserialize, Debug)]
+struct Cursor {
+    sort: BrowseSort,
+    key: String,
+    id: i64,
+}
+
+impl Cursor {
+    fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).expect("cursors always serialize"))
+    }
+
+    fn decode(cursor: &str, sort: BrowseSort) -> Result<Self, ReamioWebError> {
+        let bad =
+            |msg: &str| ReamioWebError::IncorrectArgs(msg.to_owned(), StatusCode::BAD_REQUEST);
+        let cursor: Cursor = URL_SAFE_NO_PAD
+            .decode(cursor)
+            .ok()
+            .and_then(|x| serde_json::from_slice(&x).ok())
+            .ok_or_else(|| bad("invalid cursor"))?;
+        if cursor.sort != sort {
+            return Err(bad("cursor was made with a different sort"));
+        }
+        Ok(cursor)
+    }
+}
+
+/// One page of a listing.
+#[derive(Serialize, Debug)]
+pub struct Page<T> {
+    items: Vec<T>,
+    /// Cursor for the next page, None on the last one
+    next: Option<String>,
+}
+
+/// Rows that can be paged through.
+trait Paged {
+    fn id(&self) -> i64;
+    fn sort_key(&self) -> String;
+}
+
+/// Wrap `base`, a query with `id` and `sort_key` columns, so that it returns the page
+/// after the cursor bound to `$1` (key) and `$2` (id), limited to `$3` rows. `base`
+/// binds its own parameters from `$4` onwards.
+fn paged_query(base: &str, sort: BrowseSort) -> String {
+    let (cmp, dir) = if sort.descending() {
+        ("<", "DESC")
+    } else {
+        (">", "ASC")
+    };
+    format!(
+        "SELECT * FROM ({base}) AS page
+             WHERE $1 IS NULL OR sort_key {cmp} $1 OR (sort_key = $1 AND id {cmp} $2)
+             ORDER BY sort_key {dir}, id {dir}
+             LIMIT $3;"
+    )
+}
+
+/// Bind the cursor and limit of [[paged_query]], fetching one extra row to find out
+/// whether there is a next page.
+fn bind_page<'q, O>(
+    query: QueryAs<'q, Sqlite, O, SqliteArguments<'q>>,
+    cursor: Option<&Cursor>,
+    limit: i64,
+) -> QueryAs<'q, Sqlite, O, SqliteArguments<'q>> {
+    query
+        .bind(cursor.map(|x| x.key.clone()))
+        .bind(cursor.map(|x| x.id))
+        .bind(limit + 1)
+}
+
+/// Turn the rows of [[bind_page]] into a [[Page]].
+fn into_page<T: Paged>(mut rows: Vec<T>, sort: BrowseSort, limit: i64) -> Page<T> {
+    let more = rows.len() as i64 > limit;
+    rows.truncate(limit as usize);
+    let next = rows.last().filter(|_| more).map(|x| {
+        Cursor {
+            sort,
+            key: x.sort_key(),
+            id: x.id(),
+        }
+        .encode()
+    });
+    Page { items: rows, next }
+}
+
+/// Resolve the sort, cursor and page size of a request.
+fn page_args(
+    args: &BrowseArgs,
+    default: BrowseSort,
+) -> Result<(BrowseSort, Option<Cursor>, i64), ReamioWebError> {
+    let sort = args.sort.unwrap_or(default);
+    let cursor = args
+        .cursor
+        .as_deref()
+        .map(|x| Cursor::decode(x, sort))
+        .transpose()?;
+    let limit = args.limit.unwrap_or(PAGE_DEFAULT).clamp(1, PAGE_MAX);
+    Ok((sort, cursor, limit))
+}
+
+/// SQL matching tracks (`track` being the id column) by the artist bound to `param`,
+/// where NULL matches everything and 0 matches tracks without an artist. `role` is
+/// an optional [[ArtistRole]] parameter restricting what the artist has to be
+/// credited as.
+fn track_by_artist(track: &str, param: &str, role: &str) -> String {
+    format!(
+        "({param} IS NULL
+          OR ({param} = 0 AND {} IS NULL)
+          OR EXISTS (SELECT 1 FROM artist_tracks
+                         WHERE artist_tracks.track = {track} AND artist_tracks.artist = {param}
+                               AND ({role} IS NULL OR artist_tracks.role = {role})))",
+        main_artist(track)
+    )
+}
+
+/// An artist credited on a track or album.
+#[derive(Serialize, Debug)]
+pub struct Credit {
+    id: i64,
+    name: String,
+    /// None on albums, which only have album artists
+    #[serde(skip_serializing_if = "Option::is_none")]
+    role: Option<ArtistRole>,
+}
+
+/// Every artist credited on the given tracks, main artists first.
+async fn track_credits(
+    db: &mut sqlx::SqliteConnection,
+    tracks: &[i64],
+) -> Result<HashMap<i64, Vec<Credit>>, sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT artist_tracks.track, artist.id, artist.name, artist_tracks.role
+             FROM artist_tracks JOIN artist ON artist.id = artist_tracks.artist
+             WHERE artist_tracks.track IN (SELECT value FROM json_each($1))
+             ORDER BY CASE artist_tracks.role
+                          WHEN 'artist' THEN 0 WHEN 'featured' THEN 1
+                          WHEN 'composer' THEN 2 ELSE 3 END,
+                      artist_tracks.position;",
+    )
+    .bind(serde_json::to_string(tracks).expect("ids always serialize"))
+    .fetch_all(db)
+    .await?;
+    let mut ret = HashMap::<i64, Vec<Credit>>::new();
+    for row in rows {
+        ret.entry(row.get("track")).or_default().push(Credit {
+            id: row.get("id"),
+            name: row.get("name"),
+            role: ArtistRole::from_str(row.get("role")),
+        });
+    }
+    Ok(ret)
+}
+
+/// Every album artist of the given albums, in order.
+async fn album_credits(
+    db: &mut sqlx::SqliteConnection,
+    albums: &[i64],
+) -> Result<HashMap<i64, Vec<Credit>>, sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT album_artists.album, artist.id, artist.name
+             FROM album_artists JOIN artist ON artist.id = album_artists.artist
+             WHERE album_artists.album IN (SELECT value FROM json_each($1))
+             ORDER BY album_artists.position;",
+    )
+    .bind(serde_json::to_string(albums).expect("ids always serialize"))
+    .fetch_all(db)
+    .await?;
+    let mut ret = HashMap::<i64, Vec<Credit>>::new();
+    for row in rows {
+        ret.entry(row.get("album")).or_default().push(Credit {
+            id: row.get("id"),
+            name: row.get("name"),
+            role: None,
+        });
+    }
+    Ok(ret)
+}
+
+#[derive(Serialize, sqlx::FromRow, Debug)]
+pub struct ArtistItem {
+    id: i64,
+    name: String,
+    album_count: i64,
+    track_count: i64,
+    #[serde(skip)]
+    sort_key: String,
+}
+
+impl Paged for ArtistItem {
+    fn id(&self) -> i64 {
+        self.id
+    }
+
+    fn sort_key(&self) -> String {
+        self.sort_key.clone()
+    }
+}
+
+/// List artists, including the unknown artist if there are tracks without one.
+///
+/// Path: GET /api/artists?sort={}&limit={}&cursor={}
+///
+/// Arguments:
+/// - State(state): State<ReamioApp>
+///   Server state. [[ReamioApp]].
+/// - user: AuthUser
+///   Whose library to list. [[AuthUser]].
+/// - Query(args): Query<BrowseArgs>
+///   See [[BrowseArgs]].
+#[tracing::instrument]
+pub async fn list_artists(
+    State(state): State<ReamioApp>,
+    user: AuthUser,
+    Query(args): Query<BrowseArgs>,
+) -> Result<Json<Page<ArtistItem>>, ReamioWebError> {
+    let (sort, cursor, limit) = page_args(&args, BrowseSort::default())?;
+    let base = format!(
+        "SELECT *, {} AS sort_key FROM (
+             SELECT artist.id, artist.name,
+                    COUNT(DISTINCT CASE WHEN artist_tracks.track IS NOT NULL
+                        THEN COALESCE(album_tracks.album, 0) END) AS album_count,
+                    COUNT(DISTINCT artist_tracks.track) AS track_count
+                 FROM artist
+                 LEFT JOIN artist_tracks ON artist_tracks.artist = artist.id
+                 LEFT JOIN album_tracks ON album_tracks.track = artist_tracks.track
+                 GROUP BY artist.id
+             UNION ALL
+             SELECT 0, '{UNKNOWN_ARTIST}',
+                    COUNT(DISTINCT COALESCE(album_tracks.album, 0)),
+                    COUNT(*)
+                 FROM track
+                 LEFT JOIN album_tracks ON album_tracks.track = track.id
+                 WHERE {}
+                 HAVING COUNT(*) > 0)",
+        sort.key("name", false)?,
+        track_by_artist("track.id", "0", "NULL"),
+    );
+
+    let mut db = fetch_users_music_db(state.music_dbs, &user.username).await?;
+    let rows = bind_page(
+        sqlx::query_as::<_, ArtistItem>(&paged_query(&base, sort)),
+        cursor.as_ref(),
+        limit,
+    )
+    .fetch_all(&mut *db)
+    .await?;
+    Ok(Json(into_page(rows, sort, limit)))
+}
+
+#[derive(Serialize, sqlx::FromRow, Debug)]
+pub struct AlbumItem {
+    id: i64,
+    name: String,
+    /// The first album artist, None for the unknown album
+    artist_id: Option<i64>,
+    artist_name: Option<String>,
+    /// Every album artist
+    #[sqlx(skip)]
+    artists: Vec<Credit>,
+    year: Option<i64>,
+    compilation: bool,
+    track_count: i64,
+    /// See [[get_art]]
+    art: Option<i64>,
+    #[serde(skip)]
+    sort_key: String,
+}
+
+impl Paged for AlbumItem {
+    fn id(&self) -> i64 {
+        self.id
+    }
+
+    fn sort_key(&self) -> String {
+        self.sort_key.clone()
+    }
+}
+
+/// List the albums an artist is an album artist of or has tracks on. Tracks of theirs
+/// without an album show up under the unknown album.
+///
+/// Path: GET /api/artists/{id}/albums?sort={}&limit={}&cursor={}
+///
+/// Arguments:
+/// - State(state): State<ReamioApp>
+///   Server state. [[ReamioApp]].
+/// - user: AuthUser
+///   Whose library to list. [[AuthUser]].
+/// - Path(id): Path<i64>
+///   Artist id, 0 for the unknown artist.
+/// - Query(args): Query<BrowseArgs>
+///   See [[BrowseArgs]].
+#[tracing::instrument]
+pub async fn list_artist_albums(
+    State(state): State<ReamioApp>,
+    user: AuthUser,
+    Path(id): Path<i64>,
+    Query(args): Query<BrowseArgs>,
+) -> Result<Json<Page<AlbumItem>>, ReamioWebError> {
+    let (sort, cursor, limit) = page_args(&args, BrowseSort::default())?;
+    let base = format!(
+        "SELECT *, {} AS sort_key FROM (
+             SELECT album.id, album.name,
+                    artist.id AS artist_id, artist.name AS artist_name,
+                    album.year, album.compilation,
+                    COUNT(album_tracks.track) AS track_count, album.art
+                 FROM album
+                 JOIN album_tracks ON album_tracks.album = album.id
+                 LEFT JOIN artist ON artist.id = album.artist
+                 WHERE EXISTS (SELECT 1 FROM album_tracks AS at
+                                   WHERE at.album = album.id AND {})
+                       OR EXISTS (SELECT 1 FROM album_artists
+                                      WHERE album_artists.album = album.id
+                                            AND album_artists.artist = $4)
+                 GROUP BY album.id
+             UNION ALL
+             SELECT 0, '{UNKNOWN_ALBUM}', NULL, NULL, NULL, 0, COUNT(*), NULL
+                 FROM track
+                 WHERE NOT EXISTS (SELECT 1 FROM album_tracks WHERE album_tracks.track = track.id)
+                       AND {}
+                 HAVING COUNT(*) > 0)",
+        sort.key("name", false)?,
+        track_by_artist("at.track", "$4", "NULL"),
+        track_by_artist("track.id", "$4", "NULL"),
+    );
+
+    let mut db = fetch_users_music_db(state.music_dbs, &user.username).await?;
+    if id != 0 {
+        sqlx::query("SELECT 1 FROM artist WHERE id = $1;")
+            .bind(id)
+            .fetch_optional(&mut *db)
+            .await?
+            .ok_or_else(|| {
+                ReamioWebError::IncorrectArgs(
+                    "no such artist exists".to_owned(),
+                    StatusCode::NOT_FOUND,
+                )
+            })?;
+    }
+    let mut rows = bind_page(
+        sqlx::query_as::<_, AlbumItem>(&paged_query(&base, sort)),
+        cursor.as_ref(),
+        limit,
+    )
+    .bind(id)
+    .fetch_all(&mut *db)
+    .await?;
+    let ids = rows.iter().map(|x| x.id).collect::<Vec<_>>();
+    let mut credits = album_credits(&mut db, &ids).await?;
+    for row in rows.iter_mut() {
+        row.artists = credits.remove(&row.id).unwrap_or_default();
+    }
+    Ok(Json(into_page(rows, sort, limit)))
+}
+
+#[derive(Serialize, sqlx::FromRow, Debug)]
+pub struct TrackItem {
+    id: i64,
+    title: String,
+    /// None is the root
+    dir: Option<i64>,
+    album_id: i64,
+    album_name: String,
+    /// The main artist
+    artist_id: i64,
+    artist_name: String,
+    /// Everyone credited on the track
+    #[sqlx(skip)]
+    artists: Vec<Credit>,
+    disc_number: Option<i64>,
+    track_number: Option<i64>,
+    year: Option<i64>,
+    /// Codec, duration and friends. [[AudioInfo]].
+    #[sqlx(flatten)]
+    audio: AudioInfo,
+    /// Its own art, or else its album's or folder's. See [[get_art]].
+    art: Option<i64>,
+    #[serde(skip)]
+    sort_key: String,
+}
+
+impl Paged for TrackItem {
+    fn id(&self) -> i64 {
+        self.id
+    }
+
+    fn sort_key(&self) -> String {
+        self.sort_key.clone()
+    }
+}
+
+/// Page through tracks, with the filters already resolved.
+async fn page_tracks(
+    state: ReamioApp,
+    user: &str,
+    args: &BrowseArgs,
+    default_sort: BrowseSort,
+    filter: &TrackFilterArgs,
+) -> Result<Page<TrackItem>, ReamioWebError> {
+    let (sort, cursor, limit) = page_args(args, default_sort)?;
+    let base = format!(
+        "SELECT *, {} AS sort_key FROM (
+             SELECT track.id, track.title, track.dir,
+                    COALESCE(album.id, 0) AS album_id,
+                    COALESCE(album.name, '{UNKNOWN_ALBUM}') AS album_name,
+                    COALESCE(artist.id, 0) AS artist_id,
+                    COALESCE(artist.name, '{UNKNOWN_ARTIST}') AS artist_name,
+                    track.disc_number, track.track_number, track.year,
+                    track.container, track.codec, track.duration_ms, track.sample_rate,
+                    track.bit_depth, track.channels, track.bitrate,
+                    COALESCE(track.art, album.art, dir.art) AS art
+                 FROM track
+                 LEFT JOIN album_tracks ON album_tracks.track = track.id
+                 LEFT JOIN album ON album.id = album_tracks.album
+                 LEFT JOIN dir ON dir.node = track.dir
+                 LEFT JOIN artist ON artist.id = {}
+                 WHERE {}
+                       AND ($5 IS NULL OR COALESCE(album.id, 0) = $5))",
+        sort.key("title", true)?,
+        main_artist("track.id"),
+        track_by_artist("track.id", "$4", "$6"),
+    );
+
+    let mut db = fetch_users_music_db(state.music_dbs, user).await?;
+    let mut rows = bind_page(
+        sqlx::query_as::<_, TrackItem>(&paged_query(&base, sort)),
+        cursor.as_ref(),
+        limit,
+    )
+    .bind(filter.artist)
+    .bind(filter.album)
+    .bind(filter.role.map(|x| x.as_str()))
+    .fetch_all(&mut *db)
+    .await?;
+    let ids = rows.iter().map(|x| x.id).collect::<Vec<_>>();
+    let mut credits = track_credits(&mut db, &ids).await?;
+    for row in rows.iter_mut() {
+        row.artists = credits.remove(&row.id).unwrap_or_default();
+    }
+    Ok(into_page(rows, sort, limit))
+}
+
+/// List tracks, optionally by artist and/or album. Name sorts go by title.
+///
+/// Path: GET /api/tracks?sort={}&limit={}&cursor={}&artist={}&role={}&album={}
+///
+/// Arguments:
+/// - State(state): State<ReamioApp>
+///   Server state. [[ReamioApp]].
+/// - user: AuthUser
+///   Whose library to list. [[AuthUser]].
+/// - Query(args): Query<BrowseArgs>
+///   See [[BrowseArgs]].
+/// - Query(filter): Query<TrackFilterArgs>
+///   See [[TrackFilterArgs]].
+#[tracing::instrument]
+pub async fn list_tracks(
+    State(state): State<ReamioApp>,
+    user: AuthUser,
+    Query(args): Query<BrowseArgs>,
+    Query(filter): Query<TrackFilterArgs>,
+) -> Result<Json<Page<TrackItem>>, ReamioWebError> {
+    Ok(Json(
+        page_tracks(state, &user.username, &args, BrowseSort::default(), &filter).await?,
+    ))
+}
+
+/// List the tracks on an album, in disc and track order unless asked otherwise.
+///
+/// Path: GET /api/albums/{id}/tracks?sort={}&limit={}&cursor={}
+///
+/// Arguments:
+/// - State(state): State<ReamioApp>
+///   Server state. [[ReamioApp]].
+/// - user: AuthUser
+///   Whose library to list. [[AuthUser]].
+/// - Path(id): Path<i64>
+///   Album id, 0 for the unknown album.
+/// - Query(args): Query<BrowseArgs>
+///   See [[BrowseArgs]].
+#[tracing::instrument]
+pub async fn list_album_tracks(
+    State(state): State<ReamioApp>,
+    user: AuthUser,
+    Path(id): Path<i64>,
+    Query(args): Query<BrowseArgs>,
+) -> Result<Json<Page<TrackItem>>, ReamioWebError> {
+    if id != 0 {
+        let mut db = fetch_users_music_db(state.music_dbs.clone(), &user.username).await?;
+        sqlx::query("SELECT 1 FROM album WHERE id = $1;")
+            .bind(id)
+            .fetch_optional(&mut *db)
+            .await?
+            .ok_or_else(|| {
+                ReamioWebError::IncorrectArgs(
+                    "no such album exists".to_owned(),
+                    StatusCode::NOT_FOUND,
+                )
+            })?;
+    }
+    let filter = TrackFilterArgs {
+        artist: None,
+        role: None,
+        album: Some(id),
+    };
+    Ok(Json(
+        page_tracks(state, &user.username, &args, BrowseSort::Number, &filter).await?,
+    ))
+}
+
+#[derive(Serialize, sqlx::FromRow, Debug)]
+pub struct TrackTag {
+    key: String,
+    value: String,
+}
+
+/// Every tag read from a track's file, as it was named there. Keys repeat for tags
+/// with several values.
+///
+/// Path: GET /api/tracks/{id}/tags
+///
+/// Arguments:
+/// - State(state): State<ReamioApp>
+///   Server state. [[ReamioApp]].
+/// - user: AuthUser
+///   Whose library the track is in. [[AuthUser]].
+/// - Path(id): Path<i64>
+///   Track id.
+#[tracing::instrument]
+pub async fn track_tags(
+    State(state): State<ReamioApp>,
+    user: AuthUser,
+    Path(id): Path<i64>,
+) -> Result<Json<Vec<TrackTag>>, ReamioWebError> {
+    let mut db = fetch_users_music_db(state.music_dbs, &user.username).await?;
+    sqlx::query("SELECT 1 FROM track WHERE id = $1;")
+        .bind(id)
+        .fetch_optional(&mut *db)
+        .await?
+        .ok_or_else(|| {
+            ReamioWebError::IncorrectArgs("no such track exists".to_owned(), StatusCode::NOT_FOUND)
+        })?;
+    Ok(Json(
+        sqlx::query_as::<_, TrackTag>(
+            "SELECT key, value FROM track_tag WHERE track = $1 ORDER BY rowid;",
+        )
+        .bind(id)
+        .fetch_all(&mut *db)
+        .await?,
+    ))
+}
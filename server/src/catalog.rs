@@ -0,0 +1,502 @@
+//! Matching artists and albums by name, so that every track tagged with the same
+//! artist or album ends up under the same row.
+//!
+//! Names are matched on their [[name_key]], kept in the `name_key` column. Albums are
+//! additionally scoped by their album artist, so two different artists' "Greatest
+//! Hits" stay apart.
+//!
+//! A track can credit any number of artists, each with an [[ArtistRole]], and an
+//! album any number of album artists.
+
+use serde::{Deserialize, Serialize};
+
+use crate::prelude::*;
+use crate::tags::{TrackMetadata, year_of};
+
+/// What an artist did on a track, kept in `artist_tracks.role`.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ArtistRole {
+    /// The artist the track is by
+    Artist,
+    /// Guests, split out of artist names like "A feat. B"
+    Featured,
+    Composer,
+    Performer,
+}
+
+impl ArtistRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ArtistRole::Artist => "artist",
+            ArtistRole::Featured => "featured",
+            ArtistRole::Composer => "composer",
+            ArtistRole::Performer => "performer",
+        }
+    }
+
+    pub fn from_str(role: &str) -> Option<Self> {
+        match role {
+            "artist" => Some(ArtistRole::Artist),
+            "featured" => Some(ArtistRole::Featured),
+            "composer" => Some(ArtistRole::Composer),
+            "performer" => Some(ArtistRole::Performer),
+            _ => None,
+        }
+    }
+}
+
+/// SQL for the id of the main artist of a track (`track` being the id column): the
+/// first one credited with [[ArtistRole::Artist]].
+pub fn main_artist(track: &str) -> String {
+    format!(
+        "(SELECT artist_tracks.artist FROM artist_tracks
+              WHERE artist_tracks.track = {track} AND artist_tracks.role = 'artist'
+              ORDER BY artist_tracks.position LIMIT 1)"
+    )
+}
+
+/// Split an artist tag like "A feat. B, C & D" into the main artist and the
+/// featured ones. Only the part after the marker is split further, since plenty of
+/// artist names have a '&' in them.
+pub fn split_featured(name: &str) -> (&str, Vec<&str>) {
+    const MARKERS: [&str; 4] = [" feat. ", " feat ", " ft. ", " featuring "];
+    let found = name.char_indices().find_map(|(idx, _)| {
+        MARKERS.iter().find_map(|marker| {
+            name[idx..]
+                .get(..marker.len())
+                .filter(|x| x.eq_ignore_ascii_case(marker))
+                .map(|_| (idx, marker.len()))
+        })
+    });
+    let Some((idx, len)) = found else {
+        return (name.trim(), vec![]);
+    };
+    let featured = name[idx + len..]
+        .split([',', '&'])
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+        .collect();
+    (name[..idx].trim(), featured)
+}
+
+/// The normalized form of a name that rows are matched on: surrounding whitespace
+/// trimmed, inner runs of whitespace collapsed into a single space, and lowercased.
+pub fn name_key(name: &str) -> String {
+    name.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+/// Find the artist named `name`, creating it if there is none. The first spelling
+/// seen is the one kept as the display name.
+#[tracing::instrument(skip(db))]
+pub async fn find_or_create_artist(
+    db: &mut sqlx::SqliteConnection,
+    name: &str,
+) -> Result<i64, sqlx::Error> {
+    let key = name_key(name);
+    if let Some(row) = sqlx::query("SELECT id FROM artist WHERE name_key = $1;")
+        .bind(&key)
+        .fetch_optional(&mut *db)
+        .await?
+    {
+        let id = row.get("id");
+        trace!(id, "artist exists");
+        return Ok(id);
+    }
+    let id = sqlx::query("INSERT INTO artist (name, name_key) VALUES ($1, $2) RETURNING id;")
+        .bind(name.trim())
+        .bind(&key)
+        .fetch_one(&mut *db)
+        .await?
+        .get("id");
+    debug!(id, "artist created");
+    Ok(id)
+}
+
+/// Find the album named `name` by the album artists `artists`, creating it if there
+/// is none. Albums are told apart by their first album artist, any others are added
+/// to the album's list.
+#[tracing::instrument(skip(db))]
+pub async fn find_or_create_album(
+    db: &mut sqlx::SqliteConnection,
+    name: &str,
+    artists: &[i64],
+) -> Result<i64, sqlx::Error> {
+    let id = find_or_create_album_by(db, name, artists.first().copied()).await?;
+    for artist in artists {
+        add_album_artist(db, id, *artist).await?;
+    }
+    Ok(id)
+}
+
+/// Credit `artist` on `album` after its other album artists, if it isn't already.
+async fn add_album_artist(
+    db: &mut sqlx::SqliteConnection,
+    album: i64,
+    artist: i64,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT OR IGNORE INTO album_artists (album, artist, position)
+             SELECT $1, $2, COALESCE(MAX(position) + 1, 0) FROM album_artists
+                 WHERE album = $1;",
+    )
+    .bind(album)
+    .bind(artist)
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Credit `artist` on `track` in `role`, after any other artists in the same role.
+#[tracing::instrument(skip(db))]
+pub async fn add_track_artist(
+    db: &mut sqlx::SqliteConnection,
+    track: i64,
+    artist: i64,
+    role: ArtistRole,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT OR IGNORE INTO artist_tracks (artist, track, role, position)
+             SELECT $1, $2, $3, COALESCE(MAX(position) + 1, 0) FROM artist_tracks
+                 WHERE track = $2 AND role = $3;",
+    )
+    .bind(artist)
+    .bind(track)
+    .bind(role.as_str())
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+async fn find_or_create_album_by(
+    db: &mut sqlx::SqliteConnection,
+    name: &str,
+    artist: Option<i64>,
+) -> Result<i64, sqlx::Error> {
+    let key = name_key(name);
+    if let Some(row) = sqlx::query("SELECT id FROM album WHERE name_key = $1 AND artist IS $2;")
+        .bind(&key)
+        .bind(artist)
+        .fetch_optional(&mut *db)
+        .await?
+    {
+        let id = row.get("id");
+        trace!(id, "album exists");
+        return Ok(id);
+    }
+    let id =
+        sqlx::query("INSERT INTO album (name, name_key, artist) VALUES ($1, $2, $3) RETURNING id;")
+            .bind(name.trim())
+            .bind(&key)
+            .bind(artist)
+            .fetch_one(&mut *db)
+            .await?
+            .get("id");
+    debug!(id, "album created");
+    Ok(id)
+}
+
+/// Fill in whatever an artist doesn't have a sort name or MusicBrainz id for yet.
+#[tracing::instrument(skip(db))]
+pub async fn fill_artist_details(
+    db: &mut sqlx::SqliteConnection,
+    id: i64,
+    sort_name: Option<&str>,
+    mb_artist_id: Option<&str>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "UPDATE artist SET sort_name = COALESCE(sort_name, $2),
+                           mb_artist_id = COALESCE(mb_artist_id, $3)
+             WHERE id = $1;",
+    )
+    .bind(id)
+    .bind(sort_name)
+    .bind(mb_artist_id)
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Fill in whatever an album doesn't know yet from a track's tags. An album is a
+/// compilation if any of its tracks say so.
+#[tracing::instrument(skip(db, meta))]
+pub async fn fill_album_details(
+    db: &mut sqlx::SqliteConnection,
+    id: i64,
+    meta: &TrackMetadata,
+) -> Result<(), sqlx::Error> {
+    let year = meta
+        .original_date
+        .as_deref()
+        .or(meta.date.as_deref())
+        .and_then(year_of);
+    sqlx::query(
+        "UPDATE album SET year = COALESCE(year, $2),
+                          sort_name = COALESCE(sort_name, $3),
+                          compilation = MAX(compilation, $4),
+                          mb_album_id = COALESCE(mb_album_id, $5),
+                          mb_release_group_id = COALESCE(mb_release_group_id, $6)
+             WHERE id = $1;",
+    )
+    .bind(id)
+    .bind(year)
+    .bind(meta.album_sort.as_deref())
+    .bind(meta.compilation)
+    .bind(meta.musicbrainz.album.as_deref())
+    .bind(meta.musicbrainz.release_group.as_deref())
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Find the genre named `name`, matched like artists are, creating it if there is
+/// none.
+#[tracing::instrument(skip(db))]
+pub async fn find_or_create_genre(
+    db: &mut sqlx::SqliteConnection,
+    name: &str,
+) -> Result<i64, sqlx::Error> {
+    let key = name_key(name);
+    if let Some(row) = sqlx::query("SELECT id FROM genre WHERE name_key = $1;")
+        .bind(&key)
+        .fetch_optional(&mut *db)
+        .await?
+    {
+        return Ok(row.get("id"));
+    }
+    let id = sqlx::query("INSERT INTO genre (name, name_key) VALUES ($1, $2) RETURNING id;")
+        .bind(name.trim())
+        .bind(&key)
+        .fetch_one(&mut *db)
+        .await?
+        .get("id");
+    debug!(id, "genre created");
+    Ok(id)
+}
+
+/// Tag `track` with `genre`, after its other genres.
+#[tracing::instrument(skip(db))]
+pub async fn add_track_genre(
+    db: &mut sqlx::SqliteConnection,
+    track: i64,
+    genre: i64,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT OR IGNORE INTO track_genres (track, genre, position)
+             SELECT $1, $2, COALESCE(MAX(position) + 1, 0) FROM track_genres
+                 WHERE track = $1;",
+    )
+    .bind(track)
+    .bind(genre)
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Move everything pointing at artist `from` over to `into`, then delete `from`.
+async fn merge_artist(
+    db: &mut sqlx::SqliteConnection,
+    from: i64,
+    into: i64,
+) -> Result<(), sqlx::Error> {
+    // a track or album might already credit both
+    sqlx::query(
+        "INSERT OR IGNORE INTO artist_tracks (artist, track, role, position)
+             SELECT $2, track, role, position FROM artist_tracks WHERE artist = $1;",
+    )
+    .bind(from)
+    .bind(into)
+    .execute(&mut *db)
+    .await?;
+    sqlx::query("DELETE FROM artist_tracks WHERE artist = $1;")
+        .bind(from)
+        .execute(&mut *db)
+        .await?;
+    sqlx::query(
+        "INSERT OR IGNORE INTO album_artists (album, artist, position)
+             SELECT album, $2, position FROM album_artists WHERE artist = $1;",
+    )
+    .bind(from)
+    .bind(into)
+    .execute(&mut *db)
+    .await?;
+    sqlx::query("DELETE FROM album_artists WHERE artist = $1;")
+        .bind(from)
+        .execute(&mut *db)
+        .await?;
+    sqlx::query("UPDATE album SET artist = $2 WHERE artist = $1;")
+        .bind(from)
+        .bind(into)
+        .execute(&mut *db)
+        .await?;
+    sqlx::query("DELETE FROM artist WHERE id = $1;")
+        .bind(from)
+        .execute(&mut *db)
+        .await?;
+    Ok(())
+}
+
+/// Move every track on album `from` over to `into`, then delete `from`.
+async fn merge_album(
+    db: &mut sqlx::SqliteConnection,
+    from: i64,
+    into: i64,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE album_tracks SET album = $2 WHERE album = $1;")
+        .bind(from)
+        .bind(into)
+        .execute(&mut *db)
+        .await?;
+    sqlx::query("DELETE FROM album_artists WHERE album = $1;")
+        .bind(from)
+        .execute(&mut *db)
+        .await?;
+    sqlx::query("DELETE FROM album WHERE id = $1;")
+        .bind(from)
+        .execute(&mut *db)
+        .await?;
+    Ok(())
+}
+
+/// Where the file of a track is. Tracks imported in place, see [[import]], are read
+/// from their `source`, anything else from the user dir.
+pub fn track_path(user: &str, id: i64, source: Option<String>) -> String {
+    // this is secure because id is i64 and cannot represent anything other than [0-9]*
+    source.unwrap_or_else(|| format!("./devdir/u/{user}/{id}"))
+}
+
+/// Delete a track, along with everything pointing at it. Its file is left to the
+/// caller, since it should only go once the deletion is committed. Files of tracks
+/// imported in place aren't the server's to remove.
+pub async fn delete_track(db: &mut sqlx::SqliteConnection, id: i64) -> Result<(), sqlx::Error> {
+    for table in [
+        "artist_tracks",
+        "album_tracks",
+        "track_genres",
+        "track_tag",
+        "playlist_tracks",
+        "scrobble",
+    ] {
+        sqlx::query(&format!("DELETE FROM {table} WHERE track = $1;"))
+            .bind(id)
+            .execute(&mut *db)
+            .await?;
+    }
+    sqlx::query("DELETE FROM track WHERE id = $1;")
+        .bind(id)
+        .execute(&mut *db)
+        .await?;
+    Ok(())
+}
+
+/// Replace track `old` with `new`: its playlist entries and plays move over, then
+/// it's deleted like [[delete_track]].
+pub async fn replace_track(
+    db: &mut sqlx::SqliteConnection,
+    old: i64,
+    new: i64,
+) -> Result<(), sqlx::Error> {
+    for table in ["playlist_tracks", "scrobble"] {
+        sqlx::query(&format!("UPDATE {table} SET track = $2 WHERE track = $1;"))
+            .bind(old)
+            .bind(new)
+            .execute(&mut *db)
+            .await?;
+    }
+    delete_track(db, old).await
+}
+
+/// Fill in `name_key` for artists and albums that don't have one yet, merging any
+/// that turn out to be duplicates of each other. This only has work to do once, for
+/// music dbs that were created before ingestion matched on names.
+///
+/// Albums without an album artist take the artist credited first on their first
+/// track, leaving out featured artists, composers and performers.
+#[tracing::instrument(skip(pool))]
+pub async fn backfill_name_keys(pool: &SqlitePool) -> Result<(), sqlx::Error> {
+    let mut txn = pool.begin_with("BEGIN IMMEDIATE").await?;
+
+    // artists first, since albums are keyed by their artist
+    let artists = sqlx::query("SELECT id, name FROM artist WHERE name_key IS NULL ORDER BY id;")
+        .fetch_all(&mut *txn)
+        .await?;
+    let mut merged_artists = 0;
+    for row in artists {
+        let id: i64 = row.get("id");
+        let key = name_key(row.get("name"));
+        let keeper = sqlx::query("SELECT id FROM artist WHERE name_key = $1;")
+            .bind(&key)
+            .fetch_optional(&mut *txn)
+            .await?;
+        match keeper {
+            Some(keeper) => {
+                let keeper: i64 = keeper.get("id");
+                trace!(id, keeper, "merging duplicate artist");
+                merge_artist(&mut txn, id, keeper).await?;
+                merged_artists += 1;
+            }
+            None => {
+                sqlx::query("UPDATE artist SET name_key = $1 WHERE id = $2;")
+                    .bind(&key)
+                    .bind(id)
+                    .execute(&mut *txn)
+                    .await?;
+            }
+        }
+    }
+
+    let albums = sqlx::query(
+        "SELECT album.id, album.name, COALESCE(album.artist, (
+                 SELECT artist_tracks.artist
+                     FROM album_tracks JOIN artist_tracks
+                         ON artist_tracks.track = album_tracks.track
+                     WHERE album_tracks.album = album.id AND artist_tracks.role = 'artist'
+                     ORDER BY album_tracks.track, artist_tracks.position
+                     LIMIT 1)) AS artist
+             FROM album WHERE album.name_key IS NULL ORDER BY album.id;",
+    )
+    .fetch_all(&mut *txn)
+    .await?;
+    let mut merged_albums = 0;
+    for row in albums {
+        let id: i64 = row.get("id");
+        let artist: Option<i64> = row.get("artist");
+        let key = name_key(row.get("name"));
+        let keeper = sqlx::query("SELECT id FROM album WHERE name_key = $1 AND artist IS $2;")
+            .bind(&key)
+            .bind(artist)
+            .fetch_optional(&mut *txn)
+            .await?;
+        match keeper {
+            Some(keeper) => {
+                let keeper: i64 = keeper.get("id");
+                trace!(id, keeper, "merging duplicate album");
+                merge_album(&mut txn, id, keeper).await?;
+                merged_albums += 1;
+            }
+            None => {
+                sqlx::query("UPDATE album SET name_key = $1, artist = $2 WHERE id = $3;")
+                    .bind(&key)
+                    .bind(artist)
+                    .bind(id)
+                    .execute(&mut *txn)
+                    .await?;
+                if let Some(artist) = artist {
+                    add_album_artist(&mut txn, id, artist).await?;
+                }
+            }
+        }
+    }
+
+    txn.commit().await?;
+    if merged_artists > 0 || merged_albums > 0 {
+        info!(
+            merged_artists,
+            merged_albums, "merged duplicate artists and albums"
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,182 @@
+use serde::Deserialize;
+use std::{path::PathBuf, str::FromStr, time::Duration};
+
+use crate::prelude::*;
+
+/// Server wide settings, read once on startup from `REAMIO_*` environment variables.
+#[derive(Debug, Clone)]
+pub struct ReamioConfig {
+    /// REAMIO_OPEN_REGISTRATION: allow anyone to create an account through /api/register
+    pub open_registration: bool,
+    /// REAMIO_ADMIN_USER: the admin account created on first start
+    pub admin_user: String,
+    /// REAMIO_ADMIN_PASSWORD: password for [[admin_user]]. Generated when unset.
+    pub admin_password: Option<String>,
+    /// REAMIO_MUSIC_DB_CAPACITY: how many user music dbs can be open at once
+    pub music_db_capacity: usize,
+    /// REAMIO_MUSIC_DB_IDLE_SECS: how long a music db can go unused before it is closed
+    pub music_db_idle: Duration,
+    /// REAMIO_TRANSCODE_CACHE_MB: how much disk finished transcodes can take up
+    pub transcode_cache_bytes: u64,
+    /// REAMIO_ON_DUPLICATE: what to do with uploads already in the library, unless
+    /// the upload says otherwise. See [[DuplicatePolicy]].
+    pub on_duplicate: DuplicatePolicy,
+    /// REAMIO_ARCHIVE_MAX_MB: how much an uploaded archive can hold once extracted
+    pub archive_max_bytes: u64,
+    /// REAMIO_ARCHIVE_MAX_ENTRIES: how many files an uploaded archive can hold
+    pub archive_max_entries: usize,
+    /// REAMIO_INGEST_WORKERS: how many uploads can be processed at once. Defaults to
+    /// the number of cores.
+    pub ingest_workers: usize,
+    /// REAMIO_SHUTDOWN_GRACE_SECS: how long background tasks get to finish up on
+    /// shutdown
+    pub shutdown_grace: Duration,
+    /// REAMIO_MAINTENANCE_INTERVAL_SECS: how often to clean up, see [[maintenance]]
+    pub maintenance_interval: Duration,
+    /// REAMIO_UPLOAD_EXPIRY_HOURS: how long a resumable upload can go without
+    /// receiving anything before it's thrown away
+    pub upload_expiry: Duration,
+    /// REAMIO_JOB_RETENTION_DAYS: how long finished upload jobs are kept around
+    pub job_retention: Duration,
+    /// REAMIO_IMPORT_ROOTS: dirs on the server that libraries can be imported from,
+    /// separated like PATH. Nothing can be imported when unset. See [[import]].
+    pub import_roots: Vec<PathBuf>,
+    /// REAMIO_WATCH_DIRS: dirs on the server to keep imported in place into the root
+    /// of a user's library, as `user=dir` entries separated like PATH. See [[watcher]].
+    pub watch_dirs: Vec<(String, PathBuf)>,
+    /// REAMIO_WATCH_DEBOUNCE_SECS: how long a watched dir has to go without changes
+    /// before they're picked up
+    pub watch_debounce: Duration,
+}
+
+/// What to do with an upload whose contents, or path, are already in the user's
+/// library.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicatePolicy {
+    /// Drop the upload
+    #[default]
+    Skip,
+    /// Add the upload, and remove the tracks it duplicates. Their playlist entries
+    /// and plays move over to the new track.
+    Replace,
+    /// Add the upload next to what's there
+    Keep,
+}
+
+impl DuplicatePolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DuplicatePolicy::Skip => "skip",
+            DuplicatePolicy::Replace => "replace",
+            DuplicatePolicy::Keep => "keep",
+        }
+    }
+}
+
+impl FromStr for DuplicatePolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(DuplicatePolicy::Skip),
+            "replace" => Ok(DuplicatePolicy::Replace),
+            "keep" => Ok(DuplicatePolicy::Keep),
+            _ => Err(()),
+        }
+    }
+}
+
+impl ReamioConfig {
+    pub fn from_env() -> Self {
+        let ret = Self {
+            open_registration: env_or("REAMIO_OPEN_REGISTRATION", false),
+            admin_user: env_or("REAMIO_ADMIN_USER", "admin".to_owned()),
+            admin_password: std::env::var("REAMIO_ADMIN_PASSWORD").ok(),
+            music_db_capacity: env_or("REAMIO_MUSIC_DB_CAPACITY", 16),
+            music_db_idle: Duration::from_secs(env_or("REAMIO_MUSIC_DB_IDLE_SECS", 600)),
+            transcode_cache_bytes: env_or::<u64>("REAMIO_TRANSCODE_CACHE_MB", 1024)
+                .saturating_mul(1024 * 1024),
+            on_duplicate: env_or("REAMIO_ON_DUPLICATE", DuplicatePolicy::default()),
+            archive_max_bytes: env_or::<u64>("REAMIO_ARCHIVE_MAX_MB", 8192)
+                .saturating_mul(1024 * 1024),
+            archive_max_entries: env_or("REAMIO_ARCHIVE_MAX_ENTRIES", 10000),
+            ingest_workers: env_or(
+                "REAMIO_INGEST_WORKERS",
+                std::thread::available_parallelism().map_or(1, |x| x.get()),
+            ),
+            shutdown_grace: Duration::from_secs(env_or("REAMIO_SHUTDOWN_GRACE_SECS", 30)),
+            maintenance_interval: Duration::from_secs(
+                env_or::<u64>("REAMIO_MAINTENANCE_INTERVAL_SECS", 60 * 60).max(1),
+            ),
+            upload_expiry: Duration::from_secs(
+                env_or::<u64>("REAMIO_UPLOAD_EXPIRY_HOURS", 24).saturating_mul(60 * 60),
+            ),
+            job_retention: Duration::from_secs(
+                env_or::<u64>("REAMIO_JOB_RETENTION_DAYS", 7).saturating_mul(24 * 60 * 60),
+            ),
+            import_roots: std::env::var_os("REAMIO_IMPORT_ROOTS")
+                .map(|x| std::env::split_paths(&x).collect())
+                .unwrap_or_default(),
+            watch_dirs: std::env::var_os("REAMIO_WATCH_DIRS")
+                .map(|x| {
+                    std::env::split_paths(&x)
+                        .filter_map(parse_watch_dir)
+                        .collect()
+                })
+                .unwrap_or_default(),
+            watch_debounce: Duration::from_secs(env_or("REAMIO_WATCH_DEBOUNCE_SECS", 5)),
+        };
+        debug!(
+            open_registration = ret.open_registration,
+            admin_user = ret.admin_user,
+            music_db_capacity = ret.music_db_capacity,
+            music_db_idle = ?ret.music_db_idle,
+            transcode_cache_bytes = ret.transcode_cache_bytes,
+            on_duplicate = ret.on_duplicate.as_str(),
+            archive_max_bytes = ret.archive_max_bytes,
+            archive_max_entries = ret.archive_max_entries,
+            ingest_workers = ret.ingest_workers,
+            shutdown_grace = ?ret.shutdown_grace,
+            maintenance_interval = ?ret.maintenance_interval,
+            upload_expiry = ?ret.upload_expiry,
+            job_retention = ?ret.job_retention,
+            import_roots = ?ret.import_roots,
+            watch_dirs = ?ret.watch_dirs,
+            watch_debounce = ?ret.watch_debounce,
+            "config loaded"
+        );
+        ret
+    }
+}
+
+/// Parse a `user=dir` entry of REAMIO_WATCH_DIRS.
+fn parse_watch_dir(entry: PathBuf) -> Option<(String, PathBuf)> {
+    let parsed = entry
+        .to_str()
+        .and_then(|x| x.split_once('='))
+        .filter(|(user, dir)| !user.trim().is_empty() && !dir.is_empty())
+        .map(|(user, dir)| (user.trim().to_lowercase(), PathBuf::from(dir)));
+    if parsed.is_none() {
+        warn!(?entry, "watch dir is not of the form user=dir, ignoring it");
+    }
+    parsed
+}
+
+/// Parse an environment variable, falling back to `default` if it is unset or does
+/// not parse.
+fn env_or<T>(key: &str, default: T) -> T
+where
+    T: FromStr,
+{
+    match std::env::var(key) {
+        Ok(val) => match val.parse() {
+            Ok(x) => x,
+            Err(_) => {
+                warn!(key, val, "could not parse config value, using the default");
+                default
+            }
+        },
+        Err(_) => default,
+    }
+}
@@ -0,0 +1,233 @@
+//! Browsing the folder layout tracks were uploaded with, as recorded in `dir` and
+//! `dir_tree` by [[task_populate_mdata_userdb_proccessing]].
+
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+    http::StatusCode,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::ReamioApp;
+use crate::auth::AuthUser;
+use crate::catalog::main_artist;
+use crate::prelude::*;
+
+/// Deepest a dir can be nested before the tree is assumed to loop.
+const DIR_DEPTH_MAX: i64 = 256;
+
+#[derive(Serialize, sqlx::FromRow, Debug)]
+pub struct DirEntry {
+    pub node: i64,
+    pub name: String,
+    /// Dirs directly inside of this one
+    pub dir_count: i64,
+    /// Tracks directly inside of this one
+    pub track_count: i64,
+}
+
+/// Subdirs of `parent`, where None is the root.
+pub async fn child_dirs(
+    db: &mut sqlx::SqliteConnection,
+    parent: Option<i64>,
+) -> Result<Vec<DirEntry>, sqlx::Error> {
+    sqlx::query_as::<_, DirEntry>(
+        "SELECT dir.node, dir.name,
+                (SELECT COUNT(*) FROM dir_tree AS sub WHERE sub.parent = dir.node) AS dir_count,
+                (SELECT COUNT(*) FROM track WHERE track.dir = dir.node) AS track_count
+             FROM dir_tree JOIN dir ON dir.node = dir_tree.node
+             WHERE dir_tree.parent IS $1
+             ORDER BY dir.name COLLATE NOCASE;",
+    )
+    .bind(parent)
+    .fetch_all(db)
+    .await
+}
+
+#[derive(Serialize, sqlx::FromRow, Debug)]
+pub struct Crumb {
+    pub node: i64,
+    pub name: String,
+}
+
+/// The dirs from the root down to `node`, including it. Empty if `node` doesn't
+/// exist.
+pub async fn breadcrumbs(
+    db: &mut sqlx::SqliteConnection,
+    node: i64,
+) -> Result<Vec<Crumb>, sqlx::Error> {
+    sqlx::query_as::<_, Crumb>(
+        "WITH RECURSIVE crumbs (node, parent, depth) AS (
+             SELECT node, parent, 0 FROM dir_tree WHERE node = $1
+             UNION ALL
+             SELECT dir_tree.node, dir_tree.parent, crumbs.depth + 1
+                 FROM dir_tree JOIN crumbs ON dir_tree.node = crumbs.parent
+                 WHERE crumbs.depth < $2
+         )
+         SELECT dir.node, dir.name
+             FROM crumbs JOIN dir ON dir.node = crumbs.node
+             ORDER BY crumbs.depth DESC;",
+    )
+    .bind(node)
+    .bind(DIR_DEPTH_MAX)
+    .fetch_all(db)
+    .await
+}
+
+#[derive(Serialize, sqlx::FromRow, Debug)]
+pub struct DirTrack {
+    id: i64,
+    title: String,
+    /// Original file name
+    fname: String,
+    album_id: Option<i64>,
+    album_name: Option<String>,
+    artist_id: Option<i64>,
+    artist_name: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct DirListing {
+    /// None is the root
+    node: Option<i64>,
+    /// From the top level dir down to this one, empty for the root
+    path: Vec<Crumb>,
+    dirs: Vec<DirEntry>,
+    tracks: Vec<DirTrack>,
+}
+
+/// List a dir, where None is the root.
+#[tracing::instrument]
+async fn list_dir(
+    state: ReamioApp,
+    user: &str,
+    node: Option<i64>,
+) -> Result<DirListing, ReamioWebError> {
+    let mut db = fetch_users_music_db(state.music_dbs, user).await?;
+    let path = match node {
+        Some(node) => {
+            let path = breadcrumbs(&mut db, node).await?;
+            if path.is_empty() {
+                return Err(ReamioWebError::IncorrectArgs(
+                    "no such dir exists".to_owned(),
+                    StatusCode::NOT_FOUND,
+                ));
+            }
+            path
+        }
+        None => vec![],
+    };
+    let dirs = child_dirs(&mut db, node).await?;
+    let tracks = sqlx::query_as::<_, DirTrack>(&format!(
+        "SELECT track.id, track.title, track.fname,
+                album.id AS album_id, album.name AS album_name,
+                artist.id AS artist_id, artist.name AS artist_name
+             FROM track
+             LEFT JOIN album_tracks ON album_tracks.track = track.id
+             LEFT JOIN album ON album.id = album_tracks.album
+             LEFT JOIN artist ON artist.id = {}
+             WHERE track.dir IS $1
+             ORDER BY track.fname COLLATE NOCASE;",
+        main_artist("track.id")
+    ))
+    .bind(node)
+    .fetch_all(&mut *db)
+    .await?;
+    trace!(dirs = dirs.len(), tracks = tracks.len(), "dir listed");
+
+    Ok(DirListing {
+        node,
+        path,
+        dirs,
+        tracks,
+    })
+}
+
+/// List the top level of a user's folders.
+///
+/// Path: GET /api/dirs
+#[tracing::instrument]
+pub async fn list_root(
+    State(state): State<ReamioApp>,
+    user: AuthUser,
+) -> Result<Json<DirListing>, ReamioWebError> {
+    Ok(Json(list_dir(state, &user.username, None).await?))
+}
+
+/// List the folders and tracks directly inside of a folder, along with the path
+/// leading to it.
+///
+/// Path: GET /api/dirs/{node}
+///
+/// Arguments:
+/// - State(state): State<ReamioApp>
+///   Server state. [[ReamioApp]].
+/// - user: AuthUser
+///   Whose folders to list. [[AuthUser]].
+/// - Path(node): Path<i64>
+///   Dir node.
+#[tracing::instrument]
+pub async fn list_node(
+    State(state): State<ReamioApp>,
+    user: AuthUser,
+    Path(node): Path<i64>,
+) -> Result<Json<DirListing>, ReamioWebError> {
+    Ok(Json(list_dir(state, &user.username, Some(node)).await?))
+}
+
+/// [[ResolveArgs]]
+/// Query arguments for [[resolve]]
+///
+/// Fields:
+/// - path: String
+///   A folder path in the same form as uploads use, such as `/Music/Artist`.
+#[derive(Deserialize, Debug)]
+pub struct ResolveArgs {
+    pub path: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ResolveReturn {
+    /// None is the root
+    node: Option<i64>,
+    path: Vec<Crumb>,
+}
+
+/// Find the dir node for a folder path.
+///
+/// Path: GET /api/dirs/resolve?path={}
+#[tracing::instrument]
+pub async fn resolve(
+    State(state): State<ReamioApp>,
+    user: AuthUser,
+    Query(ResolveArgs { path }): Query<ResolveArgs>,
+) -> Result<Json<ResolveReturn>, ReamioWebError> {
+    let mut db = fetch_users_music_db(state.music_dbs, &user.username).await?;
+    let mut node = None::<i64>;
+    // the leading '/', and a trailing one, leave empty segments behind
+    for frag in path.split('/').map(str::trim).filter(|x| !x.is_empty()) {
+        node = Some(
+            sqlx::query(
+                "SELECT dir.node
+                     FROM dir_tree JOIN dir ON dir.node = dir_tree.node
+                     WHERE dir_tree.parent IS $1 AND dir.name IS $2;",
+            )
+            .bind(node)
+            .bind(frag)
+            .fetch_optional(&mut *db)
+            .await?
+            .ok_or_else(|| {
+                ReamioWebError::IncorrectArgs(
+                    format!("no dir named \"{frag}\" exists there"),
+                    StatusCode::NOT_FOUND,
+                )
+            })?
+            .get("node"),
+        );
+    }
+    let path = match node {
+        Some(node) => breadcrumbs(&mut db, node).await?,
+        None => vec![],
+    };
+    Ok(Json(ResolveReturn { node, path }))
+}
@@ -12,6 +12,8 @@ pub enum ReamioWebError {
     AxumError(axum::Error, StatusCode),
     IOError(std::io::Error, StatusCode),
     TryFromIntError(std::num::TryFromIntError, StatusCode),
+    PasswordHashError(argon2::password_hash::Error, StatusCode),
+    JoinError(tokio::task::JoinError, StatusCode),
     IncorrectArgs(String, StatusCode),
 }
 
@@ -71,6 +73,34 @@ impl From<(StatusCode, std::num::TryFromIntError)> for ReamioWebError {
     }
 }
 
+impl From<argon2::password_hash::Error> for ReamioWebError {
+    fn from(value: argon2::password_hash::Error) -> Self {
+        ReamioWebError::from((StatusCode::INTERNAL_SERVER_ERROR, value))
+    }
+}
+
+impl From<(StatusCode, argon2::password_hash::Error)> for ReamioWebError {
+    #[tracing::instrument]
+    fn from((sc, err): (StatusCode, argon2::password_hash::Error)) -> Self {
+        error!(?sc, ?err, "reamioweberror generated");
+        Self::PasswordHashError(err, sc)
+    }
+}
+
+impl From<tokio::task::JoinError> for ReamioWebError {
+    fn from(value: tokio::task::JoinError) -> Self {
+        ReamioWebError::from((StatusCode::INTERNAL_SERVER_ERROR, value))
+    }
+}
+
+impl From<(StatusCode, tokio::task::JoinError)> for ReamioWebError {
+    #[tracing::instrument]
+    fn from((sc, err): (StatusCode, tokio::task::JoinError)) -> Self {
+        error!(?sc, ?err, "reamioweberror generated");
+        Self::JoinError(err, sc)
+    }
+}
+
 impl IntoResponse for ReamioWebError {
     fn into_response(self) -> Response {
         let code = match &self {
@@ -78,6 +108,8 @@ impl IntoResponse for ReamioWebError {
             | ReamioWebError::AxumError(_, status_code)
             | ReamioWebError::IOError(_, status_code)
             | ReamioWebError::TryFromIntError(_, status_code)
+            | ReamioWebError::PasswordHashError(_, status_code)
+            | ReamioWebError::JoinError(_, status_code)
             | ReamioWebError::IncorrectArgs(_, status_code) => status_code,
         };
         let msg: &dyn ToString = match &self {
@@ -85,6 +117,8 @@ impl IntoResponse for ReamioWebError {
             ReamioWebError::AxumError(error, _) => error,
             ReamioWebError::IOError(error, _) => error,
             ReamioWebError::TryFromIntError(error, _) => error,
+            ReamioWebError::PasswordHashError(error, _) => error,
+            ReamioWebError::JoinError(error, _) => error,
             ReamioWebError::IncorrectArgs(error, _) => error,
         };
 
@@ -96,14 +130,18 @@ impl IntoResponse for ReamioWebError {
 /// processing side. This is specifically for _not_ user facing web points, but
 /// more so for exposing raw errors in the console and (eventually) to the user as
 /// well.
-#[allow(dead_code)]
+#[allow(dead_code, clippy::upper_case_acronyms)]
 #[derive(Debug)]
 pub enum ReamioProcessingErrorInternal {
     SQL(sqlx::Error),
     IO(std::io::Error),
     PathError(ReamioPathError),
     ID3(id3::Error),
+    Image(image::ImageError),
     MetaFlac(metaflac::Error),
+    Symphonia(symphonia::core::errors::Error),
+    Vorbis(vorbis_rs::VorbisError),
+    Join(tokio::task::JoinError),
 }
 
 impl From<sqlx::Error> for ReamioProcessingErrorInternal {
@@ -134,6 +172,13 @@ impl From<id3::Error> for ReamioProcessingErrorInternal {
     }
 }
 
+impl From<image::ImageError> for ReamioProcessingErrorInternal {
+    #[tracing::instrument]
+    fn from(value: image::ImageError) -> Self {
+        Self::Image(value)
+    }
+}
+
 impl From<metaflac::Error> for ReamioProcessingErrorInternal {
     #[tracing::instrument]
     fn from(value: metaflac::Error) -> Self {
@@ -141,7 +186,44 @@ impl From<metaflac::Error> for ReamioProcessingErrorInternal {
     }
 }
 
+impl From<symphonia::core::errors::Error> for ReamioProcessingErrorInternal {
+    #[tracing::instrument]
+    fn from(value: symphonia::core::errors::Error) -> Self {
+        Self::Symphonia(value)
+    }
+}
+
+impl From<vorbis_rs::VorbisError> for ReamioProcessingErrorInternal {
+    #[tracing::instrument]
+    fn from(value: vorbis_rs::VorbisError) -> Self {
+        Self::Vorbis(value)
+    }
+}
+
+impl From<tokio::task::JoinError> for ReamioProcessingErrorInternal {
+    #[tracing::instrument]
+    fn from(value: tokio::task::JoinError) -> Self {
+        Self::Join(value)
+    }
+}
+
 #[derive(Debug)]
 pub struct ReamioPathError {
     pub msg: String,
 }
+
+impl std::fmt::Display for ReamioProcessingErrorInternal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::SQL(err) => write!(f, "database error: {err}"),
+            Self::IO(err) => write!(f, "file error: {err}"),
+            Self::PathError(err) => write!(f, "invalid path: {}", err.msg),
+            Self::ID3(err) => write!(f, "unreadable ID3 tag: {err}"),
+            Self::Image(err) => write!(f, "unreadable image: {err}"),
+            Self::MetaFlac(err) => write!(f, "unreadable FLAC metadata: {err}"),
+            Self::Symphonia(err) => write!(f, "unreadable audio: {err}"),
+            Self::Vorbis(err) => write!(f, "unreadable Vorbis stream: {err}"),
+            Self::Join(err) => write!(f, "processing crashed: {err}"),
+        }
+    }
+}
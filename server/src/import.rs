@@ -0,0 +1,425 @@
+//! Importing music that is already on the server's disk, from within one of
+//! [[ReamioConfig::import_roots]].
+//!
+//! An import walks a dir and queues every audio file and cover image in it as an
+//! upload at its path inside of the dir, to be processed like any other. The files
+//! are either copied into the user dir, or referenced in place, in which case the
+//! track is read from wherever the file is, and the server never moves or removes it.
+//!
+//! Imports referenced in place can be rescanned: new files are queued, files whose
+//! mtime changed are queued again to replace their track (which, if only the mtime
+//! changed, just takes note of it), and the tracks of files that are gone are queued
+//! for removal. A track whose file turns up elsewhere in the library follows it,
+//! rather than being removed.
+
+use axum::{Json, extract::State, http::StatusCode};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use crate::ReamioApp;
+use crate::art::is_sidecar_name;
+use crate::config::DuplicatePolicy;
+use crate::prelude::*;
+use crate::users::AdminUser;
+
+/// Extensions of the files worth queueing, besides cover images. Anything else
+/// would only end up as a failed job.
+const AUDIO_EXTENSIONS: &[&str] = &[
+    "aac", "aif", "aiff", "alac", "ape", "flac", "m4a", "m4b", "mka", "mp2", "mp3", "mp4", "oga",
+    "ogg", "opus", "wav", "wv",
+];
+
+/// Nanoseconds in a second, to compare [[mtime_of]] with times in unix seconds.
+const NANOS_PER_SEC: i64 = 1_000_000_000;
+
+/// mtime of a file, in unix nanoseconds, as stored in `track.source_mtime`. Whole
+/// seconds would miss a file that is written twice within the same second.
+pub fn mtime_of(meta: &std::fs::Metadata) -> i64 {
+    meta.modified()
+        .ok()
+        .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |x| i64::try_from(x.as_nanos()).unwrap_or(i64::MAX))
+}
+
+/// A file found by [[walk]].
+#[derive(Debug)]
+struct Found {
+    /// Absolute path
+    source: String,
+    /// Path relative to the dir being walked
+    rel: String,
+    mtime: i64,
+}
+
+fn is_importable(fname: &str) -> bool {
+    is_sidecar_name(fname)
+        || fname
+            .rsplit_once('.')
+            .is_some_and(|(_, ext)| AUDIO_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+}
+
+/// Every file worth importing under `root`, along with how many were left out for
+/// having a path that isn't UTF-8. Hidden files and dirs are skipped, and symlinks
+/// aren't followed, so nothing outside of `root` is reached. A dir that can't be
+/// read fails the whole walk, since a rescan would take its files to be gone.
+fn walk(root: &Path) -> Result<(Vec<Found>, u64), std::io::Error> {
+    let mut found = vec![];
+    let mut skipped = 0;
+    let mut dirs = vec![(root.to_path_buf(), String::new())];
+    while let Some((dir, rel)) = dirs.pop() {
+        let entries = std::fs::read_dir(&dir)
+            .map_err(|err| std::io::Error::new(err.kind(), format!("{}: {err}", dir.display())))?;
+        for entry in entries {
+            let entry = entry?;
+            let Ok(name) = entry.file_name().into_string() else {
+                warn!(path = ?entry.path(), "path is not UTF-8, skipping");
+                skipped += 1;
+                continue;
+            };
+            if name.starts_with('.') {
+                continue;
+            }
+            let file_type = entry.file_type()?;
+            let entry_rel = format!("{rel}/{name}");
+            if file_type.is_dir() {
+                dirs.push((entry.path(), entry_rel));
+            } else if file_type.is_file() && is_importable(&name) {
+                let Some(source) = entry.path().to_str().map(str::to_owned) else {
+                    skipped += 1;
+                    continue;
+                };
+                found.push(Found {
+                    source,
+                    rel: entry_rel,
+                    mtime: mtime_of(&entry.metadata()?),
+                });
+            }
+        }
+    }
+    found.sort_by(|a, b| a.rel.cmp(&b.rel));
+    Ok((found, skipped))
+}
+
+/// [[ImportArgs]]
+/// Arguments for [[import_library]]
+///
+/// Fields:
+/// - user: String
+///   The user the library is imported for.
+/// - source: String
+///   Dir on the server to import, within one of [[ReamioConfig::import_roots]].
+/// - path: Option<String>
+///   Folder the dir is imported into, following the path semantics of
+///   [[UploadArgs]]. Defaults to the root of the user dir. Rescans should use the
+///   same one as the import.
+/// - in_place: bool
+///   Reference the files where they are rather than copying them. False by default.
+/// - rescan: bool
+///   Also pick up changed and removed files, see [[import]]. Only for imports
+///   referenced in place.
+/// - on_duplicate: Option<DuplicatePolicy>
+///   As in [[UploadArgs]], for every new file.
+#[derive(Deserialize, Debug)]
+pub struct ImportArgs {
+    pub user: String,
+    pub source: String,
+    pub path: Option<String>,
+    #[serde(default)]
+    pub in_place: bool,
+    #[serde(default)]
+    pub rescan: bool,
+    pub on_duplicate: Option<DuplicatePolicy>,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct ImportReturn {
+    /// The dir imported, as resolved on the server
+    source: String,
+    /// New files queued for processing
+    queued: u64,
+    /// Files queued again for their mtime changing
+    changed: u64,
+    /// Files already in the library, or processed since they last changed
+    unchanged: u64,
+    /// Files already waiting on processing, left alone
+    pending: u64,
+    /// Files left out, see [[walk]]
+    skipped: u64,
+    /// Files that are gone, whose tracks are queued for removal
+    gone: u64,
+}
+
+/// An import of a dir on the server, checked and ready for [[scan_dir]].
+#[derive(Debug)]
+pub struct DirImport {
+    pub user: String,
+    /// Absolute path of the dir, with symlinks resolved
+    pub source: String,
+    /// Library folder the dir goes into, without a trailing '/'
+    pub dest: String,
+    pub in_place: bool,
+    pub rescan: bool,
+    pub on_duplicate: DuplicatePolicy,
+    /// Leave the tracks of a rescanned dir be when every last one of their files is
+    /// gone, which is more likely a dir that isn't mounted than a library that's all
+    /// gone.
+    pub keep_if_all_gone: bool,
+}
+
+/// A file for [[enqueue]] to queue.
+#[derive(Debug)]
+pub struct QueuedFile {
+    user: String,
+    /// Path in the user's library
+    path: String,
+    source: String,
+    in_place: bool,
+    on_duplicate: DuplicatePolicy,
+    removal: bool,
+}
+
+/// Walk a dir, finding what's new in it, and on a rescan what changed or is gone, as
+/// explained in [[import]]. What's found is for [[enqueue]] to queue.
+#[tracing::instrument(skip(user_db))]
+pub async fn scan_dir(
+    user_db: &SqlitePool,
+    music_dbs: &MusicDbMapRef,
+    import: &DirImport,
+) -> Result<(ImportReturn, Vec<QueuedFile>), ReamioWebError> {
+    let user = &import.user;
+    let walk_root = PathBuf::from(&import.source);
+    let (found, skipped) = tokio::task::spawn_blocking(move || walk(&walk_root)).await??;
+    debug!(found = found.len(), skipped, "dir walked");
+    let mut ret = ImportReturn {
+        source: import.source.clone(),
+        skipped,
+        ..Default::default()
+    };
+
+    // tracks already referencing files in here, by file, with the mtime they had
+    let prefix = format!("{}/", import.source);
+    let known: HashMap<String, (i64, Option<i64>)> = if import.in_place {
+        let mut db = fetch_users_music_db(music_dbs.clone(), user).await?;
+        sqlx::query(
+            "SELECT id, source, source_mtime FROM track
+                 WHERE substr(source, 1, length($1)) = $1;",
+        )
+        .bind(&prefix)
+        .fetch_all(&mut *db)
+        .await?
+        .into_iter()
+        .map(|x| (x.get("source"), (x.get("id"), x.get("source_mtime"))))
+        .collect()
+    } else {
+        HashMap::new()
+    };
+    // and jobs for files in here, by file: whether one is still waiting on processing,
+    // and when the last one was queued
+    let jobs: HashMap<String, (bool, Option<i64>)> = sqlx::query(
+        "SELECT source, MAX(status IN ('queued', 'processing')) AS pending,
+                MAX(created) AS created
+             FROM uploaded_files
+             WHERE user = $1 AND substr(source, 1, length($2)) = $2
+             GROUP BY source;",
+    )
+    .bind(user)
+    .bind(&prefix)
+    .fetch_all(user_db)
+    .await?
+    .into_iter()
+    .map(|x| (x.get("source"), (x.get("pending"), x.get("created"))))
+    .collect();
+
+    let mut queue = vec![];
+    let mut push = |source: &str, rel: &str, on_duplicate, removal| {
+        queue.push(QueuedFile {
+            user: user.clone(),
+            path: format!("{}{rel}", import.dest),
+            source: source.to_owned(),
+            in_place: import.in_place,
+            on_duplicate,
+            removal,
+        })
+    };
+    for file in &found {
+        let (pending, queued_at) = jobs.get(&file.source).copied().unwrap_or_default();
+        if pending {
+            ret.pending += 1;
+            continue;
+        }
+        match known.get(&file.source) {
+            // cover images, and files that failed, don't become tracks. they're only
+            // queued again once they change
+            None if queued_at.is_some_and(|x| x.saturating_mul(NANOS_PER_SEC) > file.mtime) => {
+                ret.unchanged += 1
+            }
+            None => {
+                ret.queued += 1;
+                push(&file.source, &file.rel, import.on_duplicate, false);
+            }
+            // the track of a file that changed is replaced, so the new one takes
+            // over its spot in playlists
+            Some((_, mtime)) if import.rescan && *mtime != Some(file.mtime) => {
+                ret.changed += 1;
+                push(&file.source, &file.rel, DuplicatePolicy::Replace, false);
+            }
+            Some(_) => ret.unchanged += 1,
+        }
+    }
+
+    if import.rescan {
+        let on_disk = found.iter().map(|x| &x.source).collect::<HashSet<_>>();
+        let gone = known
+            .keys()
+            .filter(|x| !on_disk.contains(x) && !jobs.get(*x).is_some_and(|(pending, _)| *pending))
+            .collect::<Vec<_>>();
+        if import.keep_if_all_gone && !gone.is_empty() && gone.len() == known.len() {
+            warn!(count = gone.len(), "no track has a file, not removing any");
+        } else {
+            for source in gone {
+                let rel = &source[import.source.len()..];
+                push(source, rel, import.on_duplicate, true);
+                ret.gone += 1;
+            }
+        }
+    }
+
+    Ok((ret, queue))
+}
+
+/// Queue files found by [[scan_dir]], all or none of them. Removals go last, so that
+/// a file that moved is found at its new place before its track would be removed,
+/// and the track follows it.
+#[tracing::instrument(skip_all, fields(count = files.len()))]
+pub async fn enqueue(
+    user_db: &SqlitePool,
+    waker: &WakeTx<PopulateMetadata>,
+    mut files: Vec<QueuedFile>,
+) -> Result<(), ReamioWebError> {
+    if files.is_empty() {
+        return Ok(());
+    }
+    files.sort_by_key(|x| x.removal);
+    let mut txn = user_db.begin_with("BEGIN IMMEDIATE").await?;
+    for file in &files {
+        let fid: i64 = sqlx::query(
+            "INSERT INTO uploaded_files (orig_path, user, fid, on_duplicate, source, in_place,
+                                         removal, created)
+                 VALUES ($1, $2, NULL, $3, $4, $5, $6, unixepoch()) RETURNING fid;",
+        )
+        .bind(&file.path)
+        .bind(&file.user)
+        .bind(file.on_duplicate.as_str())
+        .bind(&file.source)
+        .bind(file.in_place)
+        .bind(file.removal)
+        .fetch_one(&mut *txn)
+        .await?
+        .get("fid");
+        trace!(
+            fid,
+            source = file.source,
+            removal = file.removal,
+            "file queued"
+        );
+    }
+    txn.commit().await?;
+    wake(waker, PopulateMetadata);
+    Ok(())
+}
+
+/// Import, or rescan, a dir on the server into a user's library. The files are
+/// queued like uploads, and show up in the user's [[list_jobs]].
+///
+/// Path: POST /api/admin/import
+///
+/// Arguments:
+/// - State(state): State<ReamioApp>
+///   Server state. [[ReamioApp]].
+/// - _admin: AdminUser
+///   Only admins can import. [[AdminUser]].
+/// - Json(args): Json<ImportArgs>
+///   See [[ImportArgs]].
+#[tracing::instrument]
+pub async fn import_library(
+    State(state): State<ReamioApp>,
+    _admin: AdminUser,
+    Json(args): Json<ImportArgs>,
+) -> Result<Json<ImportReturn>, ReamioWebError> {
+    if state.config.import_roots.is_empty() {
+        return Err(ReamioWebError::IncorrectArgs(
+            "no import roots are configured".to_owned(),
+            StatusCode::FORBIDDEN,
+        ));
+    }
+    if args.rescan && !args.in_place {
+        return Err(ReamioWebError::IncorrectArgs(
+            "only imports referenced in place can be rescanned".to_owned(),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+    // empty for the root of the user dir
+    let dest = args.path.unwrap_or_default();
+    if !dest.is_empty() && !dest.starts_with('/') {
+        return Err(ReamioWebError::IncorrectArgs(
+            "the path is not absolute".to_owned(),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+
+    let user = args.user.trim().to_lowercase();
+    sqlx::query("SELECT username_lower FROM users WHERE username_lower = $1;")
+        .bind(&user)
+        .fetch_optional(&state.user_db)
+        .await?
+        .ok_or_else(|| {
+            ReamioWebError::IncorrectArgs("no such user exists".to_owned(), StatusCode::NOT_FOUND)
+        })?;
+
+    // resolved first, so that `..` and symlinks can't lead out of the roots
+    let source = match tokio::fs::canonicalize(&args.source).await {
+        Ok(x) if x.is_dir() => x,
+        _ => {
+            return Err(ReamioWebError::IncorrectArgs(
+                "no such dir exists".to_owned(),
+                StatusCode::BAD_REQUEST,
+            ));
+        }
+    };
+    let mut allowed = false;
+    for root in &state.config.import_roots {
+        if let Ok(root) = tokio::fs::canonicalize(root).await {
+            allowed |= source.starts_with(root);
+        }
+    }
+    if !allowed {
+        return Err(ReamioWebError::IncorrectArgs(
+            "dir is not within an import root".to_owned(),
+            StatusCode::FORBIDDEN,
+        ));
+    }
+    let Some(source) = source.to_str().map(str::to_owned) else {
+        return Err(ReamioWebError::IncorrectArgs(
+            "dir path is not UTF-8".to_owned(),
+            StatusCode::BAD_REQUEST,
+        ));
+    };
+
+    let import = DirImport {
+        user,
+        source,
+        dest: dest.trim_end_matches('/').to_owned(),
+        in_place: args.in_place,
+        rescan: args.rescan,
+        on_duplicate: args.on_duplicate.unwrap_or(state.config.on_duplicate),
+        keep_if_all_gone: true,
+    };
+    let (ret, queue) = scan_dir(&state.user_db, &state.music_dbs, &import).await?;
+    enqueue(&state.user_db, &state.populate_mdata_waker, queue).await?;
+    info!(?ret, "library imported");
+    Ok(Json(ret))
+}
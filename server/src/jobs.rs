@@ -0,0 +1,291 @@
+//! What came of uploads. Every upload is a job in `uploaded_files`, which is kept
+//! after processing so its owner can see whether it became a track, or why not, and
+//! retry or dismiss it.
+
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::ReamioApp;
+use crate::auth::AuthUser;
+use crate::prelude::*;
+use crate::resumable::{BusyGuard, tus_headers};
+
+/// Default page size.
+const PAGE_DEFAULT: i64 = 50;
+/// Largest page size.
+const PAGE_MAX: i64 = 500;
+
+/// Columns of [[UploadJob]]. Resumable uploads that aren't complete yet are
+/// `receiving`, whatever their status column says.
+const JOB_COLUMNS: &str = "fid, orig_path AS path,
+     CASE WHEN upload_offset IS NOT upload_length THEN 'receiving' ELSE status END AS status,
+     message, track, hash, on_duplicate, upload_length, upload_offset, source, in_place,
+     removal, created, finished";
+
+/// Where an upload is at.
+#[derive(Serialize, Deserialize, sqlx::Type, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum JobStatus {
+    /// A resumable upload, still waiting on some of its bytes
+    Receiving,
+    /// Waiting to be processed
+    Queued,
+    Processing,
+    /// Processed. It may not have added anything, see the message.
+    Done,
+    /// Processing went wrong, see the message
+    Failed,
+}
+
+/// An upload, as listed by [[list_jobs]].
+#[derive(Serialize, sqlx::FromRow, Debug)]
+pub struct UploadJob {
+    fid: i64,
+    /// Path the file was uploaded to
+    path: String,
+    status: JobStatus,
+    /// Why processing failed, or why nothing was added
+    message: Option<String>,
+    /// Track the upload became, or was a duplicate of
+    track: Option<i64>,
+    /// sha256 of the upload, in hex
+    hash: Option<String>,
+    on_duplicate: String,
+    /// Size and progress of resumable uploads
+    upload_length: Option<i64>,
+    upload_offset: Option<i64>,
+    /// File on the server an import is of, and whether it's referenced in place. See
+    /// [[import]].
+    source: Option<String>,
+    in_place: bool,
+    /// Whether this removes the tracks of an import whose file is gone
+    removal: bool,
+    /// Unix times
+    created: Option<i64>,
+    finished: Option<i64>,
+}
+
+/// [[JobListArgs]]
+/// Query arguments for [[list_jobs]]
+///
+/// Fields:
+/// - status: Option<JobStatus>
+///   Only jobs with this status: `receiving`, `queued`, `processing`, `done` or
+///   `failed`.
+/// - limit: Option<i64>
+///   Page size, 50 by default and at most 500.
+/// - before: Option<i64>
+///   The `next` value of the previous page.
+#[derive(Deserialize, Debug)]
+pub struct JobListArgs {
+    pub status: Option<JobStatus>,
+    pub limit: Option<i64>,
+    pub before: Option<i64>,
+}
+
+/// One page of jobs, newest first.
+#[derive(Serialize, Debug)]
+pub struct JobPage {
+    items: Vec<UploadJob>,
+    /// `before` for the next page, None on the last one
+    next: Option<i64>,
+}
+
+/// List the user's uploads, newest first.
+///
+/// Path: GET /api/uploads?status={}&limit={}&before={}
+///
+/// Arguments:
+/// - State(state): State<ReamioApp>
+///   Server state. [[ReamioApp]].
+/// - user: AuthUser
+///   The user whose uploads are listed. [[AuthUser]].
+/// - Query(args): Query<JobListArgs>
+///   See [[JobListArgs]].
+#[tracing::instrument]
+pub async fn list_jobs(
+    State(state): State<ReamioApp>,
+    user: AuthUser,
+    Query(args): Query<JobListArgs>,
+) -> Result<Json<JobPage>, ReamioWebError> {
+    let limit = args.limit.unwrap_or(PAGE_DEFAULT).clamp(1, PAGE_MAX);
+    // one more than asked for, to know if there's a next page
+    let mut items = sqlx::query_as::<_, UploadJob>(&format!(
+        "SELECT * FROM (SELECT {JOB_COLUMNS} FROM uploaded_files WHERE user = $1)
+             WHERE fid < $2 AND ($3 IS NULL OR status = $3)
+             ORDER BY fid DESC LIMIT $4;"
+    ))
+    .bind(&user.username)
+    .bind(args.before.unwrap_or(i64::MAX))
+    .bind(args.status)
+    .bind(limit + 1)
+    .fetch_all(&state.user_db)
+    .await?;
+    let next = if items.len() as i64 > limit {
+        items.truncate(limit as usize);
+        items.last().map(|x| x.fid)
+    } else {
+        None
+    };
+    trace!(count = items.len(), next);
+    Ok(Json(JobPage { items, next }))
+}
+
+/// Whether a job still has a file to process: its temp file, or for imports, the
+/// file they're of.
+pub async fn job_file_exists(fid: i64, source: Option<&str>) -> Result<bool, std::io::Error> {
+    if tokio::fs::try_exists(format!("./devdir/temp/{fid}")).await? {
+        return Ok(true);
+    }
+    match source {
+        Some(source) => tokio::fs::try_exists(source).await,
+        None => Ok(false),
+    }
+}
+
+/// A job of `user`, or 404.
+async fn fetch_job(
+    state: &ReamioApp,
+    user: &AuthUser,
+    fid: i64,
+) -> Result<UploadJob, ReamioWebError> {
+    sqlx::query_as::<_, UploadJob>(&format!(
+        "SELECT {JOB_COLUMNS} FROM uploaded_files WHERE fid = $1 AND user = $2;"
+    ))
+    .bind(fid)
+    .bind(&user.username)
+    .fetch_optional(&state.user_db)
+    .await?
+    .ok_or_else(|| {
+        ReamioWebError::IncorrectArgs("no such upload exists".to_owned(), StatusCode::NOT_FOUND)
+    })
+}
+
+/// Get a single upload.
+///
+/// Path: GET /api/uploads/{fid}
+///
+/// Arguments:
+/// - State(state): State<ReamioApp>
+///   Server state. [[ReamioApp]].
+/// - user: AuthUser
+///   The user the upload belongs to. [[AuthUser]].
+/// - Path(fid): Path<i64>
+///   Upload id.
+#[tracing::instrument]
+pub async fn get_job(
+    State(state): State<ReamioApp>,
+    user: AuthUser,
+    Path(fid): Path<i64>,
+) -> Result<Json<UploadJob>, ReamioWebError> {
+    Ok(Json(fetch_job(&state, &user, fid).await?))
+}
+
+/// Queue a failed upload to be processed again, like after a fix on the server.
+/// Uploads that failed for being unreadable are gone, and have to be uploaded again.
+///
+/// Path: POST /api/uploads/{fid}/retry
+///
+/// Arguments:
+/// - State(state): State<ReamioApp>
+///   Server state. [[ReamioApp]].
+/// - user: AuthUser
+///   The user the upload belongs to. [[AuthUser]].
+/// - Path(fid): Path<i64>
+///   Upload id.
+#[tracing::instrument]
+pub async fn retry_job(
+    State(state): State<ReamioApp>,
+    user: AuthUser,
+    Path(fid): Path<i64>,
+) -> Result<Json<UploadJob>, ReamioWebError> {
+    let job = fetch_job(&state, &user, fid).await?;
+    if job.status != JobStatus::Failed {
+        return Err(ReamioWebError::IncorrectArgs(
+            "only failed uploads can be retried".to_owned(),
+            StatusCode::CONFLICT,
+        ));
+    }
+    if !job.removal && !job_file_exists(fid, job.source.as_deref()).await? {
+        return Err(ReamioWebError::IncorrectArgs(
+            "the uploaded file is gone, upload it again".to_owned(),
+            StatusCode::CONFLICT,
+        ));
+    }
+
+    sqlx::query(
+        "UPDATE uploaded_files SET status = 'queued', message = NULL, finished = NULL
+             WHERE fid = $1 AND status = 'failed';",
+    )
+    .bind(fid)
+    .execute(&state.user_db)
+    .await?;
+    debug!(fid, "upload requeued");
+    wake(&state.populate_mdata_waker, PopulateMetadata);
+    Ok(Json(fetch_job(&state, &user, fid).await?))
+}
+
+/// Dismiss a finished upload, or abandon a resumable one that's still being
+/// received, which is termination in tus terms. Whatever is left of the file goes
+/// with it. Uploads that are queued or processing can't be dismissed.
+///
+/// Path: DELETE /api/uploads/{fid}
+///
+/// Arguments:
+/// - State(state): State<ReamioApp>
+///   Server state. [[ReamioApp]].
+/// - user: AuthUser
+///   The user the upload belongs to. [[AuthUser]].
+/// - Path(fid): Path<i64>
+///   Upload id.
+#[tracing::instrument]
+pub async fn dismiss_job(
+    State(state): State<ReamioApp>,
+    user: AuthUser,
+    Path(fid): Path<i64>,
+) -> Result<Response, ReamioWebError> {
+    let _guard = BusyGuard::lock(&state.uploads_busy, fid).ok_or_else(|| {
+        ReamioWebError::IncorrectArgs(
+            "upload is being written to".to_owned(),
+            StatusCode::CONFLICT,
+        )
+    })?;
+    let job = fetch_job(&state, &user, fid).await?;
+    if matches!(job.status, JobStatus::Queued | JobStatus::Processing) {
+        return Err(ReamioWebError::IncorrectArgs(
+            "upload is waiting on processing".to_owned(),
+            StatusCode::CONFLICT,
+        ));
+    }
+
+    // the status is checked again, in case processing picked it up in the meantime
+    let deleted = sqlx::query(
+        "DELETE FROM uploaded_files
+             WHERE fid = $1
+                   AND (status IN ('done', 'failed') OR upload_offset < upload_length);",
+    )
+    .bind(fid)
+    .execute(&state.user_db)
+    .await?
+    .rows_affected();
+    if deleted == 0 {
+        return Err(ReamioWebError::IncorrectArgs(
+            "upload is waiting on processing".to_owned(),
+            StatusCode::CONFLICT,
+        ));
+    }
+    match tokio::fs::remove_file(format!("./devdir/temp/{fid}")).await {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+            warn!(fid, ?err, "could not remove dismissed upload");
+        }
+        _ => {}
+    }
+    debug!(fid, status = ?job.status, "upload dismissed");
+    Ok((StatusCode::NO_CONTENT, tus_headers()).into_response())
+}
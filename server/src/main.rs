@@ -2,145 +2,99 @@ use axum::{
     Json, Router,
     body::Body,
     extract::{DefaultBodyLimit, Query, State},
-    http::StatusCode,
     response::IntoResponse,
-    routing::{get, post},
+    routing::{get, patch, post},
 };
 use bytes::Buf;
 use futures::TryStreamExt;
 use serde::{Deserialize, Serialize};
-use sqlx::pool::PoolConnection;
+use sha2::{Digest, Sha256};
 use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions};
-use std::{collections::HashMap, sync::Arc};
-use tokio::{
-    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
-    sync::{RwLock, watch},
-};
+use std::sync::Arc;
+use tokio::{io::AsyncWriteExt, sync::watch};
 
+mod archive;
+mod art;
+mod auth;
+mod browse;
+mod catalog;
+mod config;
+mod dirs;
 mod error;
+mod import;
+mod jobs;
+mod maintenance;
+mod musicdb;
 mod prelude;
+mod probe;
 mod process;
+mod resumable;
+mod search;
+mod stream;
+mod subsonic;
+mod supervisor;
+mod tags;
+mod transcode;
+mod users;
+#[cfg(target_os = "linux")]
+mod watcher;
 
+use crate::auth::AuthUser;
+use crate::config::{DuplicatePolicy, ReamioConfig};
+use crate::musicdb::MusicDbMap;
 use crate::prelude::*;
+use crate::supervisor::{HealthMap, Supervisor};
 
 #[derive(Clone)]
 pub struct ReamioApp {
+    pub config: Arc<ReamioConfig>,
     pub user_db: SqlitePool,
     pub music_dbs: MusicDbMapRef,
     pub populate_mdata_waker: WakeTx<PopulateMetadata>,
+    pub uploads_busy: resumable::BusyUploads,
+    pub health: HealthMap,
 }
 
 impl std::fmt::Debug for ReamioApp {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("ReamioApp")
+            .field("config", &self.config)
             .field("user_db", &self.user_db)
             .field("music_dbs", &self.music_dbs)
             .finish_non_exhaustive()
     }
 }
 
-#[tracing::instrument]
-pub async fn fetch_users_music_db<U>(
-    music_dbs: MusicDbMapRef,
-    user: U,
-) -> PoolConnection<sqlx::Sqlite>
-where
-    U: AsRef<str> + std::fmt::Debug,
-{
-    // TODO: create pools on demand, user management
-    let music_db_hold = music_dbs.upgrade().unwrap();
-    let music_db = music_db_hold.read().await;
-    let db_pool = music_db.get(user.as_ref()).unwrap();
-    db_pool.acquire().await.unwrap()
-}
-
-#[derive(Deserialize)]
-struct DownloadArgs {
-    id: i64,
-    offset_begin: u64,
-    offset_end: u64,
-}
-
-#[derive(Serialize)]
-struct DownloadReturn {
-    chunk: bytes::Bytes,
-}
-
-#[tracing::instrument]
-async fn download_track(
-    State(state): State<ReamioApp>,
-    Query(DownloadArgs {
-        id,
-        offset_begin,
-        offset_end,
-    }): Query<DownloadArgs>,
-) -> Result<Json<DownloadReturn>, ReamioWebError> {
-    // A limit: offset must represent a valid range
-    if offset_begin > offset_end {
-        return Err(ReamioWebError::IncorrectArgs(
-            "offset_begin is bigger than offset_end".to_owned(),
-            StatusCode::BAD_REQUEST,
-        ));
-    }
-
-    // A limit: no more than 1mb of reading.
-    if (offset_end - offset_begin) > 2u64.saturating_pow(20) {
-        return Err(ReamioWebError::IncorrectArgs(
-            "offset buffer size exceded".to_owned(),
-            StatusCode::BAD_REQUEST,
-        ));
-    }
-
-    // Open and setup file
-    let mut open = tokio::fs::OpenOptions::new();
-    open.read(true);
-    let mut file = open
-        .open(format!("./devdir/u/{}/{id}", "powpingdone"))
-        .await
-        .map_err(|_| {
-            ReamioWebError::IncorrectArgs("no such track exists".to_owned(), StatusCode::NOT_FOUND)
-        })?;
-    debug!(?file, "file opened");
-    let seek = file.seek(std::io::SeekFrom::Start(offset_begin)).await?;
-    trace!(seek, "file seeked");
-
-    // read it, and clamp down the bufsize
-    let mut buf = bytes::BytesMut::zeroed((offset_end - offset_begin).try_into()?);
-    let read_amt = file.read_buf(&mut buf).await?;
-    debug!(read_amt, "read bytes from file");
-    if read_amt < (offset_end - offset_begin).try_into()? {
-        trace!("clamping buffer");
-        buf.resize(read_amt, 0);
-    }
-
-    Ok(Json(DownloadReturn {
-        chunk: buf.freeze(),
-    }))
-}
-
 /// [[UploadArgs]]
 /// Query arguments for a function
 ///
 /// Fields:
 /// - pub path: String
-///     Path relative to the root of the user dir that the file will be uploaded to.
-///     The path represented follows the following semantics:
-///     
+///   Path relative to the root of the user dir that the file will be uploaded to.
+///   The path represented follows the following semantics:
+///
 ///     + The path is valid utf-8 (ie: String::from_utf8).
 ///     + Each item is seperated by a '/'.
 ///     + The path starts with '/', indicating the root of the dir.
 ///     + There is no preceding items for the first '/'.
 ///     + All items before the last item is a folder.
 ///     + The last item is the file name.
+/// - pub on_duplicate: Option<DuplicatePolicy>
+///   What to do if the file, or another one at the same path, is already in the
+///   library: `skip`, `replace` or `keep`. Defaults to the server's setting, see
+///   [[ReamioConfig::on_duplicate]].
 #[derive(Deserialize)]
 struct UploadArgs {
     // TODO: Newtype this into something like "ReamioPath" with checks
     pub path: Option<String>,
+    pub on_duplicate: Option<DuplicatePolicy>,
 }
 
 #[derive(Serialize)]
 struct UploadReturn {
     written: usize,
+    /// sha256 of the upload, in hex
+    hash: Option<String>,
 }
 
 /// Ingest track. This does not process any tracks, only writes them to disk
@@ -150,21 +104,28 @@ struct UploadReturn {
 ///
 /// Arguments:
 /// - State(state): State<ReamioApp>
-///     Server state. [[ReamioApp]].
+///   Server state. [[ReamioApp]].
+/// - user: AuthUser
+///   The user the upload is assigned to. [[AuthUser]].
 /// - Query(UploadArgs { path }): Query<UploadArgs>
-///     Query args. See [[UploadArgs]] for a description of the query parameters for this function.
+///   Query args. See [[UploadArgs]] for a description of the query parameters for this function.
 /// - body: Body,
-///     Body of HTTP request. This is the item to be uploaded in it's entirety.
+///   Body of HTTP request. This is the item to be uploaded in it's entirety.
 #[tracing::instrument]
 async fn upload_track(
     State(state): State<ReamioApp>,
-    Query(UploadArgs { path }): Query<UploadArgs>,
+    user: AuthUser,
+    Query(UploadArgs { path, on_duplicate }): Query<UploadArgs>,
     body: Body,
 ) -> Result<Json<UploadReturn>, ReamioWebError> {
     let Some(path) = path else {
         trace!("no path was attached to the query");
-        return Ok(Json(UploadReturn { written: 0 }));
+        return Ok(Json(UploadReturn {
+            written: 0,
+            hash: None,
+        }));
     };
+    let on_duplicate = on_duplicate.unwrap_or(state.config.on_duplicate);
     debug!("Uploading path {}", path);
 
     // begin transaction
@@ -177,53 +138,87 @@ async fn upload_track(
     // Uuid is a external module in sqlite and I dont want to actually load a module right now
     // TODO: change this to a uuid and possibly make this path safe
     let fid: i64 = sqlx::query(
-        "INSERT INTO uploaded_files (orig_path, user, fid) VALUES ($1, $2, NULL) RETURNING fid;",
+        "INSERT INTO uploaded_files (orig_path, user, fid, on_duplicate, created)
+             VALUES ($1, $2, NULL, $3, unixepoch()) RETURNING fid;",
     )
     .bind(path)
-    // TODO: dynamic users
-    .bind("powpingdone")
+    .bind(&user.username)
+    .bind(on_duplicate.as_str())
     .fetch_one(&mut *txn)
     .await?
     .get("fid");
     trace!(fid);
 
-    // write out file
-    let mut file = tokio::fs::OpenOptions::new()
-        .create(true)
-        .write(true)
-        // this is secure because fid is i64 and cannot represent anything other than [0-9]*
-        .open(format!("./devdir/temp/{fid}"))
-        .await
-        .unwrap();
-    trace!("file opened");
+    // write out file. on any error from here on the file is removed again, and the
+    // dropped transaction takes the row with it
+    // this is secure because fid is i64 and cannot represent anything other than [0-9]*
+    let temp_path = format!("./devdir/temp/{fid}");
+    let written: Result<(usize, String), ReamioWebError> = async {
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(&temp_path)
+            .await?;
+        trace!("file opened");
 
-    let mut body = body.into_data_stream();
-    let mut size_acc = 0;
-    while let Some(mut chunk) = body.try_next().await? {
-        while chunk.has_remaining() {
-            size_acc += file.write_buf(&mut chunk).await.unwrap();
+        // hashed on the way through, so duplicates can be found without reading it again
+        let mut body = body.into_data_stream();
+        let mut hasher = Sha256::new();
+        let mut size_acc = 0;
+        while let Some(mut chunk) = body.try_next().await? {
+            hasher.update(&chunk);
+            while chunk.has_remaining() {
+                size_acc += file.write_buf(&mut chunk).await?;
+            }
         }
-    }
-    file.sync_data().await.unwrap();
-    drop(file);
-    debug!(size_acc, fid, "file written");
+        file.sync_data().await?;
+        drop(file);
+        let hash = hasher
+            .finalize()
+            .iter()
+            .map(|x| format!("{x:02x}"))
+            .collect::<String>();
+        debug!(size_acc, fid, hash, "file written");
 
-    // operation is good
-    txn.commit().await?;
-    trace!(fid, "transaction finished");
+        sqlx::query("UPDATE uploaded_files SET hash = $1 WHERE fid = $2;")
+            .bind(&hash)
+            .bind(fid)
+            .execute(&mut *txn)
+            .await?;
+
+        // operation is good
+        txn.commit().await?;
+        trace!(fid, "transaction finished");
+        Ok((size_acc, hash))
+    }
+    .await;
+    let (size_acc, hash) = match written {
+        Ok(x) => x,
+        Err(err) => {
+            if let Err(err) = tokio::fs::remove_file(&temp_path).await {
+                warn!(fid, ?err, "could not remove the file of a failed upload");
+            }
+            return Err(err);
+        }
+    };
 
     // wake the mdata
-    //
-    // TODO maintenace task: on transaction error, the files are left behind. clean
-    // them up.
-    state.populate_mdata_waker.send(PopulateMetadata).unwrap();
+    wake(&state.populate_mdata_waker, PopulateMetadata);
     trace!("sent waker for task");
-    Ok(Json(UploadReturn { written: size_acc }))
+    Ok(Json(UploadReturn {
+        written: size_acc,
+        hash: Some(hash),
+    }))
 }
 
-/// Dump table for display.
+/// Dump table for display. Tracks with no album or artist are listed under the
+/// unknown ones, with id 0. [[browse]] has paginated versions of this.
 #[tracing::instrument]
-async fn get_artist_album_track(State(state): State<ReamioApp>) -> impl IntoResponse {
+async fn get_artist_album_track(
+    State(state): State<ReamioApp>,
+    user: AuthUser,
+) -> impl IntoResponse {
     #[derive(Serialize, sqlx::FromRow, Debug)]
     struct RetRow {
         album_name: String,
@@ -235,24 +230,25 @@ async fn get_artist_album_track(State(state): State<ReamioApp>) -> impl IntoResp
         track_id: i64,
     }
 
-    // TODO user handling
-    let mut db = fetch_users_music_db(state.music_dbs, "powpingdone").await;
+    let mut db = fetch_users_music_db(state.music_dbs, &user.username).await?;
     Ok::<_, error::ReamioWebError>(Json(
-        sqlx::query_as::<_, RetRow>(
+        sqlx::query_as::<_, RetRow>(&format!(
             "SELECT
-               album.id AS album_id,
-               album.name AS album_name,
-               artist.id AS artist_id,
-               artist.name AS artist_name,
+               COALESCE(album.id, 0) AS album_id,
+               COALESCE(album.name, '{}') AS album_name,
+               COALESCE(artist.id, 0) AS artist_id,
+               COALESCE(artist.name, '{}') AS artist_name,
                track.id AS track_id,
                track.title AS track_title
-           FROM album
-           JOIN album_tracks ON album.id = album_tracks.album
-           JOIN track ON album_tracks.track = track.id
-           JOIN artist_tracks ON track.id = artist_tracks.track
-           JOIN artist ON artist_tracks.artist = artist.id
-           GROUP BY artist.id, album.id, track.id;",
-        )
+           FROM track
+           LEFT JOIN album_tracks ON album_tracks.track = track.id
+           LEFT JOIN album ON album.id = album_tracks.album
+           LEFT JOIN artist ON artist.id = {}
+           ORDER BY artist_id, album_id, track.id;",
+            browse::UNKNOWN_ALBUM,
+            browse::UNKNOWN_ARTIST,
+            catalog::main_artist("track.id"),
+        ))
         .fetch_all(&mut *db)
         .await?,
     ))
@@ -267,6 +263,7 @@ async fn main() {
         .pretty()
         .init();
 
+    let config = Arc::new(ReamioConfig::from_env());
     let user_db = SqlitePoolOptions::new()
         .connect_with(
             SqliteConnectOptions::new()
@@ -280,71 +277,175 @@ async fn main() {
         .run(&user_db)
         .await
         .unwrap();
-    sqlx::query(
-        "INSERT OR IGNORE INTO users (username_lower, username_orig, phc) VALUES ('powpingdone', 'powpingdone', '');",
-    )
-        .execute(&user_db)
-        .await
-        .unwrap();
-
-    // testing db
-    let ppd_db = SqlitePoolOptions::new()
-        .connect_with(
-            SqliteConnectOptions::new()
-                .filename("./devdir/u/powpingdone/music.db")
-                .create_if_missing(true)
-                .journal_mode(SqliteJournalMode::Wal),
-        )
-        .await
-        .unwrap();
-    sqlx::migrate!("src/migrations/per_user")
-        .run(&ppd_db)
-        .await
-        .unwrap();
+    users::bootstrap_admin(&user_db, &config).await.unwrap();
+    tokio::fs::create_dir_all("./devdir/temp").await.unwrap();
 
     // setup state props
-    let music_dbs = Arc::new(RwLock::new(HashMap::from([(
-        "powpingdone".to_owned(),
-        ppd_db,
-    )])));
+    let music_dbs = Arc::new(MusicDbMap::new(user_db.clone(), &config));
     let w_music_dbs = Arc::downgrade(&music_dbs);
 
-    // fire background tasks
+    // fire background tasks, which are restarted if they crash
     let (tx_mdata, rx_mdata) = watch::channel(PopulateMetadata);
-    let mdata_bg_task = tokio::spawn(process::task_populate_mdata(
-        rx_mdata,
-        user_db.clone(),
-        w_music_dbs.clone(),
-    ));
+    let mut supervisor = Supervisor::new();
+    {
+        let user_db = user_db.clone();
+        let music_dbs = w_music_dbs.clone();
+        let workers = config.ingest_workers;
+        // stops by itself once the server, and with it the waker, is gone
+        supervisor.spawn("populate_mdata", move |_| {
+            process::task_populate_mdata(
+                rx_mdata.clone(),
+                user_db.clone(),
+                music_dbs.clone(),
+                workers,
+            )
+        });
+    }
+    {
+        let music_dbs = w_music_dbs.clone();
+        supervisor.spawn("evict_idle", move |shutdown| {
+            musicdb::task_evict_idle(music_dbs.clone(), shutdown)
+        });
+    }
+    {
+        let user_db = user_db.clone();
+        let music_dbs = w_music_dbs.clone();
+        let config = config.clone();
+        supervisor.spawn("maintenance", move |shutdown| {
+            maintenance::task_maintenance(
+                user_db.clone(),
+                music_dbs.clone(),
+                config.clone(),
+                shutdown,
+            )
+        });
+    }
+    if !config.watch_dirs.is_empty() {
+        #[cfg(target_os = "linux")]
+        {
+            let user_db = user_db.clone();
+            let music_dbs = w_music_dbs.clone();
+            let waker = tx_mdata.clone();
+            let config = config.clone();
+            supervisor.spawn("watch", move |shutdown| {
+                watcher::task_watch(
+                    user_db.clone(),
+                    music_dbs.clone(),
+                    waker.clone(),
+                    config.clone(),
+                    shutdown,
+                )
+            });
+        }
+        #[cfg(not(target_os = "linux"))]
+        warn!("watching dirs needs inotify, which is linux only, not watching any");
+    }
+    let shutdown_grace = config.shutdown_grace;
 
     // run server
     let state = ReamioApp {
+        config,
         user_db,
         music_dbs: w_music_dbs,
         populate_mdata_waker: tx_mdata,
+        uploads_busy: Default::default(),
+        health: supervisor.health(),
     };
     let router = Router::new()
         .nest(
             "/api",
             Router::new()
+                .route("/health", get(supervisor::get_health))
+                .route("/login", post(auth::login))
+                .route("/logout", post(auth::logout))
+                .route("/register", post(users::register))
+                .route(
+                    "/admin/users",
+                    get(users::list_users).post(users::admin_create_user),
+                )
+                .route(
+                    "/admin/users/{user}",
+                    patch(users::update_user).delete(users::delete_user),
+                )
+                .route("/admin/import", post(import::import_library))
                 .route("/tabledump", get(get_artist_album_track))
+                .route("/search", get(search::search))
+                .route("/artists", get(browse::list_artists))
+                .route("/artists/{id}/albums", get(browse::list_artist_albums))
+                .route("/albums/{id}/tracks", get(browse::list_album_tracks))
+                .route("/tracks", get(browse::list_tracks))
+                .route("/tracks/{id}/tags", get(browse::track_tags))
+                .route("/dirs", get(dirs::list_root))
+                .route("/dirs/resolve", get(dirs::resolve))
+                .route("/dirs/{node}", get(dirs::list_node))
+                .route("/track/{id}/stream", get(stream::stream_track))
+                .route("/track/{id}/transcode", get(transcode::transcode_track))
+                .route("/art/{id}", get(art::get_art))
+                .route(
+                    "/subsonic/password",
+                    post(subsonic::create_app_password).delete(subsonic::delete_app_password),
+                )
                 .merge(
                     Router::new()
                         .route("/upload", post(upload_track))
+                        .route("/upload/archive", post(archive::upload_archive))
+                        .route(
+                            "/uploads",
+                            get(jobs::list_jobs)
+                                .post(resumable::create_upload)
+                                .options(resumable::tus_options),
+                        )
+                        .route(
+                            "/uploads/{fid}",
+                            get(jobs::get_job)
+                                .head(resumable::upload_offset)
+                                .patch(resumable::append_upload)
+                                .delete(jobs::dismiss_job),
+                        )
+                        .route("/uploads/{fid}/retry", post(jobs::retry_job))
                         // TODO: dynamically enable large uploads via an in-server toggle
                         .layer(DefaultBodyLimit::disable()),
                 ),
         )
+        .route(
+            "/rest/{method}",
+            get(subsonic::dispatch).post(subsonic::dispatch),
+        )
         .layer(tower_http::trace::TraceLayer::new_for_http())
         .with_state(state);
     axum::serve(
         tokio::net::TcpListener::bind("0.0.0.0:8080").await.unwrap(),
         router,
     )
+    .with_graceful_shutdown(shutdown_signal())
     .await
     .unwrap();
+    info!("shutting down");
 
-    // cleanup, and drop everything
+    // cleanup, and drop everything. uploads being processed get to finish before
+    // the music dbs are closed
+    supervisor.shutdown(shutdown_grace).await;
     drop(music_dbs);
-    drop(mdata_bg_task.await);
+}
+
+/// Resolves on ctrl-c, or SIGTERM.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("could not listen for ctrl-c");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("could not listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
 }
@@ -0,0 +1,367 @@
+//! Periodic cleanup, for whatever crashes and failed transactions leave behind.
+//!
+//! Every run reconciles, in order:
+//!
+//!   + `uploaded_files` against `devdir/temp`: abandoned resumable uploads and old
+//!     finished jobs go, and queued jobs whose file is gone fail.
+//!   + `devdir/temp` against `uploaded_files`: files no job points at go.
+//!   + `devdir/u/<user>/` against the user's `track` and `art` rows: files with no
+//!     track are moved to `devdir/quarantine/<user>/`, tracks with no file are
+//!     deleted, and art nothing points at goes. Tracks imported in place are left
+//!     to [[import]] rescans.
+//!
+//! and logs a [[MaintenanceReport]] of what it did.
+
+use serde::Serialize;
+use std::{
+    collections::HashSet,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::catalog;
+use crate::config::ReamioConfig;
+use crate::jobs::job_file_exists;
+use crate::prelude::*;
+
+/// How old a file without an owner has to be before it's cleaned up, since uploads
+/// write their file before the row that owns it is committed.
+const ORPHAN_GRACE: Duration = Duration::from_secs(60 * 60);
+
+const QUARANTINE_DIR: &str = "./devdir/quarantine";
+
+/// What a maintenance run did.
+#[derive(Serialize, Debug, Default)]
+pub struct MaintenanceReport {
+    /// Resumable uploads that stopped receiving data, and were thrown away
+    uploads_expired: u64,
+    /// Finished jobs past [[ReamioConfig::job_retention]]
+    jobs_pruned: u64,
+    /// Queued jobs whose file went missing, marked as failed
+    jobs_missing_file: u64,
+    /// Files in `devdir/temp` that no job points at
+    temp_removed: u64,
+    /// Files in a user dir that no track points at
+    files_quarantined: u64,
+    /// Tracks whose file is missing
+    tracks_removed: u64,
+    /// Art that nothing points at, or that has no row
+    art_removed: u64,
+    /// Dirs in `devdir/u` that don't belong to any user. These are only reported.
+    unknown_user_dirs: u64,
+}
+
+/// Run [[run_maintenance]] every [[ReamioConfig::maintenance_interval]], starting
+/// right away to clean up after an unclean shutdown.
+#[tracing::instrument(skip(config))]
+pub async fn task_maintenance(
+    user_db: SqlitePool,
+    music_dbs: MusicDbMapRef,
+    config: Arc<ReamioConfig>,
+    shutdown: CancellationToken,
+) {
+    let mut interval = tokio::time::interval(config.maintenance_interval);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.cancelled() => break,
+        }
+        let report = run_maintenance(&user_db, &music_dbs, &config).await;
+        info!(?report, "maintenance done");
+    }
+}
+
+/// Go through every reconciliation once. A step that fails is logged and skipped,
+/// so one broken user dir doesn't hold up the rest.
+#[tracing::instrument(skip(config))]
+pub async fn run_maintenance(
+    user_db: &SqlitePool,
+    music_dbs: &MusicDbMapRef,
+    config: &ReamioConfig,
+) -> MaintenanceReport {
+    let mut report = MaintenanceReport::default();
+    if let Err(err) = reconcile_jobs(user_db, config, &mut report).await {
+        error!(?err, "while reconciling upload jobs");
+    }
+    if let Err(err) = reconcile_temp(user_db, &mut report).await {
+        error!(?err, "while reconciling the temp dir");
+    }
+
+    let users = match sqlx::query("SELECT username_lower FROM users;")
+        .fetch_all(user_db)
+        .await
+    {
+        Ok(rows) => rows
+            .into_iter()
+            .map(|x| x.get::<String, _>("username_lower"))
+            .collect::<HashSet<_>>(),
+        Err(err) => {
+            error!(?err, "while listing users");
+            return report;
+        }
+    };
+    match list_dir("./devdir/u").await {
+        Ok(dirs) => {
+            for dir in dirs {
+                if !users.contains(&dir) {
+                    warn!(dir, "user dir without a user, leaving it alone");
+                    report.unknown_user_dirs += 1;
+                }
+            }
+        }
+        Err(err) => error!(?err, "while listing user dirs"),
+    }
+    for user in users {
+        // users that never uploaded anything have no dir, and nothing to clean up
+        if !tokio::fs::try_exists(format!("./devdir/u/{user}"))
+            .await
+            .unwrap_or(false)
+        {
+            continue;
+        }
+        if let Err(err) = reconcile_user(music_dbs, &user, &mut report)
+            .instrument(info_span!("user", user))
+            .await
+        {
+            error!(user, ?err, "while reconciling user dir");
+        }
+    }
+    report
+}
+
+/// Names of the entries of a dir.
+async fn list_dir(path: &str) -> Result<Vec<String>, std::io::Error> {
+    let mut names = vec![];
+    let mut dir = match tokio::fs::read_dir(path).await {
+        Ok(x) => x,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(names),
+        Err(err) => return Err(err),
+    };
+    while let Some(entry) = dir.next_entry().await? {
+        names.push(entry.file_name().to_string_lossy().into_owned());
+    }
+    Ok(names)
+}
+
+/// Whether the file or dir at `path` was last changed over [[ORPHAN_GRACE]] ago.
+async fn is_stale(path: &str) -> bool {
+    tokio::fs::metadata(path)
+        .await
+        .and_then(|x| x.modified())
+        .ok()
+        .and_then(|x| x.elapsed().ok())
+        .is_some_and(|x| x > ORPHAN_GRACE)
+}
+
+/// Remove a file or dir, not minding if it's already gone.
+async fn remove_path(path: &str) -> Result<(), std::io::Error> {
+    let ret = match tokio::fs::metadata(path).await {
+        Ok(meta) if meta.is_dir() => tokio::fs::remove_dir_all(path).await,
+        Ok(_) => tokio::fs::remove_file(path).await,
+        Err(err) => Err(err),
+    };
+    match ret {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+async fn remove_temp_files(fids: &[i64]) {
+    for fid in fids {
+        if let Err(err) = remove_path(&format!("./devdir/temp/{fid}")).await {
+            warn!(fid, ?err, "could not remove temp file");
+        }
+    }
+}
+
+/// Reconcile `uploaded_files` against `devdir/temp`.
+#[tracing::instrument(skip_all)]
+async fn reconcile_jobs(
+    user_db: &SqlitePool,
+    config: &ReamioConfig,
+    report: &mut MaintenanceReport,
+) -> Result<(), ReamioProcessingErrorInternal> {
+    let fids = |rows: Vec<sqlx::sqlite::SqliteRow>| {
+        rows.into_iter()
+            .map(|x| x.get::<i64, _>("fid"))
+            .collect::<Vec<_>>()
+    };
+
+    let expired = fids(
+        sqlx::query(
+            "DELETE FROM uploaded_files
+                 WHERE upload_offset < upload_length AND touched < unixepoch() - $1
+                 RETURNING fid;",
+        )
+        .bind(config.upload_expiry.as_secs() as i64)
+        .fetch_all(user_db)
+        .await?,
+    );
+    remove_temp_files(&expired).await;
+    debug!(?expired, "abandoned uploads expired");
+    report.uploads_expired += expired.len() as u64;
+
+    // failed jobs hold on to their file for a retry until now
+    let pruned = fids(
+        sqlx::query(
+            "DELETE FROM uploaded_files
+                 WHERE status IN ('done', 'failed') AND finished < unixepoch() - $1
+                 RETURNING fid;",
+        )
+        .bind(config.job_retention.as_secs() as i64)
+        .fetch_all(user_db)
+        .await?,
+    );
+    remove_temp_files(&pruned).await;
+    debug!(count = pruned.len(), "finished jobs pruned");
+    report.jobs_pruned += pruned.len() as u64;
+
+    let queued = sqlx::query(
+        "SELECT fid, source FROM uploaded_files
+             WHERE status = 'queued' AND upload_offset IS upload_length AND NOT removal;",
+    )
+    .fetch_all(user_db)
+    .await?;
+    for row in queued {
+        let fid: i64 = row.get("fid");
+        if job_file_exists(fid, row.get("source")).await? {
+            continue;
+        }
+        warn!(fid, "queued upload has no file");
+        sqlx::query(
+            "UPDATE uploaded_files
+                 SET status = 'failed', message = 'the uploaded file is missing, upload it again',
+                     finished = unixepoch()
+                 WHERE fid = $1 AND status = 'queued';",
+        )
+        .bind(fid)
+        .execute(user_db)
+        .await?;
+        report.jobs_missing_file += 1;
+    }
+    Ok(())
+}
+
+/// Reconcile `devdir/temp` against `uploaded_files`. Everything in there is either
+/// named after the fid of its job, or left behind, like a failed upload's file or the
+/// staging dir of an archive.
+#[tracing::instrument(skip_all)]
+async fn reconcile_temp(
+    user_db: &SqlitePool,
+    report: &mut MaintenanceReport,
+) -> Result<(), ReamioProcessingErrorInternal> {
+    let fids = sqlx::query("SELECT fid FROM uploaded_files;")
+        .fetch_all(user_db)
+        .await?
+        .into_iter()
+        .map(|x| x.get::<i64, _>("fid"))
+        .collect::<HashSet<_>>();
+    for name in list_dir("./devdir/temp").await? {
+        if name.parse().is_ok_and(|x: i64| fids.contains(&x)) {
+            continue;
+        }
+        let path = format!("./devdir/temp/{name}");
+        if !is_stale(&path).await {
+            trace!(name, "orphan too recent, may still be in use");
+            continue;
+        }
+        debug!(name, "removing orphaned temp file");
+        remove_path(&path).await?;
+        report.temp_removed += 1;
+    }
+    Ok(())
+}
+
+/// Reconcile `devdir/u/<user>/` against the user's music db. The write lock of the
+/// music db is held all the way through, so no upload is half way through being
+/// added while this looks.
+async fn reconcile_user(
+    music_dbs: &MusicDbMapRef,
+    user: &str,
+    report: &mut MaintenanceReport,
+) -> Result<(), ReamioProcessingErrorInternal> {
+    let mut music_db = fetch_users_music_db(music_dbs.clone(), user)
+        .await
+        .map_err(|err| std::io::Error::other(format!("{err:?}")))?;
+    let mut txn = music_db.begin_with("BEGIN IMMEDIATE").await?;
+    let dir = format!("./devdir/u/{user}");
+
+    // tracks are files named after their id, except for those imported in place,
+    // whose files are looked after by rescans
+    let tracks = sqlx::query("SELECT id FROM track WHERE source IS NULL;")
+        .fetch_all(&mut *txn)
+        .await?
+        .into_iter()
+        .map(|x| x.get::<i64, _>("id"))
+        .collect::<HashSet<_>>();
+    let files = list_dir(&dir)
+        .await?
+        .into_iter()
+        .filter_map(|x| x.parse::<i64>().ok())
+        .collect::<HashSet<_>>();
+
+    let mut quarantined = vec![];
+    for id in files.difference(&tracks) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |x| x.as_secs());
+        let to_dir = format!("{QUARANTINE_DIR}/{user}");
+        tokio::fs::create_dir_all(&to_dir).await?;
+        let to = format!("{to_dir}/{id}-{now}");
+        warn!(id, to, "file without a track, quarantining");
+        tokio::fs::rename(format!("{dir}/{id}"), to).await?;
+        quarantined.push(*id);
+    }
+    report.files_quarantined += quarantined.len() as u64;
+
+    let missing = tracks.difference(&files).copied().collect::<Vec<_>>();
+    if !missing.is_empty() && missing.len() == tracks.len() {
+        // more likely a dir that isn't mounted than a library that's all gone
+        warn!(
+            count = missing.len(),
+            "no track has a file, not removing any"
+        );
+    } else {
+        for id in &missing {
+            warn!(id, "track without a file, removing");
+            catalog::delete_track(&mut txn, *id).await?;
+        }
+        report.tracks_removed += missing.len() as u64;
+    }
+
+    // art is only kept for as long as something shows it
+    let unused = sqlx::query(
+        "DELETE FROM art WHERE id NOT IN (SELECT art FROM track WHERE art IS NOT NULL
+                                          UNION SELECT art FROM album WHERE art IS NOT NULL
+                                          UNION SELECT art FROM dir WHERE art IS NOT NULL)
+             RETURNING hash;",
+    )
+    .fetch_all(&mut *txn)
+    .await?
+    .into_iter()
+    .map(|x| x.get::<String, _>("hash"))
+    .collect::<HashSet<_>>();
+    let kept = sqlx::query("SELECT hash FROM art;")
+        .fetch_all(&mut *txn)
+        .await?
+        .into_iter()
+        .map(|x| x.get::<String, _>("hash"))
+        .collect::<HashSet<_>>();
+
+    // still under the lock, so no upload is storing art right now. that includes
+    // leftover `.part`s, and files the same as one about to be stored again
+    let art_dir = format!("{dir}/art");
+    for name in list_dir(&art_dir).await? {
+        if kept.contains(&name) {
+            continue;
+        }
+        trace!(name, unused = unused.contains(&name), "removing art");
+        remove_path(&format!("{art_dir}/{name}")).await?;
+        report.art_removed += 1;
+    }
+    txn.commit().await?;
+    debug!(?quarantined, ?missing, "user dir reconciled");
+    Ok(())
+}
@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP TABLE scrobble;
+DROP TABLE playlist_tracks;
+DROP TABLE playlist;
@@ -0,0 +1,27 @@
+-- Add up migration script here
+CREATE TABLE playlist (
+       id INTEGER PRIMARY KEY,
+       name TEXT NOT NULL,
+       comment TEXT NOT NULL DEFAULT '',
+       created INTEGER NOT NULL, -- unix time
+       changed INTEGER NOT NULL -- unix time
+) STRICT;
+
+CREATE TABLE playlist_tracks (
+       playlist INTEGER NOT NULL,
+       position INTEGER NOT NULL, -- 0 based, contiguous
+       track INTEGER NOT NULL,
+       PRIMARY KEY (playlist, position),
+       FOREIGN KEY (playlist) REFERENCES playlist (id),
+       FOREIGN KEY (track) REFERENCES track (id)
+) STRICT, WITHOUT ROWID;
+
+CREATE INDEX playlist_tracks_track ON playlist_tracks (track);
+
+CREATE TABLE scrobble (
+       track INTEGER NOT NULL,
+       time INTEGER NOT NULL, -- unix time the track was played at
+       FOREIGN KEY (track) REFERENCES track (id)
+) STRICT;
+
+CREATE INDEX scrobble_track ON scrobble (track);
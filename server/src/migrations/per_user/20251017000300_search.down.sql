@@ -0,0 +1,14 @@
+-- Add down migration script here
+DROP TRIGGER search_dir_delete;
+DROP TRIGGER search_dir_update;
+DROP TRIGGER search_dir_insert;
+DROP TRIGGER search_artist_delete;
+DROP TRIGGER search_artist_update;
+DROP TRIGGER search_artist_insert;
+DROP TRIGGER search_album_delete;
+DROP TRIGGER search_album_update;
+DROP TRIGGER search_album_insert;
+DROP TRIGGER search_track_delete;
+DROP TRIGGER search_track_update;
+DROP TRIGGER search_track_insert;
+DROP TABLE search;
@@ -0,0 +1,53 @@
+-- Add up migration script here
+-- names of everything that can be searched for. the rowid encodes where the name
+-- came from, as (id << 2) | kind, with kind being 0 track, 1 album, 2 artist, 3 dir,
+-- so that the triggers can find rows again without scanning.
+CREATE VIRTUAL TABLE search USING fts5 (
+       name,
+       tokenize = 'unicode61 remove_diacritics 2'
+);
+
+INSERT INTO search (rowid, name) SELECT (id << 2) | 0, title FROM track;
+INSERT INTO search (rowid, name) SELECT (id << 2) | 1, name FROM album;
+INSERT INTO search (rowid, name) SELECT (id << 2) | 2, name FROM artist;
+INSERT INTO search (rowid, name) SELECT (node << 2) | 3, name FROM dir;
+
+CREATE TRIGGER search_track_insert AFTER INSERT ON track BEGIN
+       INSERT INTO search (rowid, name) VALUES ((new.id << 2) | 0, new.title);
+END;
+CREATE TRIGGER search_track_update AFTER UPDATE OF title ON track BEGIN
+       UPDATE search SET name = new.title WHERE rowid = (old.id << 2) | 0;
+END;
+CREATE TRIGGER search_track_delete AFTER DELETE ON track BEGIN
+       DELETE FROM search WHERE rowid = (old.id << 2) | 0;
+END;
+
+CREATE TRIGGER search_album_insert AFTER INSERT ON album BEGIN
+       INSERT INTO search (rowid, name) VALUES ((new.id << 2) | 1, new.name);
+END;
+CREATE TRIGGER search_album_update AFTER UPDATE OF name ON album BEGIN
+       UPDATE search SET name = new.name WHERE rowid = (old.id << 2) | 1;
+END;
+CREATE TRIGGER search_album_delete AFTER DELETE ON album BEGIN
+       DELETE FROM search WHERE rowid = (old.id << 2) | 1;
+END;
+
+CREATE TRIGGER search_artist_insert AFTER INSERT ON artist BEGIN
+       INSERT INTO search (rowid, name) VALUES ((new.id << 2) | 2, new.name);
+END;
+CREATE TRIGGER search_artist_update AFTER UPDATE OF name ON artist BEGIN
+       UPDATE search SET name = new.name WHERE rowid = (old.id << 2) | 2;
+END;
+CREATE TRIGGER search_artist_delete AFTER DELETE ON artist BEGIN
+       DELETE FROM search WHERE rowid = (old.id << 2) | 2;
+END;
+
+CREATE TRIGGER search_dir_insert AFTER INSERT ON dir BEGIN
+       INSERT INTO search (rowid, name) VALUES ((new.node << 2) | 3, new.name);
+END;
+CREATE TRIGGER search_dir_update AFTER UPDATE OF name ON dir BEGIN
+       UPDATE search SET name = new.name WHERE rowid = (old.node << 2) | 3;
+END;
+CREATE TRIGGER search_dir_delete AFTER DELETE ON dir BEGIN
+       DELETE FROM search WHERE rowid = (old.node << 2) | 3;
+END;
@@ -0,0 +1,6 @@
+-- Add down migration script here
+DROP INDEX album_name_key;
+DROP INDEX artist_name_key;
+ALTER TABLE album DROP COLUMN artist;
+ALTER TABLE album DROP COLUMN name_key;
+ALTER TABLE artist DROP COLUMN name_key;
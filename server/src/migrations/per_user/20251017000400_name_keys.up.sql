@@ -0,0 +1,11 @@
+-- Add up migration script here
+-- name_key is the normalized name artists and albums are matched on, filled in (and
+-- duplicates merged) by catalog::backfill_name_keys when the db is opened, since the
+-- normalization lives in rust.
+ALTER TABLE artist ADD COLUMN name_key TEXT NULL;
+ALTER TABLE album ADD COLUMN name_key TEXT NULL;
+-- the album artist, NULL for albums without one
+ALTER TABLE album ADD COLUMN artist INTEGER NULL REFERENCES artist (id);
+
+CREATE UNIQUE INDEX artist_name_key ON artist (name_key);
+CREATE UNIQUE INDEX album_name_key ON album (name_key, COALESCE(artist, 0));
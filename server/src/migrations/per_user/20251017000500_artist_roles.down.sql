@@ -0,0 +1,16 @@
+-- Add down migration script here
+DROP INDEX album_artists_artist;
+DROP TABLE album_artists;
+
+CREATE TABLE artist_tracks_plain (
+       artist INTEGER NOT NULL,
+       track INTEGER NOT NULL,
+       PRIMARY KEY (artist, track),
+       FOREIGN KEY (artist) REFERENCES artist (id),
+       FOREIGN KEY (track) REFERENCES track (id)
+) STRICT, WITHOUT ROWID;
+
+INSERT OR IGNORE INTO artist_tracks_plain (artist, track)
+       SELECT artist, track FROM artist_tracks;
+DROP TABLE artist_tracks;
+ALTER TABLE artist_tracks_plain RENAME TO artist_tracks;
@@ -0,0 +1,34 @@
+-- Add up migration script here
+-- artist_tracks gains the role an artist has on a track, and their order within it
+CREATE TABLE artist_tracks_roles (
+       artist INTEGER NOT NULL,
+       track INTEGER NOT NULL,
+       role TEXT NOT NULL CHECK (role IN ('artist', 'featured', 'composer', 'performer')),
+       position INTEGER NOT NULL, -- order of the artist among those with the same role
+       PRIMARY KEY (artist, track, role),
+       FOREIGN KEY (artist) REFERENCES artist (id),
+       FOREIGN KEY (track) REFERENCES track (id)
+) STRICT, WITHOUT ROWID;
+
+INSERT INTO artist_tracks_roles (artist, track, role, position)
+       SELECT artist, track, 'artist', 0 FROM artist_tracks;
+DROP TABLE artist_tracks;
+ALTER TABLE artist_tracks_roles RENAME TO artist_tracks;
+
+CREATE INDEX artist_tracks_track ON artist_tracks (track, role, position);
+
+-- every album artist, in order. album.artist stays the first of them, since albums
+-- are told apart by it
+CREATE TABLE album_artists (
+       album INTEGER NOT NULL,
+       artist INTEGER NOT NULL,
+       position INTEGER NOT NULL,
+       PRIMARY KEY (album, artist),
+       FOREIGN KEY (album) REFERENCES album (id),
+       FOREIGN KEY (artist) REFERENCES artist (id)
+) STRICT, WITHOUT ROWID;
+
+CREATE INDEX album_artists_artist ON album_artists (artist);
+
+INSERT INTO album_artists (album, artist, position)
+       SELECT id, artist, 0 FROM album WHERE artist IS NOT NULL;
@@ -0,0 +1,26 @@
+-- Add down migration script here
+DROP INDEX track_number;
+DROP INDEX track_genres_genre;
+DROP TABLE track_genres;
+DROP TABLE genre;
+
+ALTER TABLE artist DROP COLUMN mb_artist_id;
+ALTER TABLE artist DROP COLUMN sort_name;
+
+ALTER TABLE album DROP COLUMN mb_release_group_id;
+ALTER TABLE album DROP COLUMN mb_album_id;
+ALTER TABLE album DROP COLUMN compilation;
+ALTER TABLE album DROP COLUMN sort_name;
+ALTER TABLE album DROP COLUMN year;
+
+ALTER TABLE track DROP COLUMN mb_release_track_id;
+ALTER TABLE track DROP COLUMN mb_track_id;
+ALTER TABLE track DROP COLUMN sort_name;
+ALTER TABLE track DROP COLUMN comment;
+ALTER TABLE track DROP COLUMN original_date;
+ALTER TABLE track DROP COLUMN date;
+ALTER TABLE track DROP COLUMN year;
+ALTER TABLE track DROP COLUMN disc_total;
+ALTER TABLE track DROP COLUMN disc_number;
+ALTER TABLE track DROP COLUMN track_total;
+ALTER TABLE track DROP COLUMN track_number;
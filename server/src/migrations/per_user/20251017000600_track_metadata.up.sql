@@ -0,0 +1,42 @@
+-- Add up migration script here
+ALTER TABLE track ADD COLUMN track_number INTEGER NULL;
+ALTER TABLE track ADD COLUMN track_total INTEGER NULL;
+ALTER TABLE track ADD COLUMN disc_number INTEGER NULL;
+ALTER TABLE track ADD COLUMN disc_total INTEGER NULL;
+ALTER TABLE track ADD COLUMN year INTEGER NULL;
+ALTER TABLE track ADD COLUMN date TEXT NULL; -- as tagged, usually YYYY or YYYY-MM-DD
+ALTER TABLE track ADD COLUMN original_date TEXT NULL;
+ALTER TABLE track ADD COLUMN comment TEXT NULL;
+ALTER TABLE track ADD COLUMN sort_name TEXT NULL;
+ALTER TABLE track ADD COLUMN mb_track_id TEXT NULL; -- recording
+ALTER TABLE track ADD COLUMN mb_release_track_id TEXT NULL;
+
+-- album and artist fields are filled in by the first track that has them
+ALTER TABLE album ADD COLUMN year INTEGER NULL;
+ALTER TABLE album ADD COLUMN sort_name TEXT NULL;
+ALTER TABLE album ADD COLUMN compilation INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE album ADD COLUMN mb_album_id TEXT NULL; -- release
+ALTER TABLE album ADD COLUMN mb_release_group_id TEXT NULL;
+
+ALTER TABLE artist ADD COLUMN sort_name TEXT NULL;
+ALTER TABLE artist ADD COLUMN mb_artist_id TEXT NULL;
+
+CREATE TABLE genre (
+       id INTEGER PRIMARY KEY,
+       name TEXT NOT NULL,
+       name_key TEXT NOT NULL UNIQUE -- see catalog::name_key
+) STRICT;
+
+CREATE TABLE track_genres (
+       track INTEGER NOT NULL,
+       genre INTEGER NOT NULL,
+       position INTEGER NOT NULL,
+       PRIMARY KEY (track, genre),
+       FOREIGN KEY (track) REFERENCES track (id),
+       FOREIGN KEY (genre) REFERENCES genre (id)
+) STRICT, WITHOUT ROWID;
+
+CREATE INDEX track_genres_genre ON track_genres (genre);
+
+-- albums list their tracks in this order
+CREATE INDEX track_number ON track (disc_number, track_number);
@@ -0,0 +1,3 @@
+-- Add down migration script here
+DROP INDEX track_tag_track;
+DROP TABLE track_tag;
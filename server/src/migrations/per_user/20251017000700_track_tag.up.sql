@@ -0,0 +1,11 @@
+-- Add up migration script here
+-- every textual tag read from a track's file, as it was in the file. keys repeat for
+-- multiple values, and rowid keeps them in the order they were read
+CREATE TABLE track_tag (
+       track INTEGER NOT NULL,
+       key TEXT NOT NULL,
+       value TEXT NOT NULL,
+       FOREIGN KEY (track) REFERENCES track (id)
+) STRICT;
+
+CREATE INDEX track_tag_track ON track_tag (track);
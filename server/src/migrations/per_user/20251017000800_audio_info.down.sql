@@ -0,0 +1,8 @@
+-- Add down migration script here
+ALTER TABLE track DROP COLUMN bitrate;
+ALTER TABLE track DROP COLUMN channels;
+ALTER TABLE track DROP COLUMN bit_depth;
+ALTER TABLE track DROP COLUMN sample_rate;
+ALTER TABLE track DROP COLUMN duration_ms;
+ALTER TABLE track DROP COLUMN codec;
+ALTER TABLE track DROP COLUMN container;
@@ -0,0 +1,10 @@
+-- Add up migration script here
+-- what the demuxer found in the file, see probe::AudioInfo. NULL for tracks
+-- uploaded before this, until they're rescanned
+ALTER TABLE track ADD COLUMN container TEXT NULL; -- mp3, flac, ogg, mp4, wav, aiff, aac
+ALTER TABLE track ADD COLUMN codec TEXT NULL;
+ALTER TABLE track ADD COLUMN duration_ms INTEGER NULL;
+ALTER TABLE track ADD COLUMN sample_rate INTEGER NULL;
+ALTER TABLE track ADD COLUMN bit_depth INTEGER NULL; -- lossless codecs only
+ALTER TABLE track ADD COLUMN channels INTEGER NULL;
+ALTER TABLE track ADD COLUMN bitrate INTEGER NULL; -- average, in bits per second
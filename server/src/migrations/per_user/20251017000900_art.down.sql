@@ -0,0 +1,5 @@
+-- Add down migration script here
+ALTER TABLE dir DROP COLUMN art;
+ALTER TABLE album DROP COLUMN art;
+ALTER TABLE track DROP COLUMN art;
+DROP TABLE art;
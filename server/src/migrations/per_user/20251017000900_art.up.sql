@@ -0,0 +1,16 @@
+-- Add up migration script here
+-- cover art, stored once per distinct image under u/<user>/art/<hash>
+CREATE TABLE art (
+       id INTEGER PRIMARY KEY,
+       hash TEXT NOT NULL UNIQUE, -- sha256 of the image, in hex
+       mime TEXT NOT NULL,
+       width INTEGER NOT NULL,
+       height INTEGER NOT NULL
+) STRICT;
+
+-- embedded in the track's file
+ALTER TABLE track ADD COLUMN art INTEGER NULL REFERENCES art (id);
+-- the first embedded art of its tracks, unless there's a cover image in its folder
+ALTER TABLE album ADD COLUMN art INTEGER NULL REFERENCES art (id);
+-- cover.jpg, folder.png and the like
+ALTER TABLE dir ADD COLUMN art INTEGER NULL REFERENCES art (id);
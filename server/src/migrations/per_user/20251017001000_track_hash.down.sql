@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP INDEX track_path;
+DROP INDEX track_hash;
+ALTER TABLE track DROP COLUMN hash;
@@ -0,0 +1,5 @@
+-- Add up migration script here
+ALTER TABLE track ADD COLUMN hash TEXT NULL; -- sha256 of the file, in hex
+
+CREATE INDEX track_hash ON track (hash);
+CREATE INDEX track_path ON track (dir, fname);
@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP INDEX track_source;
+ALTER TABLE track DROP COLUMN source_mtime;
+ALTER TABLE track DROP COLUMN source;
@@ -0,0 +1,6 @@
+-- Add up migration script here
+-- tracks imported in place are read from where they are, instead of the user dir
+ALTER TABLE track ADD COLUMN source TEXT NULL; -- absolute path of the file, when imported in place
+ALTER TABLE track ADD COLUMN source_mtime INTEGER NULL; -- unix mtime of source when it was last ingested
+
+CREATE INDEX track_source ON track (source);
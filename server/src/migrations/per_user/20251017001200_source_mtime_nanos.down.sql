@@ -0,0 +1,2 @@
+-- Add down migration script here
+UPDATE track SET source_mtime = source_mtime / 1000000000 WHERE source_mtime IS NOT NULL;
@@ -0,0 +1,3 @@
+-- Add up migration script here
+-- source_mtime goes from unix seconds to unix nanoseconds
+UPDATE track SET source_mtime = source_mtime * 1000000000 WHERE source_mtime IS NOT NULL;
@@ -0,0 +1,10 @@
+-- Add up migration script here
+CREATE TABLE sessions (
+       token TEXT PRIMARY KEY NOT NULL, -- opaque bearer token handed out on login
+       user TEXT NOT NULL, -- user the session authenticates as
+       created INTEGER NOT NULL, -- unix time of login
+       expires INTEGER NOT NULL, -- unix time past which the token is rejected
+       FOREIGN KEY (user) REFERENCES users(username_lower)
+) STRICT, WITHOUT ROWID;
+
+CREATE INDEX sessions_user ON sessions (user);
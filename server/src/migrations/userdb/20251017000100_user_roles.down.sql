@@ -0,0 +1,3 @@
+-- Add down migration script here
+ALTER TABLE users DROP COLUMN disabled;
+ALTER TABLE users DROP COLUMN admin;
@@ -0,0 +1,6 @@
+-- Add up migration script here
+ALTER TABLE users ADD COLUMN admin INTEGER NOT NULL DEFAULT 0; -- 1 if the user can manage other users
+ALTER TABLE users ADD COLUMN disabled INTEGER NOT NULL DEFAULT 0; -- 1 if the user can no longer log in
+
+-- users before this point were created by hand, and were the ones running the server
+UPDATE users SET admin = 1;
@@ -0,0 +1,4 @@
+-- Add up migration script here
+-- the subsonic token auth scheme hashes the password itself with a salt, so this
+-- has to be kept in the clear. it is a generated app password, never the login one.
+ALTER TABLE users ADD COLUMN subsonic_password TEXT NULL;
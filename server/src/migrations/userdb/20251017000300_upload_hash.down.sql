@@ -0,0 +1,3 @@
+-- Add down migration script here
+ALTER TABLE uploaded_files DROP COLUMN on_duplicate;
+ALTER TABLE uploaded_files DROP COLUMN hash;
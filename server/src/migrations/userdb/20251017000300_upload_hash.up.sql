@@ -0,0 +1,4 @@
+-- Add up migration script here
+ALTER TABLE uploaded_files ADD COLUMN hash TEXT NULL; -- sha256 of the upload, in hex
+-- what to do when the file or its path is already in the library: skip, replace or keep
+ALTER TABLE uploaded_files ADD COLUMN on_duplicate TEXT NOT NULL DEFAULT 'skip';
@@ -0,0 +1,4 @@
+-- Add down migration script here
+ALTER TABLE uploaded_files DROP COLUMN touched;
+ALTER TABLE uploaded_files DROP COLUMN upload_offset;
+ALTER TABLE uploaded_files DROP COLUMN upload_length;
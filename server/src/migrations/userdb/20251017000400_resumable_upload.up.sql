@@ -0,0 +1,6 @@
+-- Add up migration script here
+-- resumable uploads come in over several requests. the processor only picks up rows
+-- where both are equal, which includes uploads sent in one go, where both are NULL
+ALTER TABLE uploaded_files ADD COLUMN upload_length INTEGER NULL; -- total size the client announced
+ALTER TABLE uploaded_files ADD COLUMN upload_offset INTEGER NULL; -- bytes received so far
+ALTER TABLE uploaded_files ADD COLUMN touched INTEGER NULL; -- unix time data was last received
@@ -0,0 +1,10 @@
+-- Add down migration script here
+DROP INDEX uploaded_files_user;
+DROP INDEX uploaded_files_status;
+-- finished jobs were deleted right away before this
+DELETE FROM uploaded_files WHERE status IN ('done', 'failed');
+ALTER TABLE uploaded_files DROP COLUMN finished;
+ALTER TABLE uploaded_files DROP COLUMN created;
+ALTER TABLE uploaded_files DROP COLUMN track;
+ALTER TABLE uploaded_files DROP COLUMN message;
+ALTER TABLE uploaded_files DROP COLUMN status;
@@ -0,0 +1,10 @@
+-- Add up migration script here
+-- uploads are kept after processing, so their owner can find out what came of them
+ALTER TABLE uploaded_files ADD COLUMN status TEXT NOT NULL DEFAULT 'queued'; -- queued, processing, done or failed
+ALTER TABLE uploaded_files ADD COLUMN message TEXT NULL; -- why processing failed, or why nothing was added
+ALTER TABLE uploaded_files ADD COLUMN track INTEGER NULL; -- track the upload became, or duplicates, in the user's music db
+ALTER TABLE uploaded_files ADD COLUMN created INTEGER NULL; -- unix time of the upload
+ALTER TABLE uploaded_files ADD COLUMN finished INTEGER NULL; -- unix time processing finished
+
+CREATE INDEX uploaded_files_status ON uploaded_files (status);
+CREATE INDEX uploaded_files_user ON uploaded_files (user);
@@ -0,0 +1,6 @@
+-- Add down migration script here
+DROP INDEX uploaded_files_source;
+-- imports that weren't copied yet have nothing to go on without source
+DELETE FROM uploaded_files WHERE source IS NOT NULL AND status IN ('queued', 'processing');
+ALTER TABLE uploaded_files DROP COLUMN in_place;
+ALTER TABLE uploaded_files DROP COLUMN source;
@@ -0,0 +1,6 @@
+-- Add up migration script here
+-- imports of files already on the server, rather than uploads
+ALTER TABLE uploaded_files ADD COLUMN source TEXT NULL; -- absolute path of the imported file
+ALTER TABLE uploaded_files ADD COLUMN in_place INTEGER NOT NULL DEFAULT 0; -- 1 to reference source instead of copying it
+
+CREATE INDEX uploaded_files_source ON uploaded_files (source);
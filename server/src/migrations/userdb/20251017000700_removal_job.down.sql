@@ -0,0 +1,3 @@
+-- Add down migration script here
+DELETE FROM uploaded_files WHERE removal = 1;
+ALTER TABLE uploaded_files DROP COLUMN removal;
@@ -0,0 +1,2 @@
+-- Add up migration script here
+ALTER TABLE uploaded_files ADD COLUMN removal INTEGER NOT NULL DEFAULT 0; -- 1 to remove the tracks of source, whose file is gone
@@ -0,0 +1,3 @@
+-- Add down migration script here
+DELETE FROM sessions;
+ALTER TABLE sessions RENAME COLUMN token_hash TO token;
@@ -0,0 +1,6 @@
+-- Add up migration script here
+-- sessions are looked up by the sha256 of their token, so that the table alone can't
+-- be used to log in. the tokens handed out so far can't be hashed from here, so those
+-- sessions end and their users log in again
+DELETE FROM sessions;
+ALTER TABLE sessions RENAME COLUMN token TO token_hash; -- hex sha256 of the bearer token
@@ -0,0 +1,238 @@
+use axum::http::StatusCode;
+use sqlx::pool::PoolConnection;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
+
+use crate::config::ReamioConfig;
+use crate::prelude::*;
+
+/// The set of open per user music dbs.
+///
+/// Pools are opened (and migrated) the first time a user's db is asked for, and
+/// are closed again either when more than [[ReamioConfig::music_db_capacity]] are
+/// open, in which case the least recently used one goes, or when they sit unused
+/// for longer than [[ReamioConfig::music_db_idle]].
+#[derive(Debug)]
+pub struct MusicDbMap {
+    user_db: SqlitePool,
+    capacity: usize,
+    idle: Duration,
+    pools: Mutex<HashMap<String, OpenMusicDb>>,
+}
+
+#[derive(Debug)]
+struct OpenMusicDb {
+    /// None until opened. This is locked while the pool is opened, so that others
+    /// asking for the same user wait on it without holding up the whole map, and
+    /// for as long as [[MusicDbMap::evict_while]] runs.
+    slot: MusicDbSlot,
+    last_used: Instant,
+}
+
+type MusicDbSlot = Arc<Mutex<Option<SqlitePool>>>;
+
+impl OpenMusicDb {
+    /// Whether someone other than the map holds on to the slot, in which case it's
+    /// in the middle of being opened or evicted, and must stay in the map.
+    fn busy(&self) -> bool {
+        Arc::strong_count(&self.slot) > 1
+    }
+}
+
+impl MusicDbMap {
+    pub fn new(user_db: SqlitePool, config: &ReamioConfig) -> Self {
+        Self {
+            user_db,
+            capacity: config.music_db_capacity.max(1),
+            idle: config.music_db_idle,
+            pools: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Get the pool for a user, opening it if it isn't already.
+    #[tracing::instrument(skip(self))]
+    pub async fn get(&self, user: &str) -> Result<SqlitePool, ReamioWebError> {
+        let slot = {
+            let mut pools = self.pools.lock().await;
+            if !pools.contains_key(user) {
+                // make room
+                while pools.len() >= self.capacity {
+                    let Some(lru) = pools
+                        .iter()
+                        .filter(|(_, open)| !open.busy())
+                        .min_by_key(|(_, open)| open.last_used)
+                        .map(|(user, _)| user.clone())
+                    else {
+                        break;
+                    };
+                    debug!(lru, "evicting least recently used music db");
+                    if let Some(open) = pools.remove(&lru) {
+                        close_in_background(lru, open.slot);
+                    }
+                }
+            }
+            let open = pools.entry(user.to_owned()).or_insert_with(|| OpenMusicDb {
+                slot: MusicDbSlot::default(),
+                last_used: Instant::now(),
+            });
+            open.last_used = Instant::now();
+            open.slot.clone()
+        };
+
+        let mut pool = slot.lock().await;
+        if let Some(pool) = &*pool {
+            return Ok(pool.clone());
+        }
+        match self.open(user).await {
+            Ok(opened) => {
+                *pool = Some(opened.clone());
+                Ok(opened)
+            }
+            Err(err) => {
+                // don't keep a slot around for users that don't exist
+                drop(pool);
+                let mut pools = self.pools.lock().await;
+                if pools
+                    .get(user)
+                    .is_some_and(|x| Arc::ptr_eq(&x.slot, &slot) && Arc::strong_count(&slot) == 2)
+                {
+                    pools.remove(user);
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// Open a user's pool, for [[get]].
+    async fn open(&self, user: &str) -> Result<SqlitePool, ReamioWebError> {
+        // don't go creating dirs for users that don't exist
+        let exists = sqlx::query("SELECT 1 FROM users WHERE username_lower = $1;")
+            .bind(user)
+            .fetch_optional(&self.user_db)
+            .await?;
+        if exists.is_none() {
+            return Err(ReamioWebError::IncorrectArgs(
+                "no such user".to_owned(),
+                StatusCode::NOT_FOUND,
+            ));
+        }
+        Ok(open_music_db(user).await?)
+    }
+
+    /// Close a user's pool, then run `f` while no pool can be opened for them. This
+    /// is for when the user's dir is about to be moved or deleted out from under the
+    /// pool.
+    #[tracing::instrument(skip(self, f))]
+    pub async fn evict_while<F, T>(&self, user: &str, f: F) -> T
+    where
+        F: Future<Output = T>,
+    {
+        let slot = {
+            let mut pools = self.pools.lock().await;
+            let open = pools.entry(user.to_owned()).or_insert_with(|| OpenMusicDb {
+                slot: MusicDbSlot::default(),
+                last_used: Instant::now(),
+            });
+            open.slot.clone()
+        };
+        // the slot is left empty afterwards, for whoever asks for the user next
+        let mut pool = slot.lock().await;
+        if let Some(pool) = pool.take() {
+            debug!("closing music db");
+            pool.close().await;
+        }
+        f.await
+    }
+
+    /// Close every pool that hasn't been used for a while.
+    #[tracing::instrument(skip(self))]
+    async fn evict_idle(&self) {
+        let mut pools = self.pools.lock().await;
+        let idle = pools
+            .iter()
+            .filter(|(_, open)| !open.busy() && open.last_used.elapsed() > self.idle)
+            .map(|(user, _)| user.clone())
+            .collect::<Vec<_>>();
+        for user in idle {
+            debug!(user, "evicting idle music db");
+            if let Some(open) = pools.remove(&user) {
+                close_in_background(user, open.slot);
+            }
+        }
+    }
+}
+
+/// Closing waits on every checked out connection, so don't hold up the caller on it.
+fn close_in_background(user: String, slot: MusicDbSlot) {
+    tokio::spawn(
+        async move {
+            if let Some(pool) = slot.lock().await.take() {
+                pool.close().await;
+                trace!("music db closed");
+            }
+        }
+        .instrument(debug_span!("music db close", user)),
+    );
+}
+
+/// Open (creating if need be) a user's music db at `devdir/u/{user}/music.db`, and
+/// bring it up to date with the per_user migrations.
+#[tracing::instrument]
+async fn open_music_db(user: &str) -> Result<SqlitePool, sqlx::Error> {
+    tokio::fs::create_dir_all(format!("./devdir/u/{user}")).await?;
+    let pool = SqlitePoolOptions::new()
+        .connect_with(
+            SqliteConnectOptions::new()
+                .filename(format!("./devdir/u/{user}/music.db"))
+                .create_if_missing(true)
+                .journal_mode(SqliteJournalMode::Wal),
+        )
+        .await?;
+    sqlx::migrate!("src/migrations/per_user").run(&pool).await?;
+    crate::catalog::backfill_name_keys(&pool).await?;
+    debug!(user, "music db opened");
+    Ok(pool)
+}
+
+/// Get a connection to a user's music db.
+#[tracing::instrument]
+pub async fn fetch_users_music_db<U>(
+    music_dbs: MusicDbMapRef,
+    user: U,
+) -> Result<PoolConnection<sqlx::Sqlite>, ReamioWebError>
+where
+    U: AsRef<str> + std::fmt::Debug,
+{
+    let music_dbs = music_dbs.upgrade().ok_or_else(|| {
+        ReamioWebError::IncorrectArgs(
+            "server is shutting down".to_owned(),
+            StatusCode::SERVICE_UNAVAILABLE,
+        )
+    })?;
+    let pool = music_dbs.get(user.as_ref()).await?;
+    Ok(pool.acquire().await?)
+}
+
+// periodically drop music dbs nobody is using
+#[tracing::instrument]
+pub async fn task_evict_idle(music_dbs: MusicDbMapRef, shutdown: CancellationToken) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.cancelled() => break,
+        }
+        // this breaks when the map has been dropped on shutdown
+        let Some(music_dbs) = music_dbs.upgrade() else {
+            break;
+        };
+        music_dbs.evict_idle().await;
+    }
+}
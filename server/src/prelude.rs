@@ -1,6 +1,6 @@
-use std::{collections::HashMap, sync::Weak};
+use std::sync::Weak;
 
-use tokio::sync::{RwLock, watch};
+use tokio::sync::watch;
 
 #[allow(unused)]
 pub use tracing::{Instrument, Level};
@@ -14,11 +14,19 @@ pub use sqlx::prelude::*;
 pub use tracing_subscriber::prelude::*;
 
 pub use crate::error::*;
-pub use crate::fetch_users_music_db;
+pub use crate::musicdb::fetch_users_music_db;
 
-pub type MusicDbMapRef = Weak<RwLock<HashMap<String, SqlitePool>>>;
+pub type MusicDbMapRef = Weak<crate::musicdb::MusicDbMap>;
 pub type WakeTx<T> = watch::Sender<T>;
 pub type WakeRx<T> = watch::Receiver<T>;
 
 // zero sized types for wakeup
 pub struct PopulateMetadata;
+
+/// Wake the task behind `waker`. The send only fails once that task is gone, which
+/// happens while shutting down; whatever was queued is then picked up on next start.
+pub fn wake<T>(waker: &WakeTx<T>, value: T) {
+    if waker.send(value).is_err() {
+        debug!("wake sent with no task listening");
+    }
+}
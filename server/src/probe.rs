@@ -0,0 +1,206 @@
+//! Finding out what's inside an uploaded file: its container, codec and stream
+//! parameters. Anything symphonia can't find an audio track in isn't audio.
+
+use serde::Serialize;
+use std::{
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+};
+use symphonia::core::{
+    codecs::{self, CODEC_TYPE_NULL, CodecType},
+    errors::Error as SymphoniaError,
+    formats::FormatOptions,
+    io::MediaSourceStream,
+    meta::MetadataOptions,
+    probe::Hint,
+};
+
+use crate::prelude::*;
+
+/// Technical details of a track, as stored on the `track` table. Everything is
+/// optional, as tracks uploaded before probing existed have none of it.
+#[derive(Serialize, sqlx::FromRow, Debug, Default, Clone)]
+pub struct AudioInfo {
+    /// Short name of the container, see [[container_of]]
+    pub container: Option<String>,
+    pub codec: Option<String>,
+    pub duration_ms: Option<i64>,
+    pub sample_rate: Option<i64>,
+    /// Only known for lossless codecs
+    pub bit_depth: Option<i64>,
+    pub channels: Option<i64>,
+    /// Average over the whole stream, in bits per second
+    pub bitrate: Option<i64>,
+}
+
+impl AudioInfo {
+    /// Duration rounded to whole seconds, which is what Subsonic wants.
+    pub fn duration_secs(&self) -> Option<i64> {
+        self.duration_ms.map(|x| (x + 500) / 1000)
+    }
+
+    /// Bitrate in kbps, which is what Subsonic wants.
+    pub fn bitrate_kbps(&self) -> Option<i64> {
+        self.bitrate.map(|x| (x + 500) / 1000)
+    }
+}
+
+/// Short name of the container a file's first bytes belong to.
+pub fn container_of(magic: &[u8]) -> Option<&'static str> {
+    if magic.starts_with(b"fLaC") {
+        Some("flac")
+    } else if magic.starts_with(b"OggS") {
+        Some("ogg")
+    } else if magic.starts_with(b"ID3") {
+        Some("mp3")
+    } else if let [0xFF, sync, ..] = magic
+        && sync & 0xE0 == 0xE0
+    {
+        // frame sync, where a layer of 0 is ADTS
+        if sync & 0x06 == 0 {
+            Some("aac")
+        } else {
+            Some("mp3")
+        }
+    } else if magic.starts_with(b"RIFF") && magic.get(8..12) == Some(b"WAVE") {
+        Some("wav")
+    } else if magic.starts_with(b"FORM") && magic.get(8..11) == Some(b"AIF") {
+        Some("aiff")
+    } else if magic.get(4..8) == Some(b"ftyp") {
+        Some("mp4")
+    } else {
+        None
+    }
+}
+
+/// The first bytes of a file, for [[container_of]]. A leading ID3v2 tag is skipped,
+/// since some taggers put one in front of anything, FLAC and WAV included.
+fn read_magic(path: &Path) -> Result<Vec<u8>, std::io::Error> {
+    let mut file = std::fs::File::open(path)?;
+    let mut magic = vec![];
+    (&mut file).take(10).read_to_end(&mut magic)?;
+    if let [b'I', b'D', b'3', _, _, _, size @ ..] = &magic[..] {
+        // the size is syncsafe, 7 bits to a byte
+        let size = size
+            .iter()
+            .fold(0, |acc, x| (acc << 7) | u64::from(x & 0x7f));
+        file.seek(SeekFrom::Start(10 + size))?;
+        let mut after = vec![];
+        file.take(12).read_to_end(&mut after)?;
+        if container_of(&after).is_some() {
+            return Ok(after);
+        }
+    } else {
+        file.take(2).read_to_end(&mut magic)?;
+    }
+    Ok(magic)
+}
+
+/// MIME type of a container from [[container_of]].
+pub fn container_mime(container: &str) -> &'static str {
+    match container {
+        "flac" => "audio/flac",
+        "ogg" => "audio/ogg",
+        "mp3" => "audio/mpeg",
+        "aac" => "audio/aac",
+        "wav" => "audio/wav",
+        "aiff" => "audio/aiff",
+        "mp4" => "audio/mp4",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Short name of a codec. Codecs symphonia can only demux (like Opus) aren't in its
+/// registry, so the common ones are named here, and the rest (PCM and ADPCM
+/// variants, like `pcm_s16le`) go by the registry's name.
+fn codec_name(codec: CodecType) -> String {
+    let name = match codec {
+        codecs::CODEC_TYPE_MP3 => "mp3",
+        codecs::CODEC_TYPE_MP2 => "mp2",
+        codecs::CODEC_TYPE_MP1 => "mp1",
+        codecs::CODEC_TYPE_FLAC => "flac",
+        codecs::CODEC_TYPE_VORBIS => "vorbis",
+        codecs::CODEC_TYPE_OPUS => "opus",
+        codecs::CODEC_TYPE_AAC => "aac",
+        codecs::CODEC_TYPE_ALAC => "alac",
+        codecs::CODEC_TYPE_WAVPACK => "wavpack",
+        codecs::CODEC_TYPE_MONKEYS_AUDIO => "ape",
+        x => {
+            return symphonia::default::get_codecs()
+                .get_codec(x)
+                .map_or_else(|| x.to_string(), |x| x.short_name.to_owned());
+        }
+    };
+    name.to_owned()
+}
+
+/// Probe a file for its first audio track. The whole file is demuxed (but not
+/// decoded) to get an exact duration and bitrate, since plenty of files (VBR mp3s
+/// without a Xing header, for one) don't say up front.
+#[tracing::instrument]
+pub fn probe_audio(path: &Path) -> Result<AudioInfo, ReamioProcessingErrorInternal> {
+    let magic = read_magic(path)?;
+
+    let file = std::fs::File::open(path)?;
+    let mss = MediaSourceStream::new(Box::new(file), Default::default());
+    let probed = symphonia::default::get_probe().format(
+        &Hint::new(),
+        mss,
+        &FormatOptions::default(),
+        &MetadataOptions::default(),
+    )?;
+    let mut format = probed.format;
+    let track = format
+        .tracks()
+        .iter()
+        .find(|x| x.codec_params.codec != CODEC_TYPE_NULL)
+        .ok_or(SymphoniaError::Unsupported("no audio track"))?;
+    let track_id = track.id;
+    let params = track.codec_params.clone();
+    trace!(?params, "audio track found");
+
+    // frames and bytes of every packet of the track
+    let (mut frames, mut bytes) = (0u64, 0u64);
+    loop {
+        match format.next_packet() {
+            Ok(packet) if packet.track_id() == track_id => {
+                frames += packet.dur;
+                bytes += packet.data.len() as u64;
+            }
+            Ok(_) => {}
+            // the end of the stream is an "error"
+            Err(SymphoniaError::IoError(err))
+                if err.kind() == std::io::ErrorKind::UnexpectedEof =>
+            {
+                break;
+            }
+            Err(SymphoniaError::ResetRequired) => break,
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    // the declared length leaves out encoder delay and padding, so it wins
+    let frames = params.n_frames.filter(|x| *x > 0).unwrap_or(frames);
+    let duration_ms = match (params.time_base, params.sample_rate) {
+        (Some(tb), _) => {
+            let time = tb.calc_time(frames);
+            Some(time.seconds as i64 * 1000 + (time.frac * 1000.0) as i64)
+        }
+        (None, Some(rate)) => Some((frames * 1000 / rate as u64) as i64),
+        (None, None) => None,
+    }
+    .filter(|x| *x > 0);
+    let bitrate = duration_ms.map(|ms| (bytes * 8 * 1000 / ms as u64) as i64);
+
+    let info = AudioInfo {
+        container: container_of(&magic).map(str::to_owned),
+        codec: Some(codec_name(params.codec)),
+        duration_ms,
+        sample_rate: params.sample_rate.map(i64::from),
+        bit_depth: params.bits_per_sample.map(i64::from),
+        channels: params.channels.map(|x| x.count() as i64),
+        bitrate,
+    };
+    debug!(?info, "audio probed");
+    Ok(info)
+}
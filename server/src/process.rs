@@ -1,90 +1,365 @@
-use id3::TagLike;
+use std::{collections::HashMap, path::Path, sync::Arc};
+use tokio::{sync::Semaphore, task::JoinSet};
 
+use crate::art::{self, is_sidecar_name};
+use crate::catalog::{self, ArtistRole, split_featured};
+use crate::config::DuplicatePolicy;
+use crate::import;
 use crate::prelude::*;
-use std::{collections::HashMap, path::Path};
+use crate::probe::probe_audio;
+use crate::resumable::hash_file;
+use crate::tags::{TrackMetadata, extract_tags, year_of};
+
+/// Album artist of compilations that don't name one.
+const VARIOUS_ARTISTS: &str = "Various Artists";
 
 // wake on new tracks
+//
+// Uploads are processed by up to `workers` workers at once, but never two of the
+// same user at once: their music db only takes one writer at a time, so a second
+// one would just sit waiting on BEGIN IMMEDIATE. Each user with queued uploads gets
+// a worker that works through them oldest first, see [[drain_user_queue]].
 #[tracing::instrument(skip(wake))]
 pub async fn task_populate_mdata(
     mut wake: WakeRx<PopulateMetadata>,
     user_db: SqlitePool,
     music_dbs: MusicDbMapRef,
+    workers: usize,
 ) {
-    // TODO: on task_populate_mdata or other subtask panics, what should we do? is
-    // this an architecture issue?
-    //
-    // this breaks on Err from changed().await when WakeTx has been fully dropped
-    while let Ok(()) = wake.changed().await {
-        // this realistically _really_ shouldn't fail
-        let uploaded_items = sqlx::query("SELECT fid, user, orig_path FROM uploaded_files;")
-            .fetch_all(&user_db)
+    // a panic in here restarts the whole task, see [[Supervisor]]. jobs cut off by
+    // a restart start over, and everything queued gets picked up
+    if let Err(err) =
+        sqlx::query("UPDATE uploaded_files SET status = 'queued' WHERE status = 'processing';")
+            .execute(&user_db)
             .await
-            .unwrap();
-        for row in uploaded_items.into_iter() {
-            // serialize
-            let user: String = row.get("user");
-            let path: String = row.get("orig_path");
-            let fid: i64 = row.get("fid");
-
-            // The following span weirdness is due to async fun stuff. Long story short,
-            // because of Poll::Pending spans will be created incorrectly per async futures
-            // pinning the span, causing it to not drop normally. Instead, instrument the future
-            // that comes from each function. The problem is the `continue` following this
-            // statement at [[exchk]]. Because we cannot `continue` from inside an async
-            // future, manually span the following statements.
-            let rowp_span = error_span!("row processing", user, path, fid);
-            let _rowp_enter = rowp_span.enter();
-            trace!("serialized row");
-
-            // [[exchk]] check for file nonexistence, which is Err or Ok and false
-            if !tokio::fs::try_exists(format!("./devdir/temp/{fid}"))
-                .in_current_span()
-                .await
-                .is_ok_and(|x| x)
-            {
-                // TODO: maint: clean up uploaded_files that have mismatched files
-                warn!(fid, "fid does not exist in temp dir");
-                continue;
-            };
+    {
+        error!("while requeueing interrupted uploads: {err:?}");
+    }
 
-            // add the track, scoped properly
-            //
-            // TODO: make this spawn as an async task
-            {
-                let mut music_db = fetch_users_music_db(music_dbs.clone(), &user).await;
-                let user_db = &user_db;
-
-                async move {
-                    let poss_txn = music_db.begin_with("BEGIN IMMEDIATE").await;
-                    match poss_txn {
-                        Err(err) => {
-                            error!("while getting db transaction connection: {}", err);
-                            return;
-                        }
-                        Ok(txn) => {
-                            let ret =
-                                task_populate_mdata_userdb_proccessing(txn, path, user, fid).await;
-                            if let Err(err) = ret {
-                                // TODO: report upload errors to the user
-                                error!("while doing upload processing: {:?}", err);
-                            }
+    let permits = Arc::new(Semaphore::new(workers.max(1)));
+    let mut running = JoinSet::new();
+    // the user each running worker is on
+    let mut busy: HashMap<tokio::task::Id, String> = HashMap::new();
+    // false once WakeTx has been fully dropped, after which what's running is let
+    // finish, but nothing new is started
+    let mut open = true;
+    loop {
+        if open {
+            // users with uploads waiting, minus those with a worker on them already.
+            // resumable uploads still coming in have an offset short of their length
+            let waiting = sqlx::query(
+                "SELECT DISTINCT user FROM uploaded_files
+                     WHERE status = 'queued' AND upload_offset IS upload_length;",
+            )
+            .fetch_all(&user_db)
+            .await;
+            match waiting {
+                Ok(rows) => {
+                    for user in rows.into_iter().map(|x| x.get::<String, _>("user")) {
+                        if busy.values().any(|x| *x == user) {
+                            continue;
                         }
+                        trace!(user, "starting worker");
+                        let span = error_span!("upload worker", user);
+                        let handle = running.spawn(
+                            drain_user_queue(
+                                user.clone(),
+                                user_db.clone(),
+                                music_dbs.clone(),
+                                permits.clone(),
+                            )
+                            .instrument(span),
+                        );
+                        busy.insert(handle.id(), user);
                     }
+                }
+                Err(err) => error!("while looking for queued uploads: {err:?}"),
+            }
+        }
 
-                    // delete upload task after previous txn
-                    let ret = sqlx::query("DELETE FROM uploaded_files WHERE fid = $1;")
-                        .bind(fid)
-                        .execute(user_db)
-                        .await;
+        // new uploads, or a worker finishing, which may have left uploads behind that
+        // were queued just as it ran out
+        tokio::select! {
+            changed = wake.changed(), if open => {
+                open = changed.is_ok();
+            }
+            Some(done) = running.join_next_with_id() => {
+                let id = match &done {
+                    Ok((id, ())) => *id,
+                    Err(err) => err.id(),
+                };
+                let user = busy.remove(&id).unwrap_or_default();
+                if let Err(err) = done {
+                    error!(user, ?err, "upload worker crashed");
+                    // whatever it was on would crash it again
+                    let ret = sqlx::query(
+                        "UPDATE uploaded_files
+                             SET status = 'failed', message = 'processing crashed',
+                                 finished = unixepoch()
+                             WHERE user = $1 AND status = 'processing';",
+                    )
+                    .bind(&user)
+                    .execute(&user_db)
+                    .await;
                     if let Err(err) = ret {
-                        error!("when deleting from uploaded_files: {err:?}");
+                        error!("when failing crashed uploads: {err:?}");
                     }
                 }
-                .in_current_span()
+            }
+            else => break,
+        }
+    }
+    debug!("upload processing stopped");
+}
+
+/// Process the queued uploads of a user, one at a time and oldest first, until there
+/// are none left. Each one needs one of `permits` while it's processed.
+async fn drain_user_queue(
+    user: String,
+    user_db: SqlitePool,
+    music_dbs: MusicDbMapRef,
+    permits: Arc<Semaphore>,
+) {
+    loop {
+        let Ok(_permit) = permits.acquire().await else {
+            return;
+        };
+        // claimed in one go, so nothing gets processed twice
+        let claimed = sqlx::query(
+            "UPDATE uploaded_files SET status = 'processing'
+                 WHERE fid = (SELECT fid FROM uploaded_files
+                                  WHERE user = $1 AND status = 'queued'
+                                        AND upload_offset IS upload_length
+                                  ORDER BY fid LIMIT 1)
+                 RETURNING fid, orig_path, hash, on_duplicate, source, in_place, removal;",
+        )
+        .bind(&user)
+        .fetch_optional(&user_db)
+        .await;
+        let row = match claimed {
+            Ok(Some(row)) => row,
+            Ok(None) => {
+                trace!("queue empty");
+                return;
+            }
+            Err(err) => {
+                error!("while claiming an upload: {err:?}");
+                return;
+            }
+        };
+
+        // serialize
+        let mut job = ClaimedJob {
+            fid: row.get("fid"),
+            path: row.get("orig_path"),
+            hash: row.get("hash"),
+            on_duplicate: row
+                .get::<String, _>("on_duplicate")
+                .parse()
+                .unwrap_or_default(),
+            source: row.get("source"),
+            in_place: row.get("in_place"),
+            removal: row.get("removal"),
+            source_mtime: None,
+        };
+        let fid = job.fid;
+        let span = error_span!("row processing", path = job.path, fid);
+        async {
+            trace!("serialized row");
+
+            // removals are of files that are gone, with nothing to prepare
+            let prepared = if job.removal {
+                Ok(())
+            } else {
+                job.prepare(&user_db).await
+            };
+            if let Err(err) = prepared {
+                finish_job(&user_db, fid, Err(err)).await;
+                return;
+            }
+
+            let outcome = match fetch_users_music_db(music_dbs.clone(), &user).await {
+                Err(err) => {
+                    error!("while fetching music db: {err:?}");
+                    Err(format!("database error: {err:?}"))
+                }
+                Ok(mut music_db) => match music_db.begin_with("BEGIN IMMEDIATE").await {
+                    Err(err) => {
+                        error!("while getting db transaction connection: {}", err);
+                        Err(format!("database error: {err}"))
+                    }
+                    Ok(txn) => task_populate_mdata_userdb_proccessing(txn, &job, user.clone())
+                        .await
+                        .map_err(|err| {
+                            error!("while doing upload processing: {:?}", err);
+                            err.to_string()
+                        }),
+                },
+            };
+            finish_job(&user_db, fid, outcome).await;
+        }
+        .instrument(span)
+        .await;
+    }
+}
+
+/// An upload that's being processed.
+#[derive(Debug)]
+struct ClaimedJob {
+    fid: i64,
+    /// Path in the user's library
+    path: String,
+    hash: Option<String>,
+    on_duplicate: DuplicatePolicy,
+    /// File on the server this is an import of, see [[import]]
+    source: Option<String>,
+    /// Whether the track is to reference [[source]] rather than a copy of it
+    in_place: bool,
+    /// Whether this is to remove the tracks of [[source]] instead, see
+    /// [[process_removal]]
+    removal: bool,
+    /// mtime of [[source]], for imports referenced in place, see [[import::mtime_of]]
+    source_mtime: Option<i64>,
+}
+
+impl ClaimedJob {
+    fn temp_path(&self) -> String {
+        format!("./devdir/temp/{}", self.fid)
+    }
+
+    /// The file to process: the temp file, or the imported file when it's referenced
+    /// in place.
+    fn file(&self) -> String {
+        match &self.source {
+            Some(source) if self.in_place => source.clone(),
+            _ => self.temp_path(),
+        }
+    }
+
+    /// Throw the file of the job away, unless it's an imported file referenced in
+    /// place, which isn't the server's to remove.
+    async fn discard(&self) -> Result<(), std::io::Error> {
+        if self.in_place {
+            return Ok(());
+        }
+        tokio::fs::remove_file(self.temp_path()).await
+    }
+
+    /// Make sure the file of the job is there. Imports are copied into the temp dir
+    /// first, unless they're referenced in place, and hashed, which wasn't done when
+    /// they were queued. Errs with why the job can't go on.
+    async fn prepare(&mut self, user_db: &SqlitePool) -> Result<(), String> {
+        let temp_path = self.temp_path();
+        // check for file nonexistence, which is Err or Ok and false
+        let in_temp = tokio::fs::try_exists(&temp_path).await.is_ok_and(|x| x);
+        let Some(source) = &self.source else {
+            if !in_temp {
+                warn!(fid = self.fid, "fid does not exist in temp dir");
+                return Err("the uploaded file is missing, upload it again".to_owned());
+            }
+            return Ok(());
+        };
+
+        let meta = match tokio::fs::metadata(source).await {
+            Ok(x) if x.is_file() => x,
+            _ => {
+                warn!(source, "imported file does not exist");
+                return Err("the imported file is missing".to_owned());
+            }
+        };
+        if self.in_place {
+            self.source_mtime = Some(import::mtime_of(&meta));
+        } else if !in_temp {
+            // through a `.part`, so that a copy cut short isn't taken for the file
+            let part = format!("{temp_path}.part");
+            trace!(source, "copying import to temp dir");
+            let copied = match tokio::fs::copy(source, &part).await {
+                Ok(_) => tokio::fs::rename(&part, &temp_path).await,
+                Err(err) => Err(err),
+            };
+            if let Err(err) = copied {
+                warn!(source, ?err, "could not copy imported file");
+                drop(tokio::fs::remove_file(&part).await);
+                return Err(format!("could not copy the imported file: {err}"));
+            }
+        }
+
+        if self.hash.is_none() {
+            let hash = hash_file(&self.file())
                 .await
+                .map_err(|err| format!("could not read the imported file: {err}"))?;
+            let ret = sqlx::query("UPDATE uploaded_files SET hash = $1 WHERE fid = $2;")
+                .bind(&hash)
+                .bind(self.fid)
+                .execute(user_db)
+                .await;
+            if let Err(err) = ret {
+                error!("when recording import hash: {err:?}");
             }
+            self.hash = Some(hash);
         }
+        Ok(())
+    }
+}
+
+/// What came of an upload that was processed without errors.
+#[derive(Debug)]
+enum Processed {
+    /// Became this track
+    Added(i64),
+    /// Was already in the library as this track, and the policy was to skip it
+    Skipped(i64),
+    /// Was the cover image of its folder
+    FolderArt,
+    /// Is a file imported in place that moved, and its track, which moved along
+    Moved(i64),
+    /// Was the removal of this track, or of nothing, as its file came back or the
+    /// track was already gone
+    Removed(Option<i64>),
+}
+
+/// Record how processing an upload went, for [[jobs]] to report back. Failures keep
+/// their temp file, if there still is one, so the job can be retried.
+#[tracing::instrument(skip(user_db))]
+async fn finish_job(user_db: &SqlitePool, fid: i64, outcome: Result<Processed, String>) {
+    let (status, message, track) = match outcome {
+        Ok(Processed::Added(track)) => ("done", None, Some(track)),
+        Ok(Processed::Skipped(track)) => (
+            "done",
+            Some(format!("already in the library as track {track}, skipped")),
+            Some(track),
+        ),
+        Ok(Processed::FolderArt) => ("done", Some("set as folder art".to_owned()), None),
+        Ok(Processed::Moved(track)) => (
+            "done",
+            Some(format!("moved track {track} here")),
+            Some(track),
+        ),
+        Ok(Processed::Removed(track)) => (
+            "done",
+            Some(match track {
+                Some(track) => format!("removed track {track}, its file is gone"),
+                None => "nothing to remove".to_owned(),
+            }),
+            track,
+        ),
+        Err(err) => ("failed", Some(err), None),
+    };
+    let ret = sqlx::query(
+        "UPDATE uploaded_files
+             SET status = $1, message = $2, track = $3, finished = unixepoch()
+             WHERE fid = $4;",
+    )
+    .bind(status)
+    .bind(&message)
+    .bind(track)
+    .bind(fid)
+    .execute(user_db)
+    .await;
+    if let Err(err) = ret {
+        error!("when recording upload outcome: {err:?}");
     }
+    debug!(status, message, track, "upload finished");
 }
 
 // subtask function as part of the above function of the same prefix.
@@ -92,44 +367,450 @@ pub async fn task_populate_mdata(
 #[tracing::instrument]
 async fn task_populate_mdata_userdb_proccessing(
     mut txn: sqlx::Transaction<'_, sqlx::Sqlite>,
-    path: String,
+    job: &ClaimedJob,
     user: String,
-    fid: i64,
-) -> Result<(), ReamioProcessingErrorInternal> {
-    // step 1: get tags
-    let mut tags = extract_tags(fid)?;
+) -> Result<Processed, ReamioProcessingErrorInternal> {
+    let path = &job.path;
+    let hash = &job.hash;
+    let on_duplicate = job.on_duplicate;
+    let source = job.source.as_deref().filter(|_| job.in_place);
+    if job.removal {
+        return process_removal(txn, job).await;
+    }
+
+    // a rescan queues files imported in place whose mtime changed. if their contents
+    // didn't, there's nothing to do but remember the new mtime
+    if let Some(source) = source {
+        let unchanged = sqlx::query(
+            "UPDATE track SET source_mtime = $3 WHERE source = $1 AND hash = $2 RETURNING id;",
+        )
+        .bind(source)
+        .bind(hash.as_deref())
+        .bind(job.source_mtime)
+        .fetch_optional(&mut *txn)
+        .await?;
+        if let Some(row) = unchanged {
+            txn.commit().await?;
+            debug!("imported file unchanged");
+            return Ok(Processed::Skipped(row.get("id")));
+        }
+
+        // a track of the same contents whose file is gone is this file, moved. the
+        // track moves along, keeping its place in playlists
+        let same =
+            sqlx::query("SELECT id, source FROM track WHERE hash = $1 AND source IS NOT $2;")
+                .bind(hash.as_deref())
+                .bind(source)
+                .fetch_all(&mut *txn)
+                .await?;
+        for row in same {
+            let Some(old): Option<String> = row.get("source") else {
+                continue;
+            };
+            if tokio::fs::try_exists(&old).await? {
+                continue;
+            }
+            let id: i64 = row.get("id");
+            let (dir, fname) = resolve_upload_path(&mut txn, path).await?;
+            sqlx::query(
+                "UPDATE track SET dir = $2, fname = $3, source = $4, source_mtime = $5
+                     WHERE id = $1;",
+            )
+            .bind(id)
+            .bind(dir)
+            .bind(fname)
+            .bind(source)
+            .bind(job.source_mtime)
+            .execute(&mut *txn)
+            .await?;
+            txn.commit().await?;
+            debug!(id, old, "imported file moved");
+            return Ok(Processed::Moved(id));
+        }
+    }
+
+    // step 0: make sure it's audio at all. Anything else is thrown away, as nothing
+    // could ever play it, except for folder cover images
+    if path
+        .rsplit('/')
+        .next()
+        .is_some_and(|x| is_sidecar_name(x.trim()))
+    {
+        return process_sidecar_art(txn, job, &user).await;
+    }
+    // the probe and tag readers do their file reading blocking
+    let file = job.file();
+    let probe_path = file.clone();
+    let probed = tokio::task::spawn_blocking(move || probe_audio(Path::new(&probe_path))).await?;
+    let audio = match probed {
+        Ok(x) => x,
+        Err(err) => {
+            warn!(?err, "upload is not audio, rejecting");
+            job.discard().await?;
+            return Err(err);
+        }
+    };
+
+    // step 1: find the folder
+    let (parent_dir, filename) = resolve_upload_path(&mut txn, path).await?;
+
+    // step 2: look for the same file, or another file at the same path
+    let rows = sqlx::query(
+        "SELECT id, source FROM track WHERE hash = $1
+         UNION SELECT id, source FROM track WHERE dir IS $2 AND fname = $3;",
+    )
+    .bind(hash.as_deref())
+    .bind(parent_dir)
+    .bind(&filename)
+    .fetch_all(&mut *txn)
+    .await?;
+    let mut duplicates = vec![];
+    for row in rows {
+        // a file imported in place is never replaced by another while it's still
+        // there, as that would leave it without a track
+        let other: Option<String> = row.get("source");
+        if let (Some(source), Some(other)) = (source, &other)
+            && on_duplicate == DuplicatePolicy::Replace
+            && source != other
+            && tokio::fs::try_exists(other).await?
+        {
+            continue;
+        }
+        duplicates.push(row.get::<i64, _>("id"));
+    }
+    if !duplicates.is_empty() {
+        info!(
+            ?duplicates,
+            ?on_duplicate,
+            "upload is already in the library"
+        );
+        if on_duplicate == DuplicatePolicy::Skip {
+            job.discard().await?;
+            return Ok(Processed::Skipped(duplicates[0]));
+        }
+    }
+
+    // step 3: get tags
+    let tags = tokio::task::spawn_blocking(move || extract_tags(Path::new(&file))).await??;
     debug!(?tags, "tags fetched");
+    let meta = TrackMetadata::from_fields(&tags.fields);
 
-    // step 2: insert track mdata
-    //
-    // TODO: support multiple Album/Artist bindings
+    // step 4: find or create the artists and album
     //
-    // TODO: actually support inserting into the same Album/Artist
-    let album_id = match tags.remove("album") {
-        Some(album) => Some(
-            sqlx::query("INSERT INTO album (name) VALUES ($1) RETURNING id;")
-                .bind(String::from_utf8(album).unwrap())
-                .fetch_one(&mut *txn)
-                .await?
-                .get::<i64, _>("id"),
-        ),
+    // "A feat. B" credits A as the artist, and B as featured
+    let mut credits = vec![];
+    let mut featured = vec![];
+    for artist in &meta.artists {
+        let (main, feat) = split_featured(artist);
+        credits.push((main, ArtistRole::Artist));
+        featured.extend(feat);
+    }
+    credits.extend(featured.into_iter().map(|x| (x, ArtistRole::Featured)));
+    credits.extend(
+        meta.composers
+            .iter()
+            .map(|x| (x.as_str(), ArtistRole::Composer)),
+    );
+    credits.extend(
+        meta.performers
+            .iter()
+            .map(|x| (x.as_str(), ArtistRole::Performer)),
+    );
+    let mut artist_ids = vec![];
+    for (name, role) in credits {
+        artist_ids.push((catalog::find_or_create_artist(&mut txn, name).await?, role));
+    }
+    // sort names and ids only line up when there's one per artist tag
+    let main_ids = artist_ids
+        .iter()
+        .filter(|(_, role)| *role == ArtistRole::Artist)
+        .map(|(id, _)| *id)
+        .collect::<Vec<_>>();
+    fill_artists(
+        &mut txn,
+        &main_ids,
+        &meta.artist_sorts,
+        &meta.musicbrainz.artists,
+    )
+    .await?;
+    debug!(?artist_ids, "artists processed");
+
+    let album_id = match &meta.album {
+        Some(album) => {
+            // without an album artist tag, the album is by the track's artists, unless
+            // it's a compilation
+            let various = [VARIOUS_ARTISTS.to_owned()];
+            let names = match (meta.album_artists.is_empty(), meta.compilation) {
+                (false, _) => &meta.album_artists[..],
+                (true, false) => &meta.artists[..],
+                (true, true) => &various[..],
+            };
+            let mut album_artist_ids = vec![];
+            for name in names {
+                let (main, _) = split_featured(name);
+                album_artist_ids.push(catalog::find_or_create_artist(&mut txn, main).await?);
+            }
+            if !meta.album_artists.is_empty() {
+                fill_artists(
+                    &mut txn,
+                    &album_artist_ids,
+                    &meta.album_artist_sorts,
+                    &meta.musicbrainz.album_artists,
+                )
+                .await?;
+            }
+            let album_id =
+                catalog::find_or_create_album(&mut txn, album, &album_artist_ids).await?;
+            catalog::fill_album_details(&mut txn, album_id, &meta).await?;
+            Some(album_id)
+        }
         None => None,
     };
     debug!(album_id, "album processed");
-    let artist_id = match tags.remove("artist") {
-        Some(artist) => Some(
-            sqlx::query("INSERT INTO artist (name) VALUES ($1) RETURNING id;")
-                .bind(String::from_utf8(artist).unwrap())
-                .fetch_one(&mut *txn)
-                .await?
-                .get::<i64, _>("id"),
-        ),
+
+    let mut genre_ids = vec![];
+    for genre in &meta.genres {
+        genre_ids.push(catalog::find_or_create_genre(&mut txn, genre).await?);
+    }
+
+    // art that can't be read is no reason to turn the track away
+    let art_id = match tags.cover() {
+        Some(cover) => match art::store_art(&mut txn, &user, &cover.data).await {
+            Ok(x) => Some(x),
+            Err(err) => {
+                warn!(?err, "embedded art unreadable, skipping");
+                None
+            }
+        },
         None => None,
     };
-    debug!(artist_id, "artist processed");
 
-    // step 3: process requested path
-    if !path.chars().next().is_some_and(|x| x == '/') {
+    // step 5: insert track with dir
+    let track_name = meta.title.as_deref().unwrap_or(&filename);
+    // CHANGING THIS RETURN TYPE HAS CONSEQUENCES
+    let track_id = sqlx::query(
+        "INSERT INTO track (title, dir, fname, track_number, track_total, disc_number,
+                            disc_total, year, date, original_date, comment, sort_name,
+                            mb_track_id, mb_release_track_id, container, codec,
+                            duration_ms, sample_rate, bit_depth, channels, bitrate, art,
+                            hash, source, source_mtime)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16,
+                     $17, $18, $19, $20, $21, $22, $23, $24, $25)
+             RETURNING id;",
+    )
+    .bind(track_name)
+    .bind(parent_dir)
+    .bind(&filename)
+    .bind(meta.track_number)
+    .bind(meta.track_total)
+    .bind(meta.disc_number)
+    .bind(meta.disc_total)
+    .bind(meta.date.as_deref().and_then(year_of))
+    .bind(meta.date.as_deref())
+    .bind(meta.original_date.as_deref())
+    .bind(meta.comment.as_deref())
+    .bind(meta.title_sort.as_deref())
+    .bind(meta.musicbrainz.track.as_deref())
+    .bind(meta.musicbrainz.release_track.as_deref())
+    .bind(audio.container.as_deref())
+    .bind(audio.codec.as_deref())
+    .bind(audio.duration_ms)
+    .bind(audio.sample_rate)
+    .bind(audio.bit_depth)
+    .bind(audio.channels)
+    .bind(audio.bitrate)
+    .bind(art_id)
+    .bind(hash.as_deref())
+    .bind(source)
+    .bind(job.source_mtime.filter(|_| source.is_some()))
+    .fetch_one(&mut *txn)
+    .await?
+    .get::<i64, _>("id");
+    debug!("track id {track_id} created");
+
+    // step 6: join track with album, artists and genres
+    for (artist_id, role) in artist_ids {
+        debug!("binding {track_id} to {artist_id} as {role:?}");
+        catalog::add_track_artist(&mut txn, track_id, artist_id, role).await?;
+    }
+    for genre_id in genre_ids {
+        catalog::add_track_genre(&mut txn, track_id, genre_id).await?;
+    }
+    for (key, value) in &tags.raw {
+        sqlx::query("INSERT INTO track_tag (track, key, value) VALUES ($1, $2, $3);")
+            .bind(track_id)
+            .bind(key)
+            .bind(value)
+            .execute(&mut *txn)
+            .await?;
+    }
+    trace!(count = tags.raw.len(), "raw tags stored");
+    if album_id.is_some() {
+        debug!("binding {track_id} to {album_id:?}");
+        sqlx::query("INSERT INTO album_tracks (track, album) VALUES ($1, $2);")
+            .bind(track_id)
+            .bind(album_id)
+            .execute(&mut *txn)
+            .await?;
+        // a cover image in the folder beats whatever is embedded
+        sqlx::query(
+            "UPDATE album SET art = COALESCE((SELECT art FROM dir WHERE node IS $2), art, $3)
+                 WHERE id = $1;",
+        )
+        .bind(album_id)
+        .bind(parent_dir)
+        .bind(art_id)
+        .execute(&mut *txn)
+        .await?;
+    }
+
+    // step 7: replace what this duplicates, if asked to
+    let replaced = match on_duplicate {
+        DuplicatePolicy::Replace => duplicates,
+        _ => vec![],
+    };
+    let mut old_files = vec![];
+    for old in replaced {
+        debug!(old, track_id, "replacing track");
+        let old_source: Option<String> = sqlx::query("SELECT source FROM track WHERE id = $1;")
+            .bind(old)
+            .fetch_one(&mut *txn)
+            .await?
+            .get("source");
+        // files imported in place stay where they are
+        if old_source.is_none() {
+            old_files.push(catalog::track_path(&user, old, None));
+        }
+        catalog::replace_track(&mut txn, old, track_id).await?;
+    }
+
+    // step 8: finally, move file, unless it's referenced where it is. this is left for
+    // last, so that only the commit can fail after it, and if it does the file goes
+    // back to where the job can be retried from
+    //
+    // note that track_id and fid is secure because it's just a number
+    let moved = match source {
+        Some(_) => None,
+        None => {
+            let temp_path = job.temp_path();
+            let to = format!("./devdir/u/{user}/{track_id}");
+            trace!("doing user movement {temp_path} -> {to}");
+            tokio::fs::rename(&temp_path, &to).await?;
+            Some((temp_path, to))
+        }
+    };
+    if let Err(err) = txn.commit().await {
+        if let Some((temp_path, to)) = moved
+            && let Err(err) = tokio::fs::rename(&to, &temp_path).await
+        {
+            error!(
+                to,
+                ?err,
+                "could not move the file back after a failed commit"
+            );
+        }
+        return Err(err.into());
+    }
+
+    // the old files only go once nothing points at them anymore
+    for old in old_files {
+        if let Err(err) = tokio::fs::remove_file(&old).await {
+            warn!(old, ?err, "could not remove the file of a replaced track");
+        }
+    }
+    Ok(Processed::Added(track_id))
+}
+
+/// Processing for removal jobs, which a rescan queues for files imported in place
+/// that are gone. Their tracks are deleted, unless the file came back since.
+#[tracing::instrument(skip(txn))]
+async fn process_removal(
+    mut txn: sqlx::Transaction<'_, sqlx::Sqlite>,
+    job: &ClaimedJob,
+) -> Result<Processed, ReamioProcessingErrorInternal> {
+    let Some(source) = &job.source else {
+        return Ok(Processed::Removed(None));
+    };
+    if tokio::fs::try_exists(source).await? {
+        debug!("file is back, keeping its track");
+        return Ok(Processed::Removed(None));
+    }
+    let ids = sqlx::query("SELECT id FROM track WHERE source = $1;")
+        .bind(source)
+        .fetch_all(&mut *txn)
+        .await?
+        .into_iter()
+        .map(|x| x.get::<i64, _>("id"))
+        .collect::<Vec<_>>();
+    for id in &ids {
+        debug!(id, "removing track");
+        catalog::delete_track(&mut txn, *id).await?;
+    }
+    txn.commit().await?;
+    Ok(Processed::Removed(ids.first().copied()))
+}
+
+/// Processing for folder cover images, see [[is_sidecar_name]]. The image becomes the
+/// art of its folder, and of the albums with tracks in there.
+#[tracing::instrument(skip(txn))]
+async fn process_sidecar_art(
+    mut txn: sqlx::Transaction<'_, sqlx::Sqlite>,
+    job: &ClaimedJob,
+    user: &str,
+) -> Result<Processed, ReamioProcessingErrorInternal> {
+    let (dir, _) = resolve_upload_path(&mut txn, &job.path).await?;
+    let Some(dir) = dir else {
+        // the root isn't a row in dir, and can't hold an album anyway
+        warn!("cover image in the root folder, ignoring");
+        txn.commit().await?;
+        job.discard().await?;
+        return Ok(Processed::FolderArt);
+    };
+    let data = tokio::fs::read(job.file()).await?;
+    let art_id = match art::store_art(&mut txn, user, &data).await {
+        Ok(x) => x,
+        Err(err @ ReamioProcessingErrorInternal::Image(_)) => {
+            warn!(?err, "cover image is not an image, rejecting");
+            job.discard().await?;
+            return Err(err);
+        }
+        Err(err) => return Err(err),
+    };
+
+    sqlx::query("UPDATE dir SET art = $1 WHERE node = $2;")
+        .bind(art_id)
+        .bind(dir)
+        .execute(&mut *txn)
+        .await?;
+    let albums = sqlx::query(
+        "UPDATE album SET art = $1
+             WHERE id IN (SELECT album_tracks.album FROM album_tracks
+                              JOIN track ON track.id = album_tracks.track
+                              WHERE track.dir = $2);",
+    )
+    .bind(art_id)
+    .bind(dir)
+    .execute(&mut *txn)
+    .await?
+    .rows_affected();
+    debug!(art_id, dir, albums, "folder art set");
+
+    txn.commit().await?;
+    // the image is in the art store now, and the job is done either way
+    if let Err(err) = job.discard().await {
+        warn!(?err, "could not remove a stored cover image");
+    }
+    Ok(Processed::FolderArt)
+}
+
+/// Split an upload's path into its folder, which is created if it doesn't exist yet,
+/// and its file name.
+#[tracing::instrument(skip(db))]
+async fn resolve_upload_path(
+    db: &mut sqlx::SqliteConnection,
+    path: &str,
+) -> Result<(Option<i64>, String), ReamioProcessingErrorInternal> {
+    if path.chars().next().is_none_or(|x| x != '/') {
         return Err(ReamioPathError {
             msg: "the path is not absolute".to_owned(),
         }
@@ -155,7 +836,7 @@ async fn task_populate_mdata_userdb_proccessing(
     let filename = filename.trim();
     debug!(?path_split, "final filename generated");
 
-    // step 4: navigate to dir in database
+    // navigate to dir in database, creating what's missing
     let parent_dir = {
         let mut dir = None::<i64>;
         for frag in path_split {
@@ -177,7 +858,7 @@ async fn task_populate_mdata_userdb_proccessing(
             )
             .bind(dir)
             .bind(frag)
-            .fetch_optional(&mut *txn)
+            .fetch_optional(&mut *db)
             .await?
             .and_then(|x| x.try_get::<i64, _>("node").ok());
 
@@ -191,13 +872,13 @@ async fn task_populate_mdata_userdb_proccessing(
                 trace!("dir {frag} does not exist, generating");
                 let pt = sqlx::query("INSERT INTO dir (name) VALUES ($1) RETURNING node;")
                     .bind(frag)
-                    .fetch_one(&mut *txn)
+                    .fetch_one(&mut *db)
                     .await?
                     .get::<i64, _>("node");
                 sqlx::query("INSERT INTO dir_tree (node, parent) VALUES ($1, $2);")
                     .bind(pt)
                     .bind(dir)
-                    .execute(&mut *txn)
+                    .execute(&mut *db)
                     .await?;
                 debug!("dir {frag} did not exist under {dir:?}, now exists at {pt}");
 
@@ -207,161 +888,33 @@ async fn task_populate_mdata_userdb_proccessing(
         }
         dir
     };
-
-    // TODO: tagging
-    //
-    // step 5: insert track with dir
-    let track_name = match tags.remove("track") {
-        Some(x) => String::from_utf8(x).unwrap(),
-        None => filename.to_owned(),
-    };
-    // CHANGING THIS RETURN TYPE HAS CONSEQUENCES
-    let track_id =
-        sqlx::query("INSERT INTO track (title, dir, fname) VALUES ($1, $2, $3) RETURNING id;")
-            .bind(track_name)
-            .bind(parent_dir)
-            .bind(filename)
-            .fetch_one(&mut *txn)
-            .await?
-            .get::<i64, _>("id");
-    debug!("track id {track_id} created");
-
-    // step 6: join track with album and artist
-    if artist_id.is_some() {
-        debug!("binding {track_id} to {artist_id:?}");
-        sqlx::query("INSERT INTO artist_tracks (track, artist) VALUES ($1, $2);")
-            .bind(track_id)
-            .bind(artist_id)
-            .execute(&mut *txn)
-            .await?;
-    }
-    if album_id.is_some() {
-        debug!("binding {track_id} to {album_id:?}");
-        sqlx::query("INSERT INTO album_tracks (track, album) VALUES ($1, $2);")
-            .bind(track_id)
-            .bind(album_id)
-            .execute(&mut *txn)
-            .await?;
-    }
-
-    // step 7: finally, move file
-    //
-    // note that track_id and fid is secure because it's just a number
-    let from = format!("./devdir/temp/{fid}");
-    let to = format!("./devdir/u/{user}/{track_id}");
-    trace!("doing user movement {from} -> {to}");
-    tokio::fs::rename(from, to).await?;
-
-    txn.commit().await?;
-    Ok(())
-}
-
-#[tracing::instrument]
-fn extract_tags(fid: i64) -> Result<HashMap<String, Vec<u8>>, ReamioProcessingErrorInternal> {
-    let path = format!("./devdir/temp/{fid}");
-    let path = Path::new(&path);
-
-    let readers: Vec<Box<dyn TagReader>> =
-        vec![Box::new(ID3TagReader), Box::new(MetaFlacTagReader)];
-    for reader in readers {
-        match reader.is_candidate(path)? {
-            Some(x) if x => return Ok(reader.tags_parse(path)?),
-            Some(_) => continue,
-            None => {
-                // unsupported is_candidate
-                if let Ok(map) = reader.tags_parse(path) {
-                    return Ok(map);
-                }
-            }
-        }
-    }
-
-    return Ok(HashMap::new());
-}
-
-trait TagReader {
-    fn is_candidate(&self, path: &Path) -> Result<Option<bool>, ReamioProcessingErrorInternal>;
-
-    fn tags_parse(
-        &self,
-        path: &Path,
-    ) -> Result<HashMap<String, Vec<u8>>, ReamioProcessingErrorInternal>;
-}
-
-/// ID3TagReader reads the tags from "MPEG" files (along with mp3, wav, aiff).
-#[derive(Debug)]
-struct ID3TagReader;
-
-impl TagReader for ID3TagReader {
-    #[tracing::instrument]
-    fn is_candidate(&self, path: &Path) -> Result<Option<bool>, ReamioProcessingErrorInternal> {
-        let file = std::fs::File::open(path)?;
-        id3::Tag::is_candidate(file)
-            .map(|x| Some(x))
-            .map_err(ReamioProcessingErrorInternal::from)
-    }
-
-    #[tracing::instrument]
-    fn tags_parse(
-        &self,
-        path: &Path,
-    ) -> Result<HashMap<String, Vec<u8>>, ReamioProcessingErrorInternal> {
-        let tag = id3::Tag::read_from_path(path)?;
-        let mut hmap = HashMap::new();
-
-        if let Some(x) = tag.title() {
-            hmap.insert("title".to_owned(), x.bytes().collect());
-        }
-        if let Some(x) = tag.artist() {
-            hmap.insert("artist".to_owned(), x.bytes().collect());
-        }
-        if let Some(x) = tag.album() {
-            hmap.insert("album".to_owned(), x.bytes().collect());
-        }
-        Ok(hmap)
-    }
+    Ok((parent_dir, filename.to_owned()))
 }
 
-/// MetaFlacTagReader reads tags from vorbis containers (ogg, flac)
-#[derive(Debug)]
-struct MetaFlacTagReader;
-
-impl TagReader for MetaFlacTagReader {
-    #[tracing::instrument]
-    fn is_candidate(&self, path: &Path) -> Result<Option<bool>, ReamioProcessingErrorInternal> {
-        let mut file = std::fs::File::open(path)?;
-        Ok(Some(metaflac::Tag::is_candidate(&mut file)))
-    }
-
-    #[tracing::instrument]
-    fn tags_parse(
-        &self,
-        path: &Path,
-    ) -> Result<HashMap<String, Vec<u8>>, ReamioProcessingErrorInternal> {
-        let tag = metaflac::Tag::read_from_path(path)?;
-        let mut hmap = HashMap::new();
-        for block in tag.get_blocks(metaflac::BlockType::VorbisComment) {
-            let metaflac::Block::VorbisComment(vc) = block else {
-                // wtf happened here
-                continue;
-            };
-
-            if let Some(x) = vc.title()
-                && let Some(x) = x.get(0)
-            {
-                hmap.insert("title".to_owned(), x.bytes().collect());
-            }
-            if let Some(x) = vc.artist()
-                && let Some(x) = x.get(0)
-            {
-                hmap.insert("artist".to_owned(), x.bytes().collect());
-            }
-            if let Some(x) = vc.album()
-                && let Some(x) = x.get(0)
-            {
-                hmap.insert("album".to_owned(), x.bytes().collect());
-            }
+/// Give artists the sort names and MusicBrainz ids tagged for them, if there's exactly
+/// one of each per artist.
+async fn fill_artists(
+    db: &mut sqlx::SqliteConnection,
+    ids: &[i64],
+    sort_names: &[String],
+    mb_artist_ids: &[String],
+) -> Result<(), sqlx::Error> {
+    for (idx, id) in ids.iter().enumerate() {
+        let sort_name = sort_names
+            .get(idx)
+            .filter(|_| sort_names.len() == ids.len());
+        let mb_artist_id = mb_artist_ids
+            .get(idx)
+            .filter(|_| mb_artist_ids.len() == ids.len());
+        if sort_name.is_some() || mb_artist_id.is_some() {
+            catalog::fill_artist_details(
+                db,
+                *id,
+                sort_name.map(String::as_str),
+                mb_artist_id.map(String::as_str),
+            )
+            .await?;
         }
-        Ok(hmap)
     }
+    Ok(())
 }
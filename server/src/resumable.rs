@@ -0,0 +1,430 @@
+//! Resumable uploads, following the core protocol of tus 1.0.0 (https://tus.io) with
+//! its creation and termination extensions.
+//!
+//! An upload is created with its total size, after which its bytes are sent over as
+//! many PATCH requests as it takes. When a connection drops, the client asks for the
+//! offset the server got to, and carries on from there. Uploads are rows of
+//! `uploaded_files` like any other, which the processor leaves alone until all of
+//! their bytes are in. Termination is dismissing the job, see [[jobs::dismiss_job]].
+
+use axum::{
+    body::Body,
+    extract::{Path, State},
+    http::{HeaderMap, HeaderName, HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use futures::TryStreamExt;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, HashSet},
+    io::SeekFrom,
+    sync::{Arc, Mutex},
+};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use crate::ReamioApp;
+use crate::auth::AuthUser;
+use crate::config::DuplicatePolicy;
+use crate::prelude::*;
+
+/// The only version of the protocol spoken here.
+const TUS_VERSION: &str = "1.0.0";
+const TUS_EXTENSIONS: &str = "creation,termination";
+/// Content type of PATCH bodies.
+const OFFSET_OCTET_STREAM: &str = "application/offset+octet-stream";
+
+const TUS_RESUMABLE: HeaderName = HeaderName::from_static("tus-resumable");
+const TUS_VERSION_HEADER: HeaderName = HeaderName::from_static("tus-version");
+const TUS_EXTENSION: HeaderName = HeaderName::from_static("tus-extension");
+const UPLOAD_LENGTH: HeaderName = HeaderName::from_static("upload-length");
+const UPLOAD_OFFSET: HeaderName = HeaderName::from_static("upload-offset");
+const UPLOAD_METADATA: HeaderName = HeaderName::from_static("upload-metadata");
+
+/// Uploads that a PATCH is writing to right now, by fid. Two requests appending to
+/// the same file at once would interleave their bytes.
+pub type BusyUploads = Arc<Mutex<HashSet<i64>>>;
+
+/// Marks an upload as busy for as long as it's held, see [[BusyUploads]].
+pub struct BusyGuard {
+    busy: BusyUploads,
+    fid: i64,
+}
+
+impl BusyGuard {
+    /// None when the upload is already busy.
+    pub fn lock(busy: &BusyUploads, fid: i64) -> Option<Self> {
+        busy.lock()
+            .expect("busy uploads lock poisoned")
+            .insert(fid)
+            .then(|| BusyGuard {
+                busy: busy.clone(),
+                fid,
+            })
+    }
+}
+
+impl Drop for BusyGuard {
+    fn drop(&mut self) {
+        self.busy
+            .lock()
+            .expect("busy uploads lock poisoned")
+            .remove(&self.fid);
+    }
+}
+
+/// sha256 of a file, in hex, as stored in `uploaded_files.hash`.
+pub async fn hash_file(path: &str) -> Result<String, std::io::Error> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|x| format!("{x:02x}"))
+        .collect())
+}
+
+/// Headers every tus response carries.
+pub fn tus_headers() -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(TUS_RESUMABLE, HeaderValue::from_static(TUS_VERSION));
+    headers
+}
+
+/// The response to requests for a version of the protocol other than ours, if this
+/// is one.
+fn version_mismatch(headers: &HeaderMap) -> Option<Response> {
+    if headers.get(TUS_RESUMABLE).is_some_and(|x| x == TUS_VERSION) {
+        return None;
+    }
+    debug!(version = ?headers.get(TUS_RESUMABLE), "unsupported tus version");
+    let mut headers = tus_headers();
+    headers.insert(TUS_VERSION_HEADER, HeaderValue::from_static(TUS_VERSION));
+    Some((StatusCode::PRECONDITION_FAILED, headers).into_response())
+}
+
+/// A numeric header, like Upload-Length or Upload-Offset.
+fn number_header(headers: &HeaderMap, name: HeaderName) -> Result<u64, ReamioWebError> {
+    headers
+        .get(&name)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.trim().parse().ok())
+        .ok_or_else(|| {
+            ReamioWebError::IncorrectArgs(
+                format!("missing or invalid {name} header"),
+                StatusCode::BAD_REQUEST,
+            )
+        })
+}
+
+/// Upload-Metadata, comma separated pairs of a key and a base64 value, where the
+/// value may be left out.
+fn parse_metadata(value: &str) -> Result<HashMap<String, String>, ReamioWebError> {
+    let invalid = || {
+        ReamioWebError::IncorrectArgs(
+            "invalid Upload-Metadata header".to_owned(),
+            StatusCode::BAD_REQUEST,
+        )
+    };
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+        .map(|pair| match pair.split_once(' ') {
+            Some((key, value)) => {
+                let value = STANDARD.decode(value.trim()).map_err(|_| invalid())?;
+                let value = String::from_utf8(value).map_err(|_| invalid())?;
+                Ok((key.to_owned(), value))
+            }
+            None => Ok((pair.to_owned(), String::new())),
+        })
+        .collect()
+}
+
+/// Say which version and extensions of tus are supported. Needs no session.
+///
+/// Path: OPTIONS /api/uploads
+#[tracing::instrument]
+pub async fn tus_options() -> Response {
+    let mut headers = tus_headers();
+    headers.insert(TUS_VERSION_HEADER, HeaderValue::from_static(TUS_VERSION));
+    headers.insert(TUS_EXTENSION, HeaderValue::from_static(TUS_EXTENSIONS));
+    (StatusCode::NO_CONTENT, headers).into_response()
+}
+
+/// Create a resumable upload, returning where to send its bytes in the Location
+/// header.
+///
+/// Path: POST /api/uploads
+///
+/// Arguments:
+/// - State(state): State<ReamioApp>
+///   Server state. [[ReamioApp]].
+/// - user: AuthUser
+///   The user the upload is assigned to. [[AuthUser]].
+/// - headers: HeaderMap
+///   Upload-Length, the size of the whole file, and Upload-Metadata, which holds:
+///
+///     + path: where the file goes, as in [[UploadArgs]]. Without it, `filename` is
+///       used to upload to the root of the user dir.
+///     + on_duplicate: as in [[UploadArgs]].
+#[tracing::instrument(skip(headers))]
+pub async fn create_upload(
+    State(state): State<ReamioApp>,
+    user: AuthUser,
+    headers: HeaderMap,
+) -> Result<Response, ReamioWebError> {
+    if let Some(resp) = version_mismatch(&headers) {
+        return Ok(resp);
+    }
+    let length = number_header(&headers, UPLOAD_LENGTH)?;
+    let metadata = match headers.get(UPLOAD_METADATA) {
+        Some(x) => parse_metadata(x.to_str().unwrap_or_default())?,
+        None => HashMap::new(),
+    };
+    trace!(length, ?metadata);
+
+    let path = metadata
+        .get("path")
+        .cloned()
+        .or_else(|| metadata.get("filename").map(|x| format!("/{x}")))
+        .ok_or_else(|| {
+            ReamioWebError::IncorrectArgs(
+                "Upload-Metadata has neither a path nor a filename".to_owned(),
+                StatusCode::BAD_REQUEST,
+            )
+        })?;
+    let on_duplicate = match metadata.get("on_duplicate") {
+        Some(x) => x.parse::<DuplicatePolicy>().map_err(|()| {
+            ReamioWebError::IncorrectArgs(
+                "on_duplicate must be skip, replace or keep".to_owned(),
+                StatusCode::BAD_REQUEST,
+            )
+        })?,
+        None => state.config.on_duplicate,
+    };
+    debug!(path, length, "creating resumable upload");
+
+    let mut txn = state.user_db.begin_with("BEGIN IMMEDIATE").await?;
+    // fid is an i64, see [[upload_track]] for why that matters
+    let fid: i64 = sqlx::query(
+        "INSERT INTO uploaded_files
+             (orig_path, user, fid, on_duplicate, upload_length, upload_offset, touched,
+              created)
+             VALUES ($1, $2, NULL, $3, $4, 0, unixepoch(), unixepoch()) RETURNING fid;",
+    )
+    .bind(&path)
+    .bind(&user.username)
+    .bind(on_duplicate.as_str())
+    .bind(i64::try_from(length)?)
+    .fetch_one(&mut *txn)
+    .await?
+    .get("fid");
+    let temp_path = format!("./devdir/temp/{fid}");
+    tokio::fs::File::create(&temp_path).await?;
+
+    // nothing is coming for an empty file, so it's done already
+    if length == 0 {
+        sqlx::query("UPDATE uploaded_files SET hash = $1 WHERE fid = $2;")
+            .bind(hash_file(&temp_path).await?)
+            .bind(fid)
+            .execute(&mut *txn)
+            .await?;
+    }
+    txn.commit().await?;
+    debug!(fid, "resumable upload created");
+    if length == 0 {
+        wake(&state.populate_mdata_waker, PopulateMetadata);
+    }
+
+    let mut headers = tus_headers();
+    headers.insert(
+        header::LOCATION,
+        HeaderValue::from_str(&format!("/api/uploads/{fid}"))
+            .expect("a number is a valid header value"),
+    );
+    Ok((StatusCode::CREATED, headers).into_response())
+}
+
+/// The total size of a resumable upload, and how much of it has been received.
+#[tracing::instrument]
+async fn fetch_upload(
+    state: &ReamioApp,
+    user: &AuthUser,
+    fid: i64,
+) -> Result<(u64, u64), ReamioWebError> {
+    let row = sqlx::query(
+        "SELECT upload_length, upload_offset FROM uploaded_files
+             WHERE fid = $1 AND user = $2 AND upload_offset IS NOT NULL;",
+    )
+    .bind(fid)
+    .bind(&user.username)
+    .fetch_optional(&state.user_db)
+    .await?
+    .ok_or_else(|| {
+        ReamioWebError::IncorrectArgs("no such upload exists".to_owned(), StatusCode::NOT_FOUND)
+    })?;
+    let length = u64::try_from(row.get::<i64, _>("upload_length"))?;
+    let offset = u64::try_from(row.get::<i64, _>("upload_offset"))?;
+    trace!(length, offset);
+    Ok((length, offset))
+}
+
+/// Find out how much of an upload was received, to resume it from there.
+///
+/// Path: HEAD /api/uploads/{fid}
+///
+/// Arguments:
+/// - State(state): State<ReamioApp>
+///   Server state. [[ReamioApp]].
+/// - user: AuthUser
+///   The user the upload belongs to. [[AuthUser]].
+/// - Path(fid): Path<i64>
+///   Upload id, from the Location given by [[create_upload]].
+/// - headers: HeaderMap
+///   Request headers, for Tus-Resumable.
+#[tracing::instrument(skip(headers))]
+pub async fn upload_offset(
+    State(state): State<ReamioApp>,
+    user: AuthUser,
+    Path(fid): Path<i64>,
+    headers: HeaderMap,
+) -> Result<Response, ReamioWebError> {
+    if let Some(resp) = version_mismatch(&headers) {
+        return Ok(resp);
+    }
+    let (length, offset) = fetch_upload(&state, &user, fid).await?;
+
+    let mut headers = tus_headers();
+    headers.insert(UPLOAD_OFFSET, HeaderValue::from(offset));
+    headers.insert(UPLOAD_LENGTH, HeaderValue::from(length));
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    Ok((StatusCode::OK, headers).into_response())
+}
+
+/// Append to an upload at the offset the server is at. Whatever arrives before a
+/// connection drops is kept. Once the last byte is in, the file is handed over to
+/// processing.
+///
+/// Path: PATCH /api/uploads/{fid}
+///
+/// Arguments:
+/// - State(state): State<ReamioApp>
+///   Server state. [[ReamioApp]].
+/// - user: AuthUser
+///   The user the upload belongs to. [[AuthUser]].
+/// - Path(fid): Path<i64>
+///   Upload id, from the Location given by [[create_upload]].
+/// - headers: HeaderMap
+///   Upload-Offset, which has to be where the server is at, and a Content-Type of
+///   `application/offset+octet-stream`.
+/// - body: Body
+///   The next bytes of the file.
+#[tracing::instrument(skip(headers, body))]
+pub async fn append_upload(
+    State(state): State<ReamioApp>,
+    user: AuthUser,
+    Path(fid): Path<i64>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Response, ReamioWebError> {
+    if let Some(resp) = version_mismatch(&headers) {
+        return Ok(resp);
+    }
+    if headers
+        .get(header::CONTENT_TYPE)
+        .is_none_or(|x| x != OFFSET_OCTET_STREAM)
+    {
+        return Err(ReamioWebError::IncorrectArgs(
+            format!("Content-Type must be {OFFSET_OCTET_STREAM}"),
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        ));
+    }
+    let client_offset = number_header(&headers, UPLOAD_OFFSET)?;
+
+    let _guard = BusyGuard::lock(&state.uploads_busy, fid).ok_or_else(|| {
+        ReamioWebError::IncorrectArgs(
+            "upload is already being written to".to_owned(),
+            StatusCode::CONFLICT,
+        )
+    })?;
+    let (length, offset) = fetch_upload(&state, &user, fid).await?;
+    if client_offset != offset {
+        debug!(client_offset, offset, "offset mismatch");
+        return Err(ReamioWebError::IncorrectArgs(
+            format!("upload is at offset {offset}"),
+            StatusCode::CONFLICT,
+        ));
+    }
+
+    let temp_path = format!("./devdir/temp/{fid}");
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .open(&temp_path)
+        .await?;
+    // anything past the offset is from a request that died before it was recorded
+    file.set_len(offset).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+
+    // written until the body ends or breaks off, keeping what came before an error
+    let remaining = length - offset;
+    let mut written = 0u64;
+    let mut body = body.into_data_stream();
+    let streamed: Result<(), ReamioWebError> = async {
+        while let Some(mut chunk) = body.try_next().await? {
+            let room = remaining - written;
+            let overflow = chunk.len() as u64 > room;
+            if overflow {
+                chunk.truncate(room as usize);
+            }
+            file.write_all(&chunk).await?;
+            written += chunk.len() as u64;
+            if overflow {
+                return Err(ReamioWebError::IncorrectArgs(
+                    "more data than the Upload-Length of the upload".to_owned(),
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                ));
+            }
+        }
+        Ok(())
+    }
+    .await;
+    file.sync_data().await?;
+    drop(file);
+    let offset = offset + written;
+    debug!(written, offset, length, ?streamed, "chunk written");
+
+    // the hash goes in with the last offset, as that is what the processor waits on
+    let hash = if written > 0 && offset == length {
+        Some(hash_file(&temp_path).await?)
+    } else {
+        None
+    };
+    sqlx::query(
+        "UPDATE uploaded_files
+             SET upload_offset = $1, hash = COALESCE($2, hash), touched = unixepoch()
+             WHERE fid = $3;",
+    )
+    .bind(i64::try_from(offset)?)
+    .bind(&hash)
+    .bind(fid)
+    .execute(&state.user_db)
+    .await?;
+    if hash.is_some() {
+        debug!(fid, hash, "resumable upload complete");
+        wake(&state.populate_mdata_waker, PopulateMetadata);
+    }
+    streamed?;
+
+    let mut headers = tus_headers();
+    headers.insert(UPLOAD_OFFSET, HeaderValue::from(offset));
+    Ok((StatusCode::NO_CONTENT, headers).into_response())
+}
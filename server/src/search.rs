@@ -0,0 +1,209 @@
+use axum::{
+    Json,
+    extract::{Query, State},
+    http::StatusCode,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::ReamioApp;
+use crate::auth::AuthUser;
+use crate::catalog::main_artist;
+use crate::prelude::*;
+
+/// Most results returned per kind.
+const SEARCH_LIMIT_MAX: i64 = 100;
+
+/// What a row of the `search` table refers to, stored in the low two bits of its
+/// rowid.
+#[derive(Debug, Clone, Copy)]
+pub enum SearchKind {
+    Track = 0,
+    Album = 1,
+    Artist = 2,
+    Dir = 3,
+}
+
+/// Turn a user query into an FTS5 match expression, where every word has to match
+/// the start of a word in the name. Returns None if there are no words at all.
+///
+/// Only runs of letters and numbers are kept, which is also how the unicode61
+/// tokenizer splits names, so nothing in the expression needs escaping.
+pub fn match_expr(query: &str) -> Option<String> {
+    let terms = query
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|x| !x.is_empty())
+        .map(|x| format!("\"{x}\"*"))
+        .collect::<Vec<_>>();
+    (!terms.is_empty()).then(|| terms.join(" "))
+}
+
+/// SQL for the ids of every `kind` matching the expression in `$1`, usable as
+/// `x.id IN ({})`.
+pub fn matching_ids(kind: SearchKind) -> String {
+    format!(
+        "SELECT rowid >> 2 FROM search WHERE search MATCH $1 AND (rowid & 3) = {}",
+        kind as i64
+    )
+}
+
+/// [[SearchArgs]]
+/// Query arguments for [[search]]
+///
+/// Fields:
+/// - q: String
+///   What to search for.
+/// - limit: Option<i64>
+///   Results per kind, 20 by default and at most 100.
+#[derive(Deserialize, Debug)]
+pub struct SearchArgs {
+    pub q: String,
+    pub limit: Option<i64>,
+}
+
+#[derive(Serialize, sqlx::FromRow, Debug)]
+pub struct TrackHit {
+    track_id: i64,
+    track_title: String,
+    album_id: Option<i64>,
+    album_name: Option<String>,
+    artist_id: Option<i64>,
+    artist_name: Option<String>,
+    /// bm25, lower is better
+    rank: f64,
+}
+
+#[derive(Serialize, sqlx::FromRow, Debug)]
+pub struct NameHit {
+    id: i64,
+    name: String,
+    /// bm25, lower is better
+    rank: f64,
+}
+
+#[derive(Serialize, sqlx::FromRow, Debug)]
+pub struct DirHit {
+    node: i64,
+    name: String,
+    /// None is the root
+    parent: Option<i64>,
+    /// bm25, lower is better
+    rank: f64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SearchReturn {
+    tracks: Vec<TrackHit>,
+    albums: Vec<NameHit>,
+    artists: Vec<NameHit>,
+    dirs: Vec<DirHit>,
+}
+
+/// Search track titles and album, artist and folder names. Every word of the query
+/// matches as a prefix, ignoring case and diacritics, and results are ranked per
+/// kind.
+///
+/// Path: GET /api/search?q={}&limit={}
+///
+/// Arguments:
+/// - State(state): State<ReamioApp>
+///   Server state. [[ReamioApp]].
+/// - user: AuthUser
+///   Whose library to search. [[AuthUser]].
+/// - Query(args): Query<SearchArgs>
+///   See [[SearchArgs]].
+#[tracing::instrument]
+pub async fn search(
+    State(state): State<ReamioApp>,
+    user: AuthUser,
+    Query(args): Query<SearchArgs>,
+) -> Result<Json<SearchReturn>, ReamioWebError> {
+    let expr = match_expr(&args.q).ok_or_else(|| {
+        ReamioWebError::IncorrectArgs(
+            "query has nothing to search for".to_owned(),
+            StatusCode::BAD_REQUEST,
+        )
+    })?;
+    let limit = args.limit.unwrap_or(20).clamp(1, SEARCH_LIMIT_MAX);
+    debug!(expr, limit, "searching");
+
+    // the kind is filtered outside of fts5, so rank everything and cut afterwards
+    let hits = |kind: SearchKind| {
+        format!(
+            "WITH hits AS (
+                 SELECT rowid >> 2 AS id, rank FROM search
+                     WHERE search MATCH $1 AND (rowid & 3) = {}
+                     ORDER BY rank LIMIT $2)",
+            kind as i64
+        )
+    };
+
+    let mut db = fetch_users_music_db(state.music_dbs, &user.username).await?;
+    let tracks = sqlx::query_as::<_, TrackHit>(&format!(
+        "{}
+         SELECT track.id AS track_id, track.title AS track_title,
+                album.id AS album_id, album.name AS album_name,
+                artist.id AS artist_id, artist.name AS artist_name,
+                hits.rank
+             FROM hits
+             JOIN track ON track.id = hits.id
+             LEFT JOIN album_tracks ON album_tracks.track = track.id
+             LEFT JOIN album ON album.id = album_tracks.album
+             LEFT JOIN artist ON artist.id = {}
+             ORDER BY hits.rank;",
+        hits(SearchKind::Track),
+        main_artist("track.id"),
+    ))
+    .bind(&expr)
+    .bind(limit)
+    .fetch_all(&mut *db)
+    .await?;
+    let albums = sqlx::query_as::<_, NameHit>(&format!(
+        "{}
+         SELECT album.id, album.name, hits.rank
+             FROM hits JOIN album ON album.id = hits.id
+             ORDER BY hits.rank;",
+        hits(SearchKind::Album)
+    ))
+    .bind(&expr)
+    .bind(limit)
+    .fetch_all(&mut *db)
+    .await?;
+    let artists = sqlx::query_as::<_, NameHit>(&format!(
+        "{}
+         SELECT artist.id, artist.name, hits.rank
+             FROM hits JOIN artist ON artist.id = hits.id
+             ORDER BY hits.rank;",
+        hits(SearchKind::Artist)
+    ))
+    .bind(&expr)
+    .bind(limit)
+    .fetch_all(&mut *db)
+    .await?;
+    let dirs = sqlx::query_as::<_, DirHit>(&format!(
+        "{}
+         SELECT dir.node, dir.name, dir_tree.parent, hits.rank
+             FROM hits
+             JOIN dir ON dir.node = hits.id
+             LEFT JOIN dir_tree ON dir_tree.node = dir.node
+             ORDER BY hits.rank;",
+        hits(SearchKind::Dir)
+    ))
+    .bind(&expr)
+    .bind(limit)
+    .fetch_all(&mut *db)
+    .await?;
+    trace!(
+        tracks = tracks.len(),
+        albums = albums.len(),
+        artists = artists.len(),
+        dirs = dirs.len(),
+        "search done"
+    );
+
+    Ok(Json(SearchReturn {
+        tracks,
+        albums,
+        artists,
+        dirs,
+    }))
+}
@@ -0,0 +1,232 @@
+use axum::{
+    body::Body,
+    extract::{Path, State},
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use headers::{
+    AcceptRanges, ContentLength, ContentRange, ETag, HeaderMapExt, IfMatch, IfModifiedSince,
+    IfNoneMatch, IfRange, IfUnmodifiedSince, LastModified,
+};
+use std::time::UNIX_EPOCH;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+
+use crate::ReamioApp;
+use crate::auth::MediaUser;
+use crate::catalog;
+use crate::prelude::*;
+use crate::probe::{container_mime, container_of};
+
+/// Stream the original upload of a track. Supports single byte ranges, along with
+/// the usual conditional request headers, so that players can seek without pulling
+/// the whole file. Browsers and external players that can't send the session token
+/// as a header pass it as `?token={token}` instead.
+///
+/// Path: GET /api/track/{id}/stream?token={token}
+///
+/// Arguments:
+/// - State(state): State<ReamioApp>
+///   Server state. [[ReamioApp]].
+/// - MediaUser(user): MediaUser
+///   The user who owns the track. [[MediaUser]].
+/// - Path(id): Path<i64>
+///   Track id.
+/// - headers: HeaderMap
+///   Request headers, for Range, If-Range, If-None-Match and friends.
+#[tracing::instrument(skip(headers))]
+pub async fn stream_track(
+    State(state): State<ReamioApp>,
+    MediaUser(user): MediaUser,
+    Path(id): Path<i64>,
+    headers: HeaderMap,
+) -> Result<Response, ReamioWebError> {
+    let mut db = fetch_users_music_db(state.music_dbs, &user.username).await?;
+    let row = sqlx::query("SELECT container, source FROM track WHERE id = $1;")
+        .bind(id)
+        .fetch_optional(&mut *db)
+        .await?
+        .ok_or_else(|| {
+            ReamioWebError::IncorrectArgs("no such track exists".to_owned(), StatusCode::NOT_FOUND)
+        })?;
+    drop(db);
+    let container: Option<String> = row.get("container");
+
+    let path = catalog::track_path(&user.username, id, row.get("source"));
+    // tracks probed on upload already know, older ones get sniffed
+    let content_type = match container {
+        Some(x) => container_mime(&x),
+        None => sniff_content_type(&path).await?,
+    };
+    serve_file(&path, content_type, &headers).await
+}
+
+/// Guess the MIME type of an audio file from its first few bytes.
+#[tracing::instrument]
+pub async fn sniff_content_type(path: &str) -> Result<&'static str, ReamioWebError> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut magic = [0u8; 12];
+    let read = file.read(&mut magic).await?;
+    let magic = &magic[..read];
+
+    let mime = container_of(magic).map_or("application/octet-stream", container_mime);
+    trace!(mime, "content type sniffed");
+    Ok(mime)
+}
+
+/// Serve a file from disk, honoring conditional and (single) range requests.
+///
+/// The ETag is derived from the size and modification time of the file, so anything
+/// that replaces the file in place changes it.
+#[tracing::instrument(skip(req_headers))]
+pub async fn serve_file(
+    path: &str,
+    content_type: &str,
+    req_headers: &HeaderMap,
+) -> Result<Response, ReamioWebError> {
+    let mut file = tokio::fs::File::open(path).await.map_err(|err| {
+        if err.kind() == std::io::ErrorKind::NotFound {
+            ReamioWebError::IncorrectArgs("file does not exist".to_owned(), StatusCode::NOT_FOUND)
+        } else {
+            err.into()
+        }
+    })?;
+    let meta = file.metadata().await?;
+    let len = meta.len();
+    let modified = meta.modified()?;
+    let mtime = modified
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_nanos())
+        .unwrap_or(0);
+    let etag = format!("\"{len:x}-{mtime:x}\"")
+        .parse::<ETag>()
+        .expect("hex digits in quotes is always a valid etag");
+    let last_modified = LastModified::from(modified);
+    trace!(len, ?etag, "file metadata");
+
+    // headers common to every response
+    let mut headers = HeaderMap::new();
+    headers.typed_insert(AcceptRanges::bytes());
+    headers.typed_insert(etag.clone());
+    headers.typed_insert(last_modified);
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("private"));
+
+    // preconditions, RFC 9110 section 13.2.2
+    let precondition_failed = if let Some(if_match) = req_headers.typed_get::<IfMatch>() {
+        !if_match.precondition_passes(&etag)
+    } else if let Some(since) = req_headers.typed_get::<IfUnmodifiedSince>() {
+        !since.precondition_passes(modified)
+    } else {
+        false
+    };
+    if precondition_failed {
+        debug!("precondition failed");
+        return Ok((StatusCode::PRECONDITION_FAILED, headers).into_response());
+    }
+    let not_modified = if let Some(if_none_match) = req_headers.typed_get::<IfNoneMatch>() {
+        !if_none_match.precondition_passes(&etag)
+    } else if let Some(since) = req_headers.typed_get::<IfModifiedSince>() {
+        !since.is_modified(modified)
+    } else {
+        false
+    };
+    if not_modified {
+        debug!("not modified");
+        return Ok((StatusCode::NOT_MODIFIED, headers).into_response());
+    }
+
+    // a stale If-Range means the client wants the whole new file
+    let range = match req_headers.get(header::RANGE) {
+        Some(_)
+            if req_headers
+                .typed_get::<IfRange>()
+                .is_some_and(|x| x.is_modified(Some(&etag), Some(&last_modified))) =>
+        {
+            trace!("if-range is stale, ignoring range");
+            None
+        }
+        Some(range) => match parse_range(range, len) {
+            RangeRequest::Satisfiable(start, end) => Some((start, end)),
+            RangeRequest::Unsatisfiable => {
+                debug!(?range, "range not satisfiable");
+                headers.typed_insert(ContentRange::unsatisfied_bytes(len));
+                return Ok((StatusCode::RANGE_NOT_SATISFIABLE, headers).into_response());
+            }
+            RangeRequest::Ignored => None,
+        },
+        None => None,
+    };
+
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_str(content_type)
+            .unwrap_or_else(|_| HeaderValue::from_static("application/octet-stream")),
+    );
+    let (status, start, end) = match range {
+        Some((start, end)) => {
+            headers.typed_insert(
+                ContentRange::bytes(start..=end, len)
+                    .expect("range is checked against the file length"),
+            );
+            (StatusCode::PARTIAL_CONTENT, start, end)
+        }
+        None => (StatusCode::OK, 0, len.saturating_sub(1)),
+    };
+    let body_len = if len == 0 { 0 } else { end - start + 1 };
+    headers.typed_insert(ContentLength(body_len));
+    debug!(?status, start, end, "serving file");
+
+    file.seek(std::io::SeekFrom::Start(start)).await?;
+    let body = Body::from_stream(ReaderStream::new(file.take(body_len)));
+    Ok((status, headers, body).into_response())
+}
+
+enum RangeRequest {
+    /// inclusive byte range inside of the file
+    Satisfiable(u64, u64),
+    Unsatisfiable,
+    /// malformed, multipart or not in bytes, serve the whole file instead
+    Ignored,
+}
+
+/// Resolve a Range header against a file of `len` bytes, RFC 9110 section 14.1.
+fn parse_range(range: &HeaderValue, len: u64) -> RangeRequest {
+    let Some(spec) = range.to_str().ok().and_then(|x| x.strip_prefix("bytes=")) else {
+        return RangeRequest::Ignored;
+    };
+    // multipart/byteranges responses aren't worth it for audio
+    if spec.contains(',') {
+        return RangeRequest::Ignored;
+    }
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return RangeRequest::Ignored;
+    };
+    let (start, end) = (start.trim(), end.trim());
+
+    if start.is_empty() {
+        // suffix range, the last N bytes
+        let Ok(suffix) = end.parse::<u64>() else {
+            return RangeRequest::Ignored;
+        };
+        if suffix == 0 || len == 0 {
+            return RangeRequest::Unsatisfiable;
+        }
+        return RangeRequest::Satisfiable(len.saturating_sub(suffix), len - 1);
+    }
+
+    let Ok(start) = start.parse::<u64>() else {
+        return RangeRequest::Ignored;
+    };
+    let end = if end.is_empty() {
+        u64::MAX
+    } else {
+        match end.parse::<u64>() {
+            Ok(x) if x >= start => x,
+            _ => return RangeRequest::Ignored,
+        }
+    };
+    if start >= len {
+        return RangeRequest::Unsatisfiable;
+    }
+    RangeRequest::Satisfiable(start, end.min(len - 1))
+}
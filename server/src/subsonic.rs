@@ -0,0 +1,369 @@
+//! A Subsonic (and OpenSubsonic) compatible API under `/rest`, so that existing
+//! clients can be pointed at the server.
+//!
+//! Every method goes through [[dispatch]], which authenticates the request with the
+//! Subsonic query parameters (`u`, `t` + `s` or `p`) and renders the reply as XML
+//! or JSON depending on `f`.
+
+use axum::{
+    Json,
+    extract::{FromRequest, Path, Request, State},
+    http::{HeaderMap, Method, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use md5::{Digest, Md5};
+use rand::RngCore;
+use serde::Serialize;
+use serde_json::{Map, Value, json};
+use std::str::FromStr;
+use subtle::ConstantTimeEq;
+
+mod library;
+mod media;
+mod playlists;
+mod response;
+
+use crate::ReamioApp;
+use crate::auth::{AuthUser, verify_password};
+use crate::prelude::*;
+pub use response::{ErrorCode, ResponseFormat, SubsonicError};
+
+/// The Subsonic API version that is implemented.
+pub const API_VERSION: &str = "1.16.1";
+
+/// Largest POST body read for form encoded parameters.
+const FORM_MAX: usize = 1024 * 1024;
+
+/// A Subsonic request, with the user already authenticated.
+///
+/// Subsonic clients send the same parameters either in the query string or as a
+/// form encoded POST body, and some parameters (like `songId`) may be repeated.
+pub struct SubsonicRequest {
+    /// username_lower of the authenticated user
+    pub user: String,
+    pub params: Params,
+    pub format: ResponseFormat,
+    /// Request headers, for Range and friends on `stream`.
+    pub headers: HeaderMap,
+}
+
+impl std::fmt::Debug for SubsonicRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // params carry credentials, keep them out of the logs
+        f.debug_struct("SubsonicRequest")
+            .field("user", &self.user)
+            .field("format", &self.format)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Ordered request parameters, which may repeat.
+pub struct Params(Vec<(String, String)>);
+
+impl Params {
+    /// First value of a parameter.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Every value of a repeated parameter, in order.
+    pub fn all<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> {
+        self.0
+            .iter()
+            .filter(move |(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn require(&self, key: &str) -> Result<&str, SubsonicError> {
+        self.get(key).ok_or_else(|| {
+            SubsonicError::new(
+                ErrorCode::MissingParameter,
+                format!("required parameter '{key}' is missing"),
+            )
+        })
+    }
+
+    /// Parse an optional parameter.
+    pub fn parse<T: FromStr>(&self, key: &str) -> Result<Option<T>, SubsonicError> {
+        self.get(key)
+            .map(|x| {
+                x.parse().map_err(|_| {
+                    SubsonicError::new(ErrorCode::Generic, format!("invalid value for '{key}'"))
+                })
+            })
+            .transpose()
+    }
+
+    /// Parse a required parameter.
+    pub fn parse_required<T: FromStr>(&self, key: &str) -> Result<T, SubsonicError> {
+        self.require(key)?;
+        self.parse(key)
+            .map(|x| x.expect("parameter was just checked for"))
+    }
+}
+
+impl FromRequest<ReamioApp> for SubsonicRequest {
+    // errors have to be rendered in the format the client asked for
+    type Rejection = Response;
+
+    async fn from_request(req: Request, state: &ReamioApp) -> Result<Self, Self::Rejection> {
+        let (parts, body) = req.into_parts();
+        let mut params = parts
+            .uri
+            .query()
+            .and_then(|x| serde_urlencoded::from_str::<Vec<(String, String)>>(x).ok())
+            .unwrap_or_default();
+        let is_form = parts
+            .headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|x| x.to_str().ok())
+            .is_some_and(|x| x.starts_with("application/x-www-form-urlencoded"));
+        if parts.method == Method::POST && is_form {
+            let body = axum::body::to_bytes(body, FORM_MAX).await.map_err(|err| {
+                debug!(?err, "could not read form body");
+                (StatusCode::BAD_REQUEST, "could not read form body").into_response()
+            })?;
+            params.extend(
+                serde_urlencoded::from_bytes::<Vec<(String, String)>>(&body).unwrap_or_default(),
+            );
+        }
+        let params = Params(params);
+        let format = ResponseFormat::from_params(&params);
+
+        match authenticate(state, &params).await {
+            Ok(user) => {
+                trace!(user, "subsonic user authenticated");
+                Ok(SubsonicRequest {
+                    user,
+                    params,
+                    format,
+                    headers: parts.headers,
+                })
+            }
+            Err(err) => Err(format.render(Err(err))),
+        }
+    }
+}
+
+/// Check the credentials of a request, returning the username_lower of the user.
+///
+/// The token scheme (`t` = md5(password + `s`)) needs the password in the clear, so
+/// it is checked against the user's app password, see [[create_app_password]].
+/// Plain `p` passwords, optionally hex encoded as `enc:{hex}`, may be either the app
+/// password or the login password.
+#[tracing::instrument(skip(state, params))]
+async fn authenticate(state: &ReamioApp, params: &Params) -> Result<String, SubsonicError> {
+    let username = params.require("u")?.trim().to_lowercase();
+    let wrong = || SubsonicError::new(ErrorCode::WrongCredentials, "wrong username or password");
+
+    // a missing user is treated as one without an app password and with an empty
+    // phc, so that neither the errors nor the time taken tell it apart
+    let (phc, app_password): (String, Option<String>) = sqlx::query(
+        "SELECT phc, subsonic_password FROM users WHERE username_lower = $1 AND disabled = 0;",
+    )
+    .bind(&username)
+    .fetch_optional(&state.user_db)
+    .await?
+    .map(|x| (x.get("phc"), x.get("subsonic_password")))
+    .unwrap_or_default();
+
+    if let (Some(token), Some(salt)) = (params.get("t"), params.get("s")) {
+        let Some(app_password) = app_password else {
+            return Err(SubsonicError::new(
+                ErrorCode::TokenAuthNotSupported,
+                "token authentication needs an app password, create one at /api/subsonic/password",
+            ));
+        };
+        let expected = hex(&Md5::digest(format!("{app_password}{salt}")));
+        let token = token.trim().to_ascii_lowercase();
+        if !bool::from(expected.as_bytes().ct_eq(token.as_bytes())) {
+            debug!(username, "subsonic token rejected");
+            return Err(wrong());
+        }
+    } else if let Some(password) = params.get("p") {
+        let password = match password.strip_prefix("enc:") {
+            Some(encoded) => unhex(encoded)
+                .and_then(|x| String::from_utf8(x).ok())
+                .ok_or_else(wrong)?,
+            None => password.to_owned(),
+        };
+        let app_ok =
+            app_password.is_some_and(|x| bool::from(x.as_bytes().ct_eq(password.as_bytes())));
+        // only the right app password skips the login password check
+        if !app_ok && !verify_password(password, phc).await? {
+            debug!(username, "subsonic password rejected");
+            return Err(wrong());
+        }
+    } else {
+        return Err(SubsonicError::new(
+            ErrorCode::MissingParameter,
+            "either 't' and 's', or 'p' is required",
+        ));
+    }
+    Ok(username)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{x:02x}")).collect()
+}
+
+fn unhex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|x| u8::from_str_radix(hex.get(x..x + 2)?, 16).ok())
+        .collect()
+}
+
+/// What a Subsonic method produces.
+pub enum Reply {
+    /// Elements to put inside of `subsonic-response`.
+    Data(Map<String, Value>),
+    /// A raw response, for the media methods.
+    Raw(Response),
+}
+
+impl Reply {
+    pub fn empty() -> Self {
+        Reply::Data(Map::new())
+    }
+
+    /// A reply with a single element, which is how most methods answer.
+    pub fn with(key: &str, value: Value) -> Self {
+        let mut map = Map::new();
+        map.insert(key.to_owned(), value);
+        Reply::Data(map)
+    }
+}
+
+/// Entry point for every Subsonic method. The `.view` suffix older clients add is
+/// accepted and ignored.
+///
+/// Path: GET/POST /rest/{method}
+///
+/// Arguments:
+/// - State(state): State<ReamioApp>
+///   Server state. [[ReamioApp]].
+/// - Path(method): Path<String>
+///   Subsonic method name, such as `getAlbum`.
+/// - req: SubsonicRequest
+///   Authenticated request. [[SubsonicRequest]].
+#[tracing::instrument]
+pub async fn dispatch(
+    State(state): State<ReamioApp>,
+    Path(method): Path<String>,
+    req: SubsonicRequest,
+) -> Response {
+    let method = method.strip_suffix(".view").unwrap_or(&method);
+    debug!(method, "subsonic call");
+    let ret = match method {
+        "ping" => Ok(Reply::empty()),
+        "getLicense" => Ok(Reply::with("license", json!({ "valid": true }))),
+        "getOpenSubsonicExtensions" => Ok(Reply::with(
+            "openSubsonicExtensions",
+            json!([{ "name": "formPost", "versions": [1] }]),
+        )),
+        "getMusicFolders" => Ok(library::get_music_folders()),
+        "getIndexes" => library::get_indexes(&state, &req).await,
+        "getMusicDirectory" => library::get_music_directory(&state, &req).await,
+        "getArtists" => library::get_artists(&state, &req).await,
+        "getArtist" => library::get_artist(&state, &req).await,
+        "getAlbum" => library::get_album(&state, &req).await,
+        "getSong" => library::get_song(&state, &req).await,
+        "getAlbumList2" => library::get_album_list2(&state, &req).await,
+        "getGenres" => library::get_genres(&state, &req).await,
+        "getRandomSongs" => library::get_random_songs(&state, &req).await,
+        "search3" => library::search3(&state, &req).await,
+        "stream" => media::stream(&state, &req).await,
+        "download" => media::download(&state, &req).await,
+        "getCoverArt" => media::get_cover_art(&state, &req).await,
+        "getPlaylists" => playlists::get_playlists(&state, &req).await,
+        "getPlaylist" => playlists::get_playlist(&state, &req).await,
+        "createPlaylist" => playlists::create_playlist(&state, &req).await,
+        "updatePlaylist" => playlists::update_playlist(&state, &req).await,
+        "deletePlaylist" => playlists::delete_playlist(&state, &req).await,
+        "scrobble" => playlists::scrobble(&state, &req).await,
+        _ => Err(SubsonicError::new(
+            ErrorCode::NotFound,
+            format!("unknown method '{method}'"),
+        )),
+    };
+    match ret {
+        Ok(Reply::Raw(resp)) => resp,
+        Ok(Reply::Data(data)) => req.format.render(Ok(data)),
+        Err(err) => {
+            debug!(?err, "subsonic call failed");
+            req.format.render(Err(err))
+        }
+    }
+}
+
+/// Split a Subsonic id such as `al-12` into its prefix and row id. Bare numbers
+/// are accepted, and get the `default` prefix.
+pub fn parse_id<'a>(id: &'a str, default: &'a str) -> Result<(&'a str, i64), SubsonicError> {
+    let (prefix, num) = id.split_once('-').unwrap_or((default, id));
+    num.parse()
+        .map(|x| (prefix, x))
+        .map_err(|_| SubsonicError::new(ErrorCode::NotFound, format!("invalid id '{id}'")))
+}
+
+/// Like [[parse_id]], but the prefix must be `prefix`.
+pub fn parse_id_of(id: &str, prefix: &str) -> Result<i64, SubsonicError> {
+    match parse_id(id, prefix)? {
+        (x, num) if x == prefix => Ok(num),
+        _ => Err(SubsonicError::new(
+            ErrorCode::NotFound,
+            format!("'{id}' is not a {prefix} id"),
+        )),
+    }
+}
+
+#[derive(Serialize)]
+pub struct AppPasswordReturn {
+    password: String,
+}
+
+/// Generate a new app password for Subsonic clients, replacing the old one. The
+/// password is only shown this once.
+///
+/// Subsonic token authentication needs the server to know the password in the
+/// clear, so clients get a separate, random password instead of the login one.
+///
+/// Path: POST /api/subsonic/password
+#[tracing::instrument]
+pub async fn create_app_password(
+    State(state): State<ReamioApp>,
+    user: AuthUser,
+) -> Result<Json<AppPasswordReturn>, ReamioWebError> {
+    let mut raw = [0u8; 16];
+    rand::rngs::OsRng.fill_bytes(&mut raw);
+    let password = hex(&raw);
+    sqlx::query("UPDATE users SET subsonic_password = $1 WHERE username_lower = $2;")
+        .bind(&password)
+        .bind(&user.username)
+        .execute(&state.user_db)
+        .await?;
+    info!(user.username, "subsonic app password created");
+    Ok(Json(AppPasswordReturn { password }))
+}
+
+/// Remove the app password, which turns off Subsonic token authentication.
+///
+/// Path: DELETE /api/subsonic/password
+#[tracing::instrument]
+pub async fn delete_app_password(
+    State(state): State<ReamioApp>,
+    user: AuthUser,
+) -> Result<StatusCode, ReamioWebError> {
+    sqlx::query("UPDATE users SET subsonic_password = NULL WHERE username_lower = $1;")
+        .bind(&user.username)
+        .execute(&state.user_db)
+        .await?;
+    info!(user.username, "subsonic app password removed");
+    Ok(StatusCode::NO_CONTENT)
+}
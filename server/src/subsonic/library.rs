@@ -0,0 +1,622 @@
+//! Browsing methods, both the folder based ones (getIndexes, getMusicDirectory) and
+//! the tag based ones (getArtists, getAlbum, ...).
+//!
+//! Ids are prefixed with what they refer to: `ar-` artists, `al-` albums, `tr-`
+//! tracks, `dr-` dirs, where `dr-0` is the root of the user's tree, and `ca-` cover
+//! art.
+
+use serde_json::{Value, json};
+
+use super::{ErrorCode, Reply, SubsonicError, SubsonicRequest, parse_id_of};
+use crate::ReamioApp;
+use crate::catalog::{main_artist, name_key};
+use crate::dirs::{breadcrumbs, child_dirs};
+use crate::prelude::*;
+use crate::probe::{AudioInfo, container_mime};
+use crate::search::{SearchKind, match_expr, matching_ids};
+
+/// The one and only music folder. Every user gets their own tree, so there is no
+/// need for more.
+const MUSIC_FOLDER_ID: i64 = 1;
+
+/// Most items a single list method returns.
+const LIST_MAX: i64 = 500;
+
+#[derive(sqlx::FromRow, Debug)]
+pub struct SongRow {
+    pub id: i64,
+    pub title: String,
+    pub dir: Option<i64>,
+    pub fname: String,
+    pub album_id: Option<i64>,
+    pub album: Option<String>,
+    pub artist_id: Option<i64>,
+    pub artist: Option<String>,
+    pub track_number: Option<i64>,
+    pub disc_number: Option<i64>,
+    pub year: Option<i64>,
+    /// The first genre
+    pub genre: Option<String>,
+    pub play_count: i64,
+    #[sqlx(flatten)]
+    pub audio: AudioInfo,
+    /// Its own art, or else its album's or folder's
+    pub art: Option<i64>,
+}
+
+/// Select songs, with `filter` (a WHERE clause) and `order` spliced in. Neither may
+/// contain user input, bind it instead.
+pub fn song_query(filter: &str, order: &str) -> String {
+    format!(
+        "SELECT track.id, track.title, track.dir, track.fname,
+                album.id AS album_id, album.name AS album,
+                artist.id AS artist_id, artist.name AS artist,
+                track.track_number, track.disc_number, track.year,
+                track.container, track.codec, track.duration_ms, track.sample_rate,
+                track.bit_depth, track.channels, track.bitrate,
+                (SELECT genre.name FROM track_genres
+                     JOIN genre ON genre.id = track_genres.genre
+                     WHERE track_genres.track = track.id
+                     ORDER BY track_genres.position LIMIT 1) AS genre,
+                (SELECT COUNT(*) FROM scrobble WHERE scrobble.track = track.id) AS play_count,
+                COALESCE(track.art, album.art, dir.art) AS art
+            FROM track
+            LEFT JOIN album_tracks ON album_tracks.track = track.id
+            LEFT JOIN album ON album.id = album_tracks.album
+            LEFT JOIN dir ON dir.node = track.dir
+            LEFT JOIN artist ON artist.id = {}
+            {filter}
+            {order};",
+        main_artist("track.id")
+    )
+}
+
+impl SongRow {
+    /// A Subsonic `Child` element.
+    pub fn to_json(&self) -> Value {
+        let suffix = self
+            .fname
+            .rsplit_once('.')
+            .map(|(_, x)| x.to_lowercase())
+            .unwrap_or_default();
+        json!({
+            "id": format!("tr-{}", self.id),
+            "parent": format!("dr-{}", self.dir.unwrap_or(0)),
+            "isDir": false,
+            "title": self.title,
+            "album": self.album,
+            "albumId": self.album_id.map(|x| format!("al-{x}")),
+            "artist": self.artist,
+            "artistId": self.artist_id.map(|x| format!("ar-{x}")),
+            "track": self.track_number,
+            "discNumber": self.disc_number,
+            "year": self.year,
+            "genre": self.genre,
+            "coverArt": self.art.map(|x| format!("ca-{x}")),
+            "contentType": self
+                .audio
+                .container
+                .as_deref()
+                .map_or_else(|| content_type_for(&suffix), container_mime),
+            "suffix": suffix,
+            "duration": self.audio.duration_secs(),
+            "bitRate": self.audio.bitrate_kbps(),
+            "samplingRate": self.audio.sample_rate,
+            "channelCount": self.audio.channels,
+            "bitDepth": self.audio.bit_depth,
+            "path": self.fname,
+            "playCount": self.play_count,
+            "isVideo": false,
+            "type": "music",
+            "mediaType": "song",
+        })
+    }
+}
+
+/// Guess a MIME type from a file extension, for listings where opening every file
+/// would be too slow.
+pub fn content_type_for(suffix: &str) -> &'static str {
+    match suffix {
+        "mp3" => "audio/mpeg",
+        "flac" => "audio/flac",
+        "ogg" | "oga" | "opus" => "audio/ogg",
+        "m4a" | "mp4" | "m4b" => "audio/mp4",
+        "aac" => "audio/aac",
+        "wav" => "audio/wav",
+        "aif" | "aiff" => "audio/aiff",
+        _ => "application/octet-stream",
+    }
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct AlbumRow {
+    id: i64,
+    name: String,
+    artist_id: Option<i64>,
+    artist: Option<String>,
+    year: Option<i64>,
+    /// The genre most of its songs have
+    genre: Option<String>,
+    song_count: i64,
+    /// In seconds
+    duration: i64,
+    art: Option<i64>,
+}
+
+/// Select albums, like [[song_query]]. An album's artist is its first album artist.
+fn album_query(filter: &str, order: &str) -> String {
+    format!(
+        "SELECT album.id, album.name,
+                artist.id AS artist_id, artist.name AS artist, album.year,
+                (SELECT genre.name FROM album_tracks AS at
+                     JOIN track_genres ON track_genres.track = at.track
+                     JOIN genre ON genre.id = track_genres.genre
+                     WHERE at.album = album.id
+                     GROUP BY genre.id ORDER BY COUNT(*) DESC LIMIT 1) AS genre,
+                COUNT(album_tracks.track) AS song_count,
+                (COALESCE(SUM(track.duration_ms), 0) + 500) / 1000 AS duration,
+                album.art
+            FROM album
+            JOIN album_tracks ON album_tracks.album = album.id
+            JOIN track ON track.id = album_tracks.track
+            LEFT JOIN artist ON artist.id = album.artist
+            {filter}
+            GROUP BY album.id
+            {order};"
+    )
+}
+
+impl AlbumRow {
+    /// A Subsonic `AlbumID3` element.
+    fn to_json(&self) -> Value {
+        json!({
+            "id": format!("al-{}", self.id),
+            "name": self.name,
+            "artist": self.artist,
+            "artistId": self.artist_id.map(|x| format!("ar-{x}")),
+            "year": self.year,
+            "genre": self.genre,
+            "coverArt": self.art.map(|x| format!("ca-{x}")),
+            "songCount": self.song_count,
+            "duration": self.duration,
+        })
+    }
+}
+
+#[derive(sqlx::FromRow, Debug)]
+struct ArtistRow {
+    id: i64,
+    name: String,
+    album_count: i64,
+}
+
+/// SQL for the ids of every album `artist` (an id column or parameter) is on, either
+/// as an album artist or credited on one of its tracks.
+fn artist_albums(artist: &str) -> String {
+    format!(
+        "SELECT album_tracks.album FROM album_tracks
+             JOIN artist_tracks ON artist_tracks.track = album_tracks.track
+             WHERE artist_tracks.artist = {artist}
+         UNION SELECT album FROM album_artists WHERE album_artists.artist = {artist}"
+    )
+}
+
+fn artist_query() -> String {
+    format!(
+        "SELECT artist.id, artist.name,
+                (SELECT COUNT(*) FROM album WHERE album.id IN ({})) AS album_count
+            FROM artist",
+        artist_albums("artist.id")
+    )
+}
+
+impl ArtistRow {
+    /// A Subsonic `ArtistID3` element.
+    fn to_json(&self) -> Value {
+        json!({
+            "id": format!("ar-{}", self.id),
+            "name": self.name,
+            "albumCount": self.album_count,
+        })
+    }
+}
+
+/// Group named entries into alphabetical `index` elements, with anything that
+/// doesn't start with a letter going under `#`.
+fn index_by_name<'a>(entries: impl Iterator<Item = (&'a str, Value)>) -> Vec<Value> {
+    let mut index: Vec<(String, Vec<Value>)> = vec![];
+    for (name, entry) in entries {
+        let letter = match name.trim().chars().next() {
+            Some(c) if c.is_alphabetic() => c.to_uppercase().collect(),
+            _ => "#".to_owned(),
+        };
+        match index.iter_mut().find(|(x, _)| *x == letter) {
+            Some((_, group)) => group.push(entry),
+            None => index.push((letter, vec![entry])),
+        }
+    }
+    index.sort_by(|(a, _), (b, _)| a.cmp(b));
+    index
+        .into_iter()
+        .map(|(name, artist)| json!({ "name": name, "artist": artist }))
+        .collect()
+}
+
+/// Clamp the usual `size` and `offset` parameters.
+fn paging(
+    req: &SubsonicRequest,
+    size_key: &str,
+    offset_key: &str,
+    default: i64,
+) -> Result<(i64, i64), SubsonicError> {
+    let size = req
+        .params
+        .parse::<i64>(size_key)?
+        .unwrap_or(default)
+        .clamp(0, LIST_MAX);
+    let offset = req.params.parse::<i64>(offset_key)?.unwrap_or(0).max(0);
+    Ok((size, offset))
+}
+
+pub fn get_music_folders() -> Reply {
+    Reply::with(
+        "musicFolders",
+        json!({ "musicFolder": [{ "id": MUSIC_FOLDER_ID, "name": "Music" }] }),
+    )
+}
+
+/// Songs directly inside of `dir`, where None is the root.
+async fn child_songs(
+    db: &mut sqlx::SqliteConnection,
+    dir: Option<i64>,
+) -> Result<Vec<SongRow>, sqlx::Error> {
+    sqlx::query_as::<_, SongRow>(&song_query(
+        "WHERE track.dir IS $1",
+        "ORDER BY track.fname COLLATE NOCASE",
+    ))
+    .bind(dir)
+    .fetch_all(db)
+    .await
+}
+
+/// The top level of the folder tree. Top level dirs are listed as the "artists" of
+/// the index, as the spec has it, and files in the root as children.
+#[tracing::instrument]
+pub async fn get_indexes(state: &ReamioApp, req: &SubsonicRequest) -> Result<Reply, SubsonicError> {
+    let mut db = fetch_users_music_db(state.music_dbs.clone(), &req.user).await?;
+    let dirs = child_dirs(&mut db, None).await?;
+    let songs = child_songs(&mut db, None).await?;
+    Ok(Reply::with(
+        "indexes",
+        json!({
+            "lastModified": 0,
+            "ignoredArticles": "",
+            "index": index_by_name(dirs.iter().map(|x| {
+                (x.name.as_str(), json!({ "id": format!("dr-{}", x.node), "name": x.name }))
+            })),
+            "child": songs.iter().map(SongRow::to_json).collect::<Vec<_>>(),
+        }),
+    ))
+}
+
+/// List a dir of the folder tree.
+#[tracing::instrument]
+pub async fn get_music_directory(
+    state: &ReamioApp,
+    req: &SubsonicRequest,
+) -> Result<Reply, SubsonicError> {
+    let node = parse_id_of(req.params.require("id")?, "dr")?;
+    let mut db = fetch_users_music_db(state.music_dbs.clone(), &req.user).await?;
+
+    // 0 is the root, which has no row
+    let (node, name, parent) = if node == 0 {
+        (None, "Music".to_owned(), None)
+    } else {
+        let mut path = breadcrumbs(&mut db, node).await?;
+        let this = path
+            .pop()
+            .ok_or_else(|| SubsonicError::not_found("directory"))?;
+        let parent = path.last().map(|x| x.node).unwrap_or(0);
+        (Some(node), this.name, Some(parent))
+    };
+
+    let dirs = child_dirs(&mut db, node).await?;
+    let songs = child_songs(&mut db, node).await?;
+    let id = format!("dr-{}", node.unwrap_or(0));
+    let mut child = dirs
+        .iter()
+        .map(|x| {
+            json!({
+                "id": format!("dr-{}", x.node),
+                "parent": id,
+                "isDir": true,
+                "title": x.name,
+            })
+        })
+        .collect::<Vec<_>>();
+    child.extend(songs.iter().map(SongRow::to_json));
+    Ok(Reply::with(
+        "directory",
+        json!({
+            "id": id,
+            "parent": parent.map(|x| format!("dr-{x}")),
+            "name": name,
+            "child": child,
+        }),
+    ))
+}
+
+#[tracing::instrument]
+pub async fn get_artists(state: &ReamioApp, req: &SubsonicRequest) -> Result<Reply, SubsonicError> {
+    let mut db = fetch_users_music_db(state.music_dbs.clone(), &req.user).await?;
+    let artists = sqlx::query_as::<_, ArtistRow>(&format!(
+        "{} ORDER BY artist.name COLLATE NOCASE;",
+        artist_query()
+    ))
+    .fetch_all(&mut *db)
+    .await?;
+    Ok(Reply::with(
+        "artists",
+        json!({
+            "ignoredArticles": "",
+            "index": index_by_name(artists.iter().map(|x| (x.name.as_str(), x.to_json()))),
+        }),
+    ))
+}
+
+#[tracing::instrument]
+pub async fn get_artist(state: &ReamioApp, req: &SubsonicRequest) -> Result<Reply, SubsonicError> {
+    let id = parse_id_of(req.params.require("id")?, "ar")?;
+    let mut db = fetch_users_music_db(state.music_dbs.clone(), &req.user).await?;
+    let artist =
+        sqlx::query_as::<_, ArtistRow>(&format!("{} WHERE artist.id = $1;", artist_query()))
+            .bind(id)
+            .fetch_optional(&mut *db)
+            .await?
+            .ok_or_else(|| SubsonicError::not_found("artist"))?;
+    let albums = sqlx::query_as::<_, AlbumRow>(&album_query(
+        &format!("WHERE album.id IN ({})", artist_albums("$1")),
+        "ORDER BY album.name COLLATE NOCASE",
+    ))
+    .bind(id)
+    .fetch_all(&mut *db)
+    .await?;
+
+    let mut ret = artist.to_json();
+    ret["album"] = albums.iter().map(AlbumRow::to_json).collect();
+    Ok(Reply::with("artist", ret))
+}
+
+#[tracing::instrument]
+pub async fn get_album(state: &ReamioApp, req: &SubsonicRequest) -> Result<Reply, SubsonicError> {
+    let id = parse_id_of(req.params.require("id")?, "al")?;
+    let mut db = fetch_users_music_db(state.music_dbs.clone(), &req.user).await?;
+    let album = sqlx::query_as::<_, AlbumRow>(&album_query("WHERE album.id = $1", ""))
+        .bind(id)
+        .fetch_optional(&mut *db)
+        .await?
+        .ok_or_else(|| SubsonicError::not_found("album"))?;
+    let songs = sqlx::query_as::<_, SongRow>(&song_query(
+        "WHERE album_tracks.album = $1",
+        "ORDER BY COALESCE(track.disc_number, 0), COALESCE(track.track_number, 0),
+                  track.fname COLLATE NOCASE",
+    ))
+    .bind(id)
+    .fetch_all(&mut *db)
+    .await?;
+
+    let mut ret = album.to_json();
+    ret["song"] = songs.iter().map(SongRow::to_json).collect();
+    Ok(Reply::with("album", ret))
+}
+
+#[tracing::instrument]
+pub async fn get_song(state: &ReamioApp, req: &SubsonicRequest) -> Result<Reply, SubsonicError> {
+    let id = parse_id_of(req.params.require("id")?, "tr")?;
+    let mut db = fetch_users_music_db(state.music_dbs.clone(), &req.user).await?;
+    let song = sqlx::query_as::<_, SongRow>(&song_query("WHERE track.id = $1", ""))
+        .bind(id)
+        .fetch_optional(&mut *db)
+        .await?
+        .ok_or_else(|| SubsonicError::not_found("song"))?;
+    Ok(Reply::with("song", song.to_json()))
+}
+
+/// Album lists for the home screen of most clients. Stars aren't tracked, so
+/// `starred` is always empty.
+#[tracing::instrument]
+pub async fn get_album_list2(
+    state: &ReamioApp,
+    req: &SubsonicRequest,
+) -> Result<Reply, SubsonicError> {
+    let (size, offset) = paging(req, "size", "offset", 10)?;
+    let mut genre = None;
+    let (filter, order) = match req.params.require("type")? {
+        "random" => (String::new(), "ORDER BY RANDOM()".to_owned()),
+        "newest" => (String::new(), "ORDER BY album.id DESC".to_owned()),
+        "alphabeticalByName" => (
+            String::new(),
+            "ORDER BY album.name COLLATE NOCASE".to_owned(),
+        ),
+        "alphabeticalByArtist" => (
+            String::new(),
+            "ORDER BY artist.name COLLATE NOCASE, album.name COLLATE NOCASE".to_owned(),
+        ),
+        "frequent" => (
+            "WHERE album.id IN (SELECT at.album FROM album_tracks AS at
+                 JOIN scrobble ON scrobble.track = at.track)"
+                .to_owned(),
+            "ORDER BY (SELECT COUNT(*) FROM scrobble
+                 JOIN album_tracks AS at ON at.track = scrobble.track
+                 WHERE at.album = album.id) DESC"
+                .to_owned(),
+        ),
+        "recent" => (
+            "WHERE album.id IN (SELECT at.album FROM album_tracks AS at
+                 JOIN scrobble ON scrobble.track = at.track)"
+                .to_owned(),
+            "ORDER BY (SELECT MAX(scrobble.time) FROM scrobble
+                 JOIN album_tracks AS at ON at.track = scrobble.track
+                 WHERE at.album = album.id) DESC"
+                .to_owned(),
+        ),
+        // a range given backwards lists newest first
+        "byYear" => {
+            let from = req.params.parse_required::<i64>("fromYear")?;
+            let to = req.params.parse_required::<i64>("toYear")?;
+            (
+                format!(
+                    "WHERE album.year BETWEEN {} AND {}",
+                    from.min(to),
+                    from.max(to)
+                ),
+                format!(
+                    "ORDER BY album.year {}, album.name COLLATE NOCASE",
+                    if from > to { "DESC" } else { "ASC" }
+                ),
+            )
+        }
+        "byGenre" => {
+            genre = Some(name_key(req.params.require("genre")?));
+            (
+                "WHERE album.id IN (SELECT at.album FROM album_tracks AS at
+                     JOIN track_genres ON track_genres.track = at.track
+                     JOIN genre ON genre.id = track_genres.genre
+                     WHERE genre.name_key = $3)"
+                    .to_owned(),
+                "ORDER BY album.name COLLATE NOCASE".to_owned(),
+            )
+        }
+        "starred" => {
+            return Ok(Reply::with("albumList2", json!({ "album": [] })));
+        }
+        other => {
+            return Err(SubsonicError::new(
+                ErrorCode::Generic,
+                format!("unknown album list type '{other}'"),
+            ));
+        }
+    };
+
+    let mut db = fetch_users_music_db(state.music_dbs.clone(), &req.user).await?;
+    let query = album_query(&filter, &format!("{order} LIMIT $1 OFFSET $2"));
+    let mut query = sqlx::query_as::<_, AlbumRow>(&query)
+        .bind(size)
+        .bind(offset);
+    if let Some(genre) = genre {
+        query = query.bind(genre);
+    }
+    let albums = query.fetch_all(&mut *db).await?;
+    Ok(Reply::with(
+        "albumList2",
+        json!({ "album": albums.iter().map(AlbumRow::to_json).collect::<Vec<_>>() }),
+    ))
+}
+
+/// Every genre, with how many songs and albums have it.
+#[tracing::instrument]
+pub async fn get_genres(state: &ReamioApp, req: &SubsonicRequest) -> Result<Reply, SubsonicError> {
+    let mut db = fetch_users_music_db(state.music_dbs.clone(), &req.user).await?;
+    let genres = sqlx::query(
+        "SELECT genre.name,
+                COUNT(DISTINCT track_genres.track) AS song_count,
+                COUNT(DISTINCT album_tracks.album) AS album_count
+             FROM genre
+             JOIN track_genres ON track_genres.genre = genre.id
+             LEFT JOIN album_tracks ON album_tracks.track = track_genres.track
+             GROUP BY genre.id
+             ORDER BY genre.name COLLATE NOCASE;",
+    )
+    .fetch_all(&mut *db)
+    .await?;
+    Ok(Reply::with(
+        "genres",
+        json!({
+            "genre": genres
+                .iter()
+                .map(|x| {
+                    json!({
+                        "value": x.get::<String, _>("name"),
+                        "songCount": x.get::<i64, _>("song_count"),
+                        "albumCount": x.get::<i64, _>("album_count"),
+                    })
+                })
+                .collect::<Vec<_>>(),
+        }),
+    ))
+}
+
+#[tracing::instrument]
+pub async fn get_random_songs(
+    state: &ReamioApp,
+    req: &SubsonicRequest,
+) -> Result<Reply, SubsonicError> {
+    let (size, _) = paging(req, "size", "offset", 10)?;
+    let mut db = fetch_users_music_db(state.music_dbs.clone(), &req.user).await?;
+    let songs = sqlx::query_as::<_, SongRow>(&song_query("", "ORDER BY RANDOM() LIMIT $1"))
+        .bind(size)
+        .fetch_all(&mut *db)
+        .await?;
+    Ok(Reply::with(
+        "randomSongs",
+        json!({ "song": songs.iter().map(SongRow::to_json).collect::<Vec<_>>() }),
+    ))
+}
+
+/// Search artists, albums and songs by name, with the same matching as
+/// [[crate::search::search]]. An empty query matches everything, which some clients
+/// use to sync the whole library.
+#[tracing::instrument]
+pub async fn search3(state: &ReamioApp, req: &SubsonicRequest) -> Result<Reply, SubsonicError> {
+    // None matches everything
+    let expr = match_expr(req.params.get("query").unwrap_or_default());
+    let (artist_count, artist_offset) = paging(req, "artistCount", "artistOffset", 20)?;
+    let (album_count, album_offset) = paging(req, "albumCount", "albumOffset", 20)?;
+    let (song_count, song_offset) = paging(req, "songCount", "songOffset", 20)?;
+
+    let mut db = fetch_users_music_db(state.music_dbs.clone(), &req.user).await?;
+    let artists = sqlx::query_as::<_, ArtistRow>(&format!(
+        "{} WHERE $1 IS NULL OR artist.id IN ({})
+             ORDER BY artist.name COLLATE NOCASE LIMIT $2 OFFSET $3;",
+        artist_query(),
+        matching_ids(SearchKind::Artist)
+    ))
+    .bind(&expr)
+    .bind(artist_count)
+    .bind(artist_offset)
+    .fetch_all(&mut *db)
+    .await?;
+    let albums = sqlx::query_as::<_, AlbumRow>(&album_query(
+        &format!(
+            "WHERE $1 IS NULL OR album.id IN ({})",
+            matching_ids(SearchKind::Album)
+        ),
+        "ORDER BY album.name COLLATE NOCASE LIMIT $2 OFFSET $3",
+    ))
+    .bind(&expr)
+    .bind(album_count)
+    .bind(album_offset)
+    .fetch_all(&mut *db)
+    .await?;
+    let songs = sqlx::query_as::<_, SongRow>(&song_query(
+        &format!(
+            "WHERE $1 IS NULL OR track.id IN ({})",
+            matching_ids(SearchKind::Track)
+        ),
+        "ORDER BY track.title COLLATE NOCASE LIMIT $2 OFFSET $3",
+    ))
+    .bind(&expr)
+    .bind(song_count)
+    .bind(song_offset)
+    .fetch_all(&mut *db)
+    .await?;
+
+    Ok(Reply::with(
+        "searchResult3",
+        json!({
+            "artist": artists.iter().map(ArtistRow::to_json).collect::<Vec<_>>(),
+            "album": albums.iter().map(AlbumRow::to_json).collect::<Vec<_>>(),
+            "song": songs.iter().map(SongRow::to_json).collect::<Vec<_>>(),
+        }),
+    ))
+}
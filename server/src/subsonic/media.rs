@@ -0,0 +1,142 @@
+//! Methods that return media rather than a `subsonic-response`.
+
+use axum::{
+    http::{HeaderValue, header},
+    response::Response,
+};
+use std::path::PathBuf;
+
+use super::{Reply, SubsonicError, SubsonicRequest, parse_id, parse_id_of};
+use crate::ReamioApp;
+use crate::art::serve_art;
+use crate::catalog;
+use crate::prelude::*;
+use crate::stream::{serve_file, sniff_content_type};
+use crate::transcode::{TranscodeFormat, TranscodeProfile, transcode_file};
+
+/// Look up a track for a media method, returning its id, original file name and
+/// where its file is.
+async fn fetch_track(
+    state: &ReamioApp,
+    req: &SubsonicRequest,
+) -> Result<(i64, String, String), SubsonicError> {
+    let id = parse_id_of(req.params.require("id")?, "tr")?;
+    let mut db = fetch_users_music_db(state.music_dbs.clone(), &req.user).await?;
+    let row = sqlx::query("SELECT fname, source FROM track WHERE id = $1;")
+        .bind(id)
+        .fetch_optional(&mut *db)
+        .await?
+        .ok_or_else(|| SubsonicError::not_found("song"))?;
+    let path = catalog::track_path(&req.user, id, row.get("source"));
+    Ok((id, row.get("fname"), path))
+}
+
+async fn serve_original(req: &SubsonicRequest, path: &str) -> Result<Response, ReamioWebError> {
+    let content_type = sniff_content_type(path).await?;
+    serve_file(path, content_type, &req.headers).await
+}
+
+/// Stream a song, transcoding it when the client asks for a `format` or a
+/// `maxBitRate`. Only Ogg Vorbis and WAV can be produced, so any other lossy format
+/// is served as Ogg Vorbis, and `raw` (or asking for the format the file is already
+/// in) gets the original.
+#[tracing::instrument]
+pub async fn stream(state: &ReamioApp, req: &SubsonicRequest) -> Result<Reply, SubsonicError> {
+    let (id, fname, path) = fetch_track(state, req).await?;
+    let format = req.params.get("format").map(str::to_lowercase);
+    let max_bitrate = req.params.parse::<u32>("maxBitRate")?.unwrap_or(0);
+    let suffix = fname
+        .rsplit_once('.')
+        .map(|(_, x)| x.to_lowercase())
+        .unwrap_or_default();
+
+    let profile = match format.as_deref() {
+        Some("raw") => None,
+        Some(x) if x == suffix && max_bitrate == 0 => None,
+        Some("wav") => Some(TranscodeProfile::new(TranscodeFormat::Wav, None)?),
+        None if max_bitrate == 0 => None,
+        _ => Some(TranscodeProfile::new(
+            TranscodeFormat::Ogg,
+            (max_bitrate > 0).then(|| max_bitrate.clamp(32, 320)),
+        )?),
+    };
+    debug!(?profile, "stream profile chosen");
+
+    let resp = match profile {
+        None => serve_original(req, &path).await?,
+        Some(profile) => {
+            transcode_file(
+                PathBuf::from(path),
+                &req.user,
+                id,
+                profile,
+                state.config.transcode_cache_bytes,
+                &req.headers,
+            )
+            .await?
+        }
+    };
+    Ok(Reply::Raw(resp))
+}
+
+/// Download the original file of a song, under its original name.
+#[tracing::instrument]
+pub async fn download(state: &ReamioApp, req: &SubsonicRequest) -> Result<Reply, SubsonicError> {
+    let (_, fname, path) = fetch_track(state, req).await?;
+    let mut resp = serve_original(req, &path).await?;
+
+    // RFC 6266 / 8187 style, which gets unicode names through intact
+    let encoded = fname
+        .bytes()
+        .map(|x| match x {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (x as char).to_string()
+            }
+            x => format!("%{x:02X}"),
+        })
+        .collect::<String>();
+    if let Ok(disposition) =
+        HeaderValue::from_str(&format!("attachment; filename*=UTF-8''{encoded}"))
+    {
+        resp.headers_mut()
+            .insert(header::CONTENT_DISPOSITION, disposition);
+    }
+    Ok(Reply::Raw(resp))
+}
+
+/// Serve cover art, scaled down to `size` if given. Besides the `ca-` ids songs and
+/// albums hand out, album and song ids work too, for clients that assume they do.
+#[tracing::instrument]
+pub async fn get_cover_art(
+    state: &ReamioApp,
+    req: &SubsonicRequest,
+) -> Result<Reply, SubsonicError> {
+    let (kind, id) = parse_id(req.params.require("id")?, "ca")?;
+    let size = req.params.parse::<u32>("size")?;
+    let art_query = match kind {
+        "ca" => "SELECT id AS art FROM art WHERE id = $1;",
+        "al" => "SELECT art FROM album WHERE id = $1;",
+        "tr" => {
+            "SELECT COALESCE(track.art, album.art, dir.art) AS art FROM track
+                 LEFT JOIN album_tracks ON album_tracks.track = track.id
+                 LEFT JOIN album ON album.id = album_tracks.album
+                 LEFT JOIN dir ON dir.node = track.dir
+                 WHERE track.id = $1;"
+        }
+        "dr" => "SELECT art FROM dir WHERE node = $1;",
+        _ => return Err(SubsonicError::not_found("cover art")),
+    };
+
+    let mut db = fetch_users_music_db(state.music_dbs.clone(), &req.user).await?;
+    let art: i64 = sqlx::query(art_query)
+        .bind(id)
+        .fetch_optional(&mut *db)
+        .await?
+        .and_then(|x| x.get::<Option<i64>, _>("art"))
+        .ok_or_else(|| SubsonicError::not_found("cover art"))?;
+    drop(db);
+
+    Ok(Reply::Raw(
+        serve_art(state, &req.user, art, size, &req.headers).await?,
+    ))
+}
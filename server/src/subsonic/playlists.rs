@@ -0,0 +1,315 @@
+//! Playlists and play tracking.
+
+use serde_json::{Value, json};
+
+use super::library::{SongRow, song_query};
+use super::{ErrorCode, Reply, SubsonicError, SubsonicRequest, parse_id_of};
+use crate::ReamioApp;
+use crate::prelude::*;
+
+#[derive(sqlx::FromRow, Debug)]
+struct PlaylistRow {
+    id: i64,
+    name: String,
+    comment: String,
+    /// RFC 3339
+    created: String,
+    /// RFC 3339
+    changed: String,
+    song_count: i64,
+    /// In seconds
+    duration: i64,
+}
+
+const PLAYLIST_QUERY: &str = "SELECT playlist.id, playlist.name, playlist.comment,
+        strftime('%Y-%m-%dT%H:%M:%SZ', playlist.created, 'unixepoch') AS created,
+        strftime('%Y-%m-%dT%H:%M:%SZ', playlist.changed, 'unixepoch') AS changed,
+        (SELECT COUNT(*) FROM playlist_tracks WHERE playlist_tracks.playlist = playlist.id)
+            AS song_count,
+        (SELECT (COALESCE(SUM(track.duration_ms), 0) + 500) / 1000 FROM playlist_tracks
+             JOIN track ON track.id = playlist_tracks.track
+             WHERE playlist_tracks.playlist = playlist.id) AS duration
+    FROM playlist";
+
+impl PlaylistRow {
+    /// A Subsonic `Playlist` element. Playlists are private to their owner, since
+    /// every user has their own library.
+    fn to_json(&self, owner: &str) -> Value {
+        json!({
+            "id": format!("pl-{}", self.id),
+            "name": self.name,
+            "comment": self.comment,
+            "owner": owner,
+            "public": false,
+            "songCount": self.song_count,
+            "duration": self.duration,
+            "created": self.created,
+            "changed": self.changed,
+        })
+    }
+}
+
+/// A playlist, along with its entries.
+async fn fetch_playlist(
+    db: &mut sqlx::SqliteConnection,
+    owner: &str,
+    id: i64,
+) -> Result<Value, SubsonicError> {
+    let playlist =
+        sqlx::query_as::<_, PlaylistRow>(&format!("{PLAYLIST_QUERY} WHERE playlist.id = $1;"))
+            .bind(id)
+            .fetch_optional(&mut *db)
+            .await?
+            .ok_or_else(|| SubsonicError::not_found("playlist"))?;
+    let songs = sqlx::query_as::<_, SongRow>(&song_query(
+        "JOIN playlist_tracks ON playlist_tracks.track = track.id
+             WHERE playlist_tracks.playlist = $1",
+        "ORDER BY playlist_tracks.position",
+    ))
+    .bind(id)
+    .fetch_all(&mut *db)
+    .await?;
+
+    let mut ret = playlist.to_json(owner);
+    ret["entry"] = songs.iter().map(SongRow::to_json).collect();
+    Ok(ret)
+}
+
+/// Every track id in a playlist, in order.
+async fn playlist_tracks(
+    db: &mut sqlx::SqliteConnection,
+    id: i64,
+) -> Result<Vec<i64>, sqlx::Error> {
+    Ok(
+        sqlx::query("SELECT track FROM playlist_tracks WHERE playlist = $1 ORDER BY position;")
+            .bind(id)
+            .fetch_all(db)
+            .await?
+            .into_iter()
+            .map(|x| x.get("track"))
+            .collect(),
+    )
+}
+
+/// Replace the contents of a playlist.
+async fn write_playlist_tracks(
+    db: &mut sqlx::SqliteConnection,
+    id: i64,
+    tracks: &[i64],
+) -> Result<(), SubsonicError> {
+    sqlx::query("DELETE FROM playlist_tracks WHERE playlist = $1;")
+        .bind(id)
+        .execute(&mut *db)
+        .await?;
+    for (position, track) in tracks.iter().enumerate() {
+        let exists = sqlx::query("SELECT 1 FROM track WHERE id = $1;")
+            .bind(track)
+            .fetch_optional(&mut *db)
+            .await?;
+        if exists.is_none() {
+            return Err(SubsonicError::not_found(&format!("song tr-{track}")));
+        }
+        sqlx::query("INSERT INTO playlist_tracks (playlist, position, track) VALUES ($1, $2, $3);")
+            .bind(id)
+            .bind(position as i64)
+            .bind(track)
+            .execute(&mut *db)
+            .await?;
+    }
+    sqlx::query("UPDATE playlist SET changed = unixepoch() WHERE id = $1;")
+        .bind(id)
+        .execute(&mut *db)
+        .await?;
+    Ok(())
+}
+
+fn song_ids<'a>(req: &'a SubsonicRequest, key: &'a str) -> Result<Vec<i64>, SubsonicError> {
+    req.params.all(key).map(|x| parse_id_of(x, "tr")).collect()
+}
+
+#[tracing::instrument]
+pub async fn get_playlists(
+    state: &ReamioApp,
+    req: &SubsonicRequest,
+) -> Result<Reply, SubsonicError> {
+    let mut db = fetch_users_music_db(state.music_dbs.clone(), &req.user).await?;
+    let playlists = sqlx::query_as::<_, PlaylistRow>(&format!(
+        "{PLAYLIST_QUERY} ORDER BY playlist.name COLLATE NOCASE;"
+    ))
+    .fetch_all(&mut *db)
+    .await?;
+    Ok(Reply::with(
+        "playlists",
+        json!({
+            "playlist": playlists.iter().map(|x| x.to_json(&req.user)).collect::<Vec<_>>(),
+        }),
+    ))
+}
+
+#[tracing::instrument]
+pub async fn get_playlist(
+    state: &ReamioApp,
+    req: &SubsonicRequest,
+) -> Result<Reply, SubsonicError> {
+    let id = parse_id_of(req.params.require("id")?, "pl")?;
+    let mut db = fetch_users_music_db(state.music_dbs.clone(), &req.user).await?;
+    Ok(Reply::with(
+        "playlist",
+        fetch_playlist(&mut db, &req.user, id).await?,
+    ))
+}
+
+/// Create a playlist from `name`, or overwrite the songs of `playlistId`.
+#[tracing::instrument]
+pub async fn create_playlist(
+    state: &ReamioApp,
+    req: &SubsonicRequest,
+) -> Result<Reply, SubsonicError> {
+    let songs = song_ids(req, "songId")?;
+    let mut db = fetch_users_music_db(state.music_dbs.clone(), &req.user).await?;
+    let mut txn = db.begin_with("BEGIN IMMEDIATE").await?;
+
+    let id = match req.params.get("playlistId") {
+        Some(id) => {
+            let id = parse_id_of(id, "pl")?;
+            sqlx::query("SELECT 1 FROM playlist WHERE id = $1;")
+                .bind(id)
+                .fetch_optional(&mut *txn)
+                .await?
+                .ok_or_else(|| SubsonicError::not_found("playlist"))?;
+            id
+        }
+        None => sqlx::query(
+            "INSERT INTO playlist (name, created, changed)
+                 VALUES ($1, unixepoch(), unixepoch()) RETURNING id;",
+        )
+        .bind(req.params.require("name")?)
+        .fetch_one(&mut *txn)
+        .await?
+        .get("id"),
+    };
+    write_playlist_tracks(&mut txn, id, &songs).await?;
+    let ret = fetch_playlist(&mut txn, &req.user, id).await?;
+    txn.commit().await?;
+    debug!(id, songs = songs.len(), "playlist written");
+    Ok(Reply::with("playlist", ret))
+}
+
+/// Rename a playlist, and add or remove songs. Removals are by index into the
+/// playlist as it was before this call, and happen before the additions.
+#[tracing::instrument]
+pub async fn update_playlist(
+    state: &ReamioApp,
+    req: &SubsonicRequest,
+) -> Result<Reply, SubsonicError> {
+    let id = parse_id_of(req.params.require("playlistId")?, "pl")?;
+    let add = song_ids(req, "songIdToAdd")?;
+    let remove = req
+        .params
+        .all("songIndexToRemove")
+        .map(|x| {
+            x.parse::<usize>()
+                .map_err(|_| SubsonicError::new(ErrorCode::Generic, "invalid songIndexToRemove"))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut db = fetch_users_music_db(state.music_dbs.clone(), &req.user).await?;
+    let mut txn = db.begin_with("BEGIN IMMEDIATE").await?;
+    sqlx::query("SELECT 1 FROM playlist WHERE id = $1;")
+        .bind(id)
+        .fetch_optional(&mut *txn)
+        .await?
+        .ok_or_else(|| SubsonicError::not_found("playlist"))?;
+
+    if let Some(name) = req.params.get("name") {
+        sqlx::query("UPDATE playlist SET name = $1, changed = unixepoch() WHERE id = $2;")
+            .bind(name)
+            .bind(id)
+            .execute(&mut *txn)
+            .await?;
+    }
+    if let Some(comment) = req.params.get("comment") {
+        sqlx::query("UPDATE playlist SET comment = $1, changed = unixepoch() WHERE id = $2;")
+            .bind(comment)
+            .bind(id)
+            .execute(&mut *txn)
+            .await?;
+    }
+    if !add.is_empty() || !remove.is_empty() {
+        let tracks = playlist_tracks(&mut txn, id)
+            .await?
+            .into_iter()
+            .enumerate()
+            .filter(|(idx, _)| !remove.contains(idx))
+            .map(|(_, track)| track)
+            .chain(add)
+            .collect::<Vec<_>>();
+        write_playlist_tracks(&mut txn, id, &tracks).await?;
+    }
+    txn.commit().await?;
+    debug!(id, "playlist updated");
+    Ok(Reply::empty())
+}
+
+#[tracing::instrument]
+pub async fn delete_playlist(
+    state: &ReamioApp,
+    req: &SubsonicRequest,
+) -> Result<Reply, SubsonicError> {
+    let id = parse_id_of(req.params.require("id")?, "pl")?;
+    let mut db = fetch_users_music_db(state.music_dbs.clone(), &req.user).await?;
+    let mut txn = db.begin_with("BEGIN IMMEDIATE").await?;
+    sqlx::query("DELETE FROM playlist_tracks WHERE playlist = $1;")
+        .bind(id)
+        .execute(&mut *txn)
+        .await?;
+    let deleted = sqlx::query("DELETE FROM playlist WHERE id = $1;")
+        .bind(id)
+        .execute(&mut *txn)
+        .await?
+        .rows_affected();
+    if deleted == 0 {
+        return Err(SubsonicError::not_found("playlist"));
+    }
+    txn.commit().await?;
+    debug!(id, "playlist deleted");
+    Ok(Reply::empty())
+}
+
+/// Record plays. `time` (in milliseconds) lines up with `id` when given. "Now
+/// playing" notifications, with `submission=false`, aren't tracked.
+#[tracing::instrument]
+pub async fn scrobble(state: &ReamioApp, req: &SubsonicRequest) -> Result<Reply, SubsonicError> {
+    let ids = song_ids(req, "id")?;
+    if ids.is_empty() {
+        req.params.require("id")?;
+    }
+    if req.params.get("submission") == Some("false") {
+        return Ok(Reply::empty());
+    }
+    let times = req
+        .params
+        .all("time")
+        .map(|x| x.parse::<i64>().ok().map(|x| x / 1000))
+        .collect::<Vec<_>>();
+
+    let mut db = fetch_users_music_db(state.music_dbs.clone(), &req.user).await?;
+    let mut txn = db.begin_with("BEGIN IMMEDIATE").await?;
+    for (idx, id) in ids.iter().enumerate() {
+        let inserted = sqlx::query(
+            "INSERT INTO scrobble (track, time)
+                 SELECT id, COALESCE($2, unixepoch()) FROM track WHERE id = $1;",
+        )
+        .bind(id)
+        .bind(times.get(idx).copied().flatten())
+        .execute(&mut *txn)
+        .await?
+        .rows_affected();
+        if inserted == 0 {
+            return Err(SubsonicError::not_found(&format!("song tr-{id}")));
+        }
+    }
+    txn.commit().await?;
+    debug!(plays = ids.len(), "scrobbled");
+    Ok(Reply::empty())
+}
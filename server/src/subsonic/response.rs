@@ -0,0 +1,229 @@
+use axum::{
+    http::{HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use serde_json::{Map, Value, json};
+
+use super::{API_VERSION, Params};
+use crate::prelude::*;
+
+/// Error codes from the Subsonic spec.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    Generic = 0,
+    MissingParameter = 10,
+    WrongCredentials = 40,
+    TokenAuthNotSupported = 41,
+    NotAuthorized = 50,
+    NotFound = 70,
+}
+
+/// An error as reported to Subsonic clients, in the `error` element of a response.
+#[derive(Debug)]
+pub struct SubsonicError {
+    pub code: ErrorCode,
+    pub msg: String,
+}
+
+impl SubsonicError {
+    pub fn new(code: ErrorCode, msg: impl Into<String>) -> Self {
+        Self {
+            code,
+            msg: msg.into(),
+        }
+    }
+
+    pub fn not_found(what: &str) -> Self {
+        Self::new(ErrorCode::NotFound, format!("{what} not found"))
+    }
+}
+
+impl From<sqlx::Error> for SubsonicError {
+    #[tracing::instrument]
+    fn from(value: sqlx::Error) -> Self {
+        error!(?value, "subsonicerror generated");
+        Self::new(ErrorCode::Generic, value.to_string())
+    }
+}
+
+impl From<ReamioWebError> for SubsonicError {
+    fn from(value: ReamioWebError) -> Self {
+        match value {
+            ReamioWebError::IncorrectArgs(msg, sc) => {
+                let code = match sc {
+                    StatusCode::NOT_FOUND => ErrorCode::NotFound,
+                    StatusCode::UNAUTHORIZED => ErrorCode::WrongCredentials,
+                    StatusCode::FORBIDDEN => ErrorCode::NotAuthorized,
+                    _ => ErrorCode::Generic,
+                };
+                Self::new(code, msg)
+            }
+            // the conversion to ReamioWebError already logged these
+            _ => Self::new(ErrorCode::Generic, "internal server error"),
+        }
+    }
+}
+
+/// The `f` parameter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResponseFormat {
+    Xml,
+    Json,
+    /// JSON wrapped in a call to the named function
+    Jsonp(String),
+}
+
+impl ResponseFormat {
+    pub fn from_params(params: &Params) -> Self {
+        match params.get("f") {
+            Some("json") => ResponseFormat::Json,
+            Some("jsonp") => match params.get("callback") {
+                Some(callback) if is_identifier(callback) => {
+                    ResponseFormat::Jsonp(callback.to_owned())
+                }
+                _ => ResponseFormat::Json,
+            },
+            _ => ResponseFormat::Xml,
+        }
+    }
+
+    /// Wrap up a reply (or an error) into a `subsonic-response`. Errors are still sent
+    /// with 200, as the spec asks.
+    pub fn render(&self, data: Result<Map<String, Value>, SubsonicError>) -> Response {
+        let mut root = Map::new();
+        root.insert(
+            "status".to_owned(),
+            json!(if data.is_ok() { "ok" } else { "failed" }),
+        );
+        root.insert("version".to_owned(), json!(API_VERSION));
+        root.insert("type".to_owned(), json!("reamio"));
+        root.insert("serverVersion".to_owned(), json!(env!("CARGO_PKG_VERSION")));
+        root.insert("openSubsonic".to_owned(), json!(true));
+        match data {
+            Ok(data) => root.extend(data),
+            Err(err) => {
+                root.insert(
+                    "error".to_owned(),
+                    json!({ "code": err.code as u32, "message": err.msg }),
+                );
+            }
+        }
+
+        // missing fields are left out, rather than being sent as null
+        let mut root = Value::Object(root);
+        strip_nulls(&mut root);
+        let Value::Object(mut root) = root else {
+            unreachable!("stripping nulls keeps the root an object")
+        };
+
+        let (content_type, body) = match self {
+            ResponseFormat::Json => (
+                "application/json",
+                json!({ "subsonic-response": root }).to_string(),
+            ),
+            ResponseFormat::Jsonp(callback) => (
+                "application/javascript",
+                format!("{callback}({});", json!({ "subsonic-response": root })),
+            ),
+            ResponseFormat::Xml => {
+                let mut out = String::from(r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+                root.insert("xmlns".to_owned(), json!("http://subsonic.org/restapi"));
+                write_element(&mut out, "subsonic-response", &Value::Object(root));
+                ("text/xml; charset=utf-8", out)
+            }
+        };
+        (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, HeaderValue::from_static(content_type))],
+            body,
+        )
+            .into_response()
+    }
+}
+
+fn strip_nulls(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            map.retain(|_, x| !x.is_null());
+            map.values_mut().for_each(strip_nulls);
+        }
+        Value::Array(items) => items.iter_mut().for_each(strip_nulls),
+        _ => (),
+    }
+}
+
+fn is_identifier(x: &str) -> bool {
+    !x.is_empty()
+        && x.chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$' || c == '.')
+}
+
+/// Write a JSON value out the way Subsonic lays out its XML: scalar fields become
+/// attributes, objects become child elements, arrays become repeated child elements
+/// and a `value` field becomes the text content.
+fn write_element(out: &mut String, name: &str, value: &Value) {
+    let Value::Object(map) = value else {
+        // a bare scalar in an array, such as a genre name
+        out.push_str(&format!("<{name}>"));
+        push_escaped(out, &scalar(value));
+        out.push_str(&format!("</{name}>"));
+        return;
+    };
+
+    out.push('<');
+    out.push_str(name);
+    for (key, value) in map {
+        if key == "value" || value.is_null() || value.is_object() || value.is_array() {
+            continue;
+        }
+        out.push_str(&format!(" {key}=\""));
+        push_escaped(out, &scalar(value));
+        out.push('"');
+    }
+
+    let text = map.get("value").filter(|x| !x.is_null());
+    let has_children = map.values().any(|x| x.is_object() || x.is_array());
+    if text.is_none() && !has_children {
+        out.push_str("/>");
+        return;
+    }
+    out.push('>');
+    if let Some(text) = text {
+        push_escaped(out, &scalar(text));
+    }
+    for (key, value) in map {
+        match value {
+            Value::Object(_) => write_element(out, key, value),
+            Value::Array(items) => {
+                for item in items {
+                    write_element(out, key, item);
+                }
+            }
+            _ => (),
+        }
+    }
+    out.push_str(&format!("</{name}>"));
+}
+
+fn scalar(value: &Value) -> String {
+    match value {
+        Value::String(x) => x.clone(),
+        Value::Null => String::new(),
+        x => x.to_string(),
+    }
+}
+
+fn push_escaped(out: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            // not allowed in XML 1.0 at all
+            c if (c as u32) < 0x20 && !matches!(c, '\t' | '\n' | '\r') => (),
+            c => out.push(c),
+        }
+    }
+}
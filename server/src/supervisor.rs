@@ -0,0 +1,218 @@
+//! Keeping the background tasks alive.
+//!
+//! Every long running task is spawned through the [[Supervisor]], which restarts it
+//! when it panics, waiting longer after each crash in a row, and keeps track of how
+//! each one is doing for [[get_health]]. On shutdown the tasks are told to stop, and
+//! waited on for a while.
+
+use axum::{Json, extract::State, http::StatusCode};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
+
+use crate::ReamioApp;
+use crate::prelude::*;
+
+/// Wait before the first restart. Doubled for every crash in a row.
+const BACKOFF_MIN: Duration = Duration::from_secs(1);
+/// Longest wait between restarts. A task that stays up for this long is taken to be
+/// healthy again, and its next crash starts over at [[BACKOFF_MIN]].
+const BACKOFF_MAX: Duration = Duration::from_secs(300);
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TaskState {
+    Running,
+    /// Crashed, and waiting to be restarted
+    Restarting,
+    /// Finished, which only happens on shutdown
+    Stopped,
+}
+
+/// How a background task is doing.
+#[derive(Serialize, Debug, Clone)]
+pub struct TaskHealth {
+    state: TaskState,
+    /// Times it crashed and was restarted since the server started
+    restarts: u32,
+    /// Unix time of the last crash
+    last_crash: Option<u64>,
+}
+
+/// Health of every supervised task, by name.
+pub type HealthMap = Arc<Mutex<BTreeMap<&'static str, TaskHealth>>>;
+
+pub struct Supervisor {
+    health: HealthMap,
+    shutdown: CancellationToken,
+    tasks: JoinSet<()>,
+}
+
+impl std::fmt::Debug for Supervisor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Supervisor")
+            .field("health", &self.health)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Supervisor {
+    pub fn new() -> Self {
+        Self {
+            health: Default::default(),
+            shutdown: CancellationToken::new(),
+            tasks: JoinSet::new(),
+        }
+    }
+
+    pub fn health(&self) -> HealthMap {
+        self.health.clone()
+    }
+
+    /// Run the task `make` returns, and again every time it panics. It is handed a
+    /// token that is cancelled on shutdown, which tasks that don't stop on their own
+    /// should watch.
+    pub fn spawn<F, Fut>(&mut self, name: &'static str, make: F)
+    where
+        F: Fn(CancellationToken) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        // listed right away, before the task gets to run
+        set_state(&self.health, name, TaskState::Running, false);
+        let health = self.health.clone();
+        let shutdown = self.shutdown.clone();
+        self.tasks.spawn(
+            supervise(name, make, health, shutdown).instrument(info_span!("supervise", name)),
+        );
+    }
+
+    /// Tell every task to stop, and wait up to `grace` for them to do so.
+    #[tracing::instrument]
+    pub async fn shutdown(mut self, grace: Duration) {
+        self.shutdown.cancel();
+        let all_stopped = tokio::time::timeout(grace, async {
+            while self.tasks.join_next().await.is_some() {}
+        })
+        .await;
+        match all_stopped {
+            Ok(()) => debug!("background tasks stopped"),
+            Err(_) => {
+                let running = self
+                    .health
+                    .lock()
+                    .expect("health lock poisoned")
+                    .iter()
+                    .filter(|(_, x)| x.state != TaskState::Stopped)
+                    .map(|(name, _)| *name)
+                    .collect::<Vec<_>>();
+                warn!(
+                    ?running,
+                    "background tasks did not stop in time, leaving them"
+                );
+            }
+        }
+    }
+}
+
+fn set_state(health: &HealthMap, name: &'static str, state: TaskState, crashed: bool) {
+    let mut health = health.lock().expect("health lock poisoned");
+    let entry = health.entry(name).or_insert(TaskHealth {
+        state,
+        restarts: 0,
+        last_crash: None,
+    });
+    entry.state = state;
+    if crashed {
+        entry.restarts += 1;
+        entry.last_crash = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .map(|x| x.as_secs());
+    }
+}
+
+/// What a task panicked with, when it's a message.
+fn panic_message(err: tokio::task::JoinError) -> String {
+    match err.try_into_panic() {
+        Ok(panic) => panic
+            .downcast_ref::<&str>()
+            .map(|x| x.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "panicked".to_owned()),
+        Err(err) => err.to_string(),
+    }
+}
+
+async fn supervise<F, Fut>(
+    name: &'static str,
+    make: F,
+    health: HealthMap,
+    shutdown: CancellationToken,
+) where
+    F: Fn(CancellationToken) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let mut backoff = BACKOFF_MIN;
+    loop {
+        set_state(&health, name, TaskState::Running, false);
+        let started = Instant::now();
+        // spawned on its own, so a panic stops at the JoinHandle
+        let ret = tokio::spawn(make(shutdown.clone()).in_current_span()).await;
+        let err = match ret {
+            Ok(()) => {
+                debug!("task finished");
+                break;
+            }
+            Err(err) => err,
+        };
+
+        error!(err = panic_message(err), "background task crashed");
+        if shutdown.is_cancelled() {
+            break;
+        }
+        if started.elapsed() >= BACKOFF_MAX {
+            backoff = BACKOFF_MIN;
+        }
+        set_state(&health, name, TaskState::Restarting, true);
+        info!(?backoff, "restarting background task");
+        tokio::select! {
+            _ = tokio::time::sleep(backoff) => {}
+            _ = shutdown.cancelled() => break,
+        }
+        backoff = (backoff * 2).min(BACKOFF_MAX);
+    }
+    set_state(&health, name, TaskState::Stopped, false);
+}
+
+#[derive(Serialize, Debug)]
+pub struct HealthReturn {
+    /// `ok` when every task is running, `degraded` otherwise
+    status: &'static str,
+    tasks: BTreeMap<&'static str, TaskHealth>,
+}
+
+/// Health of the server's background tasks. Answers with 503 when any of them
+/// isn't running. Needs no session, so it can be used as a liveness probe.
+///
+/// Path: GET /api/health
+///
+/// Arguments:
+/// - State(state): State<ReamioApp>
+///   Server state. [[ReamioApp]].
+#[tracing::instrument]
+pub async fn get_health(State(state): State<ReamioApp>) -> (StatusCode, Json<HealthReturn>) {
+    let tasks = state.health.lock().expect("health lock poisoned").clone();
+    let ok = tasks.values().all(|x| x.state == TaskState::Running);
+    trace!(ok, ?tasks);
+    let (code, status) = if ok {
+        (StatusCode::OK, "ok")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "degraded")
+    };
+    (code, Json(HealthReturn { status, tasks }))
+}
@@ -0,0 +1,1147 @@
+//! Reading tags out of uploaded files.
+//!
+//! Every [[TagReader]] translates its format's fields into [[TagFields]], named
+//! after the Vorbis comment fields most taggers write (`tracknumber`,
+//! `musicbrainz_albumid` and so on), which [[TrackMetadata::from_fields]] then
+//! interprets the same way no matter where they came from. Readers also hand back
+//! every frame or comment as it was in the file, see [[FileTags]].
+
+use id3::TagLike;
+use std::{collections::HashMap, path::Path};
+use symphonia::core::{
+    formats::FormatOptions,
+    io::MediaSourceStream,
+    meta::{MetadataOptions, StandardTagKey, StandardVisualKey, Tag, Value},
+    probe::Hint,
+};
+
+use crate::prelude::*;
+
+/// Tag values by lowercase Vorbis comment field name. Fields can repeat.
+pub type TagFields = HashMap<String, Vec<String>>;
+
+/// Everything read from a file's tags.
+#[derive(Debug, Default)]
+pub struct FileTags {
+    pub fields: TagFields,
+    /// Every textual frame or comment, keyed as named in the file. Stored in
+    /// `track_tag` as is.
+    pub raw: Vec<(String, String)>,
+    /// Embedded images, in the order they were found
+    pub pictures: Vec<Picture>,
+}
+
+impl FileTags {
+    /// The picture to use as cover art, which is the front cover if the tags say
+    /// which one that is, and otherwise the first.
+    pub fn cover(&self) -> Option<&Picture> {
+        self.pictures
+            .iter()
+            .find(|x| x.front)
+            .or_else(|| self.pictures.first())
+    }
+}
+
+/// An image embedded in a file's tags.
+pub struct Picture {
+    /// Whether the tags call it the front cover
+    pub front: bool,
+    pub data: Vec<u8>,
+}
+
+impl std::fmt::Debug for Picture {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // the data would drown out everything else in the logs
+        f.debug_struct("Picture")
+            .field("front", &self.front)
+            .field("len", &self.data.len())
+            .finish()
+    }
+}
+
+/// MusicBrainz identifiers, as tagged by Picard and friends.
+#[derive(Debug, Default, Clone)]
+pub struct MusicBrainzIds {
+    /// Recording id
+    pub track: Option<String>,
+    pub release_track: Option<String>,
+    /// Release id
+    pub album: Option<String>,
+    pub release_group: Option<String>,
+    /// Lines up with [[TrackMetadata::artists]]
+    pub artists: Vec<String>,
+    /// Lines up with [[TrackMetadata::album_artists]]
+    pub album_artists: Vec<String>,
+}
+
+/// What is known about a track from its tags.
+#[derive(Debug, Default, Clone)]
+pub struct TrackMetadata {
+    pub title: Option<String>,
+    pub artists: Vec<String>,
+    pub album: Option<String>,
+    pub album_artists: Vec<String>,
+    pub composers: Vec<String>,
+    pub performers: Vec<String>,
+    pub track_number: Option<i64>,
+    pub track_total: Option<i64>,
+    pub disc_number: Option<i64>,
+    pub disc_total: Option<i64>,
+    /// As tagged, usually `YYYY` or `YYYY-MM-DD`
+    pub date: Option<String>,
+    pub original_date: Option<String>,
+    pub genres: Vec<String>,
+    pub comment: Option<String>,
+    pub title_sort: Option<String>,
+    /// Lines up with [[TrackMetadata::artists]]
+    pub artist_sorts: Vec<String>,
+    pub album_sort: Option<String>,
+    /// Lines up with [[TrackMetadata::album_artists]]
+    pub album_artist_sorts: Vec<String>,
+    /// Whether the album is a compilation of various artists
+    pub compilation: bool,
+    pub musicbrainz: MusicBrainzIds,
+}
+
+/// Leading number of a number tag like "3" or "3/12", along with the total after the
+/// '/' if there is one.
+fn parse_number_pair(value: &str) -> (Option<i64>, Option<i64>) {
+    let (number, total) = match value.split_once('/') {
+        Some((number, total)) => (number, Some(total)),
+        None => (value, None),
+    };
+    (
+        number.trim().parse().ok(),
+        total.and_then(|x| x.trim().parse().ok()),
+    )
+}
+
+/// The year at the start of a date like "2001" or "2001-05-03".
+pub fn year_of(date: &str) -> Option<i64> {
+    let year = date.trim().get(..4)?;
+    year.chars()
+        .all(|c| c.is_ascii_digit())
+        .then(|| year.parse().ok())
+        .flatten()
+}
+
+impl TrackMetadata {
+    /// Interpret tag fields. Empty values are ignored, and single valued fields take
+    /// the first value.
+    pub fn from_fields(fields: &TagFields) -> Self {
+        let all = |key: &str| {
+            fields
+                .get(key)
+                .map(|x| {
+                    x.iter()
+                        .map(|x| x.trim())
+                        .filter(|x| !x.is_empty())
+                        .map(str::to_owned)
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default()
+        };
+        let first = |key: &str| all(key).into_iter().next();
+        let first_of = |keys: &[&str]| keys.iter().find_map(|x| first(x));
+
+        let (track_number, track_total) = first("tracknumber")
+            .map(|x| parse_number_pair(&x))
+            .unwrap_or_default();
+        let (disc_number, disc_total) = first("discnumber")
+            .map(|x| parse_number_pair(&x))
+            .unwrap_or_default();
+        let number = |keys: &[&str]| first_of(keys).and_then(|x| x.parse().ok());
+
+        Self {
+            title: first("title"),
+            artists: all("artist"),
+            album: first("album"),
+            album_artists: Some(all("albumartist"))
+                .filter(|x| !x.is_empty())
+                .unwrap_or_else(|| all("album artist")),
+            composers: all("composer"),
+            performers: all("performer"),
+            track_number,
+            track_total: track_total.or_else(|| number(&["tracktotal", "totaltracks"])),
+            disc_number,
+            disc_total: disc_total.or_else(|| number(&["disctotal", "totaldiscs"])),
+            date: first_of(&["date", "year"]),
+            original_date: first_of(&["originaldate", "originalyear"]),
+            genres: all("genre"),
+            comment: first_of(&["comment", "description"]),
+            title_sort: first("titlesort"),
+            artist_sorts: all("artistsort"),
+            album_sort: first("albumsort"),
+            album_artist_sorts: all("albumartistsort"),
+            compilation: first("compilation").is_some_and(|x| x != "0"),
+            musicbrainz: MusicBrainzIds {
+                track: first("musicbrainz_trackid"),
+                release_track: first("musicbrainz_releasetrackid"),
+                album: first("musicbrainz_albumid"),
+                release_group: first("musicbrainz_releasegroupid"),
+                artists: all("musicbrainz_artistid"),
+                album_artists: all("musicbrainz_albumartistid"),
+            },
+        }
+    }
+}
+
+/// Read the tags of an uploaded file, trying each reader in turn. Files none of them
+/// understand have no tags.
+#[tracing::instrument]
+pub fn extract_tags(path: &Path) -> Result<FileTags, ReamioProcessingErrorInternal> {
+    // APE goes last, as it is looked for at the end of files the others might know
+    let readers: Vec<Box<dyn TagReader>> = vec![
+        Box::new(ID3TagReader),
+        Box::new(MetaFlacTagReader),
+        Box::new(Mp4TagReader),
+        Box::new(OggTagReader),
+        Box::new(RiffTagReader),
+        Box::new(ApeTagReader),
+    ];
+    for reader in readers {
+        match reader.is_candidate(path)? {
+            Some(x) if x => return reader.tags_parse(path),
+            Some(_) => continue,
+            None => {
+                // unsupported is_candidate
+                if let Ok(map) = reader.tags_parse(path) {
+                    return Ok(map);
+                }
+            }
+        }
+    }
+
+    Ok(FileTags::default())
+}
+
+trait TagReader {
+    fn is_candidate(&self, path: &Path) -> Result<Option<bool>, ReamioProcessingErrorInternal>;
+
+    fn tags_parse(&self, path: &Path) -> Result<FileTags, ReamioProcessingErrorInternal>;
+}
+
+/// ID3TagReader reads the tags from "MPEG" files starting with an ID3v2 tag.
+#[derive(Debug)]
+struct ID3TagReader;
+
+/// Text frames and the field they hold, for frames needing no further parsing.
+const ID3_TEXT_FRAMES: [(&str, &str); 16] = [
+    ("TIT2", "title"),
+    ("TPE1", "artist"),
+    ("TALB", "album"),
+    ("TPE2", "albumartist"),
+    ("TCOM", "composer"),
+    ("TRCK", "tracknumber"),
+    ("TPOS", "discnumber"),
+    // ID3v2.3 only has a year, v2.4 has a full date
+    ("TYER", "year"),
+    ("TDRC", "date"),
+    ("TORY", "originalyear"),
+    ("TDOR", "originaldate"),
+    ("TSOT", "titlesort"),
+    ("TSOP", "artistsort"),
+    ("TSOA", "albumsort"),
+    ("TSO2", "albumartistsort"),
+    ("TCMP", "compilation"),
+];
+
+/// `TXXX` descriptions Picard uses, and the field they hold.
+const ID3_TXXX_FIELDS: [(&str, &str); 5] = [
+    ("MusicBrainz Release Track Id", "musicbrainz_releasetrackid"),
+    ("MusicBrainz Album Id", "musicbrainz_albumid"),
+    ("MusicBrainz Release Group Id", "musicbrainz_releasegroupid"),
+    ("MusicBrainz Artist Id", "musicbrainz_artistid"),
+    ("MusicBrainz Album Artist Id", "musicbrainz_albumartistid"),
+];
+
+impl TagReader for ID3TagReader {
+    #[tracing::instrument]
+    fn is_candidate(&self, path: &Path) -> Result<Option<bool>, ReamioProcessingErrorInternal> {
+        let file = std::fs::File::open(path)?;
+        id3::Tag::is_candidate(file)
+            .map(Some)
+            .map_err(ReamioProcessingErrorInternal::from)
+    }
+
+    #[tracing::instrument]
+    fn tags_parse(&self, path: &Path) -> Result<FileTags, ReamioProcessingErrorInternal> {
+        let tag = id3::Tag::read_from_path(path)?;
+        Ok(id3_tags(&tag))
+    }
+}
+
+/// Fields and raw frames of an ID3 tag, wherever in the file it was found.
+fn id3_tags(tag: &id3::Tag) -> FileTags {
+    let mut hmap = TagFields::new();
+
+    // text frames hold several values when they're null separated
+    for (frame, key) in ID3_TEXT_FRAMES {
+        if let Some(x) = tag.get(frame).and_then(|x| x.content().text_values()) {
+            hmap.entry(key.to_owned())
+                .or_default()
+                .extend(x.map(str::to_owned));
+        }
+    }
+    // a lone genre might be an ID3v1 genre number, like "(17)"
+    match tag.genres() {
+        Some(x) if x.len() > 1 => {
+            hmap.insert(
+                "genre".to_owned(),
+                x.into_iter().map(str::to_owned).collect(),
+            );
+        }
+        _ => {
+            if let Some(x) = tag.genre_parsed() {
+                hmap.insert("genre".to_owned(), vec![x.into_owned()]);
+            }
+        }
+    }
+    if let Some(x) = tag
+        .get("TMCL")
+        .and_then(|x| x.content().involved_people_list())
+    {
+        hmap.insert(
+            "performer".to_owned(),
+            x.items.iter().map(|x| x.involvee.clone()).collect(),
+        );
+    }
+    if let Some(x) = tag.comments().find(|x| x.description.is_empty()) {
+        hmap.insert("comment".to_owned(), vec![x.text.clone()]);
+    }
+    for txxx in tag.extended_texts() {
+        if let Some((_, key)) = ID3_TXXX_FIELDS
+            .iter()
+            .find(|(desc, _)| txxx.description.eq_ignore_ascii_case(desc))
+        {
+            // multiple ids are separated the same way as text frames
+            hmap.entry((*key).to_owned())
+                .or_default()
+                .extend(txxx.value.split('\0').map(str::to_owned));
+        }
+    }
+    if let Some(x) = tag
+        .unique_file_identifiers()
+        .find(|x| x.owner_identifier == "http://musicbrainz.org")
+    {
+        hmap.insert(
+            "musicbrainz_trackid".to_owned(),
+            vec![String::from_utf8_lossy(&x.identifier).into_owned()],
+        );
+    }
+    FileTags {
+        fields: hmap,
+        raw: id3_raw(tag),
+        pictures: tag
+            .pictures()
+            .map(|x| Picture {
+                front: x.picture_type == id3::frame::PictureType::CoverFront,
+                data: x.data.clone(),
+            })
+            .collect(),
+    }
+}
+
+/// Every textual frame of an ID3 tag. Frames that can appear more than once with
+/// different descriptions are keyed like `TXXX:description`, and binary ones (such
+/// as pictures, which are dealt with elsewhere) are left out.
+fn id3_raw(tag: &id3::Tag) -> Vec<(String, String)> {
+    use id3::Content;
+
+    let mut raw = vec![];
+    for frame in tag.frames() {
+        let id = frame.id();
+        match frame.content() {
+            Content::Text(_) => {
+                let values = frame.content().text_values().into_iter().flatten();
+                raw.extend(values.map(|x| (id.to_owned(), x.to_owned())));
+            }
+            Content::ExtendedText(x) => {
+                raw.push((format!("{id}:{}", x.description), x.value.clone()))
+            }
+            Content::Comment(x) if x.description.is_empty() => {
+                raw.push((id.to_owned(), x.text.clone()))
+            }
+            Content::Comment(x) => raw.push((format!("{id}:{}", x.description), x.text.clone())),
+            Content::Lyrics(x) => raw.push((id.to_owned(), x.text.clone())),
+            Content::Link(x) => raw.push((id.to_owned(), x.clone())),
+            Content::ExtendedLink(x) => {
+                raw.push((format!("{id}:{}", x.description), x.link.clone()))
+            }
+            Content::UniqueFileIdentifier(x) => raw.push((
+                format!("{id}:{}", x.owner_identifier),
+                String::from_utf8_lossy(&x.identifier).into_owned(),
+            )),
+            Content::InvolvedPeopleList(x) => raw.extend(
+                x.items
+                    .iter()
+                    .map(|x| (format!("{id}:{}", x.involvement), x.involvee.clone())),
+            ),
+            Content::Popularimeter(x) => {
+                raw.push((format!("{id}:{}", x.user), x.rating.to_string()))
+            }
+            _ => trace!(id, "skipping binary frame"),
+        }
+    }
+    raw
+}
+
+/// MetaFlacTagReader reads the vorbis comments of flac files
+#[derive(Debug)]
+struct MetaFlacTagReader;
+
+impl TagReader for MetaFlacTagReader {
+    #[tracing::instrument]
+    fn is_candidate(&self, path: &Path) -> Result<Option<bool>, ReamioProcessingErrorInternal> {
+        let mut file = std::fs::File::open(path)?;
+        Ok(Some(metaflac::Tag::is_candidate(&mut file)))
+    }
+
+    #[tracing::instrument]
+    fn tags_parse(&self, path: &Path) -> Result<FileTags, ReamioProcessingErrorInternal> {
+        let tag = metaflac::Tag::read_from_path(path)?;
+        let mut hmap = TagFields::new();
+        let mut raw = vec![];
+        for block in tag.get_blocks(metaflac::BlockType::VorbisComment) {
+            let metaflac::Block::VorbisComment(vc) = block else {
+                // wtf happened here
+                continue;
+            };
+
+            // comments already are the fields, and can repeat for multiple values
+            for (key, values) in &vc.comments {
+                hmap.entry(key.to_lowercase())
+                    .or_default()
+                    .extend(values.iter().cloned());
+                raw.extend(values.iter().map(|x| (key.clone(), x.clone())));
+            }
+        }
+        let pictures = tag
+            .pictures()
+            .map(|x| Picture {
+                front: x.picture_type == metaflac::block::PictureType::CoverFront,
+                data: x.data.clone(),
+            })
+            .collect();
+        Ok(FileTags {
+            fields: hmap,
+            raw,
+            pictures,
+        })
+    }
+}
+
+/// The first `len` bytes of a file, or fewer if it's shorter.
+fn file_magic(path: &Path, len: u64) -> Result<Vec<u8>, ReamioProcessingErrorInternal> {
+    use std::io::Read;
+
+    let mut buf = vec![];
+    std::fs::File::open(path)?.take(len).read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+/// Every tag and picture symphonia finds in a file's container, from its latest
+/// metadata revision.
+fn symphonia_tags(path: &Path) -> Result<(Vec<Tag>, Vec<Picture>), ReamioProcessingErrorInternal> {
+    let file = std::fs::File::open(path)?;
+    let mss = MediaSourceStream::new(Box::new(file), Default::default());
+    let mut probed = symphonia::default::get_probe().format(
+        &Hint::new(),
+        mss,
+        &FormatOptions::default(),
+        &MetadataOptions::default(),
+    )?;
+    let mut metadata = probed.format.metadata();
+    metadata.skip_to_latest();
+    let Some(revision) = metadata.current() else {
+        return Ok(Default::default());
+    };
+    let pictures = revision
+        .visuals()
+        .iter()
+        .map(|x| Picture {
+            front: x.usage == Some(StandardVisualKey::FrontCover),
+            data: x.data.to_vec(),
+        })
+        .collect();
+    Ok((revision.tags().to_vec(), pictures))
+}
+
+/// Standard tag keys and the field they hold, for MP4 atoms.
+const STANDARD_TAG_FIELDS: [(StandardTagKey, &str); 27] = [
+    (StandardTagKey::TrackTitle, "title"),
+    (StandardTagKey::Artist, "artist"),
+    (StandardTagKey::Album, "album"),
+    (StandardTagKey::AlbumArtist, "albumartist"),
+    (StandardTagKey::Composer, "composer"),
+    (StandardTagKey::Performer, "performer"),
+    (StandardTagKey::TrackNumber, "tracknumber"),
+    (StandardTagKey::TrackTotal, "tracktotal"),
+    (StandardTagKey::DiscNumber, "discnumber"),
+    (StandardTagKey::DiscTotal, "disctotal"),
+    (StandardTagKey::Date, "date"),
+    (StandardTagKey::OriginalDate, "originaldate"),
+    (StandardTagKey::Genre, "genre"),
+    (StandardTagKey::Comment, "comment"),
+    (StandardTagKey::Description, "description"),
+    (StandardTagKey::SortTrackTitle, "titlesort"),
+    (StandardTagKey::SortArtist, "artistsort"),
+    (StandardTagKey::SortAlbum, "albumsort"),
+    (StandardTagKey::SortAlbumArtist, "albumartistsort"),
+    (StandardTagKey::Compilation, "compilation"),
+    (StandardTagKey::MusicBrainzTrackId, "musicbrainz_trackid"),
+    (
+        StandardTagKey::MusicBrainzRecordingId,
+        "musicbrainz_trackid",
+    ),
+    (
+        StandardTagKey::MusicBrainzReleaseTrackId,
+        "musicbrainz_releasetrackid",
+    ),
+    (StandardTagKey::MusicBrainzAlbumId, "musicbrainz_albumid"),
+    (
+        StandardTagKey::MusicBrainzReleaseGroupId,
+        "musicbrainz_releasegroupid",
+    ),
+    (StandardTagKey::MusicBrainzArtistId, "musicbrainz_artistid"),
+    (
+        StandardTagKey::MusicBrainzAlbumArtistId,
+        "musicbrainz_albumartistid",
+    ),
+];
+
+/// A tag's value as text, or nothing for binary ones like cover art.
+fn symphonia_value(value: &Value) -> Option<String> {
+    match value {
+        Value::Binary(_) => None,
+        // MP4 only writes flags like `cpil` when they're set
+        Value::Flag => Some("1".to_owned()),
+        Value::Boolean(x) => Some(u8::from(*x).to_string()),
+        x => Some(x.to_string()),
+    }
+}
+
+/// Fields and raw tags from symphonia tags, by their standard key. Raw tags without a
+/// key of their own (the standard atoms) are named after the standard key.
+fn standard_tags(tags: &[Tag]) -> FileTags {
+    let mut out = FileTags::default();
+    for tag in tags {
+        let Some(value) = symphonia_value(&tag.value) else {
+            continue;
+        };
+        let field = tag
+            .std_key
+            .and_then(|key| STANDARD_TAG_FIELDS.iter().find(|(x, _)| *x == key));
+        if let Some((_, field)) = field {
+            out.fields
+                .entry((*field).to_owned())
+                .or_default()
+                .push(value.clone());
+        }
+        let key = match (&tag.std_key, tag.key.is_empty()) {
+            (Some(key), true) => format!("{key:?}"),
+            _ => tag.key.clone(),
+        };
+        out.raw.push((key, value));
+    }
+    out
+}
+
+/// Mp4TagReader reads the `ilst` atoms of MP4 files (m4a, alac, aac).
+#[derive(Debug)]
+struct Mp4TagReader;
+
+impl TagReader for Mp4TagReader {
+    #[tracing::instrument]
+    fn is_candidate(&self, path: &Path) -> Result<Option<bool>, ReamioProcessingErrorInternal> {
+        let magic = file_magic(path, 8)?;
+        Ok(Some(magic.get(4..8) == Some(b"ftyp")))
+    }
+
+    #[tracing::instrument]
+    fn tags_parse(&self, path: &Path) -> Result<FileTags, ReamioProcessingErrorInternal> {
+        let (tags, pictures) = symphonia_tags(path)?;
+        Ok(FileTags {
+            pictures,
+            ..standard_tags(&tags)
+        })
+    }
+}
+
+/// OggTagReader reads the comment header of Ogg Vorbis and Opus streams.
+#[derive(Debug)]
+struct OggTagReader;
+
+impl TagReader for OggTagReader {
+    #[tracing::instrument]
+    fn is_candidate(&self, path: &Path) -> Result<Option<bool>, ReamioProcessingErrorInternal> {
+        Ok(Some(file_magic(path, 4)? == b"OggS"))
+    }
+
+    #[tracing::instrument]
+    fn tags_parse(&self, path: &Path) -> Result<FileTags, ReamioProcessingErrorInternal> {
+        // like flac, the comment names already are the fields
+        let (tags, pictures) = symphonia_tags(path)?;
+        let mut out = FileTags {
+            pictures,
+            ..Default::default()
+        };
+        for tag in tags {
+            let Some(value) = symphonia_value(&tag.value) else {
+                continue;
+            };
+            out.fields
+                .entry(tag.key.to_lowercase())
+                .or_default()
+                .push(value.clone());
+            out.raw.push((tag.key, value));
+        }
+        Ok(out)
+    }
+}
+
+/// RiffTagReader reads the tags of WAV and AIFF files. An ID3 chunk wins over WAV's
+/// own `LIST`/`INFO` chunk, since it can hold a lot more.
+#[derive(Debug)]
+struct RiffTagReader;
+
+impl RiffTagReader {
+    /// Whether the file is WAV (`Some(false)`) or AIFF (`Some(true)`).
+    fn is_aiff(path: &Path) -> Result<Option<bool>, ReamioProcessingErrorInternal> {
+        let magic = file_magic(path, 12)?;
+        Ok(match (magic.get(..4), magic.get(8..12)) {
+            (Some(b"RIFF"), Some(b"WAVE")) => Some(false),
+            (Some(b"FORM"), Some(b"AIFF" | b"AIFC")) => Some(true),
+            _ => None,
+        })
+    }
+
+    /// Fields and raw chunks of a WAV file's `LIST`/`INFO` chunk. It can come before
+    /// or after the audio, so every top level chunk is looked at.
+    fn info_tags(path: &Path) -> Result<FileTags, ReamioProcessingErrorInternal> {
+        use std::io::{Read, Seek, SeekFrom};
+
+        let mut file = std::io::BufReader::new(std::fs::File::open(path)?);
+        let len = file.get_ref().metadata()?.len();
+        let mut out = FileTags::default();
+        let mut at = 12;
+        while at + 8 <= len {
+            let mut header = [0; 8];
+            file.seek(SeekFrom::Start(at))?;
+            file.read_exact(&mut header)?;
+            let size = u32::from_le_bytes(header[4..].try_into().unwrap()) as u64;
+            let start = at + 8;
+            // chunks are padded to an even size
+            at = start + size + size % 2;
+            if &header[..4] != b"LIST" || size < 4 {
+                continue;
+            }
+            let mut list = vec![0; size.min(len - start) as usize];
+            file.read_exact(&mut list)?;
+            let Some(mut info) = list.strip_prefix(b"INFO") else {
+                continue;
+            };
+            while info.len() >= 8 {
+                let id = String::from_utf8_lossy(&info[..4]).into_owned();
+                let size = u32::from_le_bytes(info[4..8].try_into().unwrap()) as usize;
+                let Some(value) = info.get(8..8 + size) else {
+                    break;
+                };
+                info = info.get(8 + size + size % 2..).unwrap_or_default();
+
+                let value = String::from_utf8_lossy(value)
+                    .trim_end_matches('\0')
+                    .to_owned();
+                if let Some((_, field)) = RIFF_INFO_FIELDS.iter().find(|(x, _)| *x == id) {
+                    out.fields
+                        .entry((*field).to_owned())
+                        .or_default()
+                        .push(value.clone());
+                }
+                out.raw.push((id, value));
+            }
+        }
+        Ok(out)
+    }
+}
+
+/// `INFO` chunk ids and the field they hold.
+const RIFF_INFO_FIELDS: [(&str, &str); 11] = [
+    ("INAM", "title"),
+    ("IART", "artist"),
+    ("IPRD", "album"),
+    ("IMUS", "composer"),
+    ("ITRK", "tracknumber"),
+    ("IPRT", "tracknumber"),
+    ("IFRM", "tracktotal"),
+    ("ICRD", "date"),
+    ("IGNR", "genre"),
+    ("ICMT", "comment"),
+    ("ISGN", "genre"),
+];
+
+impl TagReader for RiffTagReader {
+    #[tracing::instrument]
+    fn is_candidate(&self, path: &Path) -> Result<Option<bool>, ReamioProcessingErrorInternal> {
+        Ok(Some(Self::is_aiff(path)?.is_some()))
+    }
+
+    #[tracing::instrument]
+    fn tags_parse(&self, path: &Path) -> Result<FileTags, ReamioProcessingErrorInternal> {
+        // the id3 crate finds the chunk on its own
+        if let Some(tag) = id3::no_tag_ok(id3::Tag::read_from_path(path))? {
+            return Ok(id3_tags(&tag));
+        }
+        if Self::is_aiff(path)? == Some(true) {
+            // the NAME/AUTH/ANNO chunks are too rarely used to bother
+            return Ok(FileTags::default());
+        }
+        Self::info_tags(path)
+    }
+}
+
+/// ApeTagReader reads APEv2 tags, found at the end of Monkey's Audio, Musepack and
+/// WavPack files, and sometimes mp3s.
+#[derive(Debug)]
+struct ApeTagReader;
+
+/// APE item keys differing from the Vorbis field they hold. Keys are compared in
+/// lowercase, anything else is taken as is.
+const APE_FIELDS: [(&str, &str); 3] = [
+    ("track", "tracknumber"),
+    ("disc", "discnumber"),
+    ("year", "date"),
+];
+
+impl ApeTagReader {
+    /// Offset and contents of the 32 byte APE footer, which is either at the very end
+    /// of the file or right before an ID3v1 tag.
+    fn footer(path: &Path) -> Result<Option<(u64, [u8; 32])>, ReamioProcessingErrorInternal> {
+        use std::io::{Read, Seek, SeekFrom};
+
+        let mut file = std::fs::File::open(path)?;
+        let len = file.metadata()?.len();
+        for end in [len, len.saturating_sub(128)] {
+            if end < 32 {
+                continue;
+            }
+            let mut footer = [0; 32];
+            file.seek(SeekFrom::Start(end - 32))?;
+            file.read_exact(&mut footer)?;
+            if footer.starts_with(b"APETAGEX") {
+                return Ok(Some((end - 32, footer)));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl TagReader for ApeTagReader {
+    #[tracing::instrument]
+    fn is_candidate(&self, path: &Path) -> Result<Option<bool>, ReamioProcessingErrorInternal> {
+        Ok(Some(Self::footer(path)?.is_some()))
+    }
+
+    #[tracing::instrument]
+    fn tags_parse(&self, path: &Path) -> Result<FileTags, ReamioProcessingErrorInternal> {
+        use std::io::{Read, Seek, SeekFrom};
+
+        let Some((offset, footer)) = Self::footer(path)? else {
+            return Ok(FileTags::default());
+        };
+        let u32_at = |buf: &[u8], at: usize| {
+            buf.get(at..at + 4)
+                .map(|x| u32::from_le_bytes(x.try_into().unwrap()))
+        };
+        // the size counts the items and the footer, but not the optional header
+        let size = u32_at(&footer, 12).unwrap_or(0) as u64;
+        let count = u32_at(&footer, 16).unwrap_or(0);
+        let items_len = size.saturating_sub(32).min(offset);
+
+        let mut file = std::fs::File::open(path)?;
+        let mut items = vec![0; items_len as usize];
+        file.seek(SeekFrom::Start(offset - items_len))?;
+        file.read_exact(&mut items)?;
+
+        let mut out = FileTags::default();
+        let mut at = 0;
+        for _ in 0..count {
+            let (Some(len), Some(flags)) = (u32_at(&items, at), u32_at(&items, at + 4)) else {
+                break;
+            };
+            let key_start = at + 8;
+            let Some(key_len) = items[key_start.min(items.len())..]
+                .iter()
+                .position(|x| *x == 0)
+            else {
+                break;
+            };
+            let key = String::from_utf8_lossy(&items[key_start..key_start + key_len]);
+            let value_start = key_start + key_len + 1;
+            let Some(value) = items.get(value_start..value_start + len as usize) else {
+                break;
+            };
+            at = value_start + len as usize;
+
+            // bits 1-2 tell text (0) from binary (1) and external links (2)
+            if (flags >> 1) & 3 == 1 {
+                // pictures are named like "Cover Art (Front)", with the image after
+                // its file name
+                if key.to_lowercase().starts_with("cover art")
+                    && let Some(nul) = value.iter().position(|x| *x == 0)
+                {
+                    out.pictures.push(Picture {
+                        front: key.eq_ignore_ascii_case("cover art (front)"),
+                        data: value[nul + 1..].to_vec(),
+                    });
+                } else {
+                    trace!(%key, "skipping binary item");
+                }
+                continue;
+            }
+            let lower = key.to_lowercase();
+            let field = APE_FIELDS
+                .iter()
+                .find(|(x, _)| *x == lower)
+                .map_or(lower.clone(), |(_, x)| (*x).to_owned());
+            // multiple values are null separated
+            for value in String::from_utf8_lossy(value).split('\0') {
+                out.fields
+                    .entry(field.clone())
+                    .or_default()
+                    .push(value.to_owned());
+                out.raw.push((key.clone().into_owned(), value.to_owned()));
+            }
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// The images embedded in the fixtures, which don't need to be real ones.
+    const FRONT: &[u8] = b"\x89PNG\r\n\x1a\nfront cover";
+    const BACK: &[u8] = b"\x89PNG\r\n\x1a\nback cover";
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures")
+            .join(name)
+    }
+
+    /// A copy of a fixture with changes made to it, removed once dropped.
+    struct Scratch(PathBuf);
+
+    impl Scratch {
+        fn new(test: &str, data: &[u8]) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("reamio-tags-{}-{test}", std::process::id()));
+            std::fs::write(&path, data).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            drop(std::fs::remove_file(&self.0));
+        }
+    }
+
+    /// Tags of a fixture read by `reader`, which has to claim the file first.
+    fn read(reader: impl TagReader, path: &Path) -> FileTags {
+        assert_eq!(reader.is_candidate(path).unwrap(), Some(true), "{path:?}");
+        let tags = reader.tags_parse(path).unwrap();
+        // whichever reader it takes, it has to be this one
+        assert_eq!(extract_tags(path).unwrap().raw, tags.raw);
+        tags
+    }
+
+    fn fields(tags: &FileTags) -> Vec<(&str, Vec<&str>)> {
+        let mut out = tags
+            .fields
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.iter().map(String::as_str).collect()))
+            .collect::<Vec<_>>();
+        out.sort();
+        out
+    }
+
+    fn raw(tags: &FileTags) -> Vec<(&str, &str)> {
+        tags.raw
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect()
+    }
+
+    fn pictures(tags: &FileTags) -> Vec<(bool, &[u8])> {
+        tags.pictures
+            .iter()
+            .map(|x| (x.front, x.data.as_slice()))
+            .collect()
+    }
+
+    #[test]
+    fn mp4() {
+        let tags = read(Mp4TagReader, &fixture("tags.m4a"));
+        assert_eq!(
+            fields(&tags),
+            [
+                ("album", vec!["M4A Album"]),
+                ("albumartist", vec!["M4A Album Artist"]),
+                ("artist", vec!["M4A Artist"]),
+                ("date", vec!["2001-02-03"]),
+                (
+                    "musicbrainz_albumid",
+                    vec!["0d1d8b2e-0000-4000-8000-000000000001"]
+                ),
+                ("title", vec!["M4A Title"]),
+                ("tracknumber", vec!["3"]),
+                ("tracktotal", vec!["12"]),
+            ]
+        );
+        assert_eq!(
+            raw(&tags),
+            [
+                ("TrackTitle", "M4A Title"),
+                ("Artist", "M4A Artist"),
+                ("AlbumArtist", "M4A Album Artist"),
+                ("Album", "M4A Album"),
+                ("Date", "2001-02-03"),
+                ("TrackNumber", "3"),
+                ("TrackTotal", "12"),
+                (
+                    "com.apple.iTunes:MusicBrainz Album Id",
+                    "0d1d8b2e-0000-4000-8000-000000000001"
+                ),
+            ]
+        );
+        assert_eq!(pictures(&tags), [(true, FRONT)]);
+    }
+
+    #[test]
+    fn ogg_vorbis() {
+        let tags = read(OggTagReader, &fixture("tags.ogg"));
+        assert_eq!(
+            fields(&tags),
+            [
+                ("album", vec!["Vorbis Album"]),
+                ("artist", vec!["Vorbis Artist"]),
+                ("date", vec!["2010"]),
+                ("genre", vec!["Rock", "Jazz"]),
+                ("title", vec!["Vorbis Title"]),
+            ]
+        );
+        // keyed as they were written
+        assert_eq!(
+            raw(&tags),
+            [
+                ("TITLE", "Vorbis Title"),
+                ("ARTIST", "Vorbis Artist"),
+                ("album", "Vorbis Album"),
+                ("GENRE", "Rock"),
+                ("GENRE", "Jazz"),
+                ("DATE", "2010"),
+            ]
+        );
+        assert!(tags.pictures.is_empty());
+    }
+
+    #[test]
+    fn ogg_opus() {
+        let tags = read(OggTagReader, &fixture("tags.opus"));
+        assert_eq!(
+            fields(&tags),
+            [
+                ("album", vec!["Opus Album"]),
+                ("artist", vec!["Opus Artist One", "Opus Artist Two"]),
+                ("title", vec!["Opus Title"]),
+                ("tracknumber", vec!["7"]),
+            ]
+        );
+        // the METADATA_BLOCK_PICTURE comments become pictures, and nothing else
+        assert_eq!(
+            raw(&tags),
+            [
+                ("TITLE", "Opus Title"),
+                ("ARTIST", "Opus Artist One"),
+                ("ARTIST", "Opus Artist Two"),
+                ("Album", "Opus Album"),
+                ("TRACKNUMBER", "7"),
+            ]
+        );
+        assert_eq!(pictures(&tags), [(false, BACK), (true, FRONT)]);
+        assert_eq!(tags.cover().unwrap().data, FRONT);
+    }
+
+    #[test]
+    fn wav_info() {
+        let tags = read(RiffTagReader, &fixture("info.wav"));
+        assert_eq!(
+            fields(&tags),
+            [
+                ("album", vec!["Info Album"]),
+                ("artist", vec!["Info Artist"]),
+                ("date", vec!["1999"]),
+                ("title", vec!["Info Title"]),
+                ("tracknumber", vec!["4"]),
+            ]
+        );
+        // ISFT isn't a field, but it's kept
+        assert_eq!(
+            raw(&tags),
+            [
+                ("INAM", "Info Title"),
+                ("IART", "Info Artist"),
+                ("IPRD", "Info Album"),
+                ("ITRK", "4"),
+                ("ICRD", "1999"),
+                ("ISFT", "fixture"),
+            ]
+        );
+        assert!(tags.pictures.is_empty());
+    }
+
+    #[test]
+    fn wav_info_cut_short() {
+        // whatever the file ends in the middle of, none of the sizes in it can make
+        // reading it go out of bounds
+        let data = std::fs::read(fixture("info.wav")).unwrap();
+        for len in 0..data.len() {
+            let file = Scratch::new(&format!("cut-{len}"), &data[..len]);
+            let tags = RiffTagReader::info_tags(&file.0).unwrap();
+            assert!(tags.raw.len() <= 6, "{len}");
+        }
+
+        // nor can sizes claiming more than there is
+        let list = data.windows(4).position(|x| x == b"LIST").unwrap();
+        for at in [list + 4, list + 16] {
+            let mut data = data.clone();
+            data[at..at + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+            let file = Scratch::new(&format!("size-{at}"), &data);
+            RiffTagReader::info_tags(&file.0).unwrap();
+        }
+    }
+
+    #[test]
+    fn wav_id3() {
+        let tags = read(RiffTagReader, &fixture("id3.wav"));
+        // the INFO chunk says otherwise, but the ID3 chunk wins
+        assert_eq!(
+            fields(&tags),
+            [
+                ("album", vec!["ID3 Album"]),
+                ("artist", vec!["ID3 Artist One", "ID3 Artist Two"]),
+                ("date", vec!["2005-06-07"]),
+                (
+                    "musicbrainz_albumid",
+                    vec!["0d1d8b2e-0000-4000-8000-000000000002"]
+                ),
+                ("title", vec!["ID3 Title"]),
+                ("tracknumber", vec!["2/9"]),
+            ]
+        );
+        assert_eq!(
+            raw(&tags),
+            [
+                ("TIT2", "ID3 Title"),
+                ("TPE1", "ID3 Artist One"),
+                ("TPE1", "ID3 Artist Two"),
+                ("TALB", "ID3 Album"),
+                ("TRCK", "2/9"),
+                ("TDRC", "2005-06-07"),
+                (
+                    "TXXX:MusicBrainz Album Id",
+                    "0d1d8b2e-0000-4000-8000-000000000002"
+                ),
+            ]
+        );
+        assert_eq!(pictures(&tags), [(true, FRONT)]);
+    }
+
+    #[test]
+    fn aiff() {
+        let path = fixture("tags.aiff");
+        assert_eq!(RiffTagReader::is_aiff(&path).unwrap(), Some(true));
+        let tags = read(RiffTagReader, &path);
+        // the same tag as in id3.wav
+        let wav = RiffTagReader.tags_parse(&fixture("id3.wav")).unwrap();
+        assert_eq!(fields(&tags), fields(&wav));
+        assert_eq!(raw(&tags), raw(&wav));
+        assert_eq!(pictures(&tags), [(true, FRONT)]);
+    }
+
+    #[test]
+    fn ape() {
+        let path = fixture("tags.ape");
+        let len = std::fs::metadata(&path).unwrap().len();
+        // right before the ID3v1 tag
+        let (offset, _) = ApeTagReader::footer(&path).unwrap().unwrap();
+        assert_eq!(offset, len - 128 - 32);
+
+        let tags = read(ApeTagReader, &path);
+        // external links (Related) are kept as text
+        assert_eq!(
+            fields(&tags),
+            [
+                ("album", vec!["APE Album"]),
+                ("artist", vec!["APE Artist One", "APE Artist Two"]),
+                ("date", vec!["1987"]),
+                ("related", vec!["http://example.com"]),
+                ("title", vec!["APE Title"]),
+                ("tracknumber", vec!["5/11"]),
+            ]
+        );
+        assert_eq!(
+            raw(&tags),
+            [
+                ("Title", "APE Title"),
+                ("Artist", "APE Artist One"),
+                ("Artist", "APE Artist Two"),
+                ("Album", "APE Album"),
+                ("Track", "5/11"),
+                ("Year", "1987"),
+                ("Related", "http://example.com"),
+            ]
+        );
+        assert_eq!(pictures(&tags), [(false, BACK), (true, FRONT)]);
+        assert_eq!(tags.cover().unwrap().data, FRONT);
+    }
+
+    #[test]
+    fn ape_without_id3v1() {
+        let data = std::fs::read(fixture("tags.ape")).unwrap();
+        let file = Scratch::new("no-id3v1", &data[..data.len() - 128]);
+        let (offset, _) = ApeTagReader::footer(&file.0).unwrap().unwrap();
+        assert_eq!(offset, data.len() as u64 - 128 - 32);
+        let tags = ApeTagReader.tags_parse(&file.0).unwrap();
+        let full = ApeTagReader.tags_parse(&fixture("tags.ape")).unwrap();
+        assert_eq!(raw(&tags), raw(&full));
+        assert_eq!(pictures(&tags), pictures(&full));
+    }
+
+    #[test]
+    fn ape_bad_sizes() {
+        let data = std::fs::read(fixture("tags.ape")).unwrap();
+        let footer = data.len() - 128 - 32;
+        let patched = |test: &str, at: usize, value: u32| {
+            let mut data = data.clone();
+            data[at..at + 4].copy_from_slice(&value.to_le_bytes());
+            let file = Scratch::new(test, &data);
+            ApeTagReader.tags_parse(&file.0).unwrap()
+        };
+
+        // a tag larger than the file is read from its start, finding nothing
+        let tags = patched("size", footer + 12, u32::MAX);
+        assert!(tags.raw.is_empty() && tags.pictures.is_empty());
+        // more items than there are stops at the last one
+        let tags = patched("count", footer + 16, u32::MAX);
+        assert_eq!(tags.raw.len(), 7);
+        // an item running past the end stops there
+        let size = u32::from_le_bytes(data[footer + 12..footer + 16].try_into().unwrap());
+        let tags = patched("item", footer + 32 - size as usize, u32::MAX);
+        assert!(tags.raw.is_empty());
+    }
+}
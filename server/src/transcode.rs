@@ -0,0 +1,581 @@
+use axum::{
+    body::Body,
+    extract::{Path, Query, State},
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+use std::{
+    cell::RefCell,
+    io::{BufWriter, Seek, Write},
+    num::{NonZeroU8, NonZeroU32},
+    path::PathBuf,
+    rc::Rc,
+    time::SystemTime,
+};
+use symphonia::core::{
+    audio::{AudioBuffer, AudioBufferRef, SampleBuffer, SignalSpec},
+    codecs::{CODEC_TYPE_NULL, Decoder, DecoderOptions},
+    errors::Error as SymphoniaError,
+    formats::{FormatOptions, FormatReader},
+    io::MediaSourceStream,
+    meta::MetadataOptions,
+    probe::Hint,
+};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::ReamioApp;
+use crate::auth::AuthUser;
+use crate::catalog;
+use crate::prelude::*;
+use crate::stream::serve_file;
+
+/// Where finished transcodes are kept, one dir per user.
+const CACHE_DIR: &str = "./devdir/cache/transcode";
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TranscodeFormat {
+    /// 16 bit PCM in a RIFF WAVE container
+    Wav,
+    /// Vorbis in an Ogg container
+    Ogg,
+}
+
+/// [[TranscodeArgs]]
+/// Query arguments for [[transcode_track]]
+///
+/// Fields:
+/// - format: TranscodeFormat
+///   Output format, `wav` or `ogg`.
+/// - bitrate: Option<u32>
+///   Average bitrate in kbit/s for lossy formats. Defaults to 192, and is ignored
+///   for `wav`.
+#[derive(Deserialize, Debug)]
+pub struct TranscodeArgs {
+    pub format: TranscodeFormat,
+    pub bitrate: Option<u32>,
+}
+
+/// A fully resolved output format, which is also the key for the transcode cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TranscodeProfile {
+    pub format: TranscodeFormat,
+    pub bitrate: u32,
+}
+
+impl TranscodeProfile {
+    pub fn new(format: TranscodeFormat, bitrate: Option<u32>) -> Result<Self, ReamioWebError> {
+        let bitrate = match format {
+            TranscodeFormat::Wav => 0,
+            TranscodeFormat::Ogg => {
+                let bitrate = bitrate.unwrap_or(192);
+                if !(32..=500).contains(&bitrate) {
+                    return Err(ReamioWebError::IncorrectArgs(
+                        "bitrate must be between 32 and 500 kbit/s".to_owned(),
+                        StatusCode::BAD_REQUEST,
+                    ));
+                }
+                bitrate
+            }
+        };
+        Ok(Self { format, bitrate })
+    }
+
+    fn key(&self) -> String {
+        match self.format {
+            TranscodeFormat::Wav => "wav".to_owned(),
+            TranscodeFormat::Ogg => format!("{}k.ogg", self.bitrate),
+        }
+    }
+
+    pub fn mime(&self) -> &'static str {
+        match self.format {
+            TranscodeFormat::Wav => "audio/wav",
+            TranscodeFormat::Ogg => "audio/ogg",
+        }
+    }
+}
+
+/// Decode a track and re-encode it into another format, for clients that can't play
+/// the original. Finished transcodes are cached, and served with range support from
+/// then on. A transcode that is still running is streamed as it is produced.
+///
+/// Path: GET /api/track/{id}/transcode?format={}&bitrate={}
+///
+/// Arguments:
+/// - State(state): State<ReamioApp>
+///   Server state. [[ReamioApp]].
+/// - user: AuthUser
+///   The user who owns the track. [[AuthUser]].
+/// - Path(id): Path<i64>
+///   Track id.
+/// - Query(args): Query<TranscodeArgs>
+///   See [[TranscodeArgs]].
+/// - headers: HeaderMap
+///   Request headers, passed on when serving from the cache.
+#[tracing::instrument(skip(headers))]
+pub async fn transcode_track(
+    State(state): State<ReamioApp>,
+    user: AuthUser,
+    Path(id): Path<i64>,
+    Query(args): Query<TranscodeArgs>,
+    headers: HeaderMap,
+) -> Result<Response, ReamioWebError> {
+    let profile = TranscodeProfile::new(args.format, args.bitrate)?;
+
+    let mut db = fetch_users_music_db(state.music_dbs, &user.username).await?;
+    let source: Option<String> = sqlx::query("SELECT source FROM track WHERE id = $1;")
+        .bind(id)
+        .fetch_optional(&mut *db)
+        .await?
+        .ok_or_else(|| {
+            ReamioWebError::IncorrectArgs("no such track exists".to_owned(), StatusCode::NOT_FOUND)
+        })?
+        .get("source");
+    drop(db);
+
+    let source = PathBuf::from(catalog::track_path(&user.username, id, source));
+    transcode_file(
+        source,
+        &user.username,
+        id,
+        profile,
+        state.config.transcode_cache_bytes,
+        &headers,
+    )
+    .await
+}
+
+/// Serve `source` transcoded with `profile`, from the cache if possible. `id` must be
+/// unique to `source` within `user`.
+#[tracing::instrument(skip(headers))]
+pub async fn transcode_file(
+    source: PathBuf,
+    user: &str,
+    id: i64,
+    profile: TranscodeProfile,
+    cache_bytes: u64,
+    headers: &HeaderMap,
+) -> Result<Response, ReamioWebError> {
+    let cache_dir = PathBuf::from(format!("{CACHE_DIR}/{user}"));
+    let cached = cache_dir.join(format!("{id}.{}", profile.key()));
+
+    // a cache entry older than the track belongs to a file that has since been replaced
+    let source_modified = tokio::fs::metadata(&source).await?.modified()?;
+    let cache_modified = tokio::fs::metadata(&cached)
+        .await
+        .and_then(|x| x.modified())
+        .ok();
+    if cache_modified.is_some_and(|x| x >= source_modified) {
+        debug!(?cached, "serving from transcode cache");
+        return serve_file(&cached.to_string_lossy(), profile.mime(), headers).await;
+    }
+
+    // tokens are only used for uniqueness, in case the same transcode runs twice at once
+    tokio::fs::create_dir_all(&cache_dir).await?;
+    let partial = cache_dir.join(format!(
+        "{id}.{}.{:x}.part",
+        profile.key(),
+        rand::random::<u32>()
+    ));
+    let (tx, mut rx) = mpsc::channel(16);
+    let (ready_tx, ready_rx) = oneshot::channel();
+    let span = info_span!("transcode", ?source, ?profile);
+    tokio::task::spawn_blocking(move || {
+        let _enter = span.enter();
+        let ret = run_transcode(&source, &partial, profile, tx, ready_tx);
+        match ret {
+            Ok(()) => {
+                if let Err(err) = std::fs::rename(&partial, &cached) {
+                    error!(?err, "could not move transcode into the cache");
+                } else {
+                    info!(?cached, "transcode cached");
+                    trim_cache(cache_bytes);
+                }
+            }
+            Err(err) => {
+                // most likely the client went away
+                debug!(?err, "transcode stopped");
+                drop(std::fs::remove_file(&partial));
+            }
+        }
+    });
+
+    // wait until decoding actually starts, so that unplayable files get a real error
+    match ready_rx.await {
+        Ok(Ok(())) => (),
+        Ok(Err(msg)) => {
+            return Err(ReamioWebError::IncorrectArgs(
+                msg,
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ));
+        }
+        Err(_) => {
+            return Err(ReamioWebError::IncorrectArgs(
+                "transcoder exited before starting".to_owned(),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ));
+        }
+    }
+
+    let mut resp_headers = HeaderMap::new();
+    resp_headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static(profile.mime()),
+    );
+    resp_headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("none"));
+    resp_headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    Ok((
+        StatusCode::OK,
+        resp_headers,
+        Body::from_stream(futures::stream::poll_fn(move |cx| rx.poll_recv(cx))),
+    )
+        .into_response())
+}
+
+/// Decode `source` and encode it into `partial`, while also sending everything
+/// written over `tx`. `ready` is fired once the source has been opened, or failed to.
+fn run_transcode(
+    source: &std::path::Path,
+    partial: &std::path::Path,
+    profile: TranscodeProfile,
+    tx: mpsc::Sender<Result<bytes::Bytes, std::io::Error>>,
+    ready: oneshot::Sender<Result<(), String>>,
+) -> Result<(), ReamioProcessingErrorInternal> {
+    let opened = open_decoder(source);
+    let (mut format, mut decoder, track_id) = match opened {
+        Ok(x) => {
+            drop(ready.send(Ok(())));
+            x
+        }
+        Err(err) => {
+            drop(ready.send(Err(format!("cannot decode track: {err:?}"))));
+            return Err(err);
+        }
+    };
+
+    let file = std::fs::File::create(partial)?;
+    let mut sink = BufWriter::with_capacity(1 << 16, TeeWriter { file, tx: Some(tx) });
+    let mut encoder: Option<Box<dyn AudioEncoder>> = None;
+    loop {
+        let packet = match format.next_packet() {
+            Ok(x) => x,
+            Err(SymphoniaError::IoError(err))
+                if err.kind() == std::io::ErrorKind::UnexpectedEof =>
+            {
+                break;
+            }
+            Err(SymphoniaError::ResetRequired) => break,
+            Err(err) => return Err(err.into()),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+        let decoded = match decoder.decode(&packet) {
+            Ok(x) => x,
+            Err(SymphoniaError::DecodeError(err)) => {
+                // a corrupt packet is not worth giving up on the whole track
+                warn!(err, "skipping undecodable packet");
+                continue;
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        let encoder = match &mut encoder {
+            Some(x) => x,
+            None => encoder.insert(match profile.format {
+                TranscodeFormat::Wav => Box::new(WavEncoder::new(*decoded.spec(), &mut sink)?),
+                TranscodeFormat::Ogg => {
+                    Box::new(VorbisEncoder::new(*decoded.spec(), profile.bitrate)?)
+                }
+            }),
+        };
+        encoder.encode(decoded, &mut sink)?;
+    }
+    if let Some(encoder) = encoder {
+        encoder.finish(&mut sink)?;
+    }
+
+    let mut tee = sink.into_inner().map_err(|x| x.into_error())?;
+    if profile.format == TranscodeFormat::Wav {
+        // the stream went out with placeholder sizes, but the cached copy can be exact
+        tee.tx = None;
+        WavEncoder::patch_sizes(&mut tee.file)?;
+    }
+    tee.file.sync_data()?;
+    Ok(())
+}
+
+type OpenedDecoder = (Box<dyn FormatReader>, Box<dyn Decoder>, u32);
+
+/// Probe a file and set up a decoder for its first audio track.
+pub fn open_decoder(
+    source: &std::path::Path,
+) -> Result<OpenedDecoder, ReamioProcessingErrorInternal> {
+    let file = std::fs::File::open(source)?;
+    let mss = MediaSourceStream::new(Box::new(file), Default::default());
+    let probed = symphonia::default::get_probe().format(
+        &Hint::new(),
+        mss,
+        &FormatOptions::default(),
+        &MetadataOptions::default(),
+    )?;
+    let format = probed.format;
+    let track = format
+        .tracks()
+        .iter()
+        .find(|x| x.codec_params.codec != CODEC_TYPE_NULL)
+        .ok_or(SymphoniaError::Unsupported("no audio track"))?;
+    let track_id = track.id;
+    let decoder =
+        symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+    Ok((format, decoder, track_id))
+}
+
+/// Writes into the cache file, while passing everything on to the client.
+struct TeeWriter {
+    file: std::fs::File,
+    tx: Option<mpsc::Sender<Result<bytes::Bytes, std::io::Error>>>,
+}
+
+impl Write for TeeWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.file.write_all(buf)?;
+        if let Some(tx) = &self.tx {
+            tx.blocking_send(Ok(bytes::Bytes::copy_from_slice(buf)))
+                .map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+trait AudioEncoder {
+    fn encode(
+        &mut self,
+        buf: AudioBufferRef<'_>,
+        sink: &mut dyn Write,
+    ) -> Result<(), ReamioProcessingErrorInternal>;
+
+    fn finish(self: Box<Self>, sink: &mut dyn Write) -> Result<(), ReamioProcessingErrorInternal>;
+}
+
+/// Reject anything that changes format halfway through, such as chained ogg streams.
+fn check_spec(
+    expected: &SignalSpec,
+    got: &SignalSpec,
+) -> Result<(), ReamioProcessingErrorInternal> {
+    if expected != got {
+        return Err(SymphoniaError::Unsupported("signal format changed mid stream").into());
+    }
+    Ok(())
+}
+
+/// 16 bit little endian PCM WAV. The header is written before the length is known,
+/// so the sizes in it are the maximum until [[WavEncoder::patch_sizes]].
+struct WavEncoder {
+    spec: SignalSpec,
+    samples: Option<SampleBuffer<i16>>,
+}
+
+impl WavEncoder {
+    const HEADER_LEN: u64 = 44;
+
+    fn new(spec: SignalSpec, sink: &mut dyn Write) -> Result<Self, ReamioProcessingErrorInternal> {
+        let channels = spec.channels.count() as u16;
+        let block_align = channels * 2;
+        let byte_rate = spec.rate * block_align as u32;
+
+        let mut header = Vec::with_capacity(Self::HEADER_LEN as usize);
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&u32::MAX.to_le_bytes());
+        header.extend_from_slice(b"WAVEfmt ");
+        header.extend_from_slice(&16u32.to_le_bytes());
+        header.extend_from_slice(&1u16.to_le_bytes()); // PCM
+        header.extend_from_slice(&channels.to_le_bytes());
+        header.extend_from_slice(&spec.rate.to_le_bytes());
+        header.extend_from_slice(&byte_rate.to_le_bytes());
+        header.extend_from_slice(&block_align.to_le_bytes());
+        header.extend_from_slice(&16u16.to_le_bytes());
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&u32::MAX.to_le_bytes());
+        sink.write_all(&header)?;
+
+        Ok(Self {
+            spec,
+            samples: None,
+        })
+    }
+
+    /// Fill in the RIFF and data chunk sizes of a finished file.
+    fn patch_sizes(file: &mut std::fs::File) -> Result<(), ReamioProcessingErrorInternal> {
+        let len = file.metadata()?.len();
+        let riff = u32::try_from(len - 8).unwrap_or(u32::MAX);
+        let data = u32::try_from(len - Self::HEADER_LEN).unwrap_or(u32::MAX);
+        file.seek(std::io::SeekFrom::Start(4))?;
+        file.write_all(&riff.to_le_bytes())?;
+        file.seek(std::io::SeekFrom::Start(Self::HEADER_LEN - 4))?;
+        file.write_all(&data.to_le_bytes())?;
+        Ok(())
+    }
+}
+
+impl AudioEncoder for WavEncoder {
+    fn encode(
+        &mut self,
+        buf: AudioBufferRef<'_>,
+        sink: &mut dyn Write,
+    ) -> Result<(), ReamioProcessingErrorInternal> {
+        check_spec(&self.spec, buf.spec())?;
+        let samples = match &mut self.samples {
+            Some(x) if x.capacity() >= buf.capacity() * self.spec.channels.count() => x,
+            _ => self
+                .samples
+                .insert(SampleBuffer::new(buf.capacity() as u64, self.spec)),
+        };
+        samples.copy_interleaved_ref(buf);
+        let bytes = samples
+            .samples()
+            .iter()
+            .flat_map(|x| x.to_le_bytes())
+            .collect::<Vec<_>>();
+        sink.write_all(&bytes)?;
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>, sink: &mut dyn Write) -> Result<(), ReamioProcessingErrorInternal> {
+        sink.flush()?;
+        Ok(())
+    }
+}
+
+/// A buffer that can be written into by something that owns it, and drained by
+/// someone else.
+#[derive(Clone, Default)]
+struct SharedBuf(Rc<RefCell<Vec<u8>>>);
+
+impl Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Ogg Vorbis, in average bitrate mode.
+struct VorbisEncoder {
+    spec: SignalSpec,
+    planar: Option<AudioBuffer<f32>>,
+    // the encoder owns its sink, so it writes into a buffer that is then drained
+    encoder: Option<vorbis_rs::VorbisEncoder<SharedBuf>>,
+    out: SharedBuf,
+}
+
+impl VorbisEncoder {
+    fn new(spec: SignalSpec, bitrate: u32) -> Result<Self, ReamioProcessingErrorInternal> {
+        let rate = NonZeroU32::new(spec.rate).ok_or(SymphoniaError::Unsupported("rate of 0"))?;
+        let channels = u8::try_from(spec.channels.count())
+            .ok()
+            .and_then(NonZeroU8::new)
+            .ok_or(SymphoniaError::Unsupported("channel count"))?;
+        let average_bitrate =
+            NonZeroU32::new(bitrate * 1000).ok_or(SymphoniaError::Unsupported("bitrate of 0"))?;
+        let out = SharedBuf::default();
+        let encoder = vorbis_rs::VorbisEncoderBuilder::new(rate, channels, out.clone())?
+            .bitrate_management_strategy(vorbis_rs::VorbisBitrateManagementStrategy::Abr {
+                average_bitrate,
+            })
+            .build()?;
+        Ok(Self {
+            spec,
+            planar: None,
+            encoder: Some(encoder),
+            out,
+        })
+    }
+
+    fn drain(&mut self, sink: &mut dyn Write) -> Result<(), ReamioProcessingErrorInternal> {
+        let mut out = self.out.0.borrow_mut();
+        sink.write_all(&out)?;
+        out.clear();
+        Ok(())
+    }
+}
+
+impl AudioEncoder for VorbisEncoder {
+    fn encode(
+        &mut self,
+        buf: AudioBufferRef<'_>,
+        sink: &mut dyn Write,
+    ) -> Result<(), ReamioProcessingErrorInternal> {
+        check_spec(&self.spec, buf.spec())?;
+        let planar = match &mut self.planar {
+            Some(x) if x.capacity() >= buf.capacity() => x,
+            _ => self.planar.insert(buf.make_equivalent()),
+        };
+        buf.convert(planar);
+        if let Some(encoder) = &mut self.encoder {
+            encoder.encode_audio_block(planar.planes().planes())?;
+        }
+        self.drain(sink)
+    }
+
+    fn finish(
+        mut self: Box<Self>,
+        sink: &mut dyn Write,
+    ) -> Result<(), ReamioProcessingErrorInternal> {
+        if let Some(encoder) = self.encoder.take() {
+            encoder.finish()?;
+        }
+        self.drain(sink)?;
+        sink.flush()?;
+        Ok(())
+    }
+}
+
+/// Delete the oldest transcodes until the cache fits in `max_bytes`.
+#[tracing::instrument]
+fn trim_cache(max_bytes: u64) {
+    let mut entries = Vec::new();
+    let Ok(users) = std::fs::read_dir(CACHE_DIR) else {
+        return;
+    };
+    for user in users.flatten() {
+        let Ok(files) = std::fs::read_dir(user.path()) else {
+            continue;
+        };
+        for file in files.flatten() {
+            // transcodes in progress are not part of the cache yet
+            if file.path().extension().is_some_and(|x| x == "part") {
+                continue;
+            }
+            if let Ok(meta) = file.metadata() {
+                entries.push((
+                    meta.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                    meta.len(),
+                    file.path(),
+                ));
+            }
+        }
+    }
+
+    let mut total = entries.iter().map(|(_, len, _)| len).sum::<u64>();
+    trace!(total, max_bytes, "transcode cache size");
+    entries.sort();
+    for (_, len, path) in entries {
+        if total <= max_bytes {
+            break;
+        }
+        debug!(?path, "evicting transcode");
+        if std::fs::remove_file(&path).is_ok() {
+            total -= len;
+        }
+    }
+}
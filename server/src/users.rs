@@ -0,0 +1,496 @@
+use axum::{
+    Json,
+    extract::{FromRequestParts, Path, State},
+    http::{StatusCode, request::Parts},
+};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+use crate::ReamioApp;
+use crate::auth::{AuthUser, hash_password};
+use crate::config::ReamioConfig;
+use crate::prelude::*;
+
+/// Longest username allowed, in chars.
+const USERNAME_MAX: usize = 32;
+/// Shortest password allowed, in chars.
+const PASSWORD_MIN: usize = 8;
+
+/// A user that is allowed to manage other users. Like [[AuthUser]], adding this as
+/// a handler argument gates the handler, rejecting non admins with 403.
+#[derive(Debug, Clone)]
+pub struct AdminUser(pub AuthUser);
+
+impl FromRequestParts<ReamioApp> for AdminUser {
+    type Rejection = ReamioWebError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &ReamioApp,
+    ) -> Result<Self, Self::Rejection> {
+        let user = AuthUser::from_request_parts(parts, state).await?;
+        if !user.admin {
+            return Err(ReamioWebError::IncorrectArgs(
+                "admin access required".to_owned(),
+                StatusCode::FORBIDDEN,
+            ));
+        }
+        Ok(AdminUser(user))
+    }
+}
+
+/// Check a username, returning the (username_lower, username_orig) pair used to
+/// store it.
+///
+/// The lowered name doubles as the name of the user's `devdir/u/` dir, so this is
+/// strict: letters, numbers, '_', '-' and '.' only, not starting with a '.'.
+pub fn validate_username(name: &str) -> Result<(String, String), ReamioWebError> {
+    let orig = name.trim();
+    let bad = |msg: &str| ReamioWebError::IncorrectArgs(msg.to_owned(), StatusCode::BAD_REQUEST);
+
+    if orig.is_empty() {
+        return Err(bad("username is empty"));
+    }
+    if orig.chars().count() > USERNAME_MAX {
+        return Err(bad("username is too long"));
+    }
+    if orig.starts_with('.') {
+        return Err(bad("username cannot start with '.'"));
+    }
+    if !orig
+        .chars()
+        .all(|x| x.is_alphanumeric() || matches!(x, '_' | '-' | '.'))
+    {
+        return Err(bad(
+            "username can only contain letters, numbers, '_', '-' and '.'",
+        ));
+    }
+
+    Ok((orig.to_lowercase(), orig.to_owned()))
+}
+
+fn validate_password(password: &str) -> Result<(), ReamioWebError> {
+    if password.chars().count() < PASSWORD_MIN {
+        return Err(ReamioWebError::IncorrectArgs(
+            format!("password must be at least {PASSWORD_MIN} characters"),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+    Ok(())
+}
+
+#[derive(Serialize, sqlx::FromRow, Debug)]
+pub struct UserInfo {
+    username: String,
+    display_name: String,
+    admin: bool,
+    disabled: bool,
+}
+
+/// Fetch a single user, 404ing if they don't exist.
+async fn fetch_user(user_db: &SqlitePool, username: &str) -> Result<UserInfo, ReamioWebError> {
+    sqlx::query_as::<_, UserInfo>(
+        "SELECT username_lower AS username, username_orig AS display_name, admin, disabled
+             FROM users WHERE username_lower = $1;",
+    )
+    .bind(username)
+    .fetch_optional(user_db)
+    .await?
+    .ok_or_else(|| ReamioWebError::IncorrectArgs("no such user".to_owned(), StatusCode::NOT_FOUND))
+}
+
+/// Create a user. Their music db is created the first time it is used.
+#[tracing::instrument(skip(state, password))]
+async fn create_user(
+    state: &ReamioApp,
+    username: &str,
+    password: String,
+    admin: bool,
+) -> Result<UserInfo, ReamioWebError> {
+    let (lower, orig) = validate_username(username)?;
+    validate_password(&password)?;
+    let phc = hash_password(password).await?;
+
+    let inserted = sqlx::query(
+        "INSERT INTO users (username_lower, username_orig, phc, admin)
+             VALUES ($1, $2, $3, $4)
+             ON CONFLICT DO NOTHING
+             RETURNING username_lower;",
+    )
+    .bind(&lower)
+    .bind(&orig)
+    .bind(phc)
+    .bind(admin)
+    .fetch_optional(&state.user_db)
+    .await?;
+    if inserted.is_none() {
+        return Err(ReamioWebError::IncorrectArgs(
+            "username is taken".to_owned(),
+            StatusCode::CONFLICT,
+        ));
+    }
+
+    info!(lower, admin, "user created");
+
+    fetch_user(&state.user_db, &lower).await
+}
+
+/// Make sure there is an admin that can log in, creating [[ReamioConfig::admin_user]]
+/// (or giving them a password) if there isn't.
+#[tracing::instrument(skip(user_db, config))]
+pub async fn bootstrap_admin(
+    user_db: &SqlitePool,
+    config: &ReamioConfig,
+) -> Result<(), ReamioWebError> {
+    let usable_admin =
+        sqlx::query("SELECT 1 FROM users WHERE admin = 1 AND disabled = 0 AND phc != '' LIMIT 1;")
+            .fetch_optional(user_db)
+            .await?;
+    if usable_admin.is_some() {
+        trace!("admin exists, no bootstrap needed");
+        return Ok(());
+    }
+
+    let (lower, orig) = validate_username(&config.admin_user)?;
+    let password = match &config.admin_password {
+        Some(x) => x.clone(),
+        None => {
+            let mut raw = [0u8; 12];
+            rand::rngs::OsRng.fill_bytes(&mut raw);
+            let password = raw.iter().map(|x| format!("{x:02x}")).collect::<String>();
+            // straight to the console, once, since logs tend to be kept and shipped off
+            warn!(
+                username = orig,
+                "no usable admin exists, generated a password for one, see stderr"
+            );
+            eprintln!("generated password for admin {orig}: {password}");
+            password
+        }
+    };
+    sqlx::query(
+        "INSERT INTO users (username_lower, username_orig, phc, admin, disabled)
+             VALUES ($1, $2, $3, 1, 0)
+             ON CONFLICT DO UPDATE SET phc = excluded.phc, admin = 1, disabled = 0;",
+    )
+    .bind(&lower)
+    .bind(&orig)
+    .bind(hash_password(password).await?)
+    .execute(user_db)
+    .await?;
+    info!(lower, "admin bootstrapped");
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct RegisterArgs {
+    pub username: String,
+    pub password: String,
+}
+
+/// Create an account for yourself. Only available when the server was started with
+/// REAMIO_OPEN_REGISTRATION=true.
+///
+/// Path: /api/register
+#[tracing::instrument(skip(args))]
+pub async fn register(
+    State(state): State<ReamioApp>,
+    Json(args): Json<RegisterArgs>,
+) -> Result<Json<UserInfo>, ReamioWebError> {
+    if !state.config.open_registration {
+        return Err(ReamioWebError::IncorrectArgs(
+            "registration is closed".to_owned(),
+            StatusCode::FORBIDDEN,
+        ));
+    }
+    Ok(Json(
+        create_user(&state, &args.username, args.password, false).await?,
+    ))
+}
+
+/// List every user.
+///
+/// Path: GET /api/admin/users
+#[tracing::instrument]
+pub async fn list_users(
+    State(state): State<ReamioApp>,
+    _admin: AdminUser,
+) -> Result<Json<Vec<UserInfo>>, ReamioWebError> {
+    Ok(Json(
+        sqlx::query_as::<_, UserInfo>(
+            "SELECT username_lower AS username, username_orig AS display_name, admin, disabled
+                 FROM users ORDER BY username_lower;",
+        )
+        .fetch_all(&state.user_db)
+        .await?,
+    ))
+}
+
+#[derive(Deserialize)]
+pub struct CreateUserArgs {
+    pub username: String,
+    pub password: String,
+    #[serde(default)]
+    pub admin: bool,
+}
+
+/// Create a user.
+///
+/// Path: POST /api/admin/users
+#[tracing::instrument(skip(args))]
+pub async fn admin_create_user(
+    State(state): State<ReamioApp>,
+    _admin: AdminUser,
+    Json(args): Json<CreateUserArgs>,
+) -> Result<Json<UserInfo>, ReamioWebError> {
+    Ok(Json(
+        create_user(&state, &args.username, args.password, args.admin).await?,
+    ))
+}
+
+/// [[UpdateUserArgs]]
+/// Changes to make to a user. Every field is optional, and absent fields are left
+/// alone.
+///
+/// Fields:
+/// - username: Option<String>
+///   New username. If this only changes the case of the name, only the display
+///   name changes. Otherwise the user's files and sessions are moved over.
+/// - password: Option<String>
+///   New password. Logs the user out everywhere.
+/// - admin: Option<bool>
+/// - disabled: Option<bool>
+///   Disabling logs the user out everywhere and stops them from logging in.
+#[derive(Deserialize)]
+pub struct UpdateUserArgs {
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub admin: Option<bool>,
+    pub disabled: Option<bool>,
+}
+
+/// Rename, disable, promote or reset the password of a user.
+///
+/// Path: PATCH /api/admin/users/{user}
+#[tracing::instrument(skip(args))]
+pub async fn update_user(
+    State(state): State<ReamioApp>,
+    AdminUser(admin): AdminUser,
+    Path(user): Path<String>,
+    Json(args): Json<UpdateUserArgs>,
+) -> Result<Json<UserInfo>, ReamioWebError> {
+    let user = user.to_lowercase();
+    fetch_user(&state.user_db, &user).await?;
+
+    // locking yourself out is almost certainly a mistake
+    if user == admin.username && (args.admin == Some(false) || args.disabled == Some(true)) {
+        return Err(ReamioWebError::IncorrectArgs(
+            "cannot demote or disable yourself".to_owned(),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+
+    // everything is checked before anything is written, so that a bad part of the
+    // request, such as a taken username, leaves the user as they were
+    let phc = match args.password {
+        Some(password) => {
+            validate_password(&password)?;
+            Some(hash_password(password).await?)
+        }
+        None => None,
+    };
+    let name = args
+        .username
+        .as_deref()
+        .map(validate_username)
+        .transpose()?;
+
+    let mut txn = state.user_db.begin_with("BEGIN IMMEDIATE").await?;
+    if let Some(phc) = phc {
+        sqlx::query("UPDATE users SET phc = $1 WHERE username_lower = $2;")
+            .bind(phc)
+            .bind(&user)
+            .execute(&mut *txn)
+            .await?;
+        sqlx::query("DELETE FROM sessions WHERE user = $1;")
+            .bind(&user)
+            .execute(&mut *txn)
+            .await?;
+        debug!(user, "password changed");
+    }
+    if let Some(admin) = args.admin {
+        sqlx::query("UPDATE users SET admin = $1 WHERE username_lower = $2;")
+            .bind(admin)
+            .bind(&user)
+            .execute(&mut *txn)
+            .await?;
+        debug!(user, admin, "admin flag changed");
+    }
+    if let Some(disabled) = args.disabled {
+        sqlx::query("UPDATE users SET disabled = $1 WHERE username_lower = $2;")
+            .bind(disabled)
+            .bind(&user)
+            .execute(&mut *txn)
+            .await?;
+        if disabled {
+            sqlx::query("DELETE FROM sessions WHERE user = $1;")
+                .bind(&user)
+                .execute(&mut *txn)
+                .await?;
+        }
+        debug!(user, disabled, "disabled flag changed");
+    }
+
+    // the rename commits the rest along with it
+    let user = match name {
+        Some((lower, orig)) => rename_user(&state, txn, &user, lower, orig).await?,
+        None => {
+            txn.commit().await?;
+            user
+        }
+    };
+    Ok(Json(fetch_user(&state.user_db, &user).await?))
+}
+
+/// Rename a user to the validated `lower` and `orig`, moving everything keyed on
+/// their username_lower, and commit `txn` along with it. Returns the new
+/// username_lower.
+#[tracing::instrument(skip(state, txn))]
+async fn rename_user(
+    state: &ReamioApp,
+    mut txn: sqlx::Transaction<'_, sqlx::Sqlite>,
+    user: &str,
+    lower: String,
+    orig: String,
+) -> Result<String, ReamioWebError> {
+    // same user, different capitalization
+    if lower == user {
+        sqlx::query("UPDATE users SET username_orig = $1 WHERE username_lower = $2;")
+            .bind(&orig)
+            .bind(user)
+            .execute(&mut *txn)
+            .await?;
+        txn.commit().await?;
+        debug!(user, orig, "display name changed");
+        return Ok(lower);
+    }
+
+    let taken = sqlx::query("SELECT 1 FROM users WHERE username_lower = $1;")
+        .bind(&lower)
+        .fetch_optional(&mut *txn)
+        .await?;
+    if taken.is_some() {
+        return Err(ReamioWebError::IncorrectArgs(
+            "username is taken".to_owned(),
+            StatusCode::CONFLICT,
+        ));
+    }
+
+    // the foreign keys only line up again once every table is updated
+    sqlx::query("PRAGMA defer_foreign_keys = ON;")
+        .execute(&mut *txn)
+        .await?;
+    sqlx::query(
+        "UPDATE users SET username_lower = $1, username_orig = $2 WHERE username_lower = $3;",
+    )
+    .bind(&lower)
+    .bind(&orig)
+    .bind(user)
+    .execute(&mut *txn)
+    .await?;
+    for table in ["uploaded_files", "sessions"] {
+        sqlx::query(&format!("UPDATE {table} SET user = $1 WHERE user = $2;"))
+            .bind(&lower)
+            .bind(user)
+            .execute(&mut *txn)
+            .await?;
+    }
+
+    // the music db lives in the user's dir, so it has to be closed before the move
+    let music_dbs = state.music_dbs.upgrade().ok_or_else(|| {
+        ReamioWebError::IncorrectArgs(
+            "server is shutting down".to_owned(),
+            StatusCode::SERVICE_UNAVAILABLE,
+        )
+    })?;
+    music_dbs
+        .evict_while(user, async {
+            let from = format!("./devdir/u/{user}");
+            let to = format!("./devdir/u/{lower}");
+            trace!("moving user dir {from} -> {to}");
+            match tokio::fs::rename(&from, &to).await {
+                Ok(()) => (),
+                // never used, so there is nothing to move
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+                Err(err) => return Err(ReamioWebError::from(err)),
+            }
+            if let Err(err) = txn.commit().await {
+                // put things back the way they were
+                drop(tokio::fs::rename(&to, &from).await);
+                return Err(err.into());
+            }
+            Ok(())
+        })
+        .await?;
+    info!(user, lower, "user renamed");
+
+    Ok(lower)
+}
+
+/// Delete a user, along with their sessions, pending uploads, music db and files.
+///
+/// Path: DELETE /api/admin/users/{user}
+#[tracing::instrument]
+pub async fn delete_user(
+    State(state): State<ReamioApp>,
+    AdminUser(admin): AdminUser,
+    Path(user): Path<String>,
+) -> Result<StatusCode, ReamioWebError> {
+    let user = user.to_lowercase();
+    fetch_user(&state.user_db, &user).await?;
+    if user == admin.username {
+        return Err(ReamioWebError::IncorrectArgs(
+            "cannot delete yourself".to_owned(),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+
+    let mut txn = state.user_db.begin_with("BEGIN IMMEDIATE").await?;
+    let fids = sqlx::query("DELETE FROM uploaded_files WHERE user = $1 RETURNING fid;")
+        .bind(&user)
+        .fetch_all(&mut *txn)
+        .await?
+        .into_iter()
+        .map(|x| x.get::<i64, _>("fid"))
+        .collect::<Vec<_>>();
+    sqlx::query("DELETE FROM sessions WHERE user = $1;")
+        .bind(&user)
+        .execute(&mut *txn)
+        .await?;
+    sqlx::query("DELETE FROM users WHERE username_lower = $1;")
+        .bind(&user)
+        .execute(&mut *txn)
+        .await?;
+    txn.commit().await?;
+    debug!(user, ?fids, "user removed from user db");
+
+    // now that nothing references them, clean up the files
+    for fid in fids {
+        if let Err(err) = tokio::fs::remove_file(format!("./devdir/temp/{fid}")).await {
+            warn!(fid, ?err, "could not remove pending upload");
+        }
+    }
+    let remove_dir = async {
+        match tokio::fs::remove_dir_all(format!("./devdir/u/{user}")).await {
+            Ok(()) => Ok(()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err),
+        }
+    };
+    match state.music_dbs.upgrade() {
+        Some(music_dbs) => music_dbs.evict_while(&user, remove_dir).await?,
+        None => remove_dir.await?,
+    }
+    info!(user, "user deleted");
+
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,371 @@
+//! Keeping library folders on the server's disk imported, by watching them with
+//! inotify.
+//!
+//! Each of [[ReamioConfig::watch_dirs]] is imported in place into the root of its
+//! user's library, and rescanned on start to catch up on what changed while the
+//! server was down. From then on, every dir in there that sees files created,
+//! written, moved or deleted is rescanned once it has gone
+//! [[ReamioConfig::watch_debounce]] without any, so that an album being copied in
+//! is picked up in one go rather than file by file. See [[import]] for what a rescan
+//! queues.
+
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify, WatchDescriptor};
+use std::{
+    collections::HashMap,
+    os::fd::{AsFd, AsRawFd, RawFd},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Instant,
+};
+use tokio::io::unix::AsyncFd;
+use tokio_util::sync::CancellationToken;
+
+use crate::config::ReamioConfig;
+use crate::import::{self, DirImport};
+use crate::prelude::*;
+
+/// Changes that get a dir rescanned. Writes are watched for too, and not only their
+/// end, so that a dir a big file is being copied into doesn't look quiet.
+const WATCH_MASK: AddWatchFlags = AddWatchFlags::IN_CREATE
+    .union(AddWatchFlags::IN_MODIFY)
+    .union(AddWatchFlags::IN_CLOSE_WRITE)
+    .union(AddWatchFlags::IN_ATTRIB)
+    .union(AddWatchFlags::IN_MOVED_FROM)
+    .union(AddWatchFlags::IN_MOVED_TO)
+    .union(AddWatchFlags::IN_DELETE)
+    .union(AddWatchFlags::IN_ONLYDIR)
+    .union(AddWatchFlags::IN_DONT_FOLLOW);
+
+/// [[Inotify]], for [[AsyncFd]], which wants a raw fd.
+struct InotifyFd(Inotify);
+
+impl AsRawFd for InotifyFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_fd().as_raw_fd()
+    }
+}
+
+/// A watched dir, from [[ReamioConfig::watch_dirs]].
+#[derive(Debug)]
+struct Root {
+    user: String,
+    /// Absolute path, with symlinks resolved
+    path: PathBuf,
+}
+
+struct Watcher {
+    inotify: AsyncFd<InotifyFd>,
+    roots: Vec<Root>,
+    /// Every dir watched, and the root it's in
+    dirs: HashMap<WatchDescriptor, (usize, PathBuf)>,
+    /// Dirs that changed, with the root they're in and when they last did
+    dirty: HashMap<PathBuf, (usize, Instant)>,
+}
+
+impl Watcher {
+    /// Watch `dir` and every dir in it, leaving out hidden ones and symlinks like
+    /// [[import]] does.
+    fn watch_tree(&mut self, root: usize, dir: &Path) {
+        // reading dirs blocks, which only takes long for dirs with a lot in them
+        tokio::task::block_in_place(|| {
+            let mut dirs = vec![dir.to_path_buf()];
+            while let Some(dir) = dirs.pop() {
+                match self.inotify.get_ref().0.add_watch(&dir, WATCH_MASK) {
+                    Ok(wd) => {
+                        trace!(?dir, "watching");
+                        self.dirs.insert(wd, (root, dir.clone()));
+                    }
+                    Err(nix::errno::Errno::ENOSPC) => {
+                        error!(
+                            ?dir,
+                            "out of inotify watches, raise fs.inotify.max_user_watches"
+                        );
+                        return;
+                    }
+                    Err(err) => {
+                        warn!(?dir, ?err, "could not watch dir");
+                        continue;
+                    }
+                }
+                let Ok(entries) = std::fs::read_dir(&dir) else {
+                    continue;
+                };
+                for entry in entries.flatten() {
+                    let hidden = entry.file_name().to_string_lossy().starts_with('.');
+                    if !hidden && entry.file_type().is_ok_and(|x| x.is_dir()) {
+                        dirs.push(entry.path());
+                    }
+                }
+            }
+        });
+    }
+
+    /// Stop watching `dir` and every dir in it, as they moved away.
+    fn unwatch_tree(&mut self, dir: &Path) {
+        let gone = self
+            .dirs
+            .iter()
+            .filter(|(_, (_, x))| x.starts_with(dir))
+            .map(|(wd, _)| *wd)
+            .collect::<Vec<_>>();
+        for wd in gone {
+            self.dirs.remove(&wd);
+            // fails for watches the kernel already dropped, which is fine
+            if let Err(err) = self.inotify.get_ref().0.rm_watch(wd) {
+                trace!(?err, "could not remove watch");
+            }
+        }
+    }
+
+    fn mark_dirty(&mut self, root: usize, dir: PathBuf) {
+        self.dirty.insert(dir, (root, Instant::now()));
+    }
+
+    /// Take in a batch of inotify events.
+    fn handle_events(&mut self, events: Vec<nix::sys::inotify::InotifyEvent>) {
+        for event in events {
+            if event.mask.contains(AddWatchFlags::IN_Q_OVERFLOW) {
+                // events were lost, so anything could have changed
+                warn!("inotify queue overflowed, rescanning everything");
+                for (idx, root) in self.roots.iter().enumerate() {
+                    self.dirty.insert(root.path.clone(), (idx, Instant::now()));
+                }
+                continue;
+            }
+            let Some((root, dir)) = self.dirs.get(&event.wd).cloned() else {
+                continue;
+            };
+            if event.mask.contains(AddWatchFlags::IN_IGNORED) {
+                // the dir was deleted, or its filesystem unmounted
+                self.dirs.remove(&event.wd);
+                if dir == self.roots[root].path {
+                    warn!(?dir, "watched dir went away, no longer watching it");
+                }
+                continue;
+            }
+            let Some(name) = event.name else {
+                continue;
+            };
+            // like partial downloads and rsync's temp files, which are renamed to
+            // what they're for once done
+            if name.to_string_lossy().starts_with('.') {
+                continue;
+            }
+            let path = dir.join(&name);
+            trace!(?path, mask = ?event.mask, "change seen");
+            if event.mask.contains(AddWatchFlags::IN_ISDIR) {
+                if event
+                    .mask
+                    .intersects(AddWatchFlags::IN_CREATE | AddWatchFlags::IN_MOVED_TO)
+                {
+                    self.watch_tree(root, &path);
+                } else if event.mask.contains(AddWatchFlags::IN_MOVED_FROM) {
+                    self.unwatch_tree(&path);
+                }
+            }
+            self.mark_dirty(root, dir);
+        }
+    }
+
+    /// Take the dirs that have been quiet for `debounce`, grouped by root. Dirs that
+    /// are gone are swapped for the closest one that's still there, and dirs within
+    /// another one that's taken are left out, as rescans go all the way down.
+    fn take_quiet(&mut self, debounce: std::time::Duration) -> HashMap<usize, Vec<PathBuf>> {
+        let now = Instant::now();
+        let quiet = self
+            .dirty
+            .iter()
+            .filter(|(_, (_, at))| now.duration_since(*at) >= debounce)
+            .map(|(dir, (root, _))| (dir.clone(), *root))
+            .collect::<Vec<_>>();
+
+        let mut ret: HashMap<usize, Vec<PathBuf>> = HashMap::new();
+        for (dir, root) in quiet {
+            self.dirty.remove(&dir);
+            let root_path = &self.roots[root].path;
+            let mut dir = dir.as_path();
+            while !dir.is_dir() && dir != root_path {
+                match dir.parent() {
+                    Some(parent) => dir = parent,
+                    None => break,
+                }
+            }
+            if !dir.starts_with(root_path) || !dir.is_dir() {
+                warn!(?dir, "changed dir is gone, along with its root");
+                continue;
+            }
+            ret.entry(root).or_default().push(dir.to_path_buf());
+        }
+        for dirs in ret.values_mut() {
+            dirs.sort();
+            dirs.dedup();
+            // sorted, so a dir comes right after anything it's within
+            let mut kept: Vec<PathBuf> = vec![];
+            for dir in dirs.drain(..) {
+                if !kept.last().is_some_and(|x| dir.starts_with(x)) {
+                    kept.push(dir);
+                }
+            }
+            *dirs = kept;
+        }
+        ret
+    }
+
+    /// When the next dir will have been quiet for `debounce`.
+    fn next_quiet(&self, debounce: std::time::Duration) -> Option<Instant> {
+        self.dirty.values().map(|(_, at)| *at + debounce).min()
+    }
+}
+
+/// Rescan `dirs` within watched dir `root`, and queue what turned up in one go, so
+/// that files moved between them are recognized as such.
+async fn rescan(
+    user_db: &SqlitePool,
+    music_dbs: &MusicDbMapRef,
+    waker: &WakeTx<PopulateMetadata>,
+    config: &ReamioConfig,
+    root: &Root,
+    dirs: &[PathBuf],
+    catching_up: bool,
+) {
+    let mut queue = vec![];
+    for dir in dirs {
+        let (Some(source), Ok(rel)) = (dir.to_str(), dir.strip_prefix(&root.path)) else {
+            warn!(?dir, "path is not UTF-8, skipping");
+            continue;
+        };
+        let dest = match rel.to_str() {
+            Some("") => String::new(),
+            Some(rel) => format!("/{rel}"),
+            None => continue,
+        };
+        let import = DirImport {
+            user: root.user.clone(),
+            source: source.to_owned(),
+            dest,
+            in_place: true,
+            rescan: true,
+            on_duplicate: config.on_duplicate,
+            // changes seen as they happen are real, while on start a dir could be
+            // missing what it holds for not being mounted yet
+            keep_if_all_gone: catching_up,
+        };
+        match import::scan_dir(user_db, music_dbs, &import).await {
+            Ok((ret, files)) => {
+                debug!(?dir, ?ret, "watched dir rescanned");
+                queue.extend(files);
+            }
+            Err(err) => error!(?dir, ?err, "while rescanning watched dir"),
+        }
+    }
+    if let Err(err) = import::enqueue(user_db, waker, queue).await {
+        error!(?err, "while queueing watched dir changes");
+    }
+}
+
+/// Watch [[ReamioConfig::watch_dirs]], queueing their changes as they come. See
+/// [[watcher]].
+#[tracing::instrument(skip(waker, config))]
+pub async fn task_watch(
+    user_db: SqlitePool,
+    music_dbs: MusicDbMapRef,
+    waker: WakeTx<PopulateMetadata>,
+    config: Arc<ReamioConfig>,
+    shutdown: CancellationToken,
+) {
+    let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)
+        .expect("could not set up inotify");
+    let mut watcher = Watcher {
+        inotify: AsyncFd::new(InotifyFd(inotify)).expect("could not poll inotify"),
+        roots: vec![],
+        dirs: HashMap::new(),
+        dirty: HashMap::new(),
+    };
+
+    for (user, dir) in &config.watch_dirs {
+        let exists = sqlx::query("SELECT username_lower FROM users WHERE username_lower = $1;")
+            .bind(user)
+            .fetch_optional(&user_db)
+            .await;
+        if !matches!(exists, Ok(Some(_))) {
+            error!(
+                user,
+                ?dir,
+                "watch dir is for a user that doesn't exist, ignoring it"
+            );
+            continue;
+        }
+        let path = match tokio::fs::canonicalize(dir).await {
+            Ok(x) if x.is_dir() => x,
+            _ => {
+                error!(user, ?dir, "watch dir doesn't exist, ignoring it");
+                continue;
+            }
+        };
+        let idx = watcher.roots.len();
+        watcher.roots.push(Root {
+            user: user.clone(),
+            path: path.clone(),
+        });
+        // watched first, so nothing slips by between the rescan and the watch
+        watcher.watch_tree(idx, &path);
+        info!(user, ?path, "watching dir");
+        let root = &watcher.roots[idx];
+        rescan(
+            &user_db,
+            &music_dbs,
+            &waker,
+            &config,
+            root,
+            std::slice::from_ref(&root.path),
+            true,
+        )
+        .await;
+    }
+
+    let debounce = config.watch_debounce;
+    loop {
+        let next = watcher.next_quiet(debounce);
+        tokio::select! {
+            _ = shutdown.cancelled() => break,
+            ready = watcher.inotify.readable() => {
+                let mut guard = match ready {
+                    Ok(x) => x,
+                    Err(err) => {
+                        error!(?err, "while polling inotify");
+                        break;
+                    }
+                };
+                match guard.try_io(|x| x.get_ref().0.read_events().map_err(std::io::Error::from)) {
+                    Ok(Ok(events)) => watcher.handle_events(events),
+                    Ok(Err(err)) => error!(?err, "while reading inotify events"),
+                    // spurious wakeup, readiness is cleared
+                    Err(_) => {}
+                }
+            }
+            _ = sleep_until(next) => {
+                for (root, dirs) in watcher.take_quiet(debounce) {
+                    rescan(
+                        &user_db,
+                        &music_dbs,
+                        &waker,
+                        &config,
+                        &watcher.roots[root],
+                        &dirs,
+                        false,
+                    )
+                    .await;
+                }
+            }
+        }
+    }
+    debug!("watching stopped");
+}
+
+/// Sleep until `at`, or forever if there's nothing to wait for.
+async fn sleep_until(at: Option<Instant>) {
+    match at {
+        Some(at) => tokio::time::sleep_until(at.into()).await,
+        None => std::future::pending().await,
+    }
+}